@@ -1,27 +1,124 @@
-use sha1::Sha1;
+use crate::integrity::Integrity;
+use flate2::read::GzDecoder;
+use serde_json::{Map, Value};
 use sha2::{Digest, Sha256};
 use std::error::Error;
 use std::fs::create_dir_all;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+// Under the home directory unless `--cache`, `RNP_CACHE_DIR` or `rnp-cache=` say otherwise
 const CACHE_DIR: &str = ".rnp/cache";
+const METADATA_DIR: &str = "metadata";
+const INDEX_FILE: &str = "index.json";
+const INDEX_LOCK_FILE: &str = "index.lock";
+
+// Default budget for cached tarballs; override with `cache-max-size`
+pub const DEFAULT_MAX_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+// Cached registry documents younger than this are used without asking the
+// registry at all; override with `cache-min-time` (seconds)
+pub const DEFAULT_CACHE_MIN_TIME: Duration = Duration::from_secs(5 * 60);
+
+// Serializes read-modify-write cycles on the index between install tasks;
+// a lock on `index.lock` does the same between processes sharing the cache
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+// Cache hits not yet written to the index: (cache dir, key, accessed). An
+// install that hits the cache for every package would otherwise rewrite the
+// index once per package; these are folded into the next write instead.
+static PENDING_ACCESSES: Mutex<Vec<(PathBuf, String, u64)>> = Mutex::new(Vec::new());
+
+// A cached tarball; name and version come from the sidecar index and are
+// unknown for tarballs cached before the index existed
+pub struct CacheEntry {
+    pub key: String,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub size: u64,
+    // Seconds since the epoch; falls back to the file's mtime
+    pub accessed: u64,
+}
+
+// A cached tarball as read back
+pub enum CachedTarball {
+    Hit(Vec<u8>),
+    // Not cached, or too old to use
+    Miss,
+    // On disk but damaged (a hash mismatch, or a truncated gzip stream when
+    // no hash is known); the entry has already been evicted
+    Corrupt(String),
+}
+
+// A registry document as last served, with the ETag to revalidate it
+pub struct CachedMetadata {
+    pub etag: Option<String>,
+    pub document: Value,
+    // Seconds since the epoch of the last fetch or successful revalidation
+    pub fetched_at: u64,
+    // The registry that served it (relative tarball urls resolve against it)
+    pub registry: Option<String>,
+    // The response said `Cache-Control: no-cache`/`no-store`: always revalidate
+    pub no_cache: bool,
+}
+
+impl CachedMetadata {
+    // Young enough to use without even a conditional request
+    pub fn is_fresh(&self, min_time: Duration) -> bool {
+        let age = now_secs().saturating_sub(self.fetched_at);
+        !self.no_cache && self.fetched_at > 0 && age < min_time.as_secs()
+    }
+}
 
 pub struct PackageCache {
     cache_dir: PathBuf,
+    max_size: u64,
 }
 
-impl PackageCache {
-    pub fn new() -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let home_dir = dirs::home_dir().ok_or("Could not find home directory")?;
-        let cache_dir = home_dir.join(CACHE_DIR);
+// index.json: name, version and last access per cached tarball, plus their
+// combined size so saving a tarball doesn't have to list the whole cache
+#[derive(Default)]
+struct Index {
+    entries: Map<String, Value>,
+    // None until known (an empty cache, or an index from before it was kept)
+    total_size: Option<u64>,
+}
+
+// Both index locks, released on drop
+struct IndexGuard {
+    _file: std::fs::File,
+    _process: MutexGuard<'static, ()>,
+}
 
+impl PackageCache {
+    pub fn new(cache_dir: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
         // Create cache directory if it doesn't exist
         if !cache_dir.exists() {
-            create_dir_all(&cache_dir)?;
+            create_dir_all(cache_dir)
+                .map_err(|e| format!("Could not create cache directory {}: {}", cache_dir.display(), e))?;
         }
 
-        Ok(Self { cache_dir })
+        Ok(Self {
+            cache_dir: cache_dir.to_path_buf(),
+            max_size: DEFAULT_MAX_SIZE,
+        })
+    }
+
+    // `~/.rnp/cache`, or a temp directory when there is no home directory
+    pub fn default_dir() -> PathBuf {
+        dirs::home_dir()
+            .map(|home| home.join(CACHE_DIR))
+            .unwrap_or_else(|| std::env::temp_dir().join("rnp-cache"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
     }
 
     // Generate a cache key for a package
@@ -46,37 +143,310 @@ impl PackageCache {
         data: &[u8],
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let path = self.tarball_path(package_name, version);
+        let replaced = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
         std::fs::write(path, data)?;
+
+        let key = Self::cache_key(package_name, version);
+        self.update_index(|index| {
+            index.entries.insert(
+                key.clone(),
+                serde_json::json!({ "name": package_name, "version": version, "accessed": now_secs() }),
+            );
+            let total = match index.total_size {
+                Some(total) => total.saturating_sub(replaced) + data.len() as u64,
+                None => self.entries_from(&index.entries)?.iter().map(|entry| entry.size).sum(),
+            };
+            index.total_size = Some(total);
+            // Only an overfull cache is listed, to pick what to evict
+            if total > self.max_size {
+                self.evict_until(index, self.max_size, Some(&key))?;
+            }
+            Ok(())
+        })
+    }
+
+    // Drop least-recently-used tarballs until the cache fits in `max_size`.
+    // Returns how many tarballs were removed and how many bytes that freed.
+    pub fn prune(&self, max_size: u64) -> Result<(usize, u64), Box<dyn Error + Send + Sync>> {
+        self.update_index(|index| self.evict_until(index, max_size, None))
+    }
+
+    // Lists the cache, so the index's total is brought back in line with
+    // what is actually on disk
+    fn evict_until(
+        &self,
+        index: &mut Index,
+        max_size: u64,
+        keep: Option<&str>,
+    ) -> Result<(usize, u64), Box<dyn Error + Send + Sync>> {
+        let mut entries = self.entries_from(&index.entries)?;
+        let mut total = entries.iter().map(|entry| entry.size).sum::<u64>();
+        index.total_size = Some(total);
+        if total <= max_size {
+            return Ok((0, 0));
+        }
+
+        entries.sort_by_key(|entry| entry.accessed);
+        let mut removed = 0;
+        let mut freed = 0;
+        for entry in entries {
+            if total <= max_size {
+                break;
+            }
+            if keep == Some(entry.key.as_str()) {
+                continue;
+            }
+
+            // Another process may have evicted it already
+            match std::fs::remove_file(self.cache_dir.join(format!("{}.tgz", entry.key))) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            index.entries.remove(&entry.key);
+            total -= entry.size;
+            freed += entry.size;
+            removed += 1;
+        }
+
+        index.total_size = Some(total);
+        Ok((removed, freed))
+    }
+
+    // Every cached tarball, sorted by name@version (unknown entries last)
+    pub fn entries(&self) -> Result<Vec<CacheEntry>, Box<dyn Error + Send + Sync>> {
+        let mut entries = self.entries_from(&self.read_index().entries)?;
+        entries.sort_by(|a, b| {
+            (a.name.is_none(), &a.name, &a.version, &a.key).cmp(&(b.name.is_none(), &b.name, &b.version, &b.key))
+        });
+        Ok(entries)
+    }
+
+    fn entries_from(&self, index: &Map<String, Value>) -> Result<Vec<CacheEntry>, Box<dyn Error + Send + Sync>> {
+        let mut entries = Vec::new();
+
+        for entry in std::fs::read_dir(&self.cache_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("tgz") {
+                continue;
+            }
+            let Some(key) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let indexed = index.get(key);
+            let field = |name: &str| {
+                indexed
+                    .and_then(|v| v.get(name))
+                    .and_then(|v| v.as_str())
+                    .map(|v| v.to_string())
+            };
+            let accessed = indexed
+                .and_then(|v| v.get("accessed"))
+                .and_then(|v| v.as_u64())
+                .or_else(|| {
+                    metadata
+                        .modified()
+                        .ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map(|age| age.as_secs())
+                })
+                .unwrap_or(0);
+            entries.push(CacheEntry {
+                key: key.to_string(),
+                name: field("name"),
+                version: field("version"),
+                size: metadata.len(),
+                accessed,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.cache_dir.join(INDEX_FILE)
+    }
+
+    fn read_index(&self) -> Index {
+        let value = std::fs::read_to_string(self.index_path())
+            .ok()
+            .and_then(|data| serde_json::from_str::<Value>(&data).ok());
+        let Some(Value::Object(mut map)) = value else {
+            return Index::default();
+        };
+        match map.remove("entries") {
+            Some(Value::Object(entries)) => Index {
+                entries,
+                total_size: map.get("totalSize").and_then(|v| v.as_u64()),
+            },
+            // Older versions wrote the entries alone, without a total
+            _ => Index { entries: map, total_size: None },
+        }
+    }
+
+    // The index is rewritten through a temp file so readers never see half of it
+    fn write_index(&self, index: &Index) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let tmp_path = self.cache_dir.join(format!("{}.{}.tmp", INDEX_FILE, std::process::id()));
+        let data = serde_json::json!({ "totalSize": index.total_size, "entries": index.entries });
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&data)?)?;
+        std::fs::rename(tmp_path, self.index_path())?;
         Ok(())
     }
 
-    // Try to read a cache entry that is still fresh and valid
+    // One read-modify-write of the index under both locks, with this cache's
+    // pending accesses folded in
+    fn update_index<T>(
+        &self,
+        change: impl FnOnce(&mut Index) -> Result<T, Box<dyn Error + Send + Sync>>,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        let _guard = self.lock_index()?;
+        let mut index = self.read_index();
+        for (key, accessed) in self.take_pending_accesses() {
+            if let Some(Value::Object(entry)) = index.entries.get_mut(&key) {
+                entry.insert("accessed".to_string(), Value::from(accessed));
+            }
+        }
+        let result = change(&mut index)?;
+        self.write_index(&index)?;
+        Ok(result)
+    }
+
+    fn lock_index(&self) -> Result<IndexGuard, Box<dyn Error + Send + Sync>> {
+        let process = INDEX_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.cache_dir.join(INDEX_LOCK_FILE))?;
+        // Filesystems without locking (some network mounts) get the in-process lock only
+        match file.lock() {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(IndexGuard {
+            _file: file,
+            _process: process,
+        })
+    }
+
+    fn take_pending_accesses(&self) -> Vec<(String, u64)> {
+        let mut pending = PENDING_ACCESSES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (mine, others) = std::mem::take(&mut *pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(dir, _, _)| *dir == self.cache_dir);
+        *pending = others;
+        mine.into_iter().map(|(_, key, accessed)| (key, accessed)).collect()
+    }
+
+    // Write the access times of the cache hits since the last index write
+    pub fn flush_accesses(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let pending = PENDING_ACCESSES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !pending.iter().any(|(dir, _, _)| *dir == self.cache_dir) {
+            return Ok(());
+        }
+        drop(pending);
+        self.update_index(|_| Ok(()))
+    }
+
+    // Read a cache entry that is still fresh, checking it against `expected`
+    // (the lockfile's or the registry's hash). Damaged entries are evicted so
+    // the caller's fresh download replaces them.
     pub fn get_valid_tarball(
         &self,
         package_name: &str,
         version: &str,
-        expected_sha1: Option<&str>,
+        expected: Option<&Integrity>,
         max_age: Duration,
-    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    ) -> Result<CachedTarball, Box<dyn Error + Send + Sync>> {
         let path = self.tarball_path(package_name, version);
         if !path.exists() {
-            return Ok(None);
+            return Ok(CachedTarball::Miss);
         }
 
         if !Self::is_fresh(&path, max_age)? {
             self.invalidate_tarball(package_name, version)?;
-            return Ok(None);
+            return Ok(CachedTarball::Miss);
         }
 
-        let data = std::fs::read(&path)?;
-        if let Some(expected) = expected_sha1
-            && !Self::verify_sha1_checksum(&data, expected)
-        {
+        // Evicted by a concurrent install between the check and the read
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(CachedTarball::Miss),
+            Err(err) => return Err(err.into()),
+        };
+        let damage = match expected {
+            Some(expected) if !expected.matches(&data) => Some(format!("{} mismatch", expected.algorithm.name())),
+            Some(_) => None,
+            None if !is_complete_gzip(&data) => Some("truncated or not gzip".to_string()),
+            None => None,
+        };
+        if let Some(damage) = damage {
             self.invalidate_tarball(package_name, version)?;
-            return Ok(None);
+            return Ok(CachedTarball::Corrupt(damage));
+        }
+
+        let key = Self::cache_key(package_name, version);
+        PENDING_ACCESSES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push((self.cache_dir.clone(), key, now_secs()));
+
+        Ok(CachedTarball::Hit(data))
+    }
+
+    pub fn metadata_path(&self, package_name: &str) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(package_name);
+        let key = format!("{:x}", hasher.finalize());
+        self.cache_dir.join(METADATA_DIR).join(format!("{}.json", key))
+    }
+
+    // Unreadable or malformed entries count as a miss
+    pub fn get_metadata(&self, package_name: &str) -> Option<CachedMetadata> {
+        let data = std::fs::read_to_string(self.metadata_path(package_name)).ok()?;
+        let mut entry: Value = serde_json::from_str(&data).ok()?;
+        let etag = entry.get("etag").and_then(|v| v.as_str()).map(|v| v.to_string());
+        let registry = entry.get("registry").and_then(|v| v.as_str()).map(|v| v.to_string());
+        let fetched_at = entry.get("fetchedAt").and_then(|v| v.as_u64()).unwrap_or(0);
+        let no_cache = entry.get("noCache").and_then(|v| v.as_bool()).unwrap_or(false);
+        let document = entry.get_mut("document")?.take();
+        if !document.is_object() {
+            return None;
         }
+        Some(CachedMetadata {
+            etag,
+            document,
+            fetched_at,
+            registry,
+            no_cache,
+        })
+    }
 
-        Ok(Some(data))
+    // Stamps the entry with the current time
+    pub fn save_metadata(
+        &self,
+        package_name: &str,
+        metadata: &CachedMetadata,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = self.metadata_path(package_name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let entry = serde_json::json!({
+            "etag": metadata.etag,
+            "registry": metadata.registry,
+            "fetchedAt": now_secs(),
+            "noCache": metadata.no_cache,
+            "document": metadata.document,
+        });
+        std::fs::write(path, serde_json::to_vec(&entry)?)?;
+        Ok(())
     }
 
     // Remove a cached tarball if it exists
@@ -86,17 +456,19 @@ impl PackageCache {
         version: &str,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let path = self.tarball_path(package_name, version);
-        if path.exists() {
-            std::fs::remove_file(path)?;
-        }
-        Ok(())
-    }
+        let size = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        let removed = match std::fs::remove_file(path) {
+            Ok(()) => size,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
 
-    pub fn verify_sha1_checksum(data: &[u8], expected_sha1: &str) -> bool {
-        let mut hasher = Sha1::new();
-        hasher.update(data);
-        let actual = format!("{:x}", hasher.finalize());
-        actual.eq_ignore_ascii_case(expected_sha1)
+        let key = Self::cache_key(package_name, version);
+        self.update_index(|index| {
+            index.entries.remove(&key);
+            index.total_size = index.total_size.map(|total| total.saturating_sub(removed));
+            Ok(())
+        })
     }
 
     fn is_fresh(
@@ -108,3 +480,88 @@ impl PackageCache {
         Ok(age <= max_age)
     }
 }
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|age| age.as_secs())
+        .unwrap_or(0)
+}
+
+// Without a hash to compare, at least make sure the whole stream decodes
+fn is_complete_gzip(data: &[u8]) -> bool {
+    std::io::copy(&mut GzDecoder::new(data), &mut std::io::sink()).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip(size: usize) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::none());
+        std::io::Write::write_all(&mut encoder, &vec![b'x'; size]).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn set_accessed(cache: &PackageCache, name: &str, accessed: u64) {
+        let key = PackageCache::cache_key(name, "1.0.0");
+        cache
+            .update_index(|index| {
+                index.entries[&key]["accessed"] = Value::from(accessed);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn keeps_a_running_total_and_evicts_the_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let tarball = gzip(100);
+        let size = tarball.len() as u64;
+        let cache = PackageCache::new(dir.path()).unwrap().with_max_size(size * 2);
+
+        cache.save_tarball("old", "1.0.0", &tarball).unwrap();
+        cache.save_tarball("used", "1.0.0", &tarball).unwrap();
+        assert_eq!(cache.read_index().total_size, Some(size * 2));
+        set_accessed(&cache, "old", 1);
+        set_accessed(&cache, "used", 2);
+
+        cache.save_tarball("new", "1.0.0", &tarball).unwrap();
+        let names = cache.entries().unwrap().into_iter().filter_map(|entry| entry.name).collect::<Vec<_>>();
+        assert_eq!(names, ["new", "used"]);
+        assert_eq!(cache.read_index().total_size, Some(size * 2));
+
+        cache.invalidate_tarball("used", "1.0.0").unwrap();
+        assert_eq!(cache.read_index().total_size, Some(size));
+    }
+
+    #[test]
+    fn batches_access_times_until_flushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PackageCache::new(dir.path()).unwrap();
+        cache.save_tarball("pkg", "1.0.0", &gzip(10)).unwrap();
+        set_accessed(&cache, "pkg", 1);
+
+        let hit = cache.get_valid_tarball("pkg", "1.0.0", None, Duration::from_secs(60)).unwrap();
+        assert!(matches!(hit, CachedTarball::Hit(_)));
+        let accessed = || cache.entries().unwrap()[0].accessed;
+        assert_eq!(accessed(), 1);
+        cache.flush_accesses().unwrap();
+        assert!(accessed() > 1);
+    }
+
+    #[test]
+    fn reads_an_index_without_a_total() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PackageCache::new(dir.path()).unwrap();
+        let tarball = gzip(10);
+        std::fs::write(cache.tarball_path("pkg", "1.0.0"), &tarball).unwrap();
+        let key = PackageCache::cache_key("pkg", "1.0.0");
+        let legacy = serde_json::json!({ key: { "name": "pkg", "version": "1.0.0", "accessed": 5 } });
+        std::fs::write(dir.path().join(INDEX_FILE), legacy.to_string()).unwrap();
+
+        assert_eq!(cache.entries().unwrap()[0].name.as_deref(), Some("pkg"));
+        cache.save_tarball("other", "1.0.0", &tarball).unwrap();
+        assert_eq!(cache.read_index().total_size, Some(tarball.len() as u64 * 2));
+    }
+}
@@ -1,128 +1,391 @@
+use crate::commands::install::{
+    active_lockfile_path, load_installed_tree, load_locked_tree, within_depth,
+};
+use crate::manifest;
+use crate::output;
+use crate::registry::{DEFAULT_REGISTRY, Registries};
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressStyle};
 use reqwest;
 use semver::Version;
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
-pub async fn handle_audit_command_async() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let installed = load_installed_versions()?;
-    if installed.is_empty() {
+const AUDIT_BATCH_SIZE: usize = 100;
+const BULK_ADVISORY_PATH: &str = "/-/npm/v1/security/advisories/bulk";
+pub const DEFAULT_AUDIT_CONCURRENCY: usize = 4;
+
+const SEVERITY_LEVELS: [&str; 4] = ["low", "moderate", "high", "critical"];
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct AuditSummary {
+    pub total: usize,
+    pub critical: usize,
+    pub high: usize,
+    pub moderate: usize,
+    pub low: usize,
+}
+
+impl AuditSummary {
+    fn record(&mut self, severity: &str) {
+        self.total += 1;
+        match severity {
+            "critical" => self.critical += 1,
+            "high" => self.high += 1,
+            "moderate" => self.moderate += 1,
+            "low" => self.low += 1,
+            _ => {}
+        }
+    }
+
+    // Number of advisories at `level` or more severe
+    pub fn count_at_or_above(&self, level: &str) -> usize {
+        let threshold = SEVERITY_LEVELS.iter().position(|l| *l == level).unwrap_or(0);
+        [self.low, self.moderate, self.high, self.critical][threshold..]
+            .iter()
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditOptions {
+    pub prefix: PathBuf,
+    pub audit_level: Option<String>,
+    pub max_concurrency: usize,
+    pub depth: Option<usize>,
+    pub registries: Registries,
+    // Serves the bulk advisory API; npmjs.org unless `--audit-registry` says otherwise
+    pub audit_registry: Option<String>,
+    pub reporter: AuditReporter,
+}
+
+// How `rnp audit` prints its findings (`--reporter`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditReporter {
+    Human,
+    // One JSON document with every advisory and the summary
+    Json,
+    // One JSON object per line: each advisory as soon as its batch is
+    // answered, then the summary
+    Ndjson,
+}
+
+impl AuditReporter {
+    pub const NAMES: [&str; 3] = ["human", "json", "ndjson"];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(format!("invalid reporter '{}': expected one of {}", value, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+// One advisory as the json and ndjson reporters print it: the registry's
+// advisory fields plus the package and the version audited
+#[derive(Serialize)]
+struct AdvisoryRecord<'a> {
+    package: &'a str,
+    version: Option<&'a str>,
+    #[serde(flatten)]
+    advisory: &'a Map<String, Value>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AuditEvent<'a> {
+    Advisory(&'a AdvisoryRecord<'a>),
+    Summary(&'a AuditSummary),
+}
+
+pub async fn handle_audit_command_async(options: &AuditOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let installed = load_installed_versions(&options.prefix, options.depth)?;
+    if installed.is_empty() && options.reporter == AuditReporter::Human {
         println!("No installed dependencies found to audit.");
         return Ok(());
     }
 
-    let payload: HashMap<String, Vec<String>> = installed
-        .iter()
-        .map(|(name, version)| (name.clone(), vec![version.clone()]))
-        .collect();
-
-    let client = reqwest::Client::new();
-    let response = client
-        .post("https://registry.npmjs.org/-/npm/v1/security/advisories/bulk")
-        .json(&payload)
-        .send()
+    let mut summary = AuditSummary::default();
+    let mut map = Map::new();
+    if !installed.is_empty() {
+        let streaming = options.reporter == AuditReporter::Ndjson;
+        fetch_advisories(
+            &installed,
+            options.max_concurrency,
+            options.reporter == AuditReporter::Human,
+            &options.registries,
+            options.audit_registry.as_deref(),
+            |batch| {
+                if !streaming {
+                    map.extend(batch);
+                    return Ok(());
+                }
+                for record in advisory_records(&batch, &installed) {
+                    summary.record(record.severity());
+                    println!("{}", serde_json::to_string(&AuditEvent::Advisory(&record))?);
+                }
+                Ok(())
+            },
+        )
         .await?;
+    }
 
-    if !response.status().is_success() {
-        return Err(format!("Audit request failed: {}", response.status()).into());
+    // Empty for ndjson, which printed and counted each batch as it came
+    let records = advisory_records(&map, &installed);
+    for record in &records {
+        summary.record(record.severity());
     }
 
-    let advisories: Value = response.json().await?;
-    let Some(map) = advisories.as_object() else {
-        println!("No advisories found.");
-        return Ok(());
-    };
+    match options.reporter {
+        AuditReporter::Human => print_human(&records, &summary),
+        AuditReporter::Json => {
+            let report = serde_json::json!({ "advisories": records, "summary": summary });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        AuditReporter::Ndjson => println!("{}", serde_json::to_string(&AuditEvent::Summary(&summary))?),
+    }
+
+    // The exit code depends on --audit-level alone, whichever reporter printed
+    check_audit_level(&summary, options.audit_level.as_deref())
+}
 
-    if map.is_empty() {
+impl AdvisoryRecord<'_> {
+    fn severity(&self) -> &str {
+        self.advisory.get("severity").and_then(|v| v.as_str()).unwrap_or("unknown")
+    }
+}
+
+// The advisories in a bulk response, each with the version that was audited
+fn advisory_records<'a>(
+    map: &'a Map<String, Value>,
+    installed: &'a HashMap<String, String>,
+) -> Vec<AdvisoryRecord<'a>> {
+    let mut records = Vec::new();
+    for (pkg, entries) in map {
+        for advisory in entries.as_array().into_iter().flatten() {
+            let Some(advisory) = advisory.as_object() else {
+                continue;
+            };
+            records.push(AdvisoryRecord {
+                package: pkg,
+                version: installed.get(pkg).map(|v| v.as_str()),
+                advisory,
+            });
+        }
+    }
+    records
+}
+
+fn print_human(records: &[AdvisoryRecord], summary: &AuditSummary) {
+    if records.is_empty() {
         println!("No known vulnerabilities found.");
+        return;
+    }
+
+    println!("Security advisories detected:\n");
+    for record in records {
+        let field = |key: &str, default: &'static str| {
+            record.advisory.get(key).and_then(|v| v.as_str()).unwrap_or(default).to_string()
+        };
+        println!("- {} [{}]", record.package.bold(), output::severity(&field("severity", "unknown")));
+        println!("  {}", field("title", "Unknown advisory"));
+        println!("  {}", field("url", "N/A"));
+    }
+
+    println!("\nSummary:");
+    println!("- total: {}", summary.total);
+    println!("- {}: {}", output::severity("critical"), summary.critical);
+    println!("- {}: {}", output::severity("high"), summary.high);
+    println!("- {}: {}", output::severity("moderate"), summary.moderate);
+    println!("- {}: {}", output::severity("low"), summary.low);
+}
+
+// Audit an already-known set of `name -> version` pairs, e.g. a freshly resolved tree
+pub async fn audit_versions(
+    installed: &HashMap<String, String>,
+    registries: &Registries,
+    audit_registry: Option<&str>,
+) -> Result<AuditSummary, Box<dyn Error + Send + Sync>> {
+    let mut summary = AuditSummary::default();
+    if installed.is_empty() {
+        return Ok(summary);
+    }
+
+    fetch_advisories(installed, DEFAULT_AUDIT_CONCURRENCY, false, registries, audit_registry, |batch| {
+        for record in advisory_records(&batch, installed) {
+            summary.record(record.severity());
+        }
+        Ok(())
+    })
+    .await?;
+
+    Ok(summary)
+}
+
+pub fn check_audit_level(
+    summary: &AuditSummary,
+    audit_level: Option<&str>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(level) = audit_level else {
         return Ok(());
+    };
+
+    let count = summary.count_at_or_above(level);
+    if count > 0 {
+        return Err(format!("found {} vulnerabilities at or above '{}' severity", count, level).into());
     }
+    Ok(())
+}
 
-    let mut total = 0usize;
-    let mut critical = 0usize;
-    let mut high = 0usize;
-    let mut moderate = 0usize;
-    let mut low = 0usize;
+// Splits the request into batches that run concurrently behind a semaphore,
+// handing each batch's advisories to `on_batch` as soon as it is answered
+async fn fetch_advisories(
+    installed: &HashMap<String, String>,
+    max_concurrency: usize,
+    show_progress: bool,
+    registries: &Registries,
+    audit_registry: Option<&str>,
+    mut on_batch: impl FnMut(Map<String, Value>) -> Result<(), Box<dyn Error + Send + Sync>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let endpoint = Arc::new(bulk_advisory_url(audit_registry));
+    let mut entries = installed.iter().collect::<Vec<_>>();
+    entries.sort();
+    let batches = entries
+        .chunks(AUDIT_BATCH_SIZE)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|(name, version)| ((*name).clone(), vec![(*version).clone()]))
+                .collect::<HashMap<String, Vec<String>>>()
+        })
+        .collect::<Vec<_>>();
 
-    println!("Security advisories detected:\n");
-    for (pkg, entries) in map {
-        if let Some(list) = entries.as_array() {
-            for advisory in list {
-                total += 1;
-                let title = advisory
-                    .get("title")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("Unknown advisory");
-                let severity = advisory
-                    .get("severity")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("unknown");
-                let url = advisory
-                    .get("url")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("N/A");
-
-                match severity {
-                    "critical" => critical += 1,
-                    "high" => high += 1,
-                    "moderate" => moderate += 1,
-                    "low" => low += 1,
-                    _ => {}
-                }
+    let progress = if show_progress && batches.len() > 1 {
+        let pb = ProgressBar::new(batches.len() as u64);
+        pb.set_style(
+            ProgressStyle::with_template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} {msg}")
+                .unwrap()
+                .progress_chars("##-"),
+        );
+        pb.set_message("auditing batches");
+        Some(pb)
+    } else {
+        None
+    };
 
-                println!("- {} [{}]", pkg, severity);
-                println!("  {}", title);
-                println!("  {}", url);
+    let client = Arc::new(registries.client());
+    let registries = Arc::new(registries.clone());
+    let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for payload in batches {
+        let client = Arc::clone(&client);
+        let registries = Arc::clone(&registries);
+        let endpoint = Arc::clone(&endpoint);
+        let semaphore = Arc::clone(&semaphore);
+        let progress = progress.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            let result = fetch_advisory_batch(&client, &registries, &endpoint, &payload).await;
+            // Counted when the request completes, not when its result is collected
+            if let Some(pb) = &progress {
+                pb.inc(1);
             }
+            result
+        });
+    }
+
+    // In the order the batches finish, not the order they were sent
+    while let Some(result) = tasks.join_next().await {
+        if let Value::Object(map) = result?? {
+            on_batch(map)?;
         }
     }
 
-    println!("\nSummary:");
-    println!("- total: {}", total);
-    println!("- critical: {}", critical);
-    println!("- high: {}", high);
-    println!("- moderate: {}", moderate);
-    println!("- low: {}", low);
+    if let Some(pb) = &progress {
+        pb.finish_with_message("done");
+    }
 
     Ok(())
 }
 
-fn load_installed_versions() -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
-    if Path::new("package-lock.json").exists() {
-        return load_versions_from_lockfile();
+// `audit-registry` may be a registry root or the full bulk endpoint
+fn bulk_advisory_url(audit_registry: Option<&str>) -> String {
+    let base = audit_registry.unwrap_or(DEFAULT_REGISTRY).trim_end_matches('/');
+    if base.ends_with(BULK_ADVISORY_PATH) {
+        base.to_string()
+    } else {
+        format!("{}{}", base, BULK_ADVISORY_PATH)
     }
+}
+
+// The bulk API answers with `{ "<package>": [advisory, ...] }`; anything else
+// means the endpoint is not an advisory service
+async fn fetch_advisory_batch(
+    client: &reqwest::Client,
+    registries: &Registries,
+    endpoint: &str,
+    payload: &HashMap<String, Vec<String>>,
+) -> Result<Value, Box<dyn Error + Send + Sync>> {
+    let response = registries
+        .post_json(client, endpoint, payload)
+        .await
+        .map_err(|e| format!("Audit request to {} failed: {}", endpoint, e))?;
 
-    if Path::new("package.json").exists() {
-        return load_versions_from_manifest();
+    if !response.status().is_success() {
+        return Err(format!("Audit request to {} failed: {}", endpoint, response.status()).into());
     }
 
-    Ok(HashMap::new())
+    let body = response.text().await?;
+    let advisories = serde_json::from_str::<Value>(&body)
+        .ok()
+        .filter(|json| json.as_object().is_some_and(|map| map.values().all(Value::is_array)));
+    advisories.ok_or_else(|| {
+        format!(
+            "{} did not return a bulk advisory response (expected a JSON object of package -> advisories)",
+            endpoint
+        )
+        .into()
+    })
 }
 
-fn load_versions_from_lockfile() -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
-    let data = fs::read_to_string("package-lock.json")?;
-    let json: Value = serde_json::from_str(&data)?;
-    let mut versions = HashMap::new();
+fn load_installed_versions(
+    root: &Path,
+    max_depth: Option<usize>,
+) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    // What is actually installed beats what the lockfile says should be
+    let tree = match load_installed_tree(root)? {
+        Some(tree) => Some(tree),
+        None if active_lockfile_path(root).exists() => Some(load_locked_tree(root)?),
+        None => None,
+    };
+    if let Some(tree) = tree {
+        return Ok(tree
+            .into_iter()
+            .filter(|p| !p.info.is_workspace && within_depth(p, max_depth))
+            .map(|p| (p.info.name, p.info.version.to_string()))
+            .collect());
+    }
 
-    if let Some(packages) = json.get("packages").and_then(|v| v.as_object()) {
-        for (name, info) in packages {
-            let Some(version) = info.get("version").and_then(|v| v.as_str()) else {
-                continue;
-            };
-            if Version::parse(version).is_ok() {
-                versions.insert(name.clone(), version.to_string());
-            }
-        }
+    let manifest_path = root.join("package.json");
+    if manifest_path.exists() {
+        return load_versions_from_manifest(&manifest_path);
     }
 
-    Ok(versions)
+    Ok(HashMap::new())
 }
 
-fn load_versions_from_manifest() -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
-    let data = fs::read_to_string("package.json")?;
-    let json: Value = serde_json::from_str(&data)?;
+fn load_versions_from_manifest(path: &Path) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    let data = fs::read_to_string(path)?;
+    let json = manifest::parse(&data)?;
     let mut versions = HashMap::new();
 
     if let Some(deps) = json.get("dependencies").and_then(|v| v.as_object()) {
@@ -139,3 +402,40 @@ fn load_versions_from_manifest() -> Result<HashMap<String, String>, Box<dyn Erro
 
     Ok(versions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_registry::{Reply, TestRegistry};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn each_batch_is_handed_over_as_it_is_answered() {
+        let registry = TestRegistry::start();
+        let advisory = json!({ "severity": "high", "title": "bad" });
+        registry.route(
+            BULK_ADVISORY_PATH,
+            vec![
+                Reply::json(&json!({ "pkg-000": [advisory] })),
+                Reply::json(&json!({ "pkg-149": [advisory, advisory] })),
+            ],
+        );
+        let registries = Registries::from_config(&Config::default(), Some(registry.url()), &[]);
+        let installed = (0..150).map(|i| (format!("pkg-{:03}", i), "1.0.0".to_string())).collect::<HashMap<_, _>>();
+
+        let mut batches = Vec::new();
+        fetch_advisories(&installed, 1, false, &registries, Some(registry.url()), |batch| {
+            batches.push(advisory_records(&batch, &installed).len());
+            Ok(())
+        })
+        .await
+        .unwrap();
+        batches.sort();
+        assert_eq!(batches, [1, 2]);
+        assert_eq!(registry.hits(BULK_ADVISORY_PATH), 2);
+
+        let summary = audit_versions(&installed, &registries, Some(registry.url())).await.unwrap();
+        assert_eq!(summary.high, 4);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::cache::PackageCache;
+use crate::commands::install::{InstallOptions, add_to_cache};
+use std::error::Error;
+use std::path::Path;
+
+pub fn handle_cache_ls_command(cache_dir: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cache = PackageCache::new(cache_dir)?;
+    let entries = cache.entries()?;
+
+    if entries.is_empty() {
+        println!("Cache is empty");
+        return Ok(());
+    }
+
+    let mut total = 0;
+    for entry in &entries {
+        let label = match (&entry.name, &entry.version) {
+            (Some(name), Some(version)) => format!("{}@{}", name, version),
+            _ => format!("<unknown> ({})", &entry.key[..12.min(entry.key.len())]),
+        };
+        println!("{:<50} {:>10}", label, format_size(entry.size));
+        total += entry.size;
+    }
+
+    println!();
+    println!("{} tarball(s), {} total", entries.len(), format_size(total));
+    Ok(())
+}
+
+// Resolve a package (with `--deps`, its whole tree) and store the tarballs
+// that aren't cached yet, so later installs skip the download
+pub async fn handle_cache_add_command_async(
+    spec: &str,
+    with_deps: bool,
+    options: &InstallOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (added, bytes) = add_to_cache(spec, with_deps, options).await?;
+    println!("Added {} tarball(s), {}", added, format_size(bytes));
+    Ok(())
+}
+
+// Without `--max-size` every cached tarball is removed
+pub fn handle_cache_clean_command(cache_dir: &Path, max_size: Option<u64>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cache = PackageCache::new(cache_dir)?;
+    let (removed, freed) = cache.prune(max_size.unwrap_or(0))?;
+    println!("Removed {} tarball(s), freed {}", removed, format_size(freed));
+    Ok(())
+}
+
+// Accepts plain bytes or a KB/MB/GB suffix (binary units), e.g. `500MB`
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let upper = trimmed.to_ascii_uppercase();
+    let split = upper.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(upper.len());
+    let (number, unit) = upper.split_at(split);
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size '{}': expected e.g. 500MB or 2GB", trimmed)),
+    };
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("invalid size '{}': expected e.g. 500MB or 2GB", trimmed))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
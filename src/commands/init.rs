@@ -1,9 +1,11 @@
 use serde::Serialize;
 use serde_json::{Map, Value};
 use std::env;
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Write;
+use std::path::Path;
 
 #[derive(Serialize, Debug)]
 struct Repository {
@@ -48,8 +50,40 @@ fn prompt(field: &str, default: &str) -> String {
     }
 }
 
-pub fn handle_init(yes: bool) {
-    let current_dir = env::current_dir().unwrap();
+const GITIGNORE_ENTRIES: [&str; 2] = ["node_modules/", "*.log"];
+
+// Appends whatever of GITIGNORE_ENTRIES the project's .gitignore is missing,
+// creating the file if needed. Returns false when there was nothing to add.
+fn ensure_gitignore(root: &Path) -> io::Result<bool> {
+    let path = root.join(".gitignore");
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let present = existing
+        .lines()
+        .map(|line| line.trim().trim_start_matches('/').trim_end_matches('/'))
+        .collect::<Vec<_>>();
+
+    let missing = GITIGNORE_ENTRIES
+        .iter()
+        .filter(|entry| !present.contains(&entry.trim_end_matches('/')))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(false);
+    }
+
+    let mut contents = existing;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    for entry in missing {
+        contents.push_str(entry);
+        contents.push('\n');
+    }
+    fs::write(&path, contents)?;
+    Ok(true)
+}
+
+pub fn handle_init(root: &Path, yes: bool) {
+    let current_dir = env::current_dir().unwrap().join(root);
     let folder_name = current_dir
         .file_name()
         .unwrap()
@@ -130,9 +164,21 @@ pub fn handle_init(yes: bool) {
 
     let json = serde_json::to_string_pretty(&pkg).unwrap();
 
-    let mut file = File::create("package.json").unwrap();
+    let mut file = File::create(root.join("package.json")).unwrap();
     file.write_all(json.as_bytes()).unwrap();
 
     println!("initialized package.json to {}\n", current_dir.display());
     println!("{}\n", json);
+
+    let write_gitignore = yes || {
+        let answer = prompt("add node_modules/ to .gitignore? (y/n)", "y");
+        answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+    };
+    if write_gitignore {
+        match ensure_gitignore(root) {
+            Ok(true) => println!("updated .gitignore"),
+            Ok(false) => {},
+            Err(e) => eprintln!("Failed to write .gitignore: {}", e),
+        }
+    }
 }
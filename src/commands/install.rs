@@ -1,23 +1,37 @@
 use reqwest;
+use reqwest::StatusCode;
+use reqwest::header::{ACCEPT_ENCODING, CACHE_CONTROL, ETAG, HeaderMap, HeaderValue, IF_NONE_MATCH};
 use base64::{Engine as _, engine::general_purpose::STANDARD};
 use colored::Colorize;
 use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use semver::{Version, VersionReq};
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::sync::Arc;
-use crate::cache::PackageCache;
+use std::sync::{Arc, OnceLock};
+use crate::cache::{CachedMetadata, CachedTarball, DEFAULT_CACHE_MIN_TIME, DEFAULT_MAX_SIZE, PackageCache};
+use crate::git::{self, GitSpec};
+use crate::integrity::Integrity;
+use crate::manifest;
+use crate::output::{self, LogLevel};
+use crate::packlist::package_files;
+use crate::registry::{self, Registries};
+use crate::signatures::{SigningKey, fetch_registry_keys, verify_version};
+use crate::throttle::RateLimiter;
+use crate::workspaces::workspace_members;
+use crate::commands::audit::{audit_versions, check_audit_level};
+use crate::commands::cache::format_size;
+use crate::commands::prune::remove_dangling_bin_links;
 use tokio::sync::Semaphore;
 use tar;
 use flate2;
 use std::fs;
-use std::path::Path;
-use std::io::Cursor;
+use std::path::{Component, Path};
+use std::io::{Cursor, Read};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 use sha2::{Digest, Sha512};
@@ -25,37 +39,201 @@ use sha2::{Digest, Sha512};
 #[derive(Debug, Clone)]
 pub struct InstallOptions {
     pub no_package_lock: bool,
-    pub verbose: bool,
-    pub quiet: bool,
+    pub no_save: bool,
+    pub refresh_lockfile: bool,
+    pub force: bool,
+    pub log_level: LogLevel,
     pub ignore_scripts: bool,
     pub workspace: Option<String>,
     pub hoist_strategy: String,
+    pub prefix: PathBuf,
+    pub omit: HashSet<String>,
+    pub audit: bool,
+    pub audit_level: Option<String>,
+    // Mention installed packages that ask for funding (`fund`, `--no-fund`)
+    pub fund: bool,
+    pub offline_dir: Option<PathBuf>,
+    pub engine_strict: bool,
+    pub registries: Registries,
+    pub json: bool,
+    // Newline-delimited progress events on stdout (`--json-stream`)
+    pub json_stream: bool,
+    pub legacy_peer_deps: bool,
+    pub cache_max_size: u64,
+    pub save_section: String,
+    // Prepended to the resolved version in package.json (`save-prefix`)
+    pub save_prefix: String,
+    // Also list saved packages in `bundledDependencies` (`--save-bundle`)
+    pub save_bundle: bool,
+    // Install the version this dist-tag points at (`--tag next`)
+    pub tag: Option<String>,
+    // Only consider versions published at or before this date (`--before`)
+    pub before: Option<String>,
+    pub cache_dir: PathBuf,
+    // Ignore the range package.json already has and take the newest version
+    pub latest: bool,
+    // How long cached registry metadata is used without revalidating
+    pub cache_min_time: Duration,
+    // Cap on the combined tarball download rate, in bytes per second
+    pub max_rate: Option<u64>,
+    // Where `--audit` sends the bulk advisory request (`audit-registry`)
+    pub audit_registry: Option<String>,
+    // Copy `file:`/`link:` directory dependencies instead of symlinking them
+    pub install_links: bool,
+    // npm 2's fully nested layout instead of hoisting (`--legacy-bundling`)
+    pub legacy_bundling: bool,
+    // Print the consolidated diagnostics after the summary (`--report`)
+    pub report: bool,
+    // Upper bound on the whole resolve phase; None waits as long as it takes
+    pub resolve_timeout: Option<Duration>,
+    // Settle on one version satisfying every requirer of a package where one
+    // exists, instead of the first match (`--dedupe-on-install`)
+    pub dedupe_on_install: bool,
+    // Check the registry's signature on every version resolved
+    // (`--verify-signatures`), trusting `signature_keys` besides the keys
+    // each registry publishes
+    pub verify_signatures: bool,
+    pub signature_keys: Vec<SigningKey>,
+    // Check packages' `os`/`cpu` fields against this platform instead of the
+    // machine's (`--os`, `--cpu`), to install a tree for another target
+    pub os: Option<String>,
+    pub cpu: Option<String>,
+}
+
+pub const MAX_CONCURRENT_DOWNLOADS: usize = 15;
+pub const DEFAULT_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// Resolves `--dedupe-on-install` runs at most before keeping what it has
+const MAX_DEDUPE_PASSES: usize = 5;
+// How many entries the report's slowest-packages list keeps
+const SLOWEST_PACKAGES: usize = 5;
+
+// The package.json sections `install` can record a dependency in
+pub const SAVE_SECTIONS: [&str; 4] = [
+    "dependencies",
+    "devDependencies",
+    "optionalDependencies",
+    "peerDependencies",
+];
+
+impl Default for InstallOptions {
+    fn default() -> Self {
+        Self {
+            no_package_lock: false,
+            no_save: false,
+            refresh_lockfile: false,
+            force: false,
+            log_level: LogLevel::Info,
+            ignore_scripts: false,
+            workspace: None,
+            hoist_strategy: "safe".to_string(),
+            prefix: PathBuf::new(),
+            omit: HashSet::new(),
+            audit: false,
+            audit_level: None,
+            fund: true,
+            offline_dir: None,
+            engine_strict: false,
+            registries: Registries::default(),
+            json: false,
+            json_stream: false,
+            legacy_peer_deps: false,
+            cache_max_size: DEFAULT_MAX_SIZE,
+            save_section: "dependencies".to_string(),
+            save_prefix: "^".to_string(),
+            save_bundle: false,
+            tag: None,
+            before: None,
+            cache_dir: PackageCache::default_dir(),
+            latest: false,
+            cache_min_time: DEFAULT_CACHE_MIN_TIME,
+            max_rate: None,
+            audit_registry: None,
+            install_links: false,
+            legacy_bundling: false,
+            report: false,
+            resolve_timeout: Some(DEFAULT_RESOLVE_TIMEOUT),
+            dedupe_on_install: false,
+            verify_signatures: false,
+            signature_keys: Vec::new(),
+            os: None,
+            cpu: None,
+        }
+    }
 }
 
 impl InstallOptions {
+    fn omits(&self, kind: &str) -> bool {
+        self.omit.contains(kind)
+    }
+
+    // --legacy-peer-deps: behave like npm 6 and ignore peers entirely
+    fn skips_peers(&self) -> bool {
+        self.omits("peer") || self.legacy_peer_deps
+    }
+
+    fn node_modules_dir(&self) -> PathBuf {
+        self.prefix.join("node_modules")
+    }
+
+    fn target_os(&self) -> &str {
+        self.os.as_deref().unwrap_or(current_node_os())
+    }
+
+    fn target_cpu(&self) -> &str {
+        self.cpu.as_deref().unwrap_or(current_node_cpu())
+    }
+
+    // Below `info` only warnings and errors are printed
+    pub fn quiet(&self) -> bool {
+        self.log_level < LogLevel::Info
+    }
+
     fn info(&self, message: &str) {
-        if !self.quiet {
+        if self.log_level >= LogLevel::Info {
             println!("{}", message.cyan());
         }
     }
 
     fn success(&self, message: &str) {
-        if !self.quiet {
+        if self.log_level >= LogLevel::Info {
             println!("{}", message.green());
         }
     }
 
     fn warn(&self, message: &str) {
-        if !self.quiet {
+        if self.log_level >= LogLevel::Warn {
             eprintln!("{}", message.yellow());
         }
     }
 
     fn debug(&self, message: &str) {
-        if self.verbose && !self.quiet {
+        if self.log_level >= LogLevel::Verbose {
             println!("{}", message.dimmed());
         }
     }
+
+    // One line per event; println! holds the stdout lock for the whole line,
+    // so events from concurrent downloads never interleave
+    pub fn emit(&self, event: InstallEvent) {
+        if self.json_stream
+            && let Ok(line) = serde_json::to_string(&event)
+        {
+            println!("{}", line);
+        }
+    }
+}
+
+// What `install --json-stream` prints as it happens, for editors and other
+// tools wrapping rnp. `done` carries the same report `--json` prints.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum InstallEvent<'a> {
+    Resolving { name: &'a str, requirement: String },
+    Resolved { name: &'a str, version: String, depth: usize },
+    Downloading { name: &'a str, version: String },
+    Extracted { name: &'a str, version: String },
+    Done(&'a InstallReport),
+    Error { message: String },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,6 +247,15 @@ struct PackageLock {
     dependencies: BTreeMap<String, String>,
     #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
     workspace_paths: BTreeMap<String, String>,
+    // Direct dependencies installed with `--tag`, so `update` follows the tag
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    dist_tags: BTreeMap<String, String>,
+    // Hidden lockfile only: what the last full `rnp install` was run against
+    // (see `manifest_hash`), so an unchanged project can skip resolving.
+    // snake_case like the other fields npm doesn't know; a hidden lockfile
+    // written with `manifestHash` just costs one resolve.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    manifest_hash: Option<String>,
     packages: BTreeMap<String, LockfilePackage>,
 }
 
@@ -82,26 +269,66 @@ struct LockfilePackage {
     dependencies: BTreeMap<String, String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     shasum: Option<String>,
+    // Only reached through optionalDependencies; with its `os`/`cpu` fields
+    // this lets a locked install skip it on other platforms (or `--os`/`--cpu`
+    // targets) the way a resolving install does
+    #[serde(skip_serializing_if = "is_false", default)]
+    optional: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    os: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    cpu: Vec<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[derive(Debug, Clone)]
 pub struct NpmVersionReq {
     raw: String,
-    clauses: Vec<VersionReq>,
+    clauses: Vec<NpmClause>,
+}
+
+// One `||`-separated alternative: every comparator must hold and the
+// version must not be one of the `!=` exclusions
+#[derive(Debug, Clone)]
+struct NpmClause {
+    req: VersionReq,
+    excluded: Vec<Version>,
 }
 
 impl NpmVersionReq {
-    fn parse(input: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+    pub fn parse(input: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let raw = if input.trim().is_empty() { "*" } else { input.trim() }.to_string();
         let mut clauses = Vec::new();
 
+        // A git dependency or local package accepts whatever version it contains
+        if GitSpec::parse(&raw).is_some() || local_tarball_path(&raw).is_some() || local_directory_path(&raw).is_some() {
+            clauses.push(NpmClause {
+                req: VersionReq::STAR,
+                excluded: Vec::new(),
+            });
+            return Ok(Self { raw, clauses });
+        }
+
         for clause in raw.split("||") {
-            let normalized = normalize_npm_clause(clause.trim());
-            clauses.push(VersionReq::parse(&normalized)?);
+            let (normalized, excluded) = normalize_npm_clause(clause.trim())?;
+            let excluded = excluded
+                .iter()
+                .map(|v| Version::parse(v))
+                .collect::<Result<Vec<_>, _>>()?;
+            clauses.push(NpmClause {
+                req: VersionReq::parse(&normalized)?,
+                excluded,
+            });
         }
 
         if clauses.is_empty() {
-            clauses.push(VersionReq::parse("*")?);
+            clauses.push(NpmClause {
+                req: VersionReq::parse("*")?,
+                excluded: Vec::new(),
+            });
         }
 
         Ok(Self { raw, clauses })
@@ -111,13 +338,99 @@ impl NpmVersionReq {
         Self::parse("*")
     }
 
-    fn matches(&self, version: &Version) -> bool {
-        self.clauses.iter().any(|req| req.matches(version))
+    pub fn matches(&self, version: &Version) -> bool {
+        self.clauses
+            .iter()
+            .any(|clause| clause.req.matches(version) && !clause.excluded.contains(version))
     }
 
-    fn display(&self) -> String {
+    pub fn display(&self) -> String {
         self.raw.clone()
     }
+
+    pub fn git_source(&self) -> Option<GitSpec> {
+        GitSpec::parse(&self.raw)
+    }
+
+    pub fn local_tarball(&self) -> Option<&str> {
+        local_tarball_path(&self.raw)
+    }
+
+    pub fn local_directory(&self) -> Option<&str> {
+        local_directory_path(&self.raw)
+    }
+
+    // Git, `file:` and `link:` specs name a source rather than a version range
+    pub fn is_source_spec(&self) -> bool {
+        self.git_source().is_some() || self.local_tarball().is_some() || self.local_directory().is_some()
+    }
+}
+
+// The path in a `file:` spec that points at a packed tarball
+fn local_tarball_path(spec: &str) -> Option<&str> {
+    let path = spec.strip_prefix("file:")?;
+    (path.ends_with(".tgz") || path.ends_with(".tar.gz")).then_some(path)
+}
+
+// The path in a `file:` or `link:` spec that points at a package directory
+fn local_directory_path(spec: &str) -> Option<&str> {
+    if local_tarball_path(spec).is_some() {
+        return None;
+    }
+    spec.strip_prefix("file:").or_else(|| spec.strip_prefix("link:"))
+}
+
+// A command-line argument naming a local tarball, as the `file:` spec to save:
+// the path from the project root (`file:../pkg.tgz`), however the cwd and
+// --prefix differ, so package.json stays valid wherever the project is
+// checked out. A tarball that doesn't exist is left for reading it to report.
+fn local_tarball_argument(
+    argument: &str,
+    root: &Path,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let path = argument.strip_prefix("file:").unwrap_or(argument);
+    if local_tarball_path(&format!("file:{}", path)).is_none() {
+        return Ok(None);
+    }
+    let root = if root.as_os_str().is_empty() { Path::new(".") } else { root };
+    let (Ok(tarball), Ok(root)) = (fs::canonicalize(path), fs::canonicalize(root)) else {
+        return Ok(Some(format!("file:{}", path)));
+    };
+    Ok(Some(match relative_path(&tarball, &root) {
+        Some(relative) => format!("file:{}", relative),
+        None => format!("file:{}", tarball.display()),
+    }))
+}
+
+// `path` as seen from `base` (both canonical), with `/` separators and a
+// leading `./` or `../`; None when they share no root (another drive)
+fn relative_path(path: &Path, base: &Path) -> Option<String> {
+    let path = path.components().collect::<Vec<_>>();
+    let base = base.components().collect::<Vec<_>>();
+    if path.first() != base.first() {
+        return None;
+    }
+    let shared = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let parents = base.len() - shared;
+    let mut parts = if parents == 0 { vec![".".to_string()] } else { vec!["..".to_string(); parents] };
+    parts.extend(path[shared..].iter().map(|part| part.as_os_str().to_string_lossy().into_owned()));
+    Some(parts.join("/"))
+}
+
+// package.json from inside a packed tarball (`package/package.json`, whatever
+// the top-level folder is called)
+fn read_tarball_manifest(bytes: &[u8]) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(Cursor::new(bytes)));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw_path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        if tarball_entry_path(&raw_path, false).is_ok_and(|path| path == Some(PathBuf::from("package.json"))) {
+            let mut data = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut data)?;
+            return Ok(manifest::parse(&data)?);
+        }
+    }
+    Err("tarball has no package.json".into())
 }
 
 impl std::fmt::Display for NpmVersionReq {
@@ -126,45 +439,89 @@ impl std::fmt::Display for NpmVersionReq {
     }
 }
 
-fn normalize_npm_clause(clause: &str) -> String {
-    if clause.is_empty() || clause == "*" {
-        return "*".to_string();
-    }
+fn is_comparator_operator(c: char) -> bool {
+    matches!(c, '<' | '>' | '=' | '!' | '^' | '~')
+}
+
+fn is_wildcard_part(part: &str) -> bool {
+    part == "*" || part.eq_ignore_ascii_case("x")
+}
 
-    if let Some((start, end)) = clause.split_once(" - ") {
-        return format!(">={}, <={}", start.trim(), end.trim());
+// Converts one npm clause into the comma-separated form the `semver` crate
+// expects, returning the `!=` exclusions separately since semver can't express them.
+// Bare versions are exact matches, as in npm (`1.2.0` means `=1.2.0`).
+fn normalize_npm_clause(clause: &str) -> Result<(String, Vec<String>), String> {
+    let collapsed = clause.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.is_empty() || collapsed == "*" {
+        return Ok(("*".to_string(), Vec::new()));
     }
 
-    if clause.contains('x') || clause.contains('X') || clause.contains('*') {
-        return normalize_wildcard_clause(clause);
+    if let Some((start, end)) = collapsed.split_once(" - ") {
+        return Ok((format!(">={}, <={}", start.trim(), end.trim()), Vec::new()));
     }
 
-    let mut result = String::new();
-    let mut last_was_operator = false;
-    for token in clause.split_whitespace() {
-        if token.starts_with('<') || token.starts_with('>') {
-            if !result.is_empty() {
-                result.push_str(", ");
-            }
-            result.push_str(token);
-            last_was_operator = true;
-        } else {
-            if last_was_operator {
-                result.push_str(token);
+    let mut comparators = Vec::new();
+    let mut excluded = Vec::new();
+    let mut pending_operator = String::new();
+
+    for token in collapsed.split(' ') {
+        // `>= 1.2.7` splits the operator from its version; glue them back together
+        if token.chars().all(is_comparator_operator) {
+            pending_operator.push_str(token);
+            continue;
+        }
+
+        let token = format!("{}{}", pending_operator, token);
+        pending_operator.clear();
+
+        let operator_len = token
+            .find(|c: char| !is_comparator_operator(c))
+            .unwrap_or(token.len());
+        let (operator, version) = token.split_at(operator_len);
+        let version = version.strip_prefix('v').unwrap_or(version);
+
+        if operator == "!=" {
+            excluded.push(version.to_string());
+            continue;
+        }
+
+        let core = version.split(['-', '+']).next().unwrap_or(version);
+        if core.split('.').any(is_wildcard_part) {
+            if operator.is_empty() || operator == "=" {
+                let wildcard = normalize_wildcard_clause(version);
+                if wildcard != "*" {
+                    comparators.push(wildcard);
+                }
             } else {
-                if !result.is_empty() {
-                    result.push_str(", ");
+                // `>=1.x` -> `>=1`, which semver reads as a partial version
+                let fixed = core
+                    .split('.')
+                    .take_while(|part| !is_wildcard_part(part))
+                    .collect::<Vec<_>>()
+                    .join(".");
+                if !fixed.is_empty() {
+                    comparators.push(format!("{}{}", operator, fixed));
                 }
-                result.push_str(token);
             }
-            last_was_operator = false;
+            continue;
+        }
+
+        if operator.is_empty() {
+            comparators.push(format!("={}", version));
+        } else {
+            comparators.push(format!("{}{}", operator, version));
         }
     }
 
-    if result.is_empty() {
-        clause.to_string()
+    // `>=1.2.3 <` has nothing left for the `<` to apply to
+    if !pending_operator.is_empty() {
+        return Err(format!("'{}' ends with '{}' but no version", collapsed, pending_operator));
+    }
+
+    if comparators.is_empty() {
+        Ok(("*".to_string(), excluded))
     } else {
-        result
+        Ok((comparators.join(", "), excluded))
     }
 }
 
@@ -207,17 +564,231 @@ pub struct PackageInfo {
     pub version: Version,
     pub dependencies: HashMap<String, NpmVersionReq>,
     pub peer_dependencies: HashMap<String, NpmVersionReq>,
+    pub optional_peers: HashSet<String>,
     pub optional_dependencies: HashMap<String, NpmVersionReq>,
     pub tarball_url: String,
     pub integrity: Option<String>,
     pub shasum: Option<String>,
+    // The strongest hash of the two above, which downloads are checked against
+    pub checksum: Option<Integrity>,
     pub is_workspace: bool,
     pub workspace_path: Option<PathBuf>,
     pub engines_node: Option<NpmVersionReq>,
+    pub engines_rnp: Option<NpmVersionReq>,
     pub os_constraints: Vec<String>,
     pub cpu_constraints: Vec<String>,
     pub lifecycle_scripts: HashMap<String, String>,
     pub bin_entries: HashMap<String, String>,
+    pub bundled_dependencies: HashSet<String>,
+    // The maintainer's `deprecated` message for this version, if any
+    pub deprecated: Option<String>,
+}
+
+// Two requirements on the same package that no single version satisfies.
+// The requirement seen first (`existing_*`) keeps its version.
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub package: String,
+    pub requested: String,
+    pub requested_by: Option<String>,
+    pub depth: usize,
+    pub existing_version: String,
+    pub existing_requirement: String,
+    pub existing_requested_by: Option<String>,
+    // Further packages that asked for the existing requirement
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub existing_also_requested_by: Vec<String>,
+    pub existing_depth: usize,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Version conflict for {}: {} vs {}",
+            self.package, self.requested, self.existing_version
+        )
+    }
+}
+
+// A requirement waiting in the resolver queue:
+// (name, requirement, depth, optional, requested by)
+type QueuedRequirement = (String, NpmVersionReq, usize, bool, Option<String>);
+
+// While a requirement is waiting in the queue, further requests for it only
+// record their parent in `enqueued` instead of queueing (and fetching) it again.
+// The queue is breadth-first, so the waiting entry is already the shallowest.
+fn enqueue(
+    queue: &mut VecDeque<QueuedRequirement>,
+    enqueued: &mut HashMap<(String, String, bool), Vec<String>>,
+    entry: QueuedRequirement,
+) {
+    let key = (entry.0.clone(), entry.1.display(), entry.3);
+    match enqueued.get_mut(&key) {
+        Some(parents) => parents.extend(entry.4),
+        None => {
+            enqueued.insert(key, Vec::new());
+            queue.push_back(entry);
+        }
+    }
+}
+
+// Where a resolved package came from, kept to describe conflicts
+struct Resolution {
+    version: Version,
+    depth: usize,
+    requirement: String,
+    requested_by: Option<String>,
+    // The parents whose identical requests were folded into this one
+    other_parents: Vec<String>,
+}
+
+// What `resolve_request` starts from: the root requirements, plus how the
+// lockfile and roots that fail are treated
+#[derive(Debug, Clone, Default)]
+pub struct ResolveRequest {
+    pub roots: Vec<(String, NpmVersionReq)>,
+    // Roots from `optionalDependencies`: a failure skips them like any
+    // optional dependency instead of failing the resolve
+    pub optional_roots: HashSet<String>,
+    // Roots whose locked version is ignored, so they move within their range
+    pub unlocked: HashSet<String>,
+    // A root that can't be resolved is recorded in `failed_roots` instead of
+    // failing the whole resolve
+    pub skip_failed_roots: bool,
+}
+
+// A resolved dependency graph plus whatever could not be reconciled
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedTree {
+    pub packages: Vec<ResolvedPackage>,
+    pub conflicts: Vec<Conflict>,
+    pub unresolved: Vec<String>,
+    // Roots that could not be resolved, with why (`skip_failed_roots` only)
+    pub failed_roots: Vec<(String, String)>,
+    // Everything but engine mismatches, which only installing finds
+    pub diagnostics: Diagnostics,
+    // Time spent fetching each package's metadata, for the install's timings
+    pub(crate) metadata_times: HashMap<String, Duration>,
+}
+
+impl ResolvedTree {
+    // The version chosen for the root `name`
+    pub fn root(&self, name: &str) -> Option<&ResolvedPackage> {
+        self.packages.iter().find(|package| package.depth == 0 && package.info.name == name)
+    }
+}
+
+// What an install did. Packages are listed as `name@version`; updated ones
+// replaced a different version that was already in node_modules.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InstallReport {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub skipped: Vec<String>,
+    pub total_bytes: u64,
+    pub conflicts: Vec<Conflict>,
+    pub unresolved: Vec<String>,
+    pub elapsed_ms: u128,
+    // Time spent downloading and extracting, for the average transfer rate
+    pub download_ms: u128,
+    // Lockfile entries whose version `update` changed, added or removed
+    pub changes: Vec<VersionChange>,
+    // The packages that took longest to fetch and install, slowest first
+    pub slowest: Vec<PackageTiming>,
+    // Specs given to `install --from` that were installed, with the version
+    // each got (`spec: name@version`)
+    pub installed_specs: Vec<String>,
+    // Specs given to `install --from` that could not be installed, with why
+    pub failed_specs: Vec<String>,
+    pub diagnostics: Diagnostics,
+}
+
+// The tree's health in one place: what install otherwise reports as
+// scattered warnings (`install --report`, and `diagnostics` with --json).
+// Conflicts and unresolved packages are the report's own fields.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diagnostics {
+    pub peer_issues: Vec<PeerIssue>,
+    pub deprecated: Vec<Deprecation>,
+    pub engine_mismatches: Vec<String>,
+    pub skipped_optional: Vec<SkippedOptional>,
+    pub name_case_mismatches: Vec<NameCaseMismatch>,
+}
+
+// A peer dependency that is missing (`found` is None) or the wrong version
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerIssue {
+    pub package: String,
+    pub peer: String,
+    pub requirement: String,
+    pub found: Option<String>,
+}
+
+impl std::fmt::Display for PeerIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.found {
+            Some(version) => write!(f, "{} wants {} {}, found {}", self.package, self.peer, self.requirement, version),
+            None => write!(f, "{} wants {} {}, not installed", self.package, self.peer, self.requirement),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Deprecation {
+    pub package: String,
+    pub message: String,
+}
+
+// A package requested as `React` that the registry calls `react`. Names are
+// case-sensitive, but a case-insensitive filesystem (macOS, Windows) lets
+// both spellings share one directory, so the tree only works by accident.
+#[derive(Debug, Clone, Serialize)]
+pub struct NameCaseMismatch {
+    pub requested: String,
+    pub canonical: String,
+}
+
+impl std::fmt::Display for NameCaseMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is published as {}; use that name in package.json", self.requested, self.canonical)
+    }
+}
+
+// An optional dependency left out because it failed to resolve or install,
+// or doesn't support this platform
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedOptional {
+    pub package: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageTiming {
+    pub package: String,
+    pub metadata_ms: u128,
+    pub install_ms: u128,
+}
+
+impl PackageTiming {
+    fn total_ms(&self) -> u128 {
+        self.metadata_ms + self.install_ms
+    }
+}
+
+// `package` is the lockfile path without its `node_modules/` segments, so a
+// nested copy reads `parent > child`
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionChange {
+    pub package: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl InstallReport {
+    pub fn installed(&self) -> usize {
+        self.added.len() + self.updated.len()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -227,12 +798,72 @@ pub struct ResolvedPackage {
     pub optional: bool,
 }
 
+pub enum InstallStatus {
+    Skipped,
+    Installed {
+        integrity: Option<String>,
+        bytes: u64,
+        previous_version: Option<String>,
+    },
+}
+
 pub struct DependencyResolver {
     registry_client: Arc<reqwest::Client>,
-    conflicts: Vec<String>,
+    conflicts: Vec<Conflict>,
+    unresolved: Vec<String>,
     workspace_packages: HashMap<String, WorkspacePackage>,
+    options: InstallOptions,
+    // Time spent fetching each package's metadata while resolving
+    metadata_times: HashMap<String, Duration>,
+    // Lookups the registry already answered negatively this run, keyed by
+    // `name` (no such package) or `name@requirement` (no matching version).
+    // Kept in memory only: a package may be published a minute later.
+    failed_lookups: HashMap<String, String>,
+    // Set for batch installs: a root that can't be resolved is recorded in
+    // `failed_roots` (name, error) instead of failing the whole resolve
+    skip_failed_roots: bool,
+    failed_roots: Vec<(String, String)>,
+    // Roots from `optionalDependencies`: resolved and installed like any
+    // optional dependency, so a failure skips them instead of the install
+    optional_roots: HashSet<String>,
+    // Packages resolved so far, for the message when `resolve_timeout` hits
+    resolved_count: usize,
+    // Every distinct range each package was requested with in the last pass,
+    // for `--dedupe-on-install`
+    requirements: HashMap<String, Vec<NpmVersionReq>>,
+    // Packages a deeper requirement re-resolved in the last pass, leaving
+    // behind whatever the version it replaced pulled in
+    replaced: HashSet<String>,
+    // Recorded for the report's diagnostics
+    deprecated: Vec<Deprecation>,
+    skipped_optional: Vec<SkippedOptional>,
+    name_case_mismatches: Vec<NameCaseMismatch>,
+    // Registry -> the keys its signatures are checked against
+    signing_keys: HashMap<String, Vec<SigningKey>>,
+}
+
+// A negative answer from the registry, as opposed to a network error
+struct LookupFailure {
+    message: String,
+    // The package itself is missing, not just a version matching the request
+    whole_package: bool,
+}
+
+impl std::fmt::Display for LookupFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
 }
 
+// `main` reports errors with `{:?}`; show the message like a string error
+impl std::fmt::Debug for LookupFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.message)
+    }
+}
+
+impl std::error::Error for LookupFailure {}
+
 #[derive(Debug, Clone)]
 struct WorkspacePackage {
     version: Version,
@@ -240,11 +871,49 @@ struct WorkspacePackage {
 }
 
 impl DependencyResolver {
-    fn new(workspace_packages: HashMap<String, WorkspacePackage>) -> Self {
+    fn new(workspace_packages: HashMap<String, WorkspacePackage>, options: &InstallOptions) -> Self {
         Self {
-            registry_client: Arc::new(reqwest::Client::new()),
+            registry_client: Arc::new(options.registries.client()),
             conflicts: Vec::new(),
+            unresolved: Vec::new(),
             workspace_packages,
+            options: options.clone(),
+            metadata_times: HashMap::new(),
+            failed_lookups: HashMap::new(),
+            skip_failed_roots: false,
+            failed_roots: Vec::new(),
+            optional_roots: HashSet::new(),
+            resolved_count: 0,
+            requirements: HashMap::new(),
+            replaced: HashSet::new(),
+            deprecated: Vec::new(),
+            skipped_optional: Vec::new(),
+            name_case_mismatches: Vec::new(),
+            signing_keys: HashMap::new(),
+        }
+    }
+
+    // Wraps resolved `packages` with what resolving recorded, checking peer
+    // dependencies unless they are ignored
+    fn into_tree(self, packages: Vec<ResolvedPackage>) -> ResolvedTree {
+        let peer_issues = if self.options.skips_peers() {
+            Vec::new()
+        } else {
+            validate_peer_dependencies(&packages, &self.options)
+        };
+        ResolvedTree {
+            packages,
+            conflicts: self.conflicts,
+            unresolved: self.unresolved,
+            failed_roots: self.failed_roots,
+            diagnostics: Diagnostics {
+                peer_issues,
+                deprecated: self.deprecated,
+                engine_mismatches: Vec::new(),
+                skipped_optional: self.skipped_optional,
+                name_case_mismatches: self.name_case_mismatches,
+            },
+            metadata_times: self.metadata_times,
         }
     }
 
@@ -255,63 +924,316 @@ impl DependencyResolver {
         * Send -> The error type can be safely sent between threads.
         * Sync -> The error type can be safely shared between threads.
     */
-    pub async fn resolve_dependencies(
+    // A BFS seeded with every depth-0 package at once (e.g. everything
+    // declared in package.json)
+    pub async fn resolve_from_roots(
+        &mut self,
+        roots: &[(String, NpmVersionReq)],
+        locked_versions: Option<&HashMap<String, Version>>,
+    ) -> Result<Vec<ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut packages = self
+            .resolve_graph(roots, locked_versions)
+            .await?
+            .into_values()
+            .collect::<Vec<_>>();
+        sort_resolved_packages(&mut packages);
+        Ok(packages)
+    }
+
+    // Packages are keyed by the name they were requested under, which for a
+    // root may differ from the name its manifest declares
+    async fn resolve_graph(
+        &mut self,
+        roots: &[(String, NpmVersionReq)],
+        locked_versions: Option<&HashMap<String, Version>>,
+    ) -> Result<HashMap<String, ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(limit) = self.options.resolve_timeout else {
+            return self.resolve_graph_passes(roots, locked_versions).await;
+        };
+        self.resolved_count = 0;
+        match tokio::time::timeout(limit, self.resolve_graph_passes(roots, locked_versions)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!(
+                "dependency resolution timed out after {}s ({} package(s) resolved so far); \
+                 raise the limit with --resolve-timeout",
+                limit.as_secs(),
+                self.resolved_count
+            )
+            .into()),
+        }
+    }
+
+    // One BFS, or with --dedupe-on-install as many as it takes to settle:
+    // after each pass every package that ended up requested with ranges its
+    // chosen version doesn't all satisfy (or that was resolved twice) is
+    // pinned to the newest version that satisfies them all, and the tree is
+    // resolved again. Pins win over the lockfile.
+    async fn resolve_graph_passes(
+        &mut self,
+        roots: &[(String, NpmVersionReq)],
+        locked_versions: Option<&HashMap<String, Version>>,
+    ) -> Result<HashMap<String, ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
+        if !self.options.dedupe_on_install {
+            return self.resolve_graph_bfs(roots, locked_versions).await;
+        }
+
+        let mut pins = locked_versions.cloned().unwrap_or_default();
+        let mut pass = 1;
+        loop {
+            let resolved = self.resolve_graph_bfs(roots, Some(&pins)).await?;
+            if pass == MAX_DEDUPE_PASSES {
+                return Ok(resolved);
+            }
+
+            let mut changed = false;
+            let mut requirements = std::mem::take(&mut self.requirements).into_iter().collect::<Vec<_>>();
+            requirements.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, ranges) in requirements {
+                let Some(chosen) = resolved.get(&name) else {
+                    continue;
+                };
+                if ranges.len() < 2
+                    || ranges.iter().any(|range| range.is_source_spec())
+                    || (ranges.iter().all(|range| range.matches(&chosen.info.version))
+                        && !self.replaced.contains(&name))
+                {
+                    continue;
+                }
+                let Some(shared) = self.newest_matching_all(&name, &ranges).await else {
+                    continue;
+                };
+                if pins.get(&name) != Some(&shared) {
+                    self.options.debug(&format!(
+                        "dedupe: {}@{} satisfies {}",
+                        name,
+                        shared,
+                        ranges.iter().map(|range| range.display()).collect::<Vec<_>>().join(", ")
+                    ));
+                    pins.insert(name, shared);
+                    changed = true;
+                }
+            }
+            if !changed {
+                return Ok(resolved);
+            }
+
+            // Start the next pass from a clean slate
+            self.conflicts.clear();
+            self.unresolved.clear();
+            self.failed_roots.clear();
+            self.deprecated.clear();
+            self.skipped_optional.clear();
+            self.name_case_mismatches.clear();
+            pass += 1;
+        }
+    }
+
+    // For a name the registry doesn't know: the published package it differs
+    // from only in case, found by looking the lowercase name up
+    async fn canonical_spelling(&self, name: &str) -> Option<String> {
+        let lowercase = name.to_lowercase();
+        if lowercase == name {
+            return None;
+        }
+        let (metadata, _) = fetch_registry_document(
+            &self.registry_client,
+            &self.options.registries,
+            &self.options.cache_dir,
+            self.options.cache_min_time,
+            &lowercase,
+        )
+        .await
+        .ok()?;
+        published_versions(&lowercase, &metadata).ok()?;
+        Some(metadata["name"].as_str().unwrap_or(&lowercase).to_string())
+    }
+
+    // The newest published version of `name` within every one of `ranges`
+    async fn newest_matching_all(&self, name: &str, ranges: &[NpmVersionReq]) -> Option<Version> {
+        let (metadata, _) = fetch_registry_document(
+            &self.registry_client,
+            &self.options.registries,
+            &self.options.cache_dir,
+            self.options.cache_min_time,
+            name,
+        )
+        .await
+        .ok()?;
+        let versions = published_versions(name, &metadata).ok()?;
+        self.published_before_cutoff(versions.keys(), &metadata)
+            .filter_map(|version| Version::parse(version).ok())
+            .filter(|version| ranges.iter().all(|range| range.matches(version)))
+            .max()
+    }
+
+    // `versions` minus those published after `--before`
+    fn published_before_cutoff<'a>(
+        &self,
+        versions: impl Iterator<Item = &'a String>,
+        metadata: &'a Value,
+    ) -> impl Iterator<Item = &'a String> {
+        let cutoff = self.options.before.as_deref().and_then(registry::parse_iso8601);
+        versions.filter(move |version| {
+            cutoff.is_none_or(|cutoff| {
+                metadata["time"][version.as_str()]
+                    .as_str()
+                    .and_then(registry::parse_iso8601)
+                    .is_some_and(|published| published <= cutoff)
+            })
+        })
+    }
+
+    // The BFS itself
+    async fn resolve_graph_bfs(
         &mut self,
-        root_package: &str,
+        roots: &[(String, NpmVersionReq)],
         locked_versions: Option<&HashMap<String, Version>>,
-    ) -> Result<Vec<ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> { 
+    ) -> Result<HashMap<String, ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
         // local variable to store the packages to resolve
-        let mut to_resolve: VecDeque<(String, NpmVersionReq, usize, bool)> = VecDeque::new();
+        let mut to_resolve: VecDeque<QueuedRequirement> = VecDeque::new();
+        // (name, requirement, optional) entries still waiting in the queue, with
+        // the other parents that asked for them
+        let mut enqueued: HashMap<(String, String, bool), Vec<String>> = HashMap::new();
         // local variable to store the resolved packages
-        let mut resolved: HashMap<String, (Version, usize)> = HashMap::new();
+        let mut resolved: HashMap<String, Resolution> = HashMap::new();
         // local variable to store the resolved packages
         let mut resolved_packages: HashMap<String, ResolvedPackage> = HashMap::new();
+        // packages whose deprecation notice has already been printed
+        let mut deprecation_warned: HashSet<String> = HashSet::new();
+        self.requirements.clear();
+        self.replaced.clear();
         
-        // push the root package to the to_resolve queue
-        to_resolve.push_back((root_package.to_string(), NpmVersionReq::any()?, 0, false));
+        // push the root packages to the to_resolve queue
+        for (name, req) in roots {
+            let optional = self.optional_roots.contains(name);
+            enqueue(&mut to_resolve, &mut enqueued, (name.clone(), req.clone(), 0, optional, None));
+        }
 
         // classic BFS
-        while let Some((package_name, version_req, depth, is_optional)) = to_resolve.pop_front() {
+        while let Some((package_name, version_req, depth, is_optional, requested_by)) = to_resolve.pop_front() {
+            let other_parents = enqueued
+                .remove(&(package_name.clone(), version_req.display(), is_optional))
+                .unwrap_or_default();
+            let ranges = self.requirements.entry(package_name.clone()).or_default();
+            if !ranges.iter().any(|range| range.display() == version_req.display()) {
+                ranges.push(version_req.clone());
+            }
+
             // if the package is already resolved, skip it
-            if let Some((existing_version_req, existing_depth)) = resolved.get(&package_name) {
+            if let Some(existing) = resolved.get(&package_name) {
                 // if the version requirement matches, skip it
-                if version_req.matches(existing_version_req) {
+                if version_req.matches(&existing.version) {
                     continue;
                 } 
 
                 // if the depth is less than or equal to the existing depth, skip it
-                if depth <= *existing_depth {
-                    self.conflicts.push(format!(
-                        "Version conflict for {}: {} vs {}",
-                        package_name,
-                        version_req.display(),
-                        existing_version_req
-                    ));
+                if depth <= existing.depth {
+                    for requested_by in std::iter::once(requested_by).chain(other_parents.into_iter().map(Some)) {
+                        self.conflicts.push(Conflict {
+                            package: package_name.clone(),
+                            requested: version_req.display(),
+                            requested_by,
+                            depth,
+                            existing_version: existing.version.to_string(),
+                            existing_requirement: existing.requirement.clone(),
+                            existing_requested_by: existing.requested_by.clone(),
+                            existing_also_requested_by: existing.other_parents.clone(),
+                            existing_depth: existing.depth,
+                        });
+                    }
                     continue;
                 }
+                self.replaced.insert(package_name.clone());
             }
 
             // fetch the package metadata
             let locked_version = locked_versions.and_then(|m| m.get(&package_name));
-            let package_info = match self
-                .fetch_package_metadata(&package_name, &version_req, locked_version)
-                .await
-            {
+            let request_key = format!("{}@{}", package_name, version_req.display());
+            let known_failure = self
+                .failed_lookups
+                .get(&package_name)
+                .or_else(|| self.failed_lookups.get(&request_key))
+                .cloned();
+            let fetched = match known_failure {
+                Some(message) => Err(message.into()),
+                None => {
+                    self.options.emit(InstallEvent::Resolving {
+                        name: &package_name,
+                        requirement: version_req.display(),
+                    });
+                    let fetch_started = Instant::now();
+                    let fetched = self
+                        .fetch_package_metadata(&package_name, &version_req, locked_version)
+                        .await;
+                    let fetch_time = fetch_started.elapsed();
+                    self.options.debug(&format!("fetched {} metadata in {}ms", package_name, fetch_time.as_millis()));
+                    *self.metadata_times.entry(package_name.clone()).or_default() += fetch_time;
+                    if let Err(err) = &fetched
+                        && let Some(failure) = err.downcast_ref::<LookupFailure>()
+                    {
+                        let key = if failure.whole_package { package_name.clone() } else { request_key };
+                        self.failed_lookups.insert(key, failure.message.clone());
+                    }
+                    fetched
+                }
+            };
+            let package_info = match fetched {
                 Ok(info) => info,
                 Err(err) if is_optional => {
-                    self.conflicts.push(format!(
+                    // Usually a platform-specific build; only worth a --verbose note
+                    self.options.debug(&format!(
                         "Skipping optional dependency {} ({}): {}",
                         package_name,
                         version_req.display(),
                         err
                     ));
+                    self.skipped_optional.push(SkippedOptional {
+                        package: format!("{}@{}", package_name, version_req.display()),
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+                // With --force a broken transitive dependency doesn't sink the whole install
+                Err(err) if self.options.force && depth > 0 => {
+                    self.unresolved.push(format!(
+                        "Could not resolve {} ({}): {}",
+                        package_name,
+                        version_req.display(),
+                        err
+                    ));
+                    continue;
+                }
+                Err(err) if self.skip_failed_roots && depth == 0 => {
+                    self.failed_roots.push((package_name.clone(), err.to_string()));
                     continue;
                 }
                 Err(err) => return Err(err),
             };
 
+            if let Some(message) = &package_info.deprecated
+                && deprecation_warned.insert(package_name.clone())
+            {
+                self.options.warn(&format!(
+                    "deprecated {}@{}: {}",
+                    package_name, package_info.version, message
+                ));
+                self.deprecated.push(Deprecation {
+                    package: format!("{}@{}", package_name, package_info.version),
+                    message: message.clone(),
+                });
+            }
+
             // insert the package into the resolved map
-            resolved.insert(package_name.clone(), (package_info.version.clone(), depth));
+            resolved.insert(
+                package_name.clone(),
+                Resolution {
+                    version: package_info.version.clone(),
+                    depth,
+                    requirement: version_req.display(),
+                    requested_by,
+                    other_parents,
+                },
+            );
 
             // insert the package into the resolved packages map which is global
             resolved_packages.insert(
@@ -322,227 +1244,256 @@ impl DependencyResolver {
                     optional: is_optional,
                 },
             );
-
-            // push the dependencies to the to_resolve queue
-            for (dep_name, dep_version_req) in &package_info.dependencies {
-                to_resolve.push_back((dep_name.clone(), dep_version_req.clone(), depth + 1, false));
+            self.resolved_count = resolved_packages.len();
+            self.options.emit(InstallEvent::Resolved {
+                name: &package_name,
+                version: package_info.version.to_string(),
+                depth,
+            });
+
+            // push the dependencies to the to_resolve queue, skipping the bundled ones
+            // (in name order, so the BFS visits packages the same way every run)
+            for (dep_name, dep_version_req) in sorted_requirements(&package_info.dependencies) {
+                if package_info.bundled_dependencies.contains(dep_name) {
+                    continue;
+                }
+                enqueue(
+                    &mut to_resolve,
+                    &mut enqueued,
+                    (dep_name.clone(), dep_version_req.clone(), depth + 1, false, Some(package_name.clone())),
+                );
             }
 
-            // push peer dependencies as well
-            for (peer_name, peer_version_req) in &package_info.peer_dependencies {
-                to_resolve.push_back((peer_name.clone(), peer_version_req.clone(), depth + 1, false));
+            // push required peer dependencies as well
+            for (peer_name, peer_version_req) in sorted_requirements(&package_info.peer_dependencies) {
+                if self.options.skips_peers() || package_info.optional_peers.contains(peer_name) {
+                    continue;
+                }
+                enqueue(
+                    &mut to_resolve,
+                    &mut enqueued,
+                    (peer_name.clone(), peer_version_req.clone(), depth + 1, false, Some(package_name.clone())),
+                );
             }
 
-            for (opt_name, opt_version_req) in &package_info.optional_dependencies {
-                to_resolve.push_back((opt_name.clone(), opt_version_req.clone(), depth + 1, true));
+            for (opt_name, opt_version_req) in sorted_requirements(&package_info.optional_dependencies) {
+                if self.options.omits("optional") || package_info.bundled_dependencies.contains(opt_name) {
+                    continue;
+                }
+                enqueue(
+                    &mut to_resolve,
+                    &mut enqueued,
+                    (opt_name.clone(), opt_version_req.clone(), depth + 1, true, Some(package_name.clone())),
+                );
             }
         }
 
-        // return the resolved packages from the global map
-        let mut packages = resolved_packages.values().cloned().collect::<Vec<_>>();
-        packages.sort_by_key(|p| p.depth);
-        Ok(packages)
+        Ok(resolved_packages)
     }
  
     // Fetch package metadata from the npm registry
     async fn fetch_package_metadata(
-        &self,
+        &mut self,
         name: &str,
         version_req: &NpmVersionReq,
         locked_version: Option<&Version>,
     ) -> Result<PackageInfo, Box<dyn std::error::Error + Send + Sync>> {
-        if let Some(workspace_pkg) = self.workspace_packages.get(name) {
-            if version_req.matches(&workspace_pkg.version) {
-                return Ok(PackageInfo {
-                    name: name.to_string(),
-                    version: workspace_pkg.version.clone(),
-                    dependencies: HashMap::new(),
-                    peer_dependencies: HashMap::new(),
-                    optional_dependencies: HashMap::new(),
-                    tarball_url: String::new(),
-                    integrity: None,
-                    shasum: None,
-                    is_workspace: true,
-                    workspace_path: Some(workspace_pkg.path.clone()),
-                    engines_node: None,
-                    os_constraints: Vec::new(),
-                    cpu_constraints: Vec::new(),
-                    lifecycle_scripts: HashMap::new(),
-                    bin_entries: HashMap::new(),
-                });
+        if let Some(workspace_pkg) = self.workspace_packages.get(name)
+            && version_req.matches(&workspace_pkg.version)
+        {
+            return Ok(PackageInfo {
+                name: name.to_string(),
+                version: workspace_pkg.version.clone(),
+                dependencies: HashMap::new(),
+                peer_dependencies: HashMap::new(),
+                optional_peers: HashSet::new(),
+                optional_dependencies: HashMap::new(),
+                tarball_url: String::new(),
+                integrity: None,
+                shasum: None,
+                checksum: None,
+                is_workspace: true,
+                workspace_path: Some(workspace_pkg.path.clone()),
+                engines_node: None,
+                engines_rnp: None,
+                os_constraints: Vec::new(),
+                cpu_constraints: Vec::new(),
+                lifecycle_scripts: HashMap::new(),
+                bin_entries: HashMap::new(),
+                bundled_dependencies: HashSet::new(),
+                deprecated: None,
+            });
+        }
+
+        if let Some(spec) = version_req.git_source() {
+            return self.fetch_git_package(name, &spec);
+        }
+        if let Some(path) = version_req.local_tarball() {
+            let (info, _) = read_local_tarball(&self.options.prefix, path)?;
+            if info.name != name {
+                return Err(format!("file:{} contains {}, not {}", path, info.name, name).into());
+            }
+            return Ok(info);
+        }
+        if let Some(path) = version_req.local_directory() {
+            let info = read_local_directory(&self.options.prefix, version_req.display(), path)?;
+            if info.name != name {
+                return Err(format!("{} contains {}, not {}", version_req.display(), info.name, name).into());
             }
+            return Ok(info);
         }
 
-        let url = format!("https://registry.npmjs.org/{}", name);
-        let response = self.registry_client.get(&url).send().await?;
-        let metadata: serde_json::Value = response.json().await?;
+        let (metadata, served_by) = fetch_registry_document(
+            &self.registry_client,
+            &self.options.registries,
+            &self.options.cache_dir,
+            self.options.cache_min_time,
+            name,
+        )
+        .await?;
+        self.options.debug(&format!("{} metadata served by {}", name, served_by));
 
         // Find best matching version
-        let versions = metadata["versions"]
-            .as_object()
-            .ok_or("No versions found")?;
+        let versions = match published_versions(name, &metadata) {
+            Ok(versions) => versions,
+            Err(err) => {
+                let message = match self.canonical_spelling(name).await {
+                    Some(canonical) => format!(
+                        "{}; package names are case-sensitive, did you mean {}?",
+                        err, canonical
+                    ),
+                    None => err.to_string(),
+                };
+                return Err(LookupFailure { message, whole_package: true }.into());
+            }
+        };
 
-        let best_version = self.find_best_version(versions.keys(), version_req, locked_version)?;
-        let version_info = &metadata["versions"][&best_version.to_string()];
+        // Some registries answer for any capitalization of a name
+        if let Some(canonical) = metadata["name"].as_str()
+            && canonical != name
+            && canonical.eq_ignore_ascii_case(name)
+            && !self.name_case_mismatches.iter().any(|m| m.requested == name)
+        {
+            let mismatch = NameCaseMismatch {
+                requested: name.to_string(),
+                canonical: canonical.to_string(),
+            };
+            self.options.warn(&mismatch.to_string());
+            self.name_case_mismatches.push(mismatch);
+        }
 
-        // Parse dependencies
-        let mut dependencies = HashMap::new();
-        if let Some(deps) = version_info.get("dependencies") {
-            if let Some(deps_obj) = deps.as_object() {
-                for (dep_name, dep_version) in deps_obj {
-                    if let Some(version_str) = dep_version.as_str() {
-                        match NpmVersionReq::parse(version_str) {
-                            Ok(req) => {
-                                dependencies.insert(dep_name.clone(), req);
-                            }
-                            Err(e) => {
-                                println!(
-                                    "⚠️  Warning: Could not parse version requirement for '{}': '{}'. Error: {}. Using '*' as fallback.",
-                                    dep_name, version_str, e
-                                );
-                                if let Ok(any_version_req) = NpmVersionReq::any() {
-                                    dependencies.insert(dep_name.clone(), any_version_req);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        let candidates = self.published_before_cutoff(versions.keys(), &metadata);
+        let best_version = self
+            .find_best_version(candidates, version_req, locked_version)
+            .map_err(|e| LookupFailure {
+                message: match &self.options.before {
+                    Some(before) => format!(
+                        "No version of {} matching {} was published before {}",
+                        name,
+                        version_req.display(),
+                        before
+                    ),
+                    None => e.to_string(),
+                },
+                whole_package: false,
+            })?;
+        let version_info = &metadata["versions"][&best_version.to_string()];
 
-        let mut peer_dependencies = HashMap::new();
-        if let Some(peer_deps) = version_info.get("peerDependencies") {
-            if let Some(peer_deps_obj) = peer_deps.as_object() {
-                for (dep_name, dep_version) in peer_deps_obj {
-                    if let Some(version_str) = dep_version.as_str() {
-                        match NpmVersionReq::parse(version_str) {
-                            Ok(req) => {
-                                peer_dependencies.insert(dep_name.clone(), req);
-                            }
-                            Err(e) => {
-                                println!(
-                                    "⚠️  Warning: Could not parse peer dependency for '{}': '{}'. Error: {}. Using '*' as fallback.",
-                                    dep_name, version_str, e
-                                );
-                                if let Ok(any_version_req) = NpmVersionReq::any() {
-                                    peer_dependencies.insert(dep_name.clone(), any_version_req);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        let tarball = version_info["dist"]["tarball"]
+            .as_str()
+            .ok_or_else(|| format!("{}@{}: registry returned no tarball URL", name, best_version))?;
+        let tarball_url = resolve_tarball_url(tarball, &served_by, name)
+            .map_err(|e| format!("{}@{}: {}", name, best_version, e))?;
+
+        if self.options.verify_signatures {
+            self.check_signature(name, &best_version, &metadata).await?;
         }
 
-        let mut optional_dependencies = HashMap::new();
-        if let Some(optional_deps) = version_info.get("optionalDependencies") {
-            if let Some(optional_deps_obj) = optional_deps.as_object() {
-                for (dep_name, dep_version) in optional_deps_obj {
-                    if let Some(version_str) = dep_version.as_str() {
-                        match NpmVersionReq::parse(version_str) {
-                            Ok(req) => {
-                                optional_dependencies.insert(dep_name.clone(), req);
-                            }
-                            Err(e) => {
-                                println!(
-                                    "⚠️  Warning: Could not parse optional dependency for '{}': '{}'. Error: {}. Using '*' as fallback.",
-                                    dep_name, version_str, e
-                                );
-                                if let Ok(any_version_req) = NpmVersionReq::any() {
-                                    optional_dependencies.insert(dep_name.clone(), any_version_req);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        let mut info = package_info_from_manifest(name, best_version, version_info, tarball_url);
+        if info.deprecated.is_none() {
+            info.deprecated = deprecation_message(&metadata);
         }
+        Ok(info)
+    }
 
-        let tarball_url = version_info["dist"]["tarball"]
-            .as_str()
-            .ok_or("No tarball URL found")?
+    // `--verify-signatures`: the keys come from the registry the package is
+    // published to (its scope's registry, else the primary), asked directly
+    // rather than through a mirror, plus the `signature-keys` trusted for it.
+    // A package no key can vouch for fails.
+    async fn check_signature(
+        &mut self,
+        name: &str,
+        version: &Version,
+        metadata: &Value,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let registries = &self.options.registries;
+        let registry = registries
+            .scope_registry(name)
+            .unwrap_or(registries.primary())
             .to_string();
-
-        let shasum = version_info["dist"]["shasum"]
-            .as_str()
-            .map(|value| value.to_string());
-
-        let integrity = version_info["dist"]["integrity"]
-            .as_str()
-            .map(|value| value.to_string());
-
-        let engines_node = version_info
-            .get("engines")
-            .and_then(|v| v.get("node"))
-            .and_then(|v| v.as_str())
-            .and_then(|v| NpmVersionReq::parse(v).ok());
-
-        let os_constraints = version_info
-            .get("os")
-            .and_then(|v| v.as_array())
-            .map(|items| {
-                items
-                    .iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-
-        let cpu_constraints = version_info
-            .get("cpu")
-            .and_then(|v| v.as_array())
-            .map(|items| {
-                items
-                    .iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-
-        let mut lifecycle_scripts = HashMap::new();
-        if let Some(scripts_obj) = version_info.get("scripts").and_then(|v| v.as_object()) {
-            for script_name in ["preinstall", "install", "postinstall"] {
-                if let Some(command) = scripts_obj.get(script_name).and_then(|v| v.as_str()) {
-                    lifecycle_scripts.insert(script_name.to_string(), command.to_string());
+        if !self.signing_keys.contains_key(&registry) {
+            let mut keys = match fetch_registry_keys(&self.registry_client, registries, &registry).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    self.options.warn(&format!(
+                        "Could not fetch signing keys from {}: {}; only signature-keys are trusted for it",
+                        registry, e
+                    ));
+                    Vec::new()
                 }
-            }
+            };
+            keys.extend(
+                self.options
+                    .signature_keys
+                    .iter()
+                    .filter(|key| key.applies_to(&registry))
+                    .cloned(),
+            );
+            self.signing_keys.insert(registry.clone(), keys);
         }
 
-        let mut bin_entries = HashMap::new();
-        if let Some(bin) = version_info.get("bin") {
-            if let Some(single_bin) = bin.as_str() {
-                bin_entries.insert(default_bin_name(name), single_bin.to_string());
-            } else if let Some(bin_map) = bin.as_object() {
-                for (bin_name, bin_path) in bin_map {
-                    if let Some(bin_path_str) = bin_path.as_str() {
-                        bin_entries.insert(bin_name.clone(), bin_path_str.to_string());
-                    }
-                }
-            }
+        let keys = &self.signing_keys[&registry];
+        if keys.is_empty() {
+            return Err(format!(
+                "{}@{} can't be verified: {} publishes no signing keys and signature-keys has none for it",
+                name, version, registry
+            )
+            .into());
         }
+        let version = version.to_string();
+        verify_version(
+            name,
+            &version,
+            &metadata["versions"][&version],
+            metadata["time"][&version].as_str(),
+            keys,
+        )?;
+        self.options.debug(&format!("{}@{} has a valid registry signature", name, version));
+        Ok(())
+    }
 
-        Ok(PackageInfo {
-            name: name.to_string(),
-            version: best_version,
-            dependencies,
-            peer_dependencies,
-            optional_dependencies,
-            tarball_url,
-            integrity,
-            shasum,
-            is_workspace: false,
-            workspace_path: None,
-            engines_node,
-            os_constraints,
-            cpu_constraints,
-            lifecycle_scripts,
-            bin_entries,
-        })
+    // Check a git dependency out and read its package.json. The tarball url
+    // becomes the spec pinned to the commit, which is what the lockfile records.
+    fn fetch_git_package(&self, name: &str, spec: &GitSpec) -> Result<PackageInfo, Box<dyn std::error::Error + Send + Sync>> {
+        let cache = PackageCache::new(&self.options.cache_dir)?;
+        let (checkout, sha) = git::checkout(spec, &cache.dir().join("git"))?;
+        self.options.debug(&format!("{} checked out from {} at {}", name, spec.spec_url, sha));
+
+        let data = fs::read_to_string(checkout.join("package.json"))
+            .map_err(|e| format!("{} has no readable package.json at {}: {}", spec.spec_url, sha, e))?;
+        let manifest = manifest::parse(&data)?;
+        let version = manifest
+            .get("version")
+            .and_then(|v| v.as_str())
+            .and_then(|v| Version::parse(v).ok())
+            .ok_or_else(|| format!("{} at {} has no valid version in package.json", spec.spec_url, sha))?;
+
+        Ok(package_info_from_manifest(name, version, &manifest, spec.pinned(&sha)))
     }
 
-    fn find_best_version(
+    fn find_best_version<'a>(
         &self,
-        available_versions: serde_json::map::Keys,
+        available_versions: impl Iterator<Item = &'a String>,
         requirement: &NpmVersionReq,
         locked_version: Option<&Version>,
     ) -> Result<Version, Box<dyn std::error::Error + Send + Sync>> {
@@ -551,10 +1502,10 @@ impl DependencyResolver {
             .filter(|v| requirement.matches(v))
             .collect();
 
-        if let Some(locked) = locked_version {
-            if matching_versions.iter().any(|v| v == locked) {
-                return Ok(locked.clone());
-            }
+        if let Some(locked) = locked_version
+            && matching_versions.contains(locked)
+        {
+            return Ok(locked.clone());
         }
 
         let mut matching_versions = matching_versions;
@@ -568,15 +1519,15 @@ impl DependencyResolver {
     }
 
     // Phase 2: Parallel download and installation
+    // Records the integrity computed from each downloaded tarball back onto `packages`.
     pub async fn install_packages_parallel(
         &self,
-        packages: &Vec<ResolvedPackage>,
+        packages: &mut [ResolvedPackage],
         options: &InstallOptions,
         node_version: Option<Version>,
-    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
-        const MAX_CONCURRENT_DOWNLOADS: usize = 15;
+    ) -> Result<InstallReport, Box<dyn std::error::Error + Send + Sync>> {
         let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_DOWNLOADS));
-        let progress = if options.quiet {
+        let progress = if options.quiet() {
             None
         } else {
             let pb = ProgressBar::new(packages.len() as u64);
@@ -589,14 +1540,31 @@ impl DependencyResolver {
             Some(pb)
         };
 
+        // Packages with identical contents (aliases, republishes under another
+        // name) share one download, keyed by integrity
+        let mut integrity_counts: HashMap<&str, usize> = HashMap::new();
+        for package in packages.iter() {
+            if let Some(integrity) = package.info.integrity.as_deref() {
+                *integrity_counts.entry(integrity).or_default() += 1;
+            }
+        }
+        let shared_downloads: HashMap<String, SharedTarball> = integrity_counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(integrity, _)| (integrity.to_string(), SharedTarball::default()))
+            .collect();
+        let limiter = options.max_rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+        let started = Instant::now();
+        let mut timings = Vec::new();
+
         // Group packages by depth for proper installation order
-        let mut depth_groups: HashMap<usize, Vec<&ResolvedPackage>> = HashMap::new();
-        for package in packages {
-            depth_groups.entry(package.depth).or_default().push(package);
+        let mut depth_groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (index, package) in packages.iter().enumerate() {
+            depth_groups.entry(package.depth).or_default().push(index);
         }
 
         // Install depth by depth (to respect dependency order)
-        let mut total_installed = 0;
+        let mut report = InstallReport::default();
         let mut depths: Vec<_> = depth_groups.keys().cloned().collect();
         depths.sort_by(|a, b| b.cmp(a)); // Deepest first
 
@@ -605,29 +1573,86 @@ impl DependencyResolver {
             let mut depth_handles = Vec::new();
 
             // Install packages at same depth in parallel
-            for package in packages_at_depth {
+            for index in packages_at_depth {
                 let semaphore = Arc::clone(&semaphore);
                 let client = Arc::clone(&self.registry_client);
-                let package_to_install = package.clone();
+                let package_to_install = packages[index].clone();
                 let node_version = node_version.clone();
                 let options = options.clone();
+                let shared = package_to_install
+                    .info
+                    .integrity
+                    .as_ref()
+                    .and_then(|integrity| shared_downloads.get(integrity))
+                    .cloned();
+                let limiter = limiter.clone();
 
                 let handle = tokio::spawn(async move {
                     let _permit = semaphore.acquire().await.unwrap();
-                    Self::download_and_extract_package(client, &package_to_install, &options, node_version)
-                        .await
+                    let started = Instant::now();
+                    let status = Self::download_and_extract_package(
+                        client,
+                        &package_to_install,
+                        &options,
+                        node_version,
+                        shared,
+                        limiter.as_deref(),
+                    )
+                    .await;
+                    (status, started.elapsed())
                 });
 
-                depth_handles.push(handle);
+                depth_handles.push((index, handle));
             }
 
             // Wait for all packages at this depth to complete
-            for handle in depth_handles {
-                if handle.await?? {
-                    total_installed += 1;
-                    if let Some(pb) = &progress {
-                        pb.inc(1);
+            for (index, handle) in depth_handles {
+                let (status, install_time) = handle.await?;
+                let label = format!("{}@{}", packages[index].info.name, packages[index].info.version);
+                options.debug(&format!("installed {} in {}ms", label, install_time.as_millis()));
+                timings.push(PackageTiming {
+                    package: label.clone(),
+                    metadata_ms: self
+                        .metadata_times
+                        .get(&packages[index].info.name)
+                        .map_or(0, |time| time.as_millis()),
+                    install_ms: install_time.as_millis(),
+                });
+                if let Some(reason) = engine_mismatch(&packages[index].info, node_version.as_ref()) {
+                    report.diagnostics.engine_mismatches.push(reason);
+                }
+                let status = match status {
+                    Ok(status) => status,
+                    Err(err) if packages[index].optional => {
+                        options.debug(&format!(
+                            "Skipping optional dependency {}: {}",
+                            packages[index].info.name, err
+                        ));
+                        report.diagnostics.skipped_optional.push(SkippedOptional {
+                            package: label.clone(),
+                            reason: err.to_string(),
+                        });
+                        InstallStatus::Skipped
+                    }
+                    Err(err) => return Err(err),
+                };
+                match status {
+                    InstallStatus::Installed { integrity, bytes, previous_version } => {
+                        if integrity.is_some() {
+                            packages[index].info.integrity = integrity;
+                        }
+                        report.total_bytes += bytes;
+                        match previous_version {
+                            Some(previous) if previous != packages[index].info.version.to_string() => {
+                                report.updated.push(label)
+                            }
+                            _ => report.added.push(label),
+                        }
+                        if let Some(pb) = &progress {
+                            pb.inc(1);
+                        }
                     }
+                    InstallStatus::Skipped => report.skipped.push(label),
                 }
             }
         }
@@ -635,8 +1660,16 @@ impl DependencyResolver {
         if let Some(pb) = &progress {
             pb.finish_with_message("done");
         }
+        // Cache hits only queued their access times; record them all at once
+        if let Err(e) = PackageCache::new(&options.cache_dir).and_then(|cache| cache.flush_accesses()) {
+            output::warn(&format!("Failed to update the cache index: {}", e));
+        }
 
-        Ok(total_installed)
+        report.download_ms = started.elapsed().as_millis();
+        timings.sort_by(|a, b| b.total_ms().cmp(&a.total_ms()).then_with(|| a.package.cmp(&b.package)));
+        timings.truncate(SLOWEST_PACKAGES);
+        report.slowest = timings;
+        Ok(report)
     }
 
     async fn download_and_extract_package(
@@ -644,16 +1677,29 @@ impl DependencyResolver {
         package: &ResolvedPackage,
         options: &InstallOptions,
         node_version: Option<Version>,
-    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-        const CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
-
-        if let Err(reason) = validate_package_constraints(&package.info, node_version.as_ref()) {
-            if package.optional {
-                options.warn(&format!("Skipping optional dependency {}: {}", package.info.name, reason));
-                return Ok(false);
+        shared: Option<SharedTarball>,
+        limiter: Option<&RateLimiter>,
+    ) -> Result<InstallStatus, Box<dyn std::error::Error + Send + Sync>> {
+        let engine = match engine_mismatch(&package.info, node_version.as_ref()) {
+            Some(reason) if options.engine_strict => Err(reason),
+            Some(reason) => {
+                options.warn(&format!("Unsupported engine: {}", reason));
+                Ok(())
             }
-            return Err(reason.into());
+            None => Ok(()),
+        };
+        let platform = validate_package_constraints(&package.info, options);
+        // Optional packages that fail here are skipped (and noted) by the
+        // caller. A copy an install for another platform left behind goes;
+        // an engine mismatch is about this machine's Node, so a copy that
+        // fits the platform stays.
+        if platform.is_err() && package.optional {
+            remove_existing(&options.node_modules_dir().join(&package.info.name))?;
         }
+        engine?;
+        platform?;
+
+        let previous_version = installed_version(&options.node_modules_dir().join(&package.info.name));
 
         if package.info.is_workspace {
             let src = package
@@ -661,281 +1707,1128 @@ impl DependencyResolver {
                 .workspace_path
                 .as_ref()
                 .ok_or("Workspace package path not found")?;
-            let node_modules_path = Path::new("node_modules").join(&package.info.name);
+            let node_modules_path = options.node_modules_dir().join(&package.info.name);
             if node_modules_path.exists() {
                 fs::remove_dir_all(&node_modules_path)?;
             }
 
             if let Err(_err) = symlink_dir(src, &node_modules_path) {
-                copy_dir_recursive(src, &node_modules_path)?;
+                copy_package_contents(src, &node_modules_path)?;
             }
-            return Ok(true);
+            return Ok(InstallStatus::Installed {
+                integrity: None,
+                bytes: 0,
+                previous_version,
+            });
         }
 
-        // Initialize cache
-        let cache = PackageCache::new()?;
-        let package_version = package.info.version.to_string();
-
-        // Check cache first
-        let bytes = if let Some(cached_data) = cache.get_valid_tarball(
-            &package.info.name,
-            &package_version,
-            package.info.shasum.as_deref(),
-            CACHE_MAX_AGE,
-        )? {
-            if verify_tarball_integrity(&package.info, &cached_data).is_ok() {
-                cached_data
+        // Local directories are linked, or copied with --install-links
+        if let Some(path) = local_directory_path(&package.info.tarball_url) {
+            let src = std::path::absolute(options.prefix.join(path))?;
+            let node_modules_path = options.node_modules_dir().join(&package.info.name);
+            remove_existing(&node_modules_path)?;
+            if options.install_links {
+                copy_package_contents(&src, &node_modules_path)?;
+                create_bin_links(&package.info, &node_modules_path, &options.node_modules_dir())?;
+                run_lifecycle_scripts(&package.info, &node_modules_path, options)?;
             } else {
-                cache.invalidate_tarball(&package.info.name, &package_version)?;
-                let response = client.get(&package.info.tarball_url).send().await?;
-                let bytes = response.bytes().await?;
-                verify_tarball_integrity(&package.info, bytes.as_ref())
-                    .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { e.into() })?;
-                if let Err(e) = cache.save_tarball(&package.info.name, &package_version, &bytes) {
-                    eprintln!("  ⚠️  Failed to cache {}@{}: {}", package.info.name, package.info.version, e);
+                if let Some(parent) = node_modules_path.parent() {
+                    fs::create_dir_all(parent)?;
                 }
-                bytes.to_vec()
+                symlink_dir(&src, &node_modules_path)?;
+                create_bin_links(&package.info, &node_modules_path, &options.node_modules_dir())?;
             }
-        } else {
-            // Cache miss, stale entry, or checksum mismatch: download again
-            let response = client.get(&package.info.tarball_url).send().await?;
-            let bytes = response.bytes().await?;
+            return Ok(InstallStatus::Installed {
+                integrity: None,
+                bytes: 0,
+                previous_version,
+            });
+        }
 
-            verify_tarball_integrity(&package.info, bytes.as_ref())
-                .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { e.into() })?;
+        // Initialize cache
+        let cache = PackageCache::new(&options.cache_dir)?.with_max_size(options.cache_max_size);
 
-            // Save to cache for future use
-            if let Err(e) = cache.save_tarball(&package.info.name, &package_version, &bytes) {
-                eprintln!("  ⚠️  Failed to cache {}@{}: {}", package.info.name, package.info.version, e);
+        // Git dependencies are copied from a checkout of the pinned commit
+        if let Some(spec) = GitSpec::parse(&package.info.tarball_url) {
+            options.emit(InstallEvent::Downloading {
+                name: &package.info.name,
+                version: package.info.version.to_string(),
+            });
+            let (checkout, _) = git::checkout(&spec, &cache.dir().join("git"))?;
+            let node_modules_path = options.node_modules_dir().join(&package.info.name);
+            if node_modules_path.exists() {
+                fs::remove_dir_all(&node_modules_path)?;
             }
-            bytes.to_vec()
-        };
+            copy_package_contents(&checkout, &node_modules_path)?;
+            options.emit(InstallEvent::Extracted {
+                name: &package.info.name,
+                version: package.info.version.to_string(),
+            });
+            create_bin_links(&package.info, &node_modules_path, &options.node_modules_dir())?;
+            run_lifecycle_scripts(&package.info, &node_modules_path, options)?;
+            return Ok(InstallStatus::Installed {
+                integrity: None,
+                bytes: 0,
+                previous_version,
+            });
+        }
 
-        // Extract to node_modules
-        let node_modules_path = Path::new("node_modules").join(&package.info.name);
-        fs::create_dir_all(&node_modules_path)?;
-
-        // Extract tarball
-        /*
-            * Cursor wraps the Vec<u8> and makes it behave like a file
-            * Implements Read → lets libraries read bytes sequentially.
-            * Implements Seek → lets libraries jump around in the data if needed.
-        */
-        let tar = flate2::read::GzDecoder::new(Cursor::new(bytes)); // .tar.gz -> .tar
-        let mut archive = tar::Archive::new(tar); // .tar -> . i.e., each file in the tarball with proper directory structure
-
-        for entry in archive.entries()? {
-            let mut entry = entry?;
-            let path = entry.path()?.into_owned();
-
-            let mut components = path.components();
-            components.next(); // Skip top-level folder
-            let relative_path = components.as_path();
-            let dest_path = node_modules_path.join(relative_path);
-
-            if let Some(parent) = dest_path.parent() {
-                fs::create_dir_all(parent)?;
+        options.emit(InstallEvent::Downloading {
+            name: &package.info.name,
+            version: package.info.version.to_string(),
+        });
+        let (bytes, byte_count) = match shared {
+            Some(shared) => {
+                let mut fetched = false;
+                let bytes = shared
+                    .get_or_try_init(|| async {
+                        fetched = true;
+                        tarball_bytes(&client, &package.info, options, &cache, limiter).await.map(Arc::new)
+                    })
+                    .await?
+                    .clone();
+                if !fetched {
+                    options.debug(&format!(
+                        "{}@{} reuses an identical tarball fetched for another package",
+                        package.info.name, package.info.version
+                    ));
+                }
+                // Only the first copy counts towards the transfer size
+                let byte_count = if fetched { bytes.len() as u64 } else { 0 };
+                (bytes, byte_count)
             }
+            None => {
+                let bytes = tarball_bytes(&client, &package.info, options, &cache, limiter).await?;
+                let byte_count = bytes.len() as u64;
+                (Arc::new(bytes), byte_count)
+            }
+        };
+        let integrity = compute_integrity_sha512(&bytes);
 
-            entry.unpack(dest_path)?;
-        }
+        let node_modules_path = options.node_modules_dir().join(&package.info.name);
+        extract_tarball(&bytes, &node_modules_path)?;
+        options.emit(InstallEvent::Extracted {
+            name: &package.info.name,
+            version: package.info.version.to_string(),
+        });
 
-        create_bin_links(&package.info, &node_modules_path)?;
+        create_bin_links(&package.info, &node_modules_path, &options.node_modules_dir())?;
         run_lifecycle_scripts(&package.info, &node_modules_path, options)?;
 
-        Ok(true)
+        Ok(InstallStatus::Installed {
+            integrity: Some(integrity),
+            bytes: byte_count,
+            previous_version,
+        })
     }
 }
 
-fn load_locked_versions() -> Result<HashMap<String, Version>, Box<dyn std::error::Error + Send + Sync>> {
-    let path = Path::new("package-lock.json");
-    if !path.exists() {
-        return Ok(HashMap::new());
+// Cached tarballs older than this are downloaded again
+const TARBALL_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+// One tarball shared by every package in a run with the same integrity
+type SharedTarball = Arc<tokio::sync::OnceCell<Arc<Vec<u8>>>>;
+
+// A package's tarball from whichever source has it: a local `file:` tarball
+// is read as-is, a vendored mirror wins over the cache, and the registry is
+// the fallback (refreshing the cache).
+async fn tarball_bytes(
+    client: &reqwest::Client,
+    package: &PackageInfo,
+    options: &InstallOptions,
+    cache: &PackageCache,
+    limiter: Option<&RateLimiter>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let package_version = package.version.to_string();
+
+    if let Some(path) = local_tarball_path(&package.tarball_url) {
+        let data = fs::read(options.prefix.join(path))
+            .map_err(|e| format!("Could not read file:{}: {}", path, e))?;
+        verify_tarball_integrity(package, &data)?;
+        return Ok(data);
+    }
+    if let Some(vendored) = read_vendored_tarball(package, options)? {
+        return Ok(vendored);
     }
+    let corrupt = match cache.get_valid_tarball(
+        &package.name,
+        &package_version,
+        package.checksum.as_ref(),
+        TARBALL_CACHE_MAX_AGE,
+    )? {
+        CachedTarball::Hit(data) => return Ok(data),
+        CachedTarball::Miss => None,
+        CachedTarball::Corrupt(damage) => Some(damage),
+    };
 
-    let data = std::fs::read_to_string(path)?;
-    let lockfile: PackageLock = serde_json::from_str(&data)?;
+    // Cache miss, stale entry, or a damaged one (already evicted): download
+    // again. There is no offline mode to stop at a corrupt entry, so without
+    // the network only a vendored copy (checked above) can stand in for it.
+    let bytes = download_tarball(client, package, options, limiter)
+        .await
+        .map_err(|e| match &corrupt {
+            Some(damage) => format!(
+                "cached tarball for {}@{} was corrupt ({}) and downloading it again failed: {}",
+                package.name, package.version, damage, e
+            )
+            .into(),
+            None => e,
+        })?;
+    verify_tarball_integrity(package, &bytes)?;
+    if let Some(damage) = &corrupt {
+        options.warn(&format!(
+            "cached tarball for {}@{} was corrupt ({}); replaced it with a fresh download",
+            package.name, package.version, damage
+        ));
+    }
 
-    let mut locked_versions = HashMap::new();
-    for (path_key, locked_package) in lockfile.packages {
-        let Some(name) = lockfile_package_name(&path_key) else {
+    // Save to cache for future use
+    if let Err(e) = cache.save_tarball(&package.name, &package_version, &bytes) {
+        output::warn(&format!("Failed to cache {}@{}: {}", package.name, package.version, e));
+    }
+    Ok(bytes)
+}
+
+// Unpack a package tarball into `dest`, dropping the archive's top-level
+// folder (`package/` in npm tarballs). Every tarball source goes through here.
+// Entries whose path would leave `dest` are refused, links are skipped (npm
+// doesn't install them either), and modes are normalized to 0644/0755 so a
+// tarball can neither hide files from their owner nor ship setuid bits.
+pub fn extract_tarball(bytes: &[u8], dest: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fs::create_dir_all(dest)?;
+
+    /*
+        * Cursor wraps the bytes and makes them behave like a file
+        * Implements Read → lets libraries read bytes sequentially.
+        * Implements Seek → lets libraries jump around in the data if needed.
+    */
+    let tar = flate2::read::GzDecoder::new(Cursor::new(bytes)); // .tar.gz -> .tar
+    let mut archive = tar::Archive::new(tar); // .tar -> . i.e., each file in the tarball with proper directory structure
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw_path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let Some(relative_path) = tarball_entry_path(&raw_path, cfg!(windows))
+            .map_err(|reason| format!("refusing to extract '{}': {}", raw_path, reason))?
+        else {
             continue;
         };
-        if let Ok(version) = Version::parse(&locked_package.version) {
-            locked_versions.insert(name.to_string(), version);
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            continue;
         }
-    }
 
-    Ok(locked_versions)
+        let dest_path = dest.join(relative_path);
+        if entry_type.is_dir() {
+            fs::create_dir_all(&dest_path)?;
+            continue;
+        }
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&dest_path)?;
+
+        #[cfg(unix)]
+        {
+            let executable = entry.header().mode().unwrap_or(0o644) & 0o111 != 0;
+            let mode = if executable { 0o755 } else { 0o644 };
+            fs::set_permissions(&dest_path, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
 }
 
-fn lockfile_package_name(path_key: &str) -> Option<&str> {
-    if path_key.is_empty() {
-        return None;
+// Names Windows reserves for devices, with or without an extension (`nul.js`)
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Where a tarball entry lands relative to the package directory, or None for
+// the top-level folder itself. Both `/` and `\` separate segments (tarballs
+// packed on Windows use either), and the first segment (`package/`) is
+// dropped. `..` and absolute paths are refused everywhere; on Windows so are
+// drive letters and stream names (`:`), characters NTFS can't store, trailing
+// dots or spaces, and reserved device names.
+fn tarball_entry_path(raw: &str, windows: bool) -> Result<Option<PathBuf>, String> {
+    if raw.starts_with(['/', '\\']) {
+        return Err("absolute path".to_string());
     }
 
-    if path_key.contains("node_modules/") {
-        return path_key.rsplit("node_modules/").next();
+    let mut relative = PathBuf::new();
+    for segment in raw.split(['/', '\\']).skip(1) {
+        match segment {
+            "" | "." => continue,
+            ".." => return Err("path leaves the package directory".to_string()),
+            _ => {}
+        }
+        if windows {
+            if segment
+                .chars()
+                .any(|c| matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') || c.is_control())
+            {
+                return Err(format!("'{}' is not a valid file name on Windows", segment));
+            }
+            if segment.ends_with(['.', ' ']) {
+                return Err(format!("'{}' ends with a dot or space", segment));
+            }
+            let stem = segment.split('.').next().unwrap_or(segment).trim_end();
+            if WINDOWS_RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(stem)) {
+                return Err(format!("'{}' is a reserved device name on Windows", segment));
+            }
+        }
+        relative.push(segment);
     }
 
-    Some(path_key)
+    Ok((!relative.as_os_str().is_empty()).then_some(relative))
 }
 
-fn expand_workspace_pattern(pattern: &str) -> Vec<PathBuf> {
-    if let Some(prefix) = pattern.strip_suffix("/*") {
-        let base = Path::new(prefix);
-        let mut paths = Vec::new();
-        if let Ok(entries) = fs::read_dir(base) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if path.is_dir() {
-                    paths.push(path);
-                }
+// Read a `file:` tarball (relative to the project root) into a PackageInfo.
+// The tarball url stays the `file:` spec so the lockfile records it as-is,
+// alongside the integrity of the bytes that were read.
+fn read_local_tarball(
+    root: &Path,
+    path: &str,
+) -> Result<(PackageInfo, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = fs::read(root.join(path)).map_err(|e| format!("Could not read file:{}: {}", path, e))?;
+    let manifest = read_tarball_manifest(&bytes).map_err(|e| format!("file:{}: {}", path, e))?;
+    let name = manifest
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("file:{}: package.json has no name", path))?;
+    let version = manifest
+        .get("version")
+        .and_then(|v| v.as_str())
+        .and_then(|v| Version::parse(v).ok())
+        .ok_or_else(|| format!("file:{}: package.json has no valid version", path))?;
+
+    let mut info = package_info_from_manifest(name, version, &manifest, format!("file:{}", path));
+    rebase_local_dependencies(&mut info, Path::new(path).parent().unwrap_or(Path::new("")));
+    let integrity = compute_integrity_sha512(&bytes);
+    info.checksum = Integrity::parse(&integrity);
+    info.integrity = Some(integrity);
+    Ok((info, bytes))
+}
+
+// A `file:`/`link:` directory dependency (relative to the project root) read
+// from its package.json. Nothing is copied here; installing links or copies
+// the directory depending on --install-links.
+fn read_local_directory(
+    root: &Path,
+    spec: String,
+    path: &str,
+) -> Result<PackageInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let data = fs::read_to_string(root.join(path).join("package.json"))
+        .map_err(|e| format!("Could not read {}/package.json: {}", spec, e))?;
+    let manifest = manifest::parse(&data).map_err(|e| format!("{}: invalid package.json: {}", spec, e))?;
+    let name = manifest
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("{}: package.json has no name", spec))?;
+    let version = manifest
+        .get("version")
+        .and_then(|v| v.as_str())
+        .and_then(|v| Version::parse(v).ok())
+        .ok_or_else(|| format!("{}: package.json has no valid version", spec))?;
+
+    let mut info = package_info_from_manifest(name, version, &manifest, spec);
+    rebase_local_dependencies(&mut info, Path::new(path));
+    Ok(info)
+}
+
+// `file:` specs in a local package's manifest are relative to where that
+// package lives, not to the project. Rewriting them onto `base` (the package's
+// own directory, relative to the project root) lets chains of local packages
+// resolve, and lock, like the root's own `file:` dependencies.
+fn rebase_local_dependencies(info: &mut PackageInfo, base: &Path) {
+    for dependencies in [
+        &mut info.dependencies,
+        &mut info.optional_dependencies,
+        &mut info.peer_dependencies,
+    ] {
+        for req in dependencies.values_mut() {
+            let raw = req.display();
+            let Some((scheme, nested)) = ["file:", "link:"]
+                .into_iter()
+                .find_map(|scheme| raw.strip_prefix(scheme).map(|path| (scheme, path)))
+                .filter(|(_, path)| !Path::new(path).is_absolute())
+            else {
+                continue;
+            };
+            let rebased = format!("{}{}", scheme, join_relative(base, nested).display());
+            if let Ok(rebased) = NpmVersionReq::parse(&rebased) {
+                *req = rebased;
             }
         }
-        return paths;
     }
-
-    vec![PathBuf::from(pattern)]
 }
 
-fn load_workspace_packages() -> Result<HashMap<String, WorkspacePackage>, Box<dyn std::error::Error + Send + Sync>> {
-    let root_package_json = Path::new("package.json");
-    if !root_package_json.exists() {
-        return Ok(HashMap::new());
+// `base/relative` with `.` and `..` resolved lexically, so the spec saved to
+// the lockfile stays short and relative
+fn join_relative(base: &Path, relative: &str) -> PathBuf {
+    let mut joined = base.to_path_buf();
+    for component in Path::new(relative).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(joined.components().next_back(), Some(Component::Normal(_))) => {
+                joined.pop();
+            }
+            other => joined.push(other),
+        }
     }
+    joined
+}
 
-    let data = fs::read_to_string(root_package_json)?;
-    let json: Value = serde_json::from_str(&data)?;
-    let mut workspace_patterns: Vec<String> = Vec::new();
-
-    if let Some(workspaces) = json.get("workspaces") {
-        if let Some(arr) = workspaces.as_array() {
-            for item in arr {
-                if let Some(pattern) = item.as_str() {
-                    workspace_patterns.push(pattern.to_string());
-                }
+// Build a PackageInfo from one version's manifest: a registry document's
+// `versions[v]` entry or a git checkout's package.json
+// The ranges in one dependency section of a package manifest; a range that
+// doesn't parse is reported and accepts any version instead
+fn manifest_dependency_section(manifest: &Value, section: &str, kind: &str) -> HashMap<String, NpmVersionReq> {
+    let mut dependencies = HashMap::new();
+    let Some(deps) = manifest.get(section).and_then(|deps| deps.as_object()) else {
+        return dependencies;
+    };
+    for (dep_name, dep_version) in deps {
+        let Some(version_str) = dep_version.as_str() else {
+            continue;
+        };
+        match NpmVersionReq::parse(version_str) {
+            Ok(req) => {
+                dependencies.insert(dep_name.clone(), req);
             }
-        } else if let Some(packages) = workspaces.get("packages").and_then(|v| v.as_array()) {
-            for item in packages {
-                if let Some(pattern) = item.as_str() {
-                    workspace_patterns.push(pattern.to_string());
+            Err(e) => {
+                output::warn(&format!(
+                    "Warning: Could not parse {} for '{}': '{}'. Error: {}. Using '*' as fallback.",
+                    kind, dep_name, version_str, e
+                ));
+                if let Ok(any_version_req) = NpmVersionReq::any() {
+                    dependencies.insert(dep_name.clone(), any_version_req);
                 }
             }
         }
     }
+    dependencies
+}
 
-    let mut workspace_packages = HashMap::new();
-    for pattern in workspace_patterns {
-        for workspace_dir in expand_workspace_pattern(&pattern) {
-            let workspace_package_json = workspace_dir.join("package.json");
-            if !workspace_package_json.exists() {
-                continue;
-            }
+pub fn package_info_from_manifest(name: &str, version: Version, manifest: &Value, tarball_url: String) -> PackageInfo {
+    let dependencies = manifest_dependency_section(manifest, "dependencies", "version requirement");
+    let peer_dependencies = manifest_dependency_section(manifest, "peerDependencies", "peer dependency");
+    let optional_peers = optional_peers(manifest);
+    let optional_dependencies = manifest_dependency_section(manifest, "optionalDependencies", "optional dependency");
+
+    let shasum = manifest["dist"]["shasum"]
+        .as_str()
+        .map(|value| value.to_string());
+
+    let integrity = manifest["dist"]["integrity"]
+        .as_str()
+        .map(|value| value.to_string());
+
+    let engines_node = manifest
+        .get("engines")
+        .and_then(|v| v.get("node"))
+        .and_then(|v| v.as_str())
+        .and_then(|v| NpmVersionReq::parse(v).ok());
+
+    let engines_rnp = manifest
+        .get("engines")
+        .and_then(|v| v.get("rnp"))
+        .and_then(|v| v.as_str())
+        .and_then(|v| NpmVersionReq::parse(v).ok());
+
+    let os_constraints = manifest
+        .get("os")
+        .and_then(|v| v.as_array())
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
 
-            let workspace_data = fs::read_to_string(&workspace_package_json)?;
-            let workspace_json: Value = serde_json::from_str(&workspace_data)?;
+    let cpu_constraints = manifest
+        .get("cpu")
+        .and_then(|v| v.as_array())
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
 
-            let Some(name) = workspace_json.get("name").and_then(|v| v.as_str()) else {
-                continue;
-            };
-            let version_str = workspace_json
-                .get("version")
-                .and_then(|v| v.as_str())
-                .unwrap_or("1.0.0");
-
-            if let Ok(version) = Version::parse(version_str) {
-                workspace_packages.insert(
-                    name.to_string(),
-                    WorkspacePackage {
-                        version,
-                        path: workspace_dir,
-                    },
-                );
+    let mut lifecycle_scripts = HashMap::new();
+    if let Some(scripts_obj) = manifest.get("scripts").and_then(|v| v.as_object()) {
+        for script_name in ["preinstall", "install", "postinstall"] {
+            if let Some(command) = scripts_obj.get(script_name).and_then(|v| v.as_str()) {
+                lifecycle_scripts.insert(script_name.to_string(), command.to_string());
             }
         }
     }
 
-    Ok(workspace_packages)
-}
+    let bin_entries = bin_entries_from_manifest(name, manifest);
 
-fn workspace_manifest_path(
-    workspace_name: Option<&str>,
-    workspace_packages: &HashMap<String, WorkspacePackage>,
-) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
-    if let Some(name) = workspace_name {
-        let workspace = workspace_packages
-            .get(name)
-            .ok_or_else(|| format!("Workspace '{}' not found", name))?;
-        return Ok(workspace.path.join("package.json"));
+    // Bundled dependencies ship inside the tarball's own node_modules
+    let bundled_dependencies = match manifest
+        .get("bundleDependencies")
+        .or_else(|| manifest.get("bundledDependencies"))
+    {
+        Some(Value::Bool(true)) => dependencies.keys().cloned().collect::<HashSet<_>>(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect::<HashSet<_>>(),
+        _ => HashSet::new(),
+    };
+
+    PackageInfo {
+        name: name.to_string(),
+        version,
+        dependencies,
+        peer_dependencies,
+        optional_peers,
+        optional_dependencies,
+        tarball_url,
+        checksum: Integrity::strongest(integrity.as_deref(), shasum.as_deref()),
+        integrity,
+        shasum,
+        is_workspace: false,
+        workspace_path: None,
+        engines_node,
+        engines_rnp,
+        os_constraints,
+        cpu_constraints,
+        lifecycle_scripts,
+        bin_entries,
+        bundled_dependencies,
+        deprecated: deprecation_message(manifest),
     }
+}
 
-    Ok(PathBuf::from("package.json"))
+// `deprecated` is a message; npm writes `false` or "" once it is lifted
+fn deprecation_message(document: &Value) -> Option<String> {
+    document
+        .get("deprecated")
+        .and_then(|v| v.as_str())
+        .filter(|message| !message.trim().is_empty())
+        .map(|message| message.to_string())
 }
 
-fn read_manifest_dependencies_from(path: &Path) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Send + Sync>> {
-    let data = fs::read_to_string(path)?;
-    let json: Value = serde_json::from_str(&data)?;
-    let dependencies = json
-        .get("dependencies")
-        .and_then(|v| v.as_object())
-        .map(|deps| {
-            deps.iter()
-                .filter_map(|(name, val)| val.as_str().map(|s| (name.clone(), s.to_string())))
-                .collect::<BTreeMap<_, _>>()
-        })
-        .unwrap_or_default();
+const PACKAGE_LOCK_FILE: &str = "package-lock.json";
+const SHRINKWRAP_FILE: &str = "npm-shrinkwrap.json";
+const HIDDEN_LOCKFILE: &str = ".package-lock.json";
 
-    Ok(dependencies)
+// npm-shrinkwrap.json takes precedence over package-lock.json when both exist
+pub fn active_lockfile_path(root: &Path) -> PathBuf {
+    let shrinkwrap = root.join(SHRINKWRAP_FILE);
+    if shrinkwrap.exists() {
+        return shrinkwrap;
+    }
+    root.join(PACKAGE_LOCK_FILE)
 }
 
-fn generate_lockfile(packages: &[ResolvedPackage]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let package_json_data = std::fs::read_to_string("package.json")?;
-    let package_json: serde_json::Value = serde_json::from_str(&package_json_data)?;
+// `--include` wins over `--omit` when both name the same dependency type
+pub fn omitted_dependency_types(omit: &[String], include: &[String]) -> HashSet<String> {
+    omit.iter()
+        .filter(|kind| !include.contains(kind))
+        .cloned()
+        .collect()
+}
 
-    let root_name = package_json
-        .get("name")
-        .and_then(|value| value.as_str())
-        .unwrap_or("rnp-project")
-        .to_string();
+fn sorted_requirements(requirements: &HashMap<String, NpmVersionReq>) -> Vec<(&String, &NpmVersionReq)> {
+    let mut sorted = requirements.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+    sorted
+}
 
-    let root_version = package_json
-        .get("version")
-        .and_then(|value| value.as_str())
-        .unwrap_or("1.0.0")
-        .to_string();
+// Depth first, then name and version, so lockfiles and logs are stable across runs
+fn sort_resolved_packages(packages: &mut [ResolvedPackage]) {
+    packages.sort_by(|a, b| {
+        a.depth
+            .cmp(&b.depth)
+            .then_with(|| a.info.name.cmp(&b.info.name))
+            .then_with(|| a.info.version.cmp(&b.info.version))
+    });
+}
 
-    let root_dependencies = package_json
-        .get("dependencies")
-        .and_then(|v| v.as_object())
-        .map(|deps| {
-            deps.iter()
-                .filter_map(|(name, val)| val.as_str().map(|s| (name.clone(), s.to_string())))
-                .collect::<BTreeMap<_, _>>()
-        })
-        .unwrap_or_default();
+// Raw packument for `name`, plus the registry that ended up serving it.
+// Documents are cached on disk with their ETag. Within `min_time` of the last
+// fetch the cached body is used as-is; after that it is revalidated and a 304
+// reuses it.
+//
+// A scoped package with an `@scope:registry` is looked up on that registry
+// alone; everything else on the primary registry, then its fallback mirrors
+// (only past a 5xx or a timeout).
+pub async fn fetch_registry_document(
+    client: &reqwest::Client,
+    registries: &Registries,
+    cache_dir: &Path,
+    min_time: Duration,
+    name: &str,
+) -> Result<(Value, String), Box<dyn std::error::Error + Send + Sync>> {
+    let cache = PackageCache::new(cache_dir).ok();
+    let cached = cache.as_ref().and_then(|cache| cache.get_metadata(name));
+
+    // A scope with its own registry is only ever looked up there, never on the
+    // primary or its mirrors: the public registry must neither learn private
+    // package names nor get to answer for them
+    let scope_registry = registries.scope_registry(name);
+
+    // Only trust an unrevalidated copy from a registry still in use
+    if let Some(entry) = &cached
+        && entry.is_fresh(min_time)
+        && let Some(registry) = entry.registry.as_deref()
+        && match scope_registry {
+            Some(scope_registry) => registry == scope_registry,
+            None => registries.urls().iter().any(|url| url == registry),
+        }
+    {
+        return Ok((entry.document.clone(), registry.to_string()));
+    }
 
-    let workspace_paths = load_workspace_packages()?
-        .into_iter()
-        .map(|(name, pkg)| (name, pkg.path.to_string_lossy().to_string()))
-        .collect::<BTreeMap<_, _>>();
+    let mut headers = HeaderMap::new();
+    if let Some(etag) = cached.as_ref().and_then(|entry| entry.etag.as_deref())
+        && let Ok(value) = HeaderValue::from_str(etag)
+    {
+        headers.insert(IF_NONE_MATCH, value);
+    }
 
-    let mut lock_packages = BTreeMap::new();
-    lock_packages.insert(
-        "".to_string(),
-        LockfilePackage {
-            version: root_version.clone(),
-            resolved: String::new(),
-            integrity: None,
-            dependencies: root_dependencies.clone(),
-            shasum: None,
-        },
-    );
+    // Only the copy the condition was sent for can answer a 304
+    let cached = cached.filter(|_| headers.contains_key(IF_NONE_MATCH));
 
-    for package in packages {
-        let dependencies = package
-            .info
-            .dependencies
+    let url = format!("{}/{}", scope_registry.unwrap_or(registries.primary()), name);
+    let (mut response, mut served_by) = registries.get_with_headers(client, &url, headers).await?;
+    // A 304 nobody asked for has no body to reuse; ask again unconditionally
+    if response.status() == StatusCode::NOT_MODIFIED && cached.is_none() {
+        (response, served_by) = registries.get(client, &url).await?;
+    }
+
+    let no_cache = response
+        .headers()
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| matches!(directive.trim(), "no-cache" | "no-store"));
+
+    if response.status() == StatusCode::NOT_MODIFIED
+        && let Some(mut entry) = cached
+    {
+        // Restart the freshness window
+        entry.registry = Some(served_by.clone());
+        entry.no_cache = no_cache;
+        if let Some(cache) = &cache
+            && let Err(e) = cache.save_metadata(name, &entry)
+        {
+            output::warn(&format!("Failed to cache metadata for {}: {}", name, e));
+        }
+        return Ok((entry.document, served_by));
+    }
+
+    let status = response.status();
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let body = decompress_if_gzipped(&response.bytes().await?)
+        .map_err(|e| format!("{}: registry returned a corrupt gzip body: {}", name, e))?;
+    let document: Value = match serde_json::from_slice(&body) {
+        Ok(document) => document,
+        // Some registries and proxies answer errors with plain text or HTML
+        Err(_) if status == StatusCode::NOT_FOUND => serde_json::json!({ "error": "Not found" }),
+        Err(_) if !status.is_success() => serde_json::json!({ "error": status.to_string() }),
+        Err(e) => return Err(format!("{}: registry returned invalid metadata: {}", name, e).into()),
+    };
+
+    if !status.is_success() {
+        return Ok((document, served_by));
+    }
+
+    let entry = CachedMetadata {
+        etag,
+        document,
+        fetched_at: 0,
+        registry: Some(served_by.clone()),
+        no_cache,
+    };
+    if let Some(cache) = &cache
+        && let Err(e) = cache.save_metadata(name, &entry)
+    {
+        output::warn(&format!("Failed to cache metadata for {}: {}", name, e));
+    }
+
+    Ok((entry.document, served_by))
+}
+
+// Some mirrors gzip metadata without saying so in `Content-Encoding`, which
+// leaves the compressed bytes for us; recognize them by the gzip magic number
+fn decompress_if_gzipped(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return Ok(body.to_vec());
+    }
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(body).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+// `dist.tarball` as an absolute http(s) URL. Host-relative (`/-/pkg-1.0.0.tgz`)
+// and relative paths, which some private registries return, are resolved
+// against the registry that served the metadata.
+fn resolve_tarball_url(tarball: &str, registry: &str, name: &str) -> Result<String, String> {
+    let base = reqwest::Url::parse(&format!("{}/", registry.trim_end_matches('/')))
+        .map_err(|e| format!("invalid registry URL '{}': {}", registry, e))?;
+    let url = base
+        .join(tarball.trim())
+        .map_err(|e| format!("malformed tarball URL '{}': {}", tarball, e))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("refusing tarball URL '{}': only http(s) is supported", tarball));
+    }
+    // A registry that points a package's tarball back at its own metadata
+    // document would have us "extract" JSON
+    let metadata_url = base.join(name).map_err(|e| e.to_string())?;
+    if url.as_str().trim_end_matches('/') == metadata_url.as_str().trim_end_matches('/') {
+        return Err(format!("refusing tarball URL '{}': it points at the package's own metadata", tarball));
+    }
+
+    Ok(url.to_string())
+}
+
+async fn download_tarball(
+    client: &reqwest::Client,
+    package: &PackageInfo,
+    options: &InstallOptions,
+    limiter: Option<&RateLimiter>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    // Tarballs are gzip already; a transfer encoding on top would be decoded
+    // by the client and break the integrity check
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    let (mut response, served_by) = options
+        .registries
+        .get_with_headers(client, &package.tarball_url, headers)
+        .await?;
+    options.debug(&format!("{}@{} tarball served by {}", package.name, package.version, served_by));
+    let Some(limiter) = limiter else {
+        return Ok(response.bytes().await?.to_vec());
+    };
+
+    // Under --max-rate each chunk waits for its share of the budget
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        limiter.consume(chunk.len()).await;
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn load_locked_versions(root: &Path) -> Result<HashMap<String, Version>, Box<dyn std::error::Error + Send + Sync>> {
+    let path = active_lockfile_path(root);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let data = std::fs::read_to_string(&path)?;
+    let lockfile: PackageLock = serde_json::from_str(&data)?;
+
+    let mut locked_versions = HashMap::new();
+    for (path_key, locked_package) in lockfile.packages {
+        let Some(name) = lockfile_package_name(&path_key) else {
+            continue;
+        };
+        if let Ok(version) = Version::parse(&locked_package.version) {
+            locked_versions.insert(name.to_string(), version);
+        }
+    }
+
+    Ok(locked_versions)
+}
+
+fn read_lockfile(root: &Path) -> Result<Option<PackageLock>, Box<dyn std::error::Error + Send + Sync>> {
+    let path = active_lockfile_path(root);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&data)?))
+}
+
+fn version_changes(before: &PackageLock, after: &PackageLock) -> Vec<VersionChange> {
+    let paths = before
+        .packages
+        .keys()
+        .chain(after.packages.keys())
+        .filter(|path| !path.is_empty())
+        .collect::<BTreeSet<_>>();
+
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let from = before.packages.get(path).map(|p| p.version.clone());
+            let to = after.packages.get(path).map(|p| p.version.clone());
+            (from != to).then(|| VersionChange {
+                package: path
+                    .trim_start_matches("node_modules/")
+                    .replace("/node_modules/", " > "),
+                from,
+                to,
+            })
+        })
+        .collect()
+}
+
+fn print_version_changes(changes: &[VersionChange], options: &InstallOptions) {
+    if changes.is_empty() {
+        options.info("  no version changes");
+        return;
+    }
+    let width = changes.iter().map(|c| c.package.len()).max().unwrap_or(0);
+    for change in changes {
+        options.info(&format!(
+            "  {:<width$}  {} -> {}",
+            change.package,
+            change.from.as_deref().unwrap_or("(none)"),
+            change.to.as_deref().unwrap_or("(removed)"),
+        ));
+    }
+}
+
+fn lockfile_package_name(path_key: &str) -> Option<&str> {
+    if path_key.is_empty() {
+        return None;
+    }
+
+    if path_key.contains("node_modules/") {
+        return path_key.rsplit("node_modules/").next();
+    }
+
+    Some(path_key)
+}
+
+fn load_workspace_packages(root: &Path) -> Result<HashMap<String, WorkspacePackage>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut workspace_packages = HashMap::new();
+    for member in workspace_members(root)? {
+        let version_str = member
+            .manifest
+            .get("version")
+            .and_then(|v| v.as_str())
+            .unwrap_or("1.0.0");
+
+        if let Ok(version) = Version::parse(version_str) {
+            workspace_packages.insert(
+                member.name,
+                WorkspacePackage {
+                    version,
+                    path: member.dir,
+                },
+            );
+        }
+    }
+
+    Ok(workspace_packages)
+}
+
+fn workspace_manifest_path(
+    root: &Path,
+    workspace_name: Option<&str>,
+    workspace_packages: &HashMap<String, WorkspacePackage>,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(name) = workspace_name {
+        let workspace = workspace_packages
+            .get(name)
+            .ok_or_else(|| format!("Workspace '{}' not found", name))?;
+        return Ok(workspace.path.join("package.json"));
+    }
+
+    Ok(root.join("package.json"))
+}
+
+fn read_manifest_dependencies_from(path: &Path) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Send + Sync>> {
+    read_manifest_section(path, "dependencies")
+}
+
+pub fn read_manifest_section(
+    path: &Path,
+    section: &str,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Send + Sync>> {
+    let data = fs::read_to_string(path)?;
+    let json = manifest::parse(&data)?;
+    let dependencies = json
+        .get(section)
+        .and_then(|v| v.as_object())
+        .map(|deps| {
+            deps.iter()
+                .filter_map(|(name, val)| val.as_str().map(|s| (name.clone(), s.to_string())))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    Ok(dependencies)
+}
+
+// The sections a whole-manifest install resolves, highest precedence first
+const ROOT_SECTIONS: [&str; 3] = ["dependencies", "optionalDependencies", "devDependencies"];
+
+// Package name -> (section, range)
+type DeclaredRoots = BTreeMap<String, (&'static str, String)>;
+
+// What a whole-manifest install resolves, one range per package whatever
+// order the sections list it in: `dependencies` wins, `optionalDependencies`
+// only adds packages (installed as optional), and `devDependencies` adds what
+// neither has. Maps each name to its section and range; packages declared in
+// more than one section are reported.
+fn declared_roots(
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<DeclaredRoots, Box<dyn std::error::Error + Send + Sync>> {
+    collect_declared_roots(manifest_path, options, |message| options.warn(message))
+}
+
+// `declared_roots`, handing each duplicate declaration to `report`
+fn collect_declared_roots(
+    manifest_path: &Path,
+    options: &InstallOptions,
+    mut report: impl FnMut(&str),
+) -> Result<DeclaredRoots, Box<dyn std::error::Error + Send + Sync>> {
+    let sections = ROOT_SECTIONS.into_iter().filter(|section| match *section {
+        "optionalDependencies" => !options.omits("optional"),
+        "devDependencies" => !options.omits("dev"),
+        _ => true,
+    });
+
+    let mut declared = DeclaredRoots::new();
+    for section in sections {
+        for (name, range) in read_manifest_section(manifest_path, section)? {
+            match declared.get(&name) {
+                Some((kept_section, kept_range)) => report(&format!(
+                    "{} is declared in both {} ({}) and {} ({}); installing the {} range",
+                    name, kept_section, kept_range, section, range, kept_section
+                )),
+                None => {
+                    declared.insert(name, (section, range));
+                }
+            }
+        }
+    }
+
+    Ok(declared)
+}
+
+// Dependencies the manifests declare with different ranges, as `name: range
+// (manifest), range (manifest)`. A target whose ranges are all registry
+// ranges is fine: it is re-resolved and every manifest gets the new range.
+fn conflicting_member_ranges(
+    manifests: &[PathBuf],
+    declared_by_manifest: &[DeclaredRoots],
+    targets: &[String],
+) -> Vec<String> {
+    let mut ranges: BTreeMap<&str, Vec<(&str, &Path)>> = BTreeMap::new();
+    for (manifest_path, declared) in manifests.iter().zip(declared_by_manifest) {
+        for (name, (_, range)) in declared {
+            ranges.entry(name).or_default().push((range, manifest_path));
+        }
+    }
+
+    let is_registry_range = |range: &str| NpmVersionReq::parse(range).is_ok_and(|req| !req.is_source_spec());
+    ranges
+        .into_iter()
+        .filter(|(name, declared)| {
+            declared.iter().any(|(range, _)| *range != declared[0].0)
+                && !(targets.iter().any(|target| target == name)
+                    && declared.iter().all(|(range, _)| is_registry_range(range)))
+        })
+        .map(|(name, declared)| {
+            let sources = declared
+                .iter()
+                .map(|(range, path)| format!("{} ({})", range, path.display()))
+                .collect::<Vec<_>>();
+            format!("{}: {}", name, sources.join(", "))
+        })
+        .collect()
+}
+
+fn optional_root_names(declared: &DeclaredRoots) -> HashSet<String> {
+    declared
+        .iter()
+        .filter(|(_, (section, _))| *section == "optionalDependencies")
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+fn generate_lockfile(
+    root: &Path,
+    packages: &[ResolvedPackage],
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    write_lockfile(&build_lockfile(root, packages, options)?, &active_lockfile_path(root))
+}
+
+// npm's hidden lockfile: the same format as package-lock.json, but describing
+// what is actually in node_modules (also after --no-save/--no-package-lock
+// installs), so audit and friends can read the installed tree without
+// resolving again. Entries from earlier installs stay while their package
+// directory still exists.
+fn write_hidden_lockfile(
+    root: &Path,
+    packages: &[ResolvedPackage],
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = hidden_lockfile_path(root);
+    let mut lockfile = build_lockfile(root, packages, options)?;
+    if let Ok(data) = fs::read_to_string(&path)
+        && let Ok(previous) = serde_json::from_str::<PackageLock>(&data)
+    {
+        for (key, entry) in previous.packages {
+            lockfile.packages.entry(key).or_insert(entry);
+        }
+    }
+    lockfile
+        .packages
+        .retain(|key, _| key.is_empty() || root.join(key).join("package.json").exists());
+    lockfile.dist_tags.clear();
+    write_lockfile(&lockfile, &path)
+}
+
+// Hashes what decides the outcome of a bare `rnp install`: the dependency
+// sections of package.json, the lockfile, which dependency types are omitted
+// and the node_modules layout options
+fn manifest_hash(
+    root: &Path,
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut hasher = Sha512::new();
+    hasher.update(manifest_path.to_string_lossy().as_bytes());
+    for section in SAVE_SECTIONS {
+        hasher.update(section.as_bytes());
+        hasher.update(serde_json::to_vec(&read_manifest_section(manifest_path, section)?)?);
+    }
+    let mut omitted = options.omit.iter().collect::<Vec<_>>();
+    omitted.sort();
+    hasher.update(format!("omit={:?}", omitted).as_bytes());
+    hasher.update(
+        format!(
+            "layout={}:{}:{}",
+            options.hoist_strategy, options.legacy_bundling, options.install_links
+        )
+        .as_bytes(),
+    );
+    hasher.update(format!("platform={}:{}", options.target_os(), options.target_cpu()).as_bytes());
+    if let Ok(lockfile) = fs::read(active_lockfile_path(root)) {
+        hasher.update(&lockfile);
+    }
+    Ok(format!("sha512-{}", STANDARD.encode(hasher.finalize())))
+}
+
+fn record_manifest_hash(
+    root: &Path,
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = hidden_lockfile_path(root);
+    let Ok(data) = fs::read_to_string(&path) else {
+        return Ok(());
+    };
+    let mut lockfile = serde_json::from_str::<PackageLock>(&data)?;
+    lockfile.manifest_hash = Some(manifest_hash(root, manifest_path, options)?);
+    write_lockfile(&lockfile, &path)
+}
+
+// Whether the last full install ran against the same manifest and lockfile,
+// and everything it installed is still there
+fn install_is_up_to_date(
+    root: &Path,
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let Ok(data) = fs::read_to_string(hidden_lockfile_path(root)) else {
+        return Ok(false);
+    };
+    let Ok(lockfile) = serde_json::from_str::<PackageLock>(&data) else {
+        return Ok(false);
+    };
+    if lockfile.manifest_hash != Some(manifest_hash(root, manifest_path, options)?) {
+        return Ok(false);
+    }
+    Ok(lockfile
+        .packages
+        .keys()
+        .all(|path| path.is_empty() || root.join(path).join("package.json").exists()))
+}
+
+pub fn hidden_lockfile_path(root: &Path) -> PathBuf {
+    root.join("node_modules").join(HIDDEN_LOCKFILE)
+}
+
+fn build_lockfile(
+    root: &Path,
+    packages: &[ResolvedPackage],
+    options: &InstallOptions,
+) -> Result<PackageLock, Box<dyn std::error::Error + Send + Sync>> {
+    let package_json_data = std::fs::read_to_string(root.join("package.json"))?;
+    let package_json = manifest::parse(&package_json_data)?;
+
+    let root_name = package_json
+        .get("name")
+        .and_then(|value| value.as_str())
+        .unwrap_or("rnp-project")
+        .to_string();
+
+    let root_version = package_json
+        .get("version")
+        .and_then(|value| value.as_str())
+        .unwrap_or("1.0.0")
+        .to_string();
+
+    let root_dependencies = package_json
+        .get("dependencies")
+        .and_then(|v| v.as_object())
+        .map(|deps| {
+            deps.iter()
+                .filter_map(|(name, val)| val.as_str().map(|s| (name.clone(), s.to_string())))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    // Workspace paths are stored relative to the project root
+    let workspace_paths = load_workspace_packages(root)?
+        .into_iter()
+        .map(|(name, pkg)| {
+            let relative = pkg.path.strip_prefix(root).unwrap_or(&pkg.path);
+            (name, relative.to_string_lossy().to_string())
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let mut lock_packages = BTreeMap::new();
+    lock_packages.insert(
+        "".to_string(),
+        LockfilePackage {
+            version: root_version.clone(),
+            resolved: String::new(),
+            integrity: None,
+            dependencies: root_dependencies.clone(),
+            shasum: None,
+            optional: false,
+            os: Vec::new(),
+            cpu: Vec::new(),
+        },
+    );
+
+    let install_paths = if options.legacy_bundling {
+        legacy_install_paths(packages, options)?
+    } else {
+        packages
+            .iter()
+            .map(|package| (format!("node_modules/{}", package.info.name), package))
+            .collect()
+    };
+    for (lock_path, package) in install_paths {
+        let dependencies = package
+            .info
+            .dependencies
             .iter()
             .map(|(name, req)| (name.clone(), req.display()))
             .chain(
@@ -954,7 +2847,6 @@ fn generate_lockfile(packages: &[ResolvedPackage]) -> Result<(), Box<dyn std::er
             )
             .collect::<BTreeMap<_, _>>();
 
-        let lock_path = format!("node_modules/{}", package.info.name);
         lock_packages.insert(
             lock_path,
             LockfilePackage {
@@ -963,574 +2855,2949 @@ fn generate_lockfile(packages: &[ResolvedPackage]) -> Result<(), Box<dyn std::er
                 integrity: package.info.integrity.clone(),
                 dependencies,
                 shasum: package.info.shasum.clone(),
+                optional: package.optional,
+                os: package.info.os_constraints.clone(),
+                cpu: package.info.cpu_constraints.clone(),
             },
         );
     }
 
-    let lockfile = PackageLock {
-        name: root_name,
-        version: root_version,
-        lockfile_version: 1,
-        requires: true,
-        dependencies: root_dependencies,
-        workspace_paths,
-        packages: lock_packages,
+    // Tags stay recorded for as long as the package remains a direct dependency
+    let dist_tags = load_dist_tags(root)?
+        .into_iter()
+        .filter(|(name, _)| packages.iter().any(|p| p.depth == 0 && &p.info.name == name))
+        .collect();
+
+    let lockfile = PackageLock {
+        name: root_name,
+        version: root_version,
+        lockfile_version: 1,
+        requires: true,
+        dependencies: root_dependencies,
+        workspace_paths,
+        dist_tags,
+        manifest_hash: None,
+        packages: lock_packages,
+    };
+
+    Ok(lockfile)
+}
+
+fn load_dist_tags(root: &Path) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Send + Sync>> {
+    let path = active_lockfile_path(root);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let lockfile: PackageLock = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    Ok(lockfile.dist_tags)
+}
+
+// Remember (or forget, with None) which dist-tag a direct dependency tracks
+fn record_dist_tag(
+    root: &Path,
+    package: &str,
+    tag: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = active_lockfile_path(root);
+    let mut lockfile: PackageLock = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    let changed = match tag {
+        Some(tag) => lockfile.dist_tags.insert(package.to_string(), tag.to_string()).as_deref() != Some(tag),
+        None => lockfile.dist_tags.remove(package).is_some(),
+    };
+    if changed {
+        write_lockfile(&lockfile, &path)?;
+    }
+    Ok(())
+}
+
+// The `versions` map of a packument, or an error saying why there is none:
+// the registry reported an error, the package doesn't exist, or every
+// version has been unpublished.
+pub fn published_versions<'a>(
+    name: &str,
+    document: &'a Value,
+) -> Result<&'a Map<String, Value>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(error) = document.get("error").and_then(|v| v.as_str()) {
+        if error.eq_ignore_ascii_case("not found") {
+            return Err(format!("package {} not found in the registry", name).into());
+        }
+        return Err(format!("registry returned an error for {}: {}", name, error).into());
+    }
+
+    if let Some(versions) = document.get("versions").and_then(|v| v.as_object())
+        && !versions.is_empty()
+    {
+        return Ok(versions);
+    }
+
+    let unpublished = &document["time"]["unpublished"];
+    if document.get("versions").is_some() || !unpublished.is_null() {
+        return Err(match unpublished["time"].as_str() {
+            Some(when) => format!("package {} has no published versions (unpublished {})", name, when),
+            None => format!("package {} has no published versions", name),
+        }
+        .into());
+    }
+    if document.get("name").is_none() {
+        return Err(format!("package {} not found in the registry", name).into());
+    }
+    Err(format!("registry returned metadata for {} without a versions list", name).into())
+}
+
+// The exact version a dist-tag currently points at, as a requirement
+async fn dist_tag_requirement(
+    client: &reqwest::Client,
+    options: &InstallOptions,
+    name: &str,
+    tag: &str,
+) -> Result<NpmVersionReq, Box<dyn std::error::Error + Send + Sync>> {
+    let (document, _) = fetch_registry_document(client, &options.registries, &options.cache_dir, options.cache_min_time, name).await?;
+    let version = document["dist-tags"][tag]
+        .as_str()
+        .ok_or_else(|| format!("{} has no dist-tag '{}'", name, tag))?;
+    NpmVersionReq::parse(&format!("={}", version))
+}
+
+fn write_lockfile(lockfile: &PackageLock, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let serialized = serde_json::to_string_pretty(lockfile)?;
+    std::fs::write(path, serialized)?;
+    Ok(())
+}
+
+fn packages_from_lockfile(
+    root: &Path,
+    lockfile: &PackageLock,
+) -> Result<Vec<ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut packages = Vec::new();
+
+    for (path_key, locked) in &lockfile.packages {
+        let Some(name) = lockfile_package_name(path_key) else {
+            continue;
+        };
+
+        let version = Version::parse(&locked.version)?;
+        let dependencies = locked
+            .dependencies
+            .iter()
+            .filter_map(|(dep, req)| NpmVersionReq::parse(req).ok().map(|r| (dep.clone(), r)))
+            .collect::<HashMap<_, _>>();
+
+        let depth = path_key.matches("node_modules/").count();
+
+        let workspace_path = lockfile.workspace_paths.get(name).map(|path| root.join(path));
+        let is_workspace = workspace_path.is_some();
+
+        let info = PackageInfo {
+            name: name.to_string(),
+            version,
+            dependencies,
+            peer_dependencies: HashMap::new(),
+            optional_peers: HashSet::new(),
+            optional_dependencies: HashMap::new(),
+            tarball_url: locked.resolved.clone(),
+            integrity: locked.integrity.clone(),
+            shasum: locked.shasum.clone(),
+            checksum: Integrity::strongest(locked.integrity.as_deref(), locked.shasum.as_deref()),
+            is_workspace,
+            workspace_path,
+            engines_node: None,
+            engines_rnp: None,
+            os_constraints: locked.os.clone(),
+            cpu_constraints: locked.cpu.clone(),
+            lifecycle_scripts: HashMap::new(),
+            bin_entries: HashMap::new(),
+            bundled_dependencies: HashSet::new(),
+            deprecated: None,
+        };
+
+        packages.push(ResolvedPackage {
+            info,
+            depth,
+            optional: locked.optional,
+        });
+    }
+
+    Ok(packages)
+}
+
+// Packages pinned by the active lockfile, with `depth` measured from the
+// project's direct dependencies (0) instead of where they were hoisted to.
+// Packages nothing else depends on (e.g. devDependencies) count as direct.
+pub fn load_locked_tree(root: &Path) -> Result<Vec<ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
+    let lockfile_path = active_lockfile_path(root);
+    if !lockfile_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let lock_data = fs::read_to_string(&lockfile_path)?;
+    let lockfile: PackageLock = serde_json::from_str(&lock_data)?;
+    tree_from_lockfile(root, &lockfile)
+}
+
+// What rnp last installed into node_modules, from the hidden lockfile, with
+// depths measured like `load_locked_tree`. None when there is no readable
+// hidden lockfile (e.g. node_modules predates it); entries whose directory
+// has since been removed are left out.
+pub fn load_installed_tree(root: &Path) -> Result<Option<Vec<ResolvedPackage>>, Box<dyn std::error::Error + Send + Sync>> {
+    let Ok(data) = fs::read_to_string(hidden_lockfile_path(root)) else {
+        return Ok(None);
+    };
+    let Ok(mut lockfile) = serde_json::from_str::<PackageLock>(&data) else {
+        return Ok(None);
+    };
+    lockfile
+        .packages
+        .retain(|path, _| path.is_empty() || root.join(path).join("package.json").exists());
+    Ok(Some(tree_from_lockfile(root, &lockfile)?))
+}
+
+fn tree_from_lockfile(
+    root: &Path,
+    lockfile: &PackageLock,
+) -> Result<Vec<ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut packages = packages_from_lockfile(root, lockfile)?;
+
+    let index_by_name = packages
+        .iter()
+        .enumerate()
+        .map(|(index, p)| (p.info.name.clone(), index))
+        .collect::<HashMap<_, _>>();
+    let depended_on = packages
+        .iter()
+        .flat_map(|p| p.info.dependencies.keys().cloned())
+        .collect::<HashSet<_>>();
+
+    let mut queue = lockfile
+        .dependencies
+        .keys()
+        .cloned()
+        .chain(
+            sorted_names(&index_by_name)
+                .into_iter()
+                .filter(|name| !depended_on.contains(name)),
+        )
+        .map(|name| (name, 0))
+        .collect::<VecDeque<_>>();
+
+    let mut depths: HashMap<String, usize> = HashMap::new();
+    while let Some((name, depth)) = queue.pop_front() {
+        if depths.contains_key(&name) {
+            continue;
+        }
+        let Some(&index) = index_by_name.get(&name) else {
+            continue;
+        };
+        depths.insert(name, depth);
+
+        let mut children = packages[index].info.dependencies.keys().cloned().collect::<Vec<_>>();
+        children.sort();
+        queue.extend(children.into_iter().map(|child| (child, depth + 1)));
+    }
+
+    for package in &mut packages {
+        package.depth = depths.get(&package.info.name).copied().unwrap_or(0);
+    }
+    sort_resolved_packages(&mut packages);
+    Ok(packages)
+}
+
+fn sorted_names(index_by_name: &HashMap<String, usize>) -> Vec<String> {
+    let mut names = index_by_name.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+// `--depth <n>`: keep packages at most `n` levels below the direct dependencies
+pub fn within_depth(package: &ResolvedPackage, max_depth: Option<usize>) -> bool {
+    max_depth.is_none_or(|max| package.depth <= max)
+}
+
+// The lockfile records the `dependencies` ranges it was resolved from; for the
+// other sections a whole-manifest install seeds, each root must at least be
+// locked at a version its declared range accepts.
+fn lockfile_matches_manifest(
+    root: &Path,
+    lockfile: &PackageLock,
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    if manifest_path != root.join("package.json") {
+        return Ok(true);
+    }
+
+    let manifest_deps = read_manifest_dependencies_from(manifest_path)?;
+    if manifest_deps != lockfile.dependencies {
+        return Ok(false);
+    }
+
+    let declared = collect_declared_roots(manifest_path, options, |_| {})?;
+    Ok(declared.iter().all(|(name, (_, range))| {
+        let Ok(req) = NpmVersionReq::parse(range) else {
+            // Tags and the like can't be checked without the registry
+            return true;
+        };
+        lockfile
+            .packages
+            .get(&format!("node_modules/{}", name))
+            .and_then(|locked| Version::parse(&locked.version).ok())
+            .is_some_and(|version| req.matches(&version))
+    }))
+}
+
+fn ensure_lockfile_in_sync(
+    root: &Path,
+    lockfile: &PackageLock,
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !lockfile_matches_manifest(root, lockfile, manifest_path, options)? {
+        return Err(format!(
+            "{} and {} are out of sync. Run `rnp install` first.",
+            manifest_path.display(),
+            active_lockfile_path(root).display()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// True when the manifest already lists `package` and node_modules holds a
+// version that satisfies the recorded range.
+fn is_already_installed(
+    manifest_path: &Path,
+    package: &str,
+    options: &InstallOptions,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let manifest_deps = read_manifest_section(manifest_path, &options.save_section)?;
+    let Some(range) = manifest_deps.get(package) else {
+        return Ok(false);
+    };
+    let Ok(req) = NpmVersionReq::parse(range) else {
+        return Ok(false);
+    };
+
+    let installed_version = installed_version(&options.node_modules_dir().join(package))
+        .and_then(|v| Version::parse(&v).ok());
+
+    Ok(installed_version.is_some_and(|version| req.matches(&version)))
+}
+
+// The range `package` is declared with in package.json, looking in `section`
+// first; None for undeclared packages and ranges we can't parse
+fn declared_range(
+    manifest_path: &Path,
+    package: &str,
+    section: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    for section in std::iter::once(section).chain(SAVE_SECTIONS) {
+        if let Some(range) = read_manifest_section(manifest_path, section)?.remove(package) {
+            return Ok(NpmVersionReq::parse(&range).is_ok().then_some(range));
+        }
+    }
+    Ok(None)
+}
+
+// Version recorded in an installed package's package.json, if any
+fn installed_version(package_dir: &Path) -> Option<String> {
+    let data = fs::read_to_string(package_dir.join("package.json")).ok()?;
+    manifest::parse(&data)
+        .ok()?
+        .get("version")
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+}
+
+fn targets_other_platform(options: &InstallOptions) -> bool {
+    options.os.is_some() || options.cpu.is_some()
+}
+
+// Whether any entry carries `optional`, `os` or `cpu`. None does in a
+// lockfile written before those were recorded, but equally in a tree without
+// optional or platform-specific packages; both are treated as unknown.
+fn records_platforms(lockfile: &PackageLock) -> bool {
+    lockfile
+        .packages
+        .values()
+        .any(|package| package.optional || !package.os.is_empty() || !package.cpu.is_empty())
+}
+
+// Collect the locked packages reachable from `roots`, or None when the lockfile
+// is missing, stale, or doesn't pin every root yet.
+fn locked_install_plan(
+    project_root: &Path,
+    roots: &[String],
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<Option<Vec<ResolvedPackage>>, Box<dyn std::error::Error + Send + Sync>> {
+    let lockfile_path = active_lockfile_path(project_root);
+    if manifest_path != project_root.join("package.json") || !lockfile_path.exists() {
+        return Ok(None);
+    }
+
+    let lock_data = fs::read_to_string(&lockfile_path)?;
+    let Ok(lockfile) = serde_json::from_str::<PackageLock>(&lock_data) else {
+        return Ok(None);
+    };
+
+    if !lockfile_matches_manifest(project_root, &lockfile, manifest_path, options)? {
+        return Ok(None);
+    }
+    // Resolving reads `os`/`cpu` from the registry instead
+    if targets_other_platform(options) && !records_platforms(&lockfile) {
+        options.info(&format!(
+            "{} records no os/cpu fields; resolving to check them for --os/--cpu",
+            lockfile_path.display()
+        ));
+        return Ok(None);
+    }
+
+    // Installs are flat, so the plan holds one version of each package. Nested
+    // copies of the same version (--legacy-bundling) collapse; a package
+    // locked at two versions needs resolving to be laid out again.
+    let mut by_name = HashMap::<String, ResolvedPackage>::new();
+    for package in packages_from_lockfile(project_root, &lockfile)? {
+        match by_name.get(&package.info.name) {
+            Some(kept) if kept.info.version != package.info.version => {
+                options.debug(&format!(
+                    "{} is locked at more than one version; resolving instead",
+                    package.info.name
+                ));
+                return Ok(None);
+            }
+            Some(_) => {}
+            None => {
+                by_name.insert(package.info.name.clone(), package);
+            }
+        }
+    }
+    if !roots.iter().all(|root| by_name.contains_key(root)) {
+        return Ok(None);
+    }
+
+    let mut plan = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = roots.iter().cloned().collect::<VecDeque<_>>();
+    while let Some(name) = queue.pop_front() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        let Some(package) = by_name.get(&name) else {
+            // Every dependency must be pinned, otherwise fall back to resolution
+            return Ok(None);
+        };
+        queue.extend(package.info.dependencies.keys().cloned());
+        plan.push(package.clone());
+    }
+
+    Ok(Some(plan))
+}
+
+fn validate_peer_dependencies(packages: &[ResolvedPackage], options: &InstallOptions) -> Vec<PeerIssue> {
+    let installed_versions: HashMap<&str, &Version> = packages
+        .iter()
+        .map(|p| (p.info.name.as_str(), &p.info.version))
+        .collect();
+
+    let mut issues = Vec::new();
+
+    for package in packages {
+        for (peer_name, peer_req) in &package.info.peer_dependencies {
+            match installed_versions.get(peer_name.as_str()) {
+                Some(version) if peer_req.matches(version) => {
+                    options.debug(&format!(
+                        "peer dependency satisfied: {} -> {}@{}",
+                        package.info.name, peer_name, version
+                    ));
+                }
+                Some(version) => {
+                    options.warn(&format!(
+                        "peer dependency mismatch for {}: expected {} {}, found {}",
+                        package.info.name, peer_name, peer_req, version
+                    ));
+                    issues.push(PeerIssue {
+                        package: package.info.name.clone(),
+                        peer: peer_name.clone(),
+                        requirement: peer_req.to_string(),
+                        found: Some(version.to_string()),
+                    });
+                }
+                None if package.info.optional_peers.contains(peer_name) => {
+                    options.debug(&format!(
+                        "optional peer dependency not installed: {} -> {} {}",
+                        package.info.name, peer_name, peer_req
+                    ));
+                }
+                None => {
+                    options.warn(&format!(
+                        "missing peer dependency for {}: {} {}",
+                        package.info.name, peer_name, peer_req
+                    ));
+                    issues.push(PeerIssue {
+                        package: package.info.name.clone(),
+                        peer: peer_name.clone(),
+                        requirement: peer_req.to_string(),
+                        found: None,
+                    });
+                }
+            }
+        }
+    }
+    issues.sort_by(|a, b| (&a.package, &a.peer).cmp(&(&b.package, &b.peer)));
+    issues
+}
+
+// Peers flagged `{ "optional": true }` in peerDependenciesMeta are not
+// required to be installed
+fn optional_peers(version_info: &Value) -> HashSet<String> {
+    version_info
+        .get("peerDependenciesMeta")
+        .and_then(|v| v.as_object())
+        .map(|meta| {
+            meta.iter()
+                .filter(|(_, entry)| entry.get("optional").and_then(|v| v.as_bool()) == Some(true))
+                .map(|(peer_name, _)| peer_name.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// `bin` as a single path (named after the package) or a name -> path map
+fn bin_entries_from_manifest(name: &str, manifest: &Value) -> HashMap<String, String> {
+    let mut bin_entries = HashMap::new();
+    if let Some(bin) = manifest.get("bin") {
+        if let Some(single_bin) = bin.as_str() {
+            bin_entries.insert(default_bin_name(name), single_bin.to_string());
+        } else if let Some(bin_map) = bin.as_object() {
+            for (bin_name, bin_path) in bin_map {
+                if let Some(bin_path_str) = bin_path.as_str() {
+                    bin_entries.insert(bin_name.clone(), bin_path_str.to_string());
+                }
+            }
+        }
+    }
+    bin_entries
+}
+
+// Executables of an extracted package when its metadata listed none: the
+// installed package.json's `bin`, or else every file under the folder named
+// by `directories.bin` (an older convention), linked by file name
+fn installed_bin_entries(name: &str, package_root: &Path) -> HashMap<String, String> {
+    let Some(manifest) = fs::read_to_string(package_root.join("package.json"))
+        .ok()
+        .and_then(|data| manifest::parse(&data).ok())
+    else {
+        return HashMap::new();
+    };
+    let bin_entries = bin_entries_from_manifest(name, &manifest);
+    if !bin_entries.is_empty() || manifest.get("bin").is_some() {
+        return bin_entries;
+    }
+    let Some(bin_dir) = manifest["directories"]["bin"].as_str() else {
+        return HashMap::new();
+    };
+
+    let mut bin_entries = HashMap::new();
+    let bin_dir = Path::new(bin_dir)
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect::<PathBuf>();
+    let mut pending = vec![bin_dir];
+    while let Some(relative) = pending.pop() {
+        // `directories.bin` must not lead outside the package
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            continue;
+        }
+        let Ok(entries) = fs::read_dir(package_root.join(&relative)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.starts_with('.') {
+                continue;
+            }
+            let path = relative.join(&file_name);
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => pending.push(path),
+                Ok(kind) if kind.is_file() => {
+                    bin_entries.insert(file_name, path.to_string_lossy().replace('\\', "/"));
+                }
+                _ => {}
+            }
+        }
+    }
+    bin_entries
+}
+
+fn default_bin_name(package_name: &str) -> String {
+    package_name
+        .rsplit('/')
+        .next()
+        .unwrap_or(package_name)
+        .to_string()
+}
+
+// `node --version` is spawned at most once per process; None when node is missing
+fn current_node_version() -> Option<Version> {
+    static NODE_VERSION: OnceLock<Option<Version>> = OnceLock::new();
+
+    NODE_VERSION
+        .get_or_init(|| {
+            let output = Command::new("node").arg("--version").output().ok()?;
+            if !output.status.success() {
+                return None;
+            }
+
+            let raw = String::from_utf8_lossy(&output.stdout);
+            let trimmed = raw.trim().trim_start_matches('v');
+            Version::parse(trimmed).ok()
+        })
+        .clone()
+}
+
+// The `process.platform` values Node reports, which is what packages' `os`
+// fields list
+pub const NODE_PLATFORMS: [&str; 11] = [
+    "aix", "android", "cygwin", "darwin", "freebsd", "haiku", "linux", "netbsd", "openbsd", "sunos", "win32",
+];
+
+// The `process.arch` values, for `cpu` fields
+pub const NODE_CPUS: [&str; 12] = [
+    "arm", "arm64", "ia32", "loong64", "mips", "mipsel", "ppc", "ppc64", "riscv64", "s390", "s390x", "x64",
+];
+
+fn current_node_os() -> &'static str {
+    match std::env::consts::OS {
+        "macos" => "darwin",
+        "windows" => "win32",
+        other => other,
+    }
+}
+
+fn current_node_cpu() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "x64",
+        "x86" => "ia32",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        other => other,
+    }
+}
+
+// Vendored mirrors use `npm pack` names: `<name>-<version>.tgz`, with
+// `@scope/name` flattened to `scope-name-<version>.tgz`
+fn vendored_tarball_name(name: &str, version: &Version) -> String {
+    let flattened = name.trim_start_matches('@').replace('/', "-");
+    format!("{}-{}.tgz", flattened, version)
+}
+
+fn read_vendored_tarball(
+    package: &PackageInfo,
+    options: &InstallOptions,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(dir) = &options.offline_dir else {
+        return Ok(None);
+    };
+
+    let path = dir.join(vendored_tarball_name(&package.name, &package.version));
+    if !path.exists() {
+        options.debug(&format!("{} not in vendored mirror; falling back", path.display()));
+        return Ok(None);
+    }
+
+    let data = fs::read(&path)?;
+    verify_tarball_integrity(package, &data)
+        .map_err(|e| format!("{} ({})", e, path.display()))?;
+    options.debug(&format!("using vendored tarball {}", path.display()));
+    Ok(Some(data))
+}
+
+fn sha512_digest(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha512::new();
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+// SRI form (`sha512-<base64>`) of what we actually downloaded
+fn compute_integrity_sha512(data: &[u8]) -> String {
+    format!("sha512-{}", STANDARD.encode(sha512_digest(data)))
+}
+
+fn verify_tarball_integrity(package: &PackageInfo, data: &[u8]) -> Result<(), String> {
+    match &package.checksum {
+        Some(checksum) if !checksum.matches(data) => Err(format!(
+            "integrity verification failed for {}@{} ({})",
+            package.name,
+            package.version,
+            checksum.algorithm.name()
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn constraint_allows_current(constraints: &[String], current: &str) -> bool {
+    if constraints.is_empty() {
+        return true;
+    }
+
+    let mut positive = Vec::new();
+    let mut negative = Vec::new();
+    for rule in constraints {
+        if let Some(excluded) = rule.strip_prefix('!') {
+            negative.push(excluded);
+        } else {
+            positive.push(rule.as_str());
+        }
+    }
+
+    if negative.contains(&current) {
+        return false;
+    }
+
+    if positive.is_empty() {
+        true
+    } else {
+        positive.contains(&current)
+    }
+}
+
+// `engines` mismatches are only warnings unless engine-strict is set
+fn engine_mismatch(package: &PackageInfo, node_version: Option<&Version>) -> Option<String> {
+    if let Some(node_req) = &package.engines_node
+        && let Some(node_version) = node_version
+        && !node_req.matches(node_version)
+    {
+        return Some(format!(
+            "{} requires node '{}', current is {}",
+            package.name,
+            node_req.display(),
+            node_version
+        ));
+    }
+
+    if let Some(rnp_req) = &package.engines_rnp
+        && let Ok(rnp_version) = Version::parse(env!("CARGO_PKG_VERSION"))
+        && !rnp_req.matches(&rnp_version)
+    {
+        return Some(format!(
+            "{} requires rnp '{}', current is {}",
+            package.name,
+            rnp_req.display(),
+            rnp_version
+        ));
+    }
+
+    None
+}
+
+fn validate_package_constraints(package: &PackageInfo, options: &InstallOptions) -> Result<(), String> {
+    let os = options.target_os();
+    if !constraint_allows_current(&package.os_constraints, os) {
+        return Err(format!(
+            "{} is not supported on os '{}': {:?}",
+            package.name, os, package.os_constraints
+        ));
+    }
+
+    let cpu = options.target_cpu();
+    if !constraint_allows_current(&package.cpu_constraints, cpu) {
+        return Err(format!(
+            "{} is not supported on cpu '{}': {:?}",
+            package.name, cpu, package.cpu_constraints
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn create_bin_links(
+    package: &PackageInfo,
+    package_root: &Path,
+    node_modules: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let bin_entries = if package.bin_entries.is_empty() {
+        installed_bin_entries(&package.name, package_root)
+    } else {
+        package.bin_entries.clone()
+    };
+    if bin_entries.is_empty() {
+        return Ok(());
+    }
+
+    let bin_dir = node_modules.join(".bin");
+    fs::create_dir_all(&bin_dir)?;
+
+    for (bin_name, rel_path) in &bin_entries {
+        let src = package_root.join(rel_path);
+        let dst = bin_dir.join(bin_name);
+
+        // symlink_metadata so dangling links get replaced too
+        if fs::symlink_metadata(&dst).is_ok() {
+            fs::remove_file(&dst)?;
+        }
+
+        #[cfg(unix)]
+        {
+            // Relative to `.bin`, so the link survives moving the project
+            let target = match package_root.strip_prefix(node_modules) {
+                Ok(relative) => Path::new("..").join(relative).join(rel_path),
+                Err(_) => src.clone(),
+            };
+            std::os::unix::fs::symlink(&target, &dst)?;
+            if let Ok(metadata) = fs::metadata(&src) {
+                let mut perms = metadata.permissions();
+                perms.set_mode(0o755);
+                let _ = fs::set_permissions(&src, perms);
+            }
+        }
+
+        #[cfg(windows)]
+        {
+            let script = format!(
+                "@echo off\r\nnode \"%~dp0\\..\\{}\\{}\" %*\r\n",
+                package.name,
+                rel_path.replace('/', "\\")
+            );
+            fs::write(dst.with_extension("cmd"), script)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run_lifecycle_scripts(
+    package: &PackageInfo,
+    package_root: &Path,
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if options.ignore_scripts || package.lifecycle_scripts.is_empty() {
+        return Ok(());
+    }
+
+    for script_name in ["preinstall", "install", "postinstall"] {
+        let Some(script_cmd) = package.lifecycle_scripts.get(script_name) else {
+            continue;
+        };
+
+        options.debug(&format!("running {} for {}", script_name, package.name));
+
+        #[cfg(unix)]
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(script_cmd)
+            .current_dir(package_root)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()?;
+
+        #[cfg(windows)]
+        let status = Command::new("cmd")
+            .arg("/C")
+            .arg(script_cmd)
+            .current_dir(package_root)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()?;
+
+        if !status.success() {
+            return Err(format!(
+                "lifecycle script '{}' failed for {} with status {}",
+                script_name, package.name, status
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+// Copies only what publishing `src` would ship (see `packlist`), so local
+// copies don't drag along node_modules, build output, or VCS metadata
+fn copy_package_contents(src: &Path, dst: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fs::create_dir_all(dst)?;
+    for relative in package_files(src)? {
+        let dst_path = dst.join(&relative);
+        if let Some(parent) = dst_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(src.join(&relative), &dst_path)?;
+    }
+    Ok(())
+}
+
+// Whatever is at `path`, without following a symlink there
+fn remove_existing(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn symlink_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+
+#[cfg(windows)]
+fn symlink_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_dir(src, dst)
+}
+
+fn build_nested_node_modules(packages: &[ResolvedPackage], options: &InstallOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if options.legacy_bundling {
+        return bundle_dependencies_legacy(packages, options);
+    }
+    if options.hoist_strategy == "none" {
+        return Ok(());
+    }
+
+    let root = options.node_modules_dir();
+    let all_package_names = packages
+        .iter()
+        .map(|p| p.info.name.clone())
+        .collect::<Vec<_>>();
+
+    for package in packages {
+        let package_root = root.join(&package.info.name);
+        // Linked packages (workspaces, `file:` directories) are the user's
+        // own source trees; leave their node_modules alone
+        if fs::symlink_metadata(&package_root).is_ok_and(|m| m.file_type().is_symlink()) {
+            continue;
+        }
+        let nested = package_root.join("node_modules");
+        fs::create_dir_all(&nested)?;
+
+        let dependency_names = if options.hoist_strategy == "aggressive" {
+            all_package_names.clone()
+        } else {
+            package
+                .info
+                .dependencies
+                .keys()
+                .chain(package.info.peer_dependencies.keys())
+                .chain(package.info.optional_dependencies.keys())
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        for dep_name in dependency_names {
+            let hoisted_dep = root.join(&dep_name);
+            if !hoisted_dep.exists() {
+                continue;
+            }
+
+            let nested_dep = nested.join(&dep_name);
+            if nested_dep.exists() {
+                continue;
+            }
+
+            if let Err(err) = symlink_dir(&hoisted_dep, &nested_dep) {
+                options.warn(&format!(
+                    "could not create nested symlink {} -> {} ({})",
+                    nested_dep.display(),
+                    hoisted_dep.display(),
+                    err
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// `--legacy-bundling`: every package gets its own copy of each dependency in
+// its node_modules, the way npm 2 laid things out. Packages are installed flat
+// first; afterwards only the project's direct dependencies stay at the top and
+// the rest live on as copies at the paths `legacy_install_paths` gives them.
+fn bundle_dependencies_legacy(
+    packages: &[ResolvedPackage],
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let root = options.node_modules_dir();
+    let paths = legacy_install_paths(packages, options)?;
+
+    // Parents come before their dependencies, so every copy lands in a copy
+    for (path, package) in paths.iter().filter(|(path, _)| path.matches("node_modules/").count() > 1) {
+        let target = options.prefix.join(path);
+        let source = root.join(&package.info.name);
+        let parent = target.parent().and_then(Path::parent).ok_or("nested install path without a parent")?;
+        if !source.join("package.json").exists() || !parent.join("package.json").exists() {
+            continue;
+        }
+        remove_existing(&target)?;
+        copy_package_tree(&source, &target)?;
+    }
+
+    let top_level = paths
+        .iter()
+        .filter(|(path, _)| path.matches("node_modules/").count() == 1)
+        .map(|(_, package)| package.info.name.as_str())
+        .collect::<HashSet<_>>();
+    for package in packages.iter().filter(|p| !top_level.contains(p.info.name.as_str())) {
+        remove_existing(&root.join(&package.info.name))?;
+    }
+    remove_dangling_bin_links(&root.join(".bin"))?;
+    Ok(())
+}
+
+// Where `--legacy-bundling` puts every package, relative to the project root
+// and parents first: the project's direct dependencies at
+// `node_modules/<name>`, and below each package a copy of every dependency at
+// `<parent>/node_modules/<name>`. A dependency that is one of the package's
+// own ancestors is not copied again, which ends cycles, and linked packages
+// (workspaces, `file:` directories) are the user's own source trees, so
+// nothing is nested inside them.
+fn legacy_install_paths<'a>(
+    packages: &'a [ResolvedPackage],
+    options: &InstallOptions,
+) -> Result<Vec<(String, &'a ResolvedPackage)>, Box<dyn std::error::Error + Send + Sync>> {
+    let by_name = packages
+        .iter()
+        .map(|p| (p.info.name.as_str(), p))
+        .collect::<HashMap<_, _>>();
+
+    let mut direct = packages
+        .iter()
+        .filter(|p| p.depth == 0 || p.info.is_workspace)
+        .map(|p| p.info.name.clone())
+        .collect::<BTreeSet<_>>();
+    let manifest_path = options.prefix.join("package.json");
+    if manifest_path.exists() {
+        for section in SAVE_SECTIONS {
+            direct.extend(read_manifest_section(&manifest_path, section)?.into_keys());
+        }
+    }
+
+    let mut paths = Vec::new();
+    for name in &direct {
+        if let Some(package) = by_name.get(name.as_str()) {
+            let path = format!("node_modules/{}", name);
+            paths.push((path.clone(), *package));
+            nest_dependencies(&path, package, &mut vec![name.clone()], &by_name, options, &mut paths);
+        }
+    }
+    Ok(paths)
+}
+
+// Appends the path of each dependency of `package` below `package_path`, each
+// followed by its own dependencies
+fn nest_dependencies<'a>(
+    package_path: &str,
+    package: &ResolvedPackage,
+    ancestors: &mut Vec<String>,
+    by_name: &HashMap<&str, &'a ResolvedPackage>,
+    options: &InstallOptions,
+    paths: &mut Vec<(String, &'a ResolvedPackage)>,
+) {
+    let linked = package.info.is_workspace
+        || (local_directory_path(&package.info.tarball_url).is_some() && !options.install_links);
+    if linked {
+        return;
+    }
+
+    let mut dependency_names = package
+        .info
+        .dependencies
+        .keys()
+        .chain(package.info.optional_dependencies.keys())
+        .chain(package.info.peer_dependencies.keys().filter(|_| !options.skips_peers()))
+        .collect::<BTreeSet<_>>();
+    dependency_names.retain(|name| !ancestors.contains(name));
+
+    for dep_name in dependency_names {
+        let Some(dependency) = by_name.get(dep_name.as_str()) else {
+            continue;
+        };
+        let path = format!("{}/node_modules/{}", package_path, dep_name);
+        paths.push((path.clone(), *dependency));
+
+        ancestors.push(dep_name.clone());
+        nest_dependencies(&path, dependency, ancestors, by_name, options, paths);
+        ancestors.pop();
+    }
+}
+
+// Everything in a package directory except its own node_modules
+fn copy_package_tree(src: &Path, dst: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            if name != "node_modules" {
+                copy_package_tree(&entry.path(), &dst.join(&name))?;
+            }
+        } else if kind.is_file() {
+            fs::copy(entry.path(), dst.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn handle_ci_command_async(
+    options: InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let root = options.prefix.as_path();
+    if options.no_package_lock {
+        return Err("`rnp ci` installs exactly what package-lock.json pins, so it can't run with \
+                    --no-package-lock or `package-lock=false`; use `rnp install` instead"
+            .into());
+    }
+    let lockfile_path = active_lockfile_path(root);
+    if !lockfile_path.exists() {
+        return Err(format!("{} not found. `rnp ci` requires a lockfile.", lockfile_path.display()).into());
+    }
+
+    let workspace_packages = load_workspace_packages(root)?;
+    let manifest_path = workspace_manifest_path(root, options.workspace.as_deref(), &workspace_packages)?;
+    if !manifest_path.exists() {
+        return Err(format!("{} not found", manifest_path.display()).into());
+    }
+
+    let lock_data = fs::read_to_string(&lockfile_path)?;
+    let lockfile: PackageLock = serde_json::from_str(&lock_data)?;
+    ensure_lockfile_in_sync(root, &lockfile, &manifest_path, &options)?;
+    if targets_other_platform(&options) && !records_platforms(&lockfile) {
+        options.warn(&format!(
+            "{} records no os/cpu fields, so packages for other platforms can't be told apart for \
+             --os/--cpu; run `rnp install` with the same flags to re-resolve and record them",
+            lockfile_path.display()
+        ));
+    }
+
+    let mut packages = packages_from_lockfile(root, &lockfile)?;
+    // --legacy-bundling locks a copy of a package at every place it is nested;
+    // it is installed once and copied from there
+    packages.sort_by_key(|package| package.depth);
+    let mut seen = HashSet::new();
+    packages.retain(|package| seen.insert(package.info.name.clone()));
+    // An earlier full install may have left the omitted packages in place
+    for name in drop_omitted_packages(&mut packages, &manifest_path, &options)? {
+        remove_existing(&options.node_modules_dir().join(&name))?;
+    }
+    if packages.is_empty() {
+        options.info("Nothing to install from lockfile.");
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let mut tree = ResolvedTree {
+        packages,
+        ..Default::default()
+    };
+    let mut report = install_tree(&mut tree, &options).await?;
+
+    report.elapsed_ms = started.elapsed().as_millis();
+    print_install_report(&report, &options)?;
+    report_after_install(root, &tree.packages, &options).await
+}
+
+// `--omit`: leave out the locked packages that only the omitted package.json
+// sections lead to (devDependencies for `--omit dev`), returning their names
+fn drop_omitted_packages(
+    packages: &mut Vec<ResolvedPackage>,
+    manifest_path: &Path,
+    options: &InstallOptions,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    if options.omit.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let manifest = manifest::parse(&fs::read_to_string(manifest_path)?)?;
+    let mut kept_roots = Vec::new();
+    let mut omitted_roots = Vec::new();
+    for (section, kind) in [
+        ("dependencies", None),
+        ("devDependencies", Some("dev")),
+        ("optionalDependencies", Some("optional")),
+        ("peerDependencies", Some("peer")),
+    ] {
+        let names = manifest
+            .get(section)
+            .and_then(|v| v.as_object())
+            .map(|deps| deps.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if kind.is_some_and(|kind| options.omits(kind)) {
+            omitted_roots.extend(names);
+        } else {
+            kept_roots.extend(names);
+        }
+    }
+
+    let kept = reachable_packages(packages, kept_roots);
+    let omitted = reachable_packages(packages, omitted_roots);
+    let (installed, dropped): (Vec<_>, Vec<_>) = packages
+        .drain(..)
+        .partition(|package| kept.contains(&package.info.name) || !omitted.contains(&package.info.name));
+    *packages = installed;
+    Ok(dropped.into_iter().map(|package| package.info.name).collect())
+}
+
+// The names `roots` lead to through the packages' dependencies
+fn reachable_packages(packages: &[ResolvedPackage], roots: Vec<String>) -> HashSet<String> {
+    let by_name = packages
+        .iter()
+        .map(|package| (package.info.name.as_str(), package))
+        .collect::<HashMap<_, _>>();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from(roots);
+    while let Some(name) = queue.pop_front() {
+        let Some(package) = by_name.get(name.as_str()) else {
+            continue;
+        };
+        if seen.insert(name) {
+            queue.extend(package.info.dependencies.keys().cloned());
+        }
+    }
+    seen
+}
+
+pub async fn handle_shrinkwrap_command_async(options: &InstallOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let root = options.prefix.as_path();
+    let quiet = options.log_level < LogLevel::Info;
+    let lock_path = root.join(PACKAGE_LOCK_FILE);
+    let shrinkwrap_path = root.join(SHRINKWRAP_FILE);
+
+    if !lock_path.exists() {
+        if shrinkwrap_path.exists() {
+            if !quiet {
+                println!("{} is already up to date.", SHRINKWRAP_FILE);
+            }
+            return Ok(());
+        }
+        return generate_shrinkwrap(options).await;
+    }
+
+    let lock_data = fs::read_to_string(&lock_path)?;
+    let lockfile: PackageLock = serde_json::from_str(&lock_data)?;
+    write_lockfile(&lockfile, &shrinkwrap_path)?;
+    fs::remove_file(&lock_path)?;
+
+    if !quiet {
+        println!("{} {} -> {}", "Wrote".green(), PACKAGE_LOCK_FILE, SHRINKWRAP_FILE);
+    }
+    Ok(())
+}
+
+// With no lockfile to convert, resolve what package.json declares and write
+// the result straight to npm-shrinkwrap.json; node_modules is left alone
+async fn generate_shrinkwrap(options: &InstallOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let root = options.prefix.as_path();
+    let manifest_path = root.join("package.json");
+    if !manifest_path.exists() {
+        return Err("package.json not found. Please run `rnp init` first.".into());
+    }
+
+    let declared = declared_roots(&manifest_path, options)?;
+    let mut roots = Vec::new();
+    for (name, (_, range)) in &declared {
+        let req = NpmVersionReq::parse(range)
+            .map_err(|e| format!("Invalid version range for {} in package.json: '{}' ({})", name, range, e))?;
+        roots.push((name.clone(), req));
+    }
+    let request = ResolveRequest {
+        roots,
+        optional_roots: optional_root_names(&declared),
+        ..Default::default()
+    };
+    let tree = resolve_request(&request, options).await?;
+    for unresolved in &tree.unresolved {
+        options.warn(&format!("Could not resolve {}", unresolved));
+    }
+
+    write_lockfile(&build_lockfile(root, &tree.packages, options)?, &root.join(SHRINKWRAP_FILE))?;
+    if options.log_level >= LogLevel::Info {
+        println!("{} {} ({} packages)", "Wrote".green(), SHRINKWRAP_FILE, tree.packages.len());
+    }
+    Ok(())
+}
+
+// Updated main install function
+pub async fn handle_install_command_async(
+    package: &str,
+    options: InstallOptions,
+) -> Result<InstallReport, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let root = options.prefix.as_path();
+    let workspace_packages = load_workspace_packages(root)?;
+    let manifest_path = workspace_manifest_path(root, options.workspace.as_deref(), &workspace_packages)?;
+
+    if !manifest_path.exists() {
+        options.warn(&format!("{} not found. Please run `rnp init` first.", manifest_path.display()));
+        return Ok(InstallReport::default());
+    }
+
+    // `rnp install ./pkg-1.0.0.tgz` installs the tarball under the name in its package.json
+    let local_spec = local_tarball_argument(package, root)?;
+    let package = match &local_spec {
+        Some(spec) => {
+            let path = local_tarball_path(spec).unwrap_or_default();
+            read_local_tarball(root, path)?.0.name
+        }
+        None => package.to_string(),
+    };
+    let package = package.as_str();
+
+    if !options.force
+        && !options.refresh_lockfile
+        && !options.dedupe_on_install
+        && !options.verify_signatures
+        && !options.latest
+        && options.tag.is_none()
+        && local_spec.is_none()
+        && is_already_installed(&manifest_path, package, &options)?
+    {
+        options.success(&format!("{} is already up to date", package));
+        if options.save_bundle && !options.no_save {
+            save_bundled_dependency(&manifest_path, package, &options)?;
+        }
+        let report = InstallReport::default();
+        if options.json || options.json_stream {
+            print_install_report(&report, &options)?;
+        }
+        return Ok(report);
+    }
+
+    if current_node_version().is_none() {
+        options.warn("Node.js version could not be detected; engines.node checks are skipped.");
+    }
+
+    // Fast path: a consistent lockfile already pins everything we need
+    if !options.no_package_lock
+        && !options.refresh_lockfile
+        && !options.dedupe_on_install
+        && !options.verify_signatures
+        && !options.latest
+        && options.tag.is_none()
+        && local_spec.is_none()
+        && read_manifest_section(&manifest_path, &options.save_section)?.contains_key(package)
+        && let Some(packages) = locked_install_plan(root, &[package.to_string()], &manifest_path, &options)?
+    {
+        options.info(&format!(
+            "Using {} for {}; skipping resolution",
+            active_lockfile_path(root).display(),
+            package
+        ));
+
+        let mut tree = ResolvedTree {
+            packages,
+            ..Default::default()
+        };
+        let mut report = install_tree(&mut tree, &options).await?;
+        if options.save_bundle && !options.no_save {
+            save_bundled_dependency(&manifest_path, package, &options)?;
+        }
+
+        report.elapsed_ms = started.elapsed().as_millis();
+        print_install_report(&report, &options)?;
+        report_after_install(root, &tree.packages, &options).await?;
+        return Ok(report);
+    }
+
+    options.info(&format!("Resolving dependency tree for {}...", package));
+
+    // The range package.json already declares is the contract to resolve
+    // within; --latest (or a tag or tarball) replaces it
+    let declared_range = if options.latest {
+        None
+    } else {
+        declared_range(&manifest_path, package, &options.save_section)?
+    };
+
+    // Phase 1: Resolve all dependencies
+    let root_req = match (&local_spec, options.tag.as_deref(), &declared_range) {
+        (Some(spec), _, _) => Some(NpmVersionReq::parse(spec)?),
+        (None, Some(tag), _) => {
+            let req = dist_tag_requirement(&options.registries.client(), &options, package, tag).await?;
+            options.info(&format!("{}@{} is {}", package, tag, req));
+            Some(req)
+        }
+        (None, None, Some(range)) => {
+            options.info(&format!(
+                "Keeping {} within {} from package.json (use --latest to move past it)",
+                package, range
+            ));
+            Some(NpmVersionReq::parse(range)?)
+        }
+        (None, None, None) => None,
+    };
+    let root_req = match root_req {
+        Some(req) => req,
+        None => NpmVersionReq::any()?,
+    };
+    let request = ResolveRequest {
+        roots: vec![(package.to_string(), root_req)],
+        unlocked: options.latest.then(|| package.to_string()).into_iter().collect(),
+        ..Default::default()
+    };
+    let mut tree = resolve_request(&request, &options).await?;
+
+    options.info(&format!("Found {} package(s) to install", tree.packages.len()));
+
+    let root_version = tree
+        .root(package)
+        .map(|root| root.info.version.clone())
+        .ok_or_else(|| format!("Could not resolve {}", package))?;
+
+    options.info(&format!("Resolved {} to version {}", package, root_version));
+
+    // Phase 2: Install packages in parallel and build nested node_modules
+    // links while keeping hoisted packages at root
+    let mut report = install_tree(&mut tree, &options).await?;
+    let packages = &tree.packages;
+
+    // Phase 3: Update package.json with the ROOT package version
+    if options.no_save {
+        options.debug("Skipping package.json update (--no-save)");
+    } else {
+        let range = local_spec
+            .clone()
+            .or_else(|| declared_range.clone().filter(|_| options.tag.is_none()))
+            .unwrap_or_else(|| format!("{}{}", options.save_prefix, root_version));
+        update_package_json(
+            &manifest_path,
+            &options.save_section,
+            package,
+            &range,
+            &SAVE_SECTIONS,
+            &options,
+        )
+        .await?;
+    }
+
+    // Phase 4: Generate lockfile unless disabled by flag
+    if options.no_save {
+        options.debug("Skipping package-lock.json generation (--no-save)");
+    } else if options.no_package_lock {
+        options.debug("Skipping package-lock.json generation (--no-package-lock)");
+    } else {
+        generate_lockfile(root, packages, &options)?;
+        // Installing without --tag stops tracking whatever tag it was on
+        record_dist_tag(root, package, options.tag.as_deref())?;
+        options.success(&format!("Updated {}", active_lockfile_path(root).display()));
+    }
+
+    report.elapsed_ms = started.elapsed().as_millis();
+    print_install_report(&report, &options)?;
+    report_after_install(root, packages, &options).await?;
+    Ok(report)
+}
+
+// Bare `rnp install`: resolve everything package.json declares in one pass.
+// devDependencies are included unless dev is omitted (`--omit dev`).
+pub async fn handle_install_from_manifest_async(
+    options: InstallOptions,
+) -> Result<InstallReport, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let root = options.prefix.as_path();
+    let workspace_packages = load_workspace_packages(root)?;
+    let manifest_path = workspace_manifest_path(root, options.workspace.as_deref(), &workspace_packages)?;
+
+    if !manifest_path.exists() {
+        options.warn(&format!("{} not found. Please run `rnp init` first.", manifest_path.display()));
+        return Ok(InstallReport::default());
+    }
+
+    let declared = declared_roots(&manifest_path, &options)?;
+
+    if declared.is_empty() {
+        options.success("No dependencies declared in package.json; nothing to install.");
+        return Ok(InstallReport::default());
+    }
+
+    // Nothing changed since the last full install: skip resolving altogether
+    if !options.force
+        && !options.refresh_lockfile
+        && !options.dedupe_on_install
+        && !options.verify_signatures
+        && !options.no_package_lock
+        && install_is_up_to_date(root, &manifest_path, &options)?
+    {
+        options.success("up to date");
+        let report = InstallReport::default();
+        if options.json || options.json_stream {
+            print_install_report(&report, &options)?;
+        }
+        // The audit (and --audit-level) still covers what is installed
+        if options.audit
+            && let Some(packages) = load_installed_tree(root)?
+        {
+            run_post_install_audit(&packages, &options).await?;
+        }
+        return Ok(report);
+    }
+
+    if current_node_version().is_none() {
+        options.warn("Node.js version could not be detected; engines.node checks are skipped.");
+    }
+
+    let root_names = declared.keys().cloned().collect::<Vec<_>>();
+    if !options.no_package_lock
+        && !options.refresh_lockfile
+        && !options.dedupe_on_install
+        && !options.verify_signatures
+        && let Some(packages) = locked_install_plan(root, &root_names, &manifest_path, &options)?
+    {
+        options.info(&format!(
+            "Using {}; skipping resolution",
+            active_lockfile_path(root).display()
+        ));
+
+        let mut tree = ResolvedTree {
+            packages,
+            ..Default::default()
+        };
+        let mut report = install_tree(&mut tree, &options).await?;
+        record_manifest_hash(root, &manifest_path, &options)?;
+
+        report.elapsed_ms = started.elapsed().as_millis();
+        print_install_report(&report, &options)?;
+        report_after_install(root, &tree.packages, &options).await?;
+        return Ok(report);
+    }
+
+    let mut roots = Vec::new();
+    for (name, (_, range)) in &declared {
+        let req = NpmVersionReq::parse(range)
+            .map_err(|e| format!("Invalid version range for {} in package.json: '{}' ({})", name, range, e))?;
+        roots.push((name.clone(), req));
+    }
+
+    options.info(&format!("Resolving dependency tree for {} declared package(s)...", roots.len()));
+
+    let request = ResolveRequest {
+        roots,
+        optional_roots: optional_root_names(&declared),
+        ..Default::default()
+    };
+    let mut tree = resolve_request(&request, &options).await?;
+
+    options.info(&format!("Found {} package(s) to install", tree.packages.len()));
+
+    let mut report = install_tree(&mut tree, &options).await?;
+
+    if options.no_package_lock {
+        options.debug("Skipping package-lock.json generation (--no-package-lock)");
+    } else {
+        generate_lockfile(root, &tree.packages, &options)?;
+        options.success(&format!("Updated {}", active_lockfile_path(root).display()));
+        // Only a complete install with a lockfile written may be skipped next time
+        if tree.unresolved.is_empty() {
+            record_manifest_hash(root, &manifest_path, &options)?;
+        }
+    }
+
+    report.elapsed_ms = started.elapsed().as_millis();
+    print_install_report(&report, &options)?;
+    report_after_install(root, &tree.packages, &options).await?;
+    Ok(report)
+}
+
+// `rnp install --from <file>`: every spec in the list plus what package.json
+// already declares, resolved together and written to package.json and the
+// lockfile once. A spec that can't be resolved is reported and skipped: the
+// install is not all-or-nothing, so package.json and the lockfile end up with
+// every spec that succeeded and none of those that failed, and rerunning with
+// just the failed ones picks up where this left off.
+pub async fn handle_install_batch_async(
+    specs: &[String],
+    options: InstallOptions,
+) -> Result<InstallReport, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let root = options.prefix.as_path();
+    let workspace_packages = load_workspace_packages(root)?;
+    let manifest_path = workspace_manifest_path(root, options.workspace.as_deref(), &workspace_packages)?;
+
+    if !manifest_path.exists() {
+        options.warn(&format!("{} not found. Please run `rnp init` first.", manifest_path.display()));
+        return Ok(InstallReport::default());
+    }
+
+    let mut declared = read_manifest_dependencies_from(&manifest_path)?;
+    if !options.omits("dev") {
+        for (name, range) in read_manifest_section(&manifest_path, "devDependencies")? {
+            declared.entry(name).or_insert(range);
+        }
+    }
+
+    // name -> the spec it came from and the range it is resolved within; a
+    // bare name keeps the range package.json already has
+    let mut requested = BTreeMap::new();
+    let mut roots = Vec::new();
+    let mut failed_specs = Vec::new();
+    for spec in specs {
+        let (name, range) = crate::split_spec(spec);
+        let range = match declared.get(name) {
+            Some(declared_range) if range == "*" && !options.latest => declared_range.as_str(),
+            _ => range,
+        };
+        match NpmVersionReq::parse(range) {
+            Ok(req) if !requested.contains_key(name) => {
+                requested.insert(name.to_string(), (spec.clone(), range.to_string()));
+                roots.push((name.to_string(), req));
+            }
+            Ok(_) => options.debug(&format!("{} is listed more than once; using the first entry", name)),
+            Err(err) => failed_specs.push(format!("{}: invalid version range '{}' ({})", spec, range, err)),
+        }
+    }
+    for (name, range) in &declared {
+        if requested.contains_key(name) {
+            continue;
+        }
+        let req = NpmVersionReq::parse(range)
+            .map_err(|e| format!("Invalid version range for {} in package.json: '{}' ({})", name, range, e))?;
+        roots.push((name.clone(), req));
+    }
+
+    options.info(&format!("Resolving dependency tree for {} requested package(s)...", requested.len()));
+
+    if current_node_version().is_none() {
+        options.warn("Node.js version could not be detected; engines.node checks are skipped.");
+    }
+
+    let request = ResolveRequest {
+        roots,
+        unlocked: if options.latest { requested.keys().cloned().collect() } else { HashSet::new() },
+        skip_failed_roots: true,
+        ..Default::default()
+    };
+    let mut tree = resolve_request(&request, &options).await?;
+    for (name, err) in std::mem::take(&mut tree.failed_roots) {
+        match requested.remove(&name) {
+            Some((spec, _)) => failed_specs.push(format!("{}: {}", spec, err)),
+            None => tree.unresolved.push(format!("Could not resolve {}: {}", name, err)),
+        }
+    }
+    let chosen = requested
+        .iter()
+        .filter_map(|(name, (spec, range))| tree.root(name).map(|package| (name, spec, range, package.info.clone())))
+        .collect::<Vec<_>>();
+
+    options.info(&format!("Found {} package(s) to install", tree.packages.len()));
+
+    let mut report = install_tree(&mut tree, &options).await?;
+
+    if options.no_save {
+        options.debug("Skipping package.json update (--no-save)");
+    } else {
+        for (name, _, range, info) in &chosen {
+            // Git, file: and link: specs and ranges kept from package.json are
+            // saved as they were written
+            let keep = NpmVersionReq::parse(range).is_ok_and(|req| req.is_source_spec())
+                || declared.get(*name) == Some(*range);
+            let range = if keep {
+                range.to_string()
+            } else {
+                format!("{}{}", options.save_prefix, info.version)
+            };
+            update_package_json(&manifest_path, &options.save_section, name, &range, &SAVE_SECTIONS, &options)
+                .await?;
+        }
+    }
+
+    if options.no_save {
+        options.debug("Skipping package-lock.json generation (--no-save)");
+    } else if options.no_package_lock {
+        options.debug("Skipping package-lock.json generation (--no-package-lock)");
+    } else {
+        generate_lockfile(root, &tree.packages, &options)?;
+        options.success(&format!("Updated {}", active_lockfile_path(root).display()));
+    }
+
+    report.installed_specs = chosen
+        .iter()
+        .map(|(name, spec, _, info)| format!("{}: {}@{}", spec, name, info.version))
+        .collect();
+    report.failed_specs = failed_specs;
+    report.elapsed_ms = started.elapsed().as_millis();
+    print_install_report(&report, &options)?;
+    report_after_install(root, &tree.packages, &options).await?;
+    Ok(report)
+}
+
+// The package specs in an `install --from` file: a JSON array of strings, or
+// one spec per line (blank lines and `#` comments are skipped)
+pub fn read_spec_list(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    if data.trim_start().starts_with('[') {
+        let specs: Vec<String> = serde_json::from_str(&data)
+            .map_err(|e| format!("{} is not a JSON array of package specs: {}", path.display(), e))?;
+        return Ok(specs.into_iter().map(|spec| spec.trim().to_string()).filter(|spec| !spec.is_empty()).collect());
+    }
+    Ok(data
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+// `rnp update`: a single resolution pass over every manifest in `manifests`
+// (one, or several with --workspaces) in which only `targets` are re-resolved
+// and everything else keeps its locked version, so shared transitive
+// dependencies end up consistent. The manifests, node_modules and the
+// lockfile are then written once.
+pub async fn handle_update_packages_async(
+    targets: &[String],
+    manifests: &[PathBuf],
+    options: InstallOptions,
+) -> Result<InstallReport, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let root = options.prefix.as_path();
+
+    let declared_by_manifest = manifests
+        .iter()
+        .map(|manifest_path| declared_roots(manifest_path, &options))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Across workspace manifests too, the higher-precedence section wins
+    let mut dependencies = DeclaredRoots::new();
+    let rank = |section: &str| ROOT_SECTIONS.iter().position(|s| *s == section);
+    for declared in &declared_by_manifest {
+        for (name, (section, range)) in declared {
+            if dependencies.get(name).is_none_or(|(kept, _)| rank(section) < rank(kept)) {
+                dependencies.insert(name.clone(), (section, range.clone()));
+            }
+        }
+    }
+    let disagreements = conflicting_member_ranges(manifests, &declared_by_manifest, targets);
+    if !disagreements.is_empty() {
+        return Err(format!(
+            "workspace members declare conflicting ranges, and only one copy of each can be \
+             installed at the root:\n  {}\nAlign the ranges, or update those packages by name so \
+             every manifest gets the new one",
+            disagreements.join("\n  ")
+        )
+        .into());
+    }
+
+    let previous_lockfile = if options.no_package_lock {
+        None
+    } else {
+        read_lockfile(root)?
+    };
+    let dist_tags = if options.no_package_lock {
+        BTreeMap::new()
+    } else {
+        load_dist_tags(root)?
+    };
+    let client = options.registries.client();
+
+    let mut roots = Vec::new();
+    for (name, (_, range)) in &dependencies {
+        // A target installed with --tag moves to wherever the tag points now
+        let declared = NpmVersionReq::parse(range)
+            .map_err(|e| format!("Invalid version range for {} in package.json: '{}' ({})", name, range, e))?;
+        let req = if let Some(tag) = dist_tags.get(name).filter(|_| targets.contains(name)) {
+            dist_tag_requirement(&client, &options, name, tag).await?
+        } else if targets.contains(name) && !declared.is_source_spec() {
+            NpmVersionReq::any()?
+        } else {
+            declared
+        };
+        roots.push((name.clone(), req));
+    }
+    for target in targets {
+        if !roots.iter().any(|(name, _)| name == target) {
+            roots.push((target.clone(), NpmVersionReq::any()?));
+        }
+    }
+
+    options.info(&format!("Resolving {} package(s) to update...", targets.len()));
+
+    let request = ResolveRequest {
+        roots,
+        optional_roots: optional_root_names(&dependencies),
+        unlocked: targets.iter().cloned().collect(),
+        ..Default::default()
     };
+    let mut tree = resolve_request(&request, &options).await?;
+    let mut report = install_tree(&mut tree, &options).await?;
+    let packages = &tree.packages;
+
+    // Each manifest records the new range in whichever section declared the target
+    for (manifest_path, declared) in manifests.iter().zip(&declared_by_manifest) {
+        for target in targets {
+            let Some(package) = tree.root(target) else {
+                continue;
+            };
+            let section = if let Some((section, _)) = declared.get(target) {
+                section
+            } else if manifests.len() == 1 {
+                // A new dependency named on the command line
+                "dependencies"
+            } else {
+                continue;
+            };
+            if package.info.tarball_url.starts_with("file:")
+                || package.info.tarball_url.starts_with("link:")
+                || GitSpec::parse(&package.info.tarball_url).is_some()
+            {
+                // Git, file: and link: dependencies keep the spec they were declared with
+                continue;
+            }
+            let range = format!("{}{}", options.save_prefix, package.info.version);
+            update_package_json(manifest_path, section, target, &range, &[], &options).await?;
+        }
+    }
 
-    let serialized = serde_json::to_string_pretty(&lockfile)?;
-    std::fs::write("package-lock.json", serialized)?;
-    Ok(())
+    if options.no_package_lock {
+        options.debug("Skipping package-lock.json generation (--no-package-lock)");
+    } else {
+        generate_lockfile(root, packages, &options)?;
+        options.success(&format!("Updated {}", active_lockfile_path(root).display()));
+    }
+
+    // Everything whose version moved against the previous lockfile,
+    // transitive dependencies included
+    if let Some(previous_lockfile) = previous_lockfile {
+        report.changes = version_changes(&previous_lockfile, &build_lockfile(root, packages, &options)?);
+        print_version_changes(&report.changes, &options);
+    }
+
+    report.elapsed_ms = started.elapsed().as_millis();
+    print_install_report(&report, &options)?;
+    report_after_install(root, packages, &options).await?;
+    Ok(report)
 }
 
-fn packages_from_lockfile(
-    lockfile: &PackageLock,
-) -> Result<Vec<ResolvedPackage>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut packages = Vec::new();
+// Resolve `roots` and their dependencies without touching node_modules
+pub async fn resolve_tree(
+    roots: &[(String, NpmVersionReq)],
+    options: &InstallOptions,
+) -> Result<ResolvedTree, Box<dyn std::error::Error + Send + Sync>> {
+    let request = ResolveRequest {
+        roots: roots.to_vec(),
+        ..Default::default()
+    };
+    resolve_request(&request, options).await
+}
 
-    for (path_key, locked) in &lockfile.packages {
-        let Some(name) = lockfile_package_name(path_key) else {
-            continue;
-        };
+// `resolve_tree` with control over optional roots, which locked versions are
+// kept and whether a failing root fails the resolve. Every install command
+// resolves through here.
+pub async fn resolve_request(
+    request: &ResolveRequest,
+    options: &InstallOptions,
+) -> Result<ResolvedTree, Box<dyn std::error::Error + Send + Sync>> {
+    let root = options.prefix.as_path();
+    let workspace_packages = load_workspace_packages(root)?;
+    let mut locked_versions = if options.no_package_lock {
+        HashMap::new()
+    } else {
+        load_locked_versions(root)?
+    };
+    locked_versions.retain(|name, _| !request.unlocked.contains(name));
 
-        let version = Version::parse(&locked.version)?;
-        let dependencies = locked
-            .dependencies
-            .iter()
-            .filter_map(|(dep, req)| NpmVersionReq::parse(req).ok().map(|r| (dep.clone(), r)))
-            .collect::<HashMap<_, _>>();
+    let mut resolver = DependencyResolver::new(workspace_packages, options);
+    resolver.optional_roots = request.optional_roots.clone();
+    resolver.skip_failed_roots = request.skip_failed_roots;
+    let packages = resolver
+        .resolve_from_roots(&request.roots, Some(&locked_versions))
+        .await?;
+    Ok(resolver.into_tree(packages))
+}
 
-        let depth = path_key.matches("node_modules/").count();
+// `cache add`: resolve a spec (with `with_deps`, its whole tree) and store
+// every tarball not already cached, without touching any project. Returns how
+// many tarballs were added and their total size.
+pub async fn add_to_cache(
+    spec: &str,
+    with_deps: bool,
+    options: &InstallOptions,
+) -> Result<(usize, u64), Box<dyn std::error::Error + Send + Sync>> {
+    let (name, range) = crate::split_spec(spec);
+    let mut resolver = DependencyResolver::new(HashMap::new(), options);
+    let req = match NpmVersionReq::parse(range) {
+        Ok(req) if req.is_source_spec() => {
+            return Err(format!("{}: only registry packages can be added to the cache", spec).into());
+        }
+        Ok(req) => req,
+        Err(_) => dist_tag_requirement(&resolver.registry_client, options, name, range).await?,
+    };
 
-        let workspace_path = lockfile.workspace_paths.get(name).map(PathBuf::from);
-        let is_workspace = workspace_path.is_some();
+    let packages = if with_deps {
+        let roots = [(name.to_string(), req)];
+        let packages = resolver.resolve_from_roots(&roots, None).await?;
+        for unresolved in &resolver.unresolved {
+            options.warn(&format!("Could not resolve {}", unresolved));
+        }
+        packages.into_iter().map(|package| package.info).collect()
+    } else {
+        vec![resolver.fetch_package_metadata(name, &req, None).await?]
+    };
 
-        let info = PackageInfo {
-            name: name.to_string(),
-            version,
-            dependencies,
-            peer_dependencies: HashMap::new(),
-            optional_dependencies: HashMap::new(),
-            tarball_url: locked.resolved.clone(),
-            integrity: locked.integrity.clone(),
-            shasum: locked.shasum.clone(),
-            is_workspace,
-            workspace_path,
-            engines_node: None,
-            os_constraints: Vec::new(),
-            cpu_constraints: Vec::new(),
-            lifecycle_scripts: HashMap::new(),
-            bin_entries: HashMap::new(),
-        };
+    let cache = PackageCache::new(&options.cache_dir)?.with_max_size(options.cache_max_size);
+    let client = resolver.registry_client.clone();
+    let mut added = 0;
+    let mut bytes = 0;
+    for package in packages {
+        if package.tarball_url.is_empty() || local_tarball_path(&package.tarball_url).is_some() {
+            continue;
+        }
+        let version = package.version.to_string();
+        let cached = cache.get_valid_tarball(&package.name, &version, package.checksum.as_ref(), TARBALL_CACHE_MAX_AGE)?;
+        match cached {
+            CachedTarball::Hit(_) => {
+                options.debug(&format!("{}@{} is already cached", package.name, version));
+                continue;
+            }
+            CachedTarball::Corrupt(damage) => {
+                options.warn(&format!("Replacing corrupt cached tarball for {}@{} ({})", package.name, version, damage));
+            }
+            CachedTarball::Miss => {}
+        }
 
-        packages.push(ResolvedPackage {
-            info,
-            depth,
-            optional: false,
-        });
+        let data = download_tarball(&client, &package, options, None).await?;
+        verify_tarball_integrity(&package, &data)?;
+        cache.save_tarball(&package.name, &version, &data)?;
+        options.info(&format!("Cached {}@{}", package.name, version));
+        added += 1;
+        bytes += data.len() as u64;
     }
+    cache.flush_accesses()?;
+    Ok((added, bytes))
+}
 
-    Ok(packages)
+// Download, extract and link a resolved tree into node_modules. Every
+// install command installs through here; package.json and the lockfile are
+// left to the caller.
+pub async fn install_tree(
+    tree: &mut ResolvedTree,
+    options: &InstallOptions,
+) -> Result<InstallReport, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let workspace_packages = load_workspace_packages(&options.prefix)?;
+    let mut resolver = DependencyResolver::new(workspace_packages, options);
+    resolver.metadata_times = tree.metadata_times.clone();
+    let mut report = resolver
+        .install_packages_parallel(&mut tree.packages, options, current_node_version())
+        .await?;
+    build_nested_node_modules(&tree.packages, options)?;
+    write_hidden_lockfile(&options.prefix, &tree.packages, options)?;
+
+    report.conflicts = tree.conflicts.clone();
+    report.unresolved = tree.unresolved.clone();
+    let diagnostics = &mut report.diagnostics;
+    diagnostics.peer_issues = tree.diagnostics.peer_issues.clone();
+    diagnostics.deprecated = tree.diagnostics.deprecated.clone();
+    diagnostics.skipped_optional.extend(tree.diagnostics.skipped_optional.iter().cloned());
+    diagnostics.name_case_mismatches = tree.diagnostics.name_case_mismatches.clone();
+    report.elapsed_ms = started.elapsed().as_millis();
+    Ok(report)
 }
 
-fn ensure_lockfile_in_sync(
-    lockfile: &PackageLock,
-    manifest_path: &Path,
+// Human summary of an install, or the report itself as JSON with --json
+// (the final `done` event with --json-stream)
+pub fn print_install_report(
+    report: &InstallReport,
+    options: &InstallOptions,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if manifest_path != Path::new("package.json") {
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(report)?);
+        return Ok(());
+    }
+    if options.json_stream {
+        options.emit(InstallEvent::Done(report));
         return Ok(());
     }
 
-    let manifest_deps = read_manifest_dependencies_from(manifest_path)?;
-    if manifest_deps != lockfile.dependencies {
-        return Err(format!(
-            "{} and package-lock.json are out of sync. Run `rnp install` first.",
-            manifest_path.display()
-        )
-        .into());
+    if !report.conflicts.is_empty() {
+        options.warn("Dependency conflicts detected:");
+        for conflict in &report.conflicts {
+            if options.log_level >= LogLevel::Warn {
+                eprintln!("  {}", conflict.to_string().red());
+            }
+        }
     }
 
-    Ok(())
-}
+    if !report.unresolved.is_empty() {
+        options.warn(&format!("{} package(s) could not be resolved:", report.unresolved.len()));
+        for failure in &report.unresolved {
+            options.warn(failure);
+        }
+    }
 
-fn validate_peer_dependencies(packages: &[ResolvedPackage], options: &InstallOptions) {
-    let installed_versions: HashMap<&str, &Version> = packages
-        .iter()
-        .map(|p| (p.info.name.as_str(), &p.info.version))
-        .collect();
+    if !report.installed_specs.is_empty() {
+        options.success(&format!("{} requested package(s) installed:", report.installed_specs.len()));
+        for installed in &report.installed_specs {
+            options.success(&format!("  {}", installed));
+        }
+    }
 
-    for package in packages {
-        for (peer_name, peer_req) in &package.info.peer_dependencies {
-            match installed_versions.get(peer_name.as_str()) {
-                Some(version) if peer_req.matches(version) => {
-                    options.debug(&format!(
-                        "peer dependency satisfied: {} -> {}@{}",
-                        package.info.name, peer_name, version
-                    ));
-                }
-                Some(version) => {
-                    options.warn(&format!(
-                        "peer dependency mismatch for {}: expected {} {}, found {}",
-                        package.info.name, peer_name, peer_req, version
-                    ));
-                }
-                None => {
-                    options.warn(&format!(
-                        "missing peer dependency for {}: {} {}",
-                        package.info.name, peer_name, peer_req
-                    ));
-                }
-            }
+    if !report.failed_specs.is_empty() {
+        options.warn(&format!("{} requested package(s) could not be installed:", report.failed_specs.len()));
+        for failure in &report.failed_specs {
+            options.warn(&format!("  {}", failure));
         }
     }
-}
 
-fn default_bin_name(package_name: &str) -> String {
-    package_name
-        .rsplit('/')
-        .next()
-        .unwrap_or(package_name)
-        .to_string()
-}
+    for package in &report.updated {
+        options.debug(&format!("updated {}", package));
+    }
+    for package in &report.skipped {
+        options.debug(&format!("skipped {}", package));
+    }
+    if !report.slowest.is_empty() {
+        options.debug("slowest packages:");
+        for timing in &report.slowest {
+            options.debug(&format!(
+                "  {} {}ms (metadata {}ms, download and extract {}ms)",
+                timing.package,
+                timing.total_ms(),
+                timing.metadata_ms,
+                timing.install_ms
+            ));
+        }
+    }
 
-fn current_node_version() -> Option<Version> {
-    let output = Command::new("node").arg("--version").output().ok()?;
-    if !output.status.success() {
-        return None;
+    // With --max-rate, show what the throttled downloads averaged
+    let rate = match options.max_rate {
+        Some(_) if report.download_ms > 0 && report.total_bytes > 0 => format!(
+            ", {}/s",
+            format_size((report.total_bytes as u128 * 1000 / report.download_ms) as u64)
+        ),
+        _ => String::new(),
+    };
+    options.success(&format!(
+        "added {}, updated {}, skipped {} package(s) ({}{}) in {:.1}s",
+        report.added.len(),
+        report.updated.len(),
+        report.skipped.len(),
+        format_size(report.total_bytes),
+        rate,
+        report.elapsed_ms as f64 / 1000.0
+    ));
+    if options.report {
+        print_diagnostics(report, options);
     }
+    Ok(())
+}
 
-    let raw = String::from_utf8_lossy(&output.stdout);
-    let trimmed = raw.trim().trim_start_matches('v');
-    Version::parse(trimmed).ok()
+// `install --report`: every kind of problem with the tree under one heading
+fn print_diagnostics(report: &InstallReport, options: &InstallOptions) {
+    if options.log_level < LogLevel::Warn {
+        return;
+    }
+    let diagnostics = &report.diagnostics;
+    let sections: [(&str, Vec<String>); 7] = [
+        ("conflicts", report.conflicts.iter().map(|c| c.to_string()).collect()),
+        ("unresolved", report.unresolved.clone()),
+        ("peer dependency issues", diagnostics.peer_issues.iter().map(|p| p.to_string()).collect()),
+        (
+            "deprecated",
+            diagnostics
+                .deprecated
+                .iter()
+                .map(|d| format!("{}: {}", d.package, d.message))
+                .collect(),
+        ),
+        ("engine mismatches", diagnostics.engine_mismatches.clone()),
+        (
+            "package name case mismatches",
+            diagnostics.name_case_mismatches.iter().map(|m| m.to_string()).collect(),
+        ),
+        (
+            "skipped optional dependencies",
+            diagnostics
+                .skipped_optional
+                .iter()
+                .map(|s| format!("{}: {}", s.package, s.reason))
+                .collect(),
+        ),
+    ];
+
+    println!("\n{}", "Diagnostics:".bold());
+    if sections.iter().all(|(_, entries)| entries.is_empty()) {
+        println!("  no issues found");
+        return;
+    }
+    for (title, entries) in sections.iter().filter(|(_, entries)| !entries.is_empty()) {
+        println!("  {} ({})", title, entries.len());
+        for entry in entries {
+            println!("    {}", entry);
+        }
+    }
 }
 
-fn current_node_os() -> &'static str {
-    match std::env::consts::OS {
-        "macos" => "darwin",
-        "windows" => "win32",
-        other => other,
+// The funding notice and the `--audit` summary that follow an install's report
+async fn report_after_install(
+    root: &Path,
+    packages: &[ResolvedPackage],
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if options.fund && !options.json && !options.json_stream {
+        report_funding(root, packages, options);
     }
+    if options.audit {
+        run_post_install_audit(packages, options).await?;
+    }
+    Ok(())
 }
 
-fn current_node_cpu() -> &'static str {
-    match std::env::consts::ARCH {
-        "x86_64" => "x64",
-        "x86" => "ia32",
-        "aarch64" => "arm64",
-        "arm" => "arm",
-        other => other,
+// npm's "N packages are looking for funding" line, counted from the
+// package.json of each package installed at the top level
+fn report_funding(root: &Path, packages: &[ResolvedPackage], options: &InstallOptions) {
+    let funded = packages
+        .iter()
+        .filter(|p| !p.info.is_workspace)
+        .filter(|p| {
+            fs::read_to_string(root.join("node_modules").join(&p.info.name).join("package.json"))
+                .ok()
+                .and_then(|data| manifest::parse(&data).ok())
+                .is_some_and(|manifest| {
+                    manifest.get("funding").is_some()
+                        && manifest["version"].as_str() == Some(p.info.version.to_string().as_str())
+                })
+        })
+        .map(|p| &p.info.name)
+        .collect::<HashSet<_>>();
+    if !funded.is_empty() {
+        options.info(&format!("{} package(s) are looking for funding", funded.len()));
     }
 }
 
-fn verify_integrity_sha512(data: &[u8], integrity: &str) -> bool {
-    let Some(encoded) = integrity.strip_prefix("sha512-") else {
-        return false;
+// Summarize advisories for the tree we just installed; only fails with --audit-level
+async fn run_post_install_audit(
+    packages: &[ResolvedPackage],
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let installed = packages
+        .iter()
+        .filter(|p| !p.info.is_workspace)
+        .map(|p| (p.info.name.clone(), p.info.version.to_string()))
+        .collect::<HashMap<_, _>>();
+
+    let summary = match audit_versions(&installed, &options.registries, options.audit_registry.as_deref()).await {
+        Ok(summary) => summary,
+        Err(err) => {
+            options.warn(&format!("audit skipped: {}", err));
+            return Ok(());
+        }
     };
 
-    let Ok(expected_bytes) = STANDARD.decode(encoded) else {
-        return false;
-    };
+    if summary.total == 0 {
+        options.success("found 0 vulnerabilities");
+    } else {
+        options.warn(&format!(
+            "found {} vulnerabilities ({} critical, {} high, {} moderate, {} low); run `rnp audit` for details",
+            summary.total, summary.critical, summary.high, summary.moderate, summary.low
+        ));
+    }
 
-    let mut hasher = Sha512::new();
-    hasher.update(data);
-    let actual = hasher.finalize();
-    actual.as_slice() == expected_bytes.as_slice()
+    check_audit_level(&summary, options.audit_level.as_deref())
 }
 
-fn verify_tarball_integrity(package: &PackageInfo, data: &[u8]) -> Result<(), String> {
-    if let Some(integrity) = package.integrity.as_deref() {
-        if !verify_integrity_sha512(data, integrity) {
-            return Err(format!(
-                "integrity verification failed for {}@{}",
-                package.name, package.version
-            ));
-        }
-        return Ok(());
-    }
+async fn update_package_json(
+    package_json_path: &Path,
+    section: &str,
+    package: &str,
+    range: &str,
+    moved_from: &[&str],
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Read existing package.json
+    let data = std::fs::read_to_string(package_json_path)?;
+    let mut json = manifest::parse_for_edit(&data, package_json_path)?;
 
-    if let Some(expected_shasum) = package.shasum.as_deref()
-        && !PackageCache::verify_sha1_checksum(data, expected_shasum)
-    {
-        return Err(format!(
-            "checksum verification failed for {}@{}",
-            package.name, package.version
-        ));
+    // Ensure package.json root is a valid JSON object
+    let obj = json.as_object_mut().ok_or("Invalid package.json format")?;
+
+    // Saving into one section takes the package out of the others it was in
+    for other in moved_from.iter().filter(|other| **other != section) {
+        if let Some(serde_json::Value::Object(map)) = obj.get_mut(*other)
+            && map.remove(package).is_some()
+        {
+            options.debug(&format!("Moved {} from {} to {}", package, other, section));
+        }
     }
 
-    Ok(())
-}
+    let deps = obj
+        .entry(section)
+        .or_insert(serde_json::Value::Object(serde_json::Map::new()));
 
-fn constraint_allows_current(constraints: &[String], current: &str) -> bool {
-    if constraints.is_empty() {
-        return true;
+    if let serde_json::Value::Object(map) = deps {
+        map.insert(
+            package.to_string(),
+            serde_json::Value::String(range.to_string()),
+        );
     }
 
-    let mut positive = Vec::new();
-    let mut negative = Vec::new();
-    for rule in constraints {
-        if let Some(excluded) = rule.strip_prefix('!') {
-            negative.push(excluded);
-        } else {
-            positive.push(rule.as_str());
-        }
+    if options.save_bundle && add_bundled_dependency(obj, package) {
+        options.debug(&format!("Added {} to bundledDependencies", package));
     }
 
-    if negative.iter().any(|item| *item == current) {
-        return false;
+    // Write back with pretty formatting
+    let formatted = serde_json::to_string_pretty(&json)?;
+    std::fs::write(package_json_path, formatted)?;
+
+    options.success(&format!(
+        "Updated package.json {} with {}@{}",
+        section, package, range
+    ));
+    Ok(())
+}
+
+// `--save-bundle` for a package that was already saved: only the bundle list changes
+fn save_bundled_dependency(
+    package_json_path: &Path,
+    package: &str,
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let data = fs::read_to_string(package_json_path)?;
+    let mut json = manifest::parse_for_edit(&data, package_json_path)?;
+    let obj = json.as_object_mut().ok_or("Invalid package.json format")?;
+    if add_bundled_dependency(obj, package) {
+        fs::write(package_json_path, serde_json::to_string_pretty(&json)?)?;
+        options.success(&format!("Added {} to bundledDependencies", package));
     }
+    Ok(())
+}
 
-    if positive.is_empty() {
-        true
+// Appends `package` to the manifest's bundle list (whichever of npm's two
+// spellings it already uses), unless it is there already or the manifest
+// bundles everything (`true`). Returns whether the manifest changed.
+fn add_bundled_dependency(manifest: &mut Map<String, Value>, package: &str) -> bool {
+    let key = if manifest.contains_key("bundleDependencies") {
+        "bundleDependencies"
     } else {
-        positive.iter().any(|item| *item == current)
+        "bundledDependencies"
+    };
+    match manifest.entry(key).or_insert_with(|| Value::Array(Vec::new())) {
+        Value::Array(items) if !items.iter().any(|item| item.as_str() == Some(package)) => {
+            items.push(Value::String(package.to_string()));
+            true
+        }
+        _ => false,
     }
 }
 
-fn validate_package_constraints(
-    package: &PackageInfo,
-    node_version: Option<&Version>,
-) -> Result<(), String> {
-    if let Some(node_req) = &package.engines_node {
-        if let Some(node_version) = node_version {
-            if !node_req.matches(node_version) {
-                return Err(format!(
-                    "{} requires node '{}', current is {}",
-                    package.name,
-                    node_req.display(),
-                    node_version
-                ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_registry::{Reply, TestRegistry, tarball};
+    use serde_json::json;
+
+    #[test]
+    fn only_peers_flagged_optional_are_optional() {
+        let version_info = json!({
+            "peerDependencies": { "required-peer": "^1.0.0", "optional-peer": "^1.0.0", "other-peer": "^1.0.0" },
+            "peerDependenciesMeta": {
+                "optional-peer": { "optional": true },
+                "other-peer": { "optional": false }
             }
-        }
+        });
+        assert_eq!(optional_peers(&version_info), HashSet::from(["optional-peer".to_string()]));
+        assert!(optional_peers(&json!({ "peerDependencies": { "peer": "1" } })).is_empty());
     }
 
-    let os = current_node_os();
-    if !constraint_allows_current(&package.os_constraints, os) {
-        return Err(format!(
-            "{} is not supported on os '{}': {:?}",
-            package.name, os, package.os_constraints
-        ));
+    fn matches(range: &str, version: &str) -> bool {
+        NpmVersionReq::parse(range).unwrap().matches(&Version::parse(version).unwrap())
     }
 
-    let cpu = current_node_cpu();
-    if !constraint_allows_current(&package.cpu_constraints, cpu) {
-        return Err(format!(
-            "{} is not supported on cpu '{}': {:?}",
-            package.name, cpu, package.cpu_constraints
-        ));
+    #[test]
+    fn space_separated_comparators_all_apply() {
+        assert!(matches(">=1.2.7 <1.3.0", "1.2.7"));
+        assert!(matches(">=1.2.7 <1.3.0", "1.2.9"));
+        assert!(!matches(">=1.2.7 <1.3.0", "1.3.0"));
+        assert!(!matches(">=1.2.7 <1.3.0", "1.2.6"));
     }
 
-    Ok(())
-}
-
-fn create_bin_links(
-    package: &PackageInfo,
-    package_root: &Path,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if package.bin_entries.is_empty() {
-        return Ok(());
+    #[test]
+    fn exclusions_combine_with_bounds() {
+        let range = ">1.0.0 <=2.0.0 !=1.5.0";
+        assert!(!matches(range, "1.0.0"));
+        assert!(matches(range, "1.4.9"));
+        assert!(!matches(range, "1.5.0"));
+        assert!(matches(range, "2.0.0"));
+        assert!(!matches(range, "2.0.1"));
     }
 
-    let bin_dir = Path::new("node_modules").join(".bin");
-    fs::create_dir_all(&bin_dir)?;
-
-    for (bin_name, rel_path) in &package.bin_entries {
-        let src = package_root.join(rel_path);
-        let dst = bin_dir.join(bin_name);
+    #[test]
+    fn tabs_and_repeated_spaces_separate_comparators() {
+        assert!(matches(">=1.2.7\t<1.3.0", "1.2.8"));
+        assert!(!matches(">=1.2.7\t<1.3.0", "1.3.0"));
+        assert!(matches(">=  1.2.7    <   1.3.0", "1.2.8"));
+        assert!(!matches(">=  1.2.7    <   1.3.0", "1.2.0"));
+    }
 
-        if dst.exists() {
-            fs::remove_file(&dst)?;
-        }
+    #[test]
+    fn a_trailing_operator_is_rejected() {
+        let err = NpmVersionReq::parse(">=1.2.3 <").unwrap_err();
+        assert_eq!(err.to_string(), "'>=1.2.3 <' ends with '<' but no version");
+        assert!(NpmVersionReq::parse("^1.0.0 || >=").is_err());
+    }
 
-        #[cfg(unix)]
-        {
-            std::os::unix::fs::symlink(&src, &dst)?;
-            if let Ok(metadata) = fs::metadata(&src) {
-                let mut perms = metadata.permissions();
-                perms.set_mode(0o755);
-                let _ = fs::set_permissions(&src, perms);
-            }
+    fn resolved(name: &str, version: &str, depth: usize) -> ResolvedPackage {
+        ResolvedPackage {
+            info: PackageInfo {
+                name: name.to_string(),
+                version: Version::parse(version).unwrap(),
+                dependencies: HashMap::new(),
+                peer_dependencies: HashMap::new(),
+                optional_peers: HashSet::new(),
+                optional_dependencies: HashMap::new(),
+                tarball_url: String::new(),
+                integrity: None,
+                shasum: None,
+                checksum: None,
+                is_workspace: false,
+                workspace_path: None,
+                engines_node: None,
+                engines_rnp: None,
+                os_constraints: Vec::new(),
+                cpu_constraints: Vec::new(),
+                lifecycle_scripts: HashMap::new(),
+                bin_entries: HashMap::new(),
+                bundled_dependencies: HashSet::new(),
+                deprecated: None,
+            },
+            depth,
+            optional: false,
         }
+    }
 
-        #[cfg(windows)]
-        {
-            let script = format!(
-                "@echo off\r\nnode \"%~dp0\\..\\{}\\{}\" %*\r\n",
-                package.name,
-                rel_path.replace('/', "\\")
-            );
-            fs::write(dst.with_extension("cmd"), script)?;
-        }
+    #[test]
+    fn requirements_are_visited_in_name_order() {
+        let requirements = ["zeta", "gamma", "alpha", "beta"]
+            .iter()
+            .map(|name| (name.to_string(), NpmVersionReq::parse("^1.0.0").unwrap()))
+            .collect::<HashMap<_, _>>();
+        let names = sorted_requirements(&requirements).into_iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["alpha", "beta", "gamma", "zeta"]);
     }
 
-    Ok(())
-}
+    #[test]
+    fn resolved_packages_sort_by_depth_then_name_then_version() {
+        let mut packages = vec![
+            resolved("shared", "2.0.0", 2),
+            resolved("zeta", "1.0.0", 1),
+            resolved("shared", "1.0.0", 2),
+            resolved("app", "1.0.0", 0),
+            resolved("alpha", "1.0.0", 1),
+        ];
+        sort_resolved_packages(&mut packages);
+        let order = packages
+            .iter()
+            .map(|p| format!("{}@{}", p.info.name, p.info.version))
+            .collect::<Vec<_>>();
+        assert_eq!(order, ["app@1.0.0", "alpha@1.0.0", "zeta@1.0.0", "shared@1.0.0", "shared@2.0.0"]);
+    }
 
-fn run_lifecycle_scripts(
-    package: &PackageInfo,
-    package_root: &Path,
-    options: &InstallOptions,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if options.ignore_scripts || package.lifecycle_scripts.is_empty() {
-        return Ok(());
+    #[test]
+    fn a_requirement_waiting_in_the_queue_is_not_queued_again() {
+        let mut queue = VecDeque::new();
+        let mut enqueued = HashMap::new();
+        let base = NpmVersionReq::parse("^1.0.0").unwrap();
+        for parent in ["left", "right"] {
+            enqueue(&mut queue, &mut enqueued, ("base".to_string(), base.clone(), 1, false, Some(parent.to_string())));
+        }
+        // A different range or an optional request is a separate entry
+        let newer = NpmVersionReq::parse("^2.0.0").unwrap();
+        enqueue(&mut queue, &mut enqueued, ("base".to_string(), newer, 1, false, Some("other".to_string())));
+        enqueue(&mut queue, &mut enqueued, ("base".to_string(), base.clone(), 1, true, Some("extra".to_string())));
+
+        let parents = queue.iter().map(|entry| entry.4.as_deref().unwrap()).collect::<Vec<_>>();
+        assert_eq!(parents, ["left", "other", "extra"]);
+        assert_eq!(enqueued[&("base".to_string(), base.display(), false)], ["right"]);
     }
 
-    for script_name in ["preinstall", "install", "postinstall"] {
-        let Some(script_cmd) = package.lifecycle_scripts.get(script_name) else {
-            continue;
-        };
+    // Fetch `name` from `registry` through a metadata cache kept in `dir`
+    async fn fetch_document(registry: &TestRegistry, dir: &Path, name: &str) -> Value {
+        let registries = Registries::from_config(&Config::default(), Some(registry.url()), &[]);
+        let (document, _) = fetch_registry_document(&registries.client(), &registries, &dir.join("cache"), Duration::ZERO, name)
+            .await
+            .unwrap();
+        document
+    }
 
-        options.debug(&format!("running {} for {}", script_name, package.name));
+    #[tokio::test]
+    async fn stale_metadata_is_revalidated_with_its_etag() {
+        let registry = TestRegistry::start();
+        let packument = json!({ "name": "pkg", "versions": {} });
+        registry.route(
+            "/pkg",
+            vec![
+                Reply::json(&packument).header("ETag", "\"v1\""),
+                Reply::bytes(Vec::new()).with_status(304),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(fetch_document(&registry, dir.path(), "pkg").await, packument);
+        assert_eq!(fetch_document(&registry, dir.path(), "pkg").await, packument);
+        let requests = registry.requests("/pkg");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].get("if-none-match"), None);
+        assert_eq!(requests[1].get("if-none-match").map(String::as_str), Some("\"v1\""));
+    }
 
-        #[cfg(unix)]
-        let status = Command::new("sh")
-            .arg("-c")
-            .arg(script_cmd)
-            .current_dir(package_root)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status()?;
+    #[tokio::test]
+    async fn an_unexpected_not_modified_is_fetched_again() {
+        let registry = TestRegistry::start();
+        let packument = json!({ "name": "pkg", "versions": {} });
+        registry.route("/pkg", vec![Reply::bytes(Vec::new()).with_status(304), Reply::json(&packument)]);
+        let dir = tempfile::tempdir().unwrap();
 
-        #[cfg(windows)]
-        let status = Command::new("cmd")
-            .arg("/C")
-            .arg(script_cmd)
-            .current_dir(package_root)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status()?;
+        assert_eq!(fetch_document(&registry, dir.path(), "pkg").await, packument);
+        assert_eq!(registry.hits("/pkg"), 2);
+        assert!(registry.requests("/pkg").iter().all(|headers| !headers.contains_key("if-none-match")));
+    }
 
-        if !status.success() {
-            return Err(format!(
-                "lifecycle script '{}' failed for {} with status {}",
-                script_name, package.name, status
-            )
-            .into());
+    // A gzipped tarball written entry by entry, so tests can build archives
+    // the tar crate itself would refuse to (`../` paths)
+    fn raw_tarball(entries: &[(&str, tar::EntryType, u32, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        for (path, entry_type, mode, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(*mode);
+            header.set_size(contents.len() as u64);
+            if entry_type.is_symlink() {
+                header.set_link_name(contents).unwrap();
+                header.set_size(0);
+            }
+            header.set_cksum();
+            builder.append(&header, if entry_type.is_symlink() { &b""[..] } else { contents.as_bytes() }).unwrap();
         }
+        builder.into_inner().unwrap().finish().unwrap()
     }
 
-    Ok(())
-}
+    // Two workspace members under the project, with these dependencies
+    fn members(options: &InstallOptions, a: Value, b: Value) -> Vec<PathBuf> {
+        fs::write(options.prefix.join("package.json"), r#"{ "name": "root", "workspaces": ["packages/*"] }"#).unwrap();
+        [("a", a), ("b", b)]
+            .into_iter()
+            .map(|(name, dependencies)| {
+                let dir = options.prefix.join("packages").join(name);
+                fs::create_dir_all(&dir).unwrap();
+                let manifest = json!({ "name": name, "version": "1.0.0", "dependencies": dependencies });
+                fs::write(dir.join("package.json"), manifest.to_string()).unwrap();
+                dir.join("package.json")
+            })
+            .collect()
+    }
 
-fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    fs::create_dir_all(dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        let src_path = entry.path();
-        let dst_path = dst.join(entry.file_name());
+    #[tokio::test]
+    async fn update_refuses_members_with_conflicting_ranges() {
+        let registry = TestRegistry::start();
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        let manifests = members(
+            &options,
+            json!({ "lodash": "^3.0.0", "react": "^18.0.0" }),
+            json!({ "lodash": "^4.0.0", "react": "^18.0.0" }),
+        );
+
+        let err = handle_update_packages_async(&["react".to_string()], &manifests, options.clone())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&format!(
+            "lodash: ^3.0.0 ({}), ^4.0.0 ({})",
+            manifests[0].display(),
+            manifests[1].display()
+        )));
+        assert!(!err.contains("react:"));
+        assert_eq!(registry.hits("/lodash"), 0);
+    }
 
-        if ty.is_dir() {
-            copy_dir_recursive(&src_path, &dst_path)?;
-        } else if ty.is_file() {
-            fs::copy(&src_path, &dst_path)?;
+    #[tokio::test]
+    async fn update_aligns_members_when_the_conflict_is_a_target() {
+        let registry = TestRegistry::start();
+        registry.publish("lodash", &[("3.0.0", json!({})), ("4.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        let manifests = members(&options, json!({ "lodash": "^3.0.0" }), json!({ "lodash": "^4.0.0" }));
+
+        handle_update_packages_async(&["lodash".to_string()], &manifests, options).await.unwrap();
+        for manifest_path in &manifests {
+            let manifest = manifest::parse(&fs::read_to_string(manifest_path).unwrap()).unwrap();
+            assert_eq!(manifest["dependencies"]["lodash"], "^4.0.0");
         }
     }
-    Ok(())
-}
 
-#[cfg(unix)]
-fn symlink_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
-    std::os::unix::fs::symlink(src, dst)
-}
+    #[test]
+    fn local_tarballs_are_saved_relative_to_the_project() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("app");
+        fs::create_dir_all(root.join("vendor")).unwrap();
+        for path in [dir.path().join("shared.tgz"), root.join("vendor/pkg.tgz")] {
+            fs::write(path, b"").unwrap();
+        }
 
-#[cfg(windows)]
-fn symlink_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
-    std::os::windows::fs::symlink_dir(src, dst)
-}
+        let spec = |argument: &Path| local_tarball_argument(argument.to_str().unwrap(), &root).unwrap();
+        assert_eq!(spec(&root.join("vendor/pkg.tgz")).as_deref(), Some("file:./vendor/pkg.tgz"));
+        assert_eq!(spec(&dir.path().join("shared.tgz")).as_deref(), Some("file:../shared.tgz"));
+        assert_eq!(spec(&root.join("vendor/../vendor/pkg.tgz")).as_deref(), Some("file:./vendor/pkg.tgz"));
+        assert_eq!(spec(Path::new("missing.tgz")).as_deref(), Some("file:missing.tgz"));
+        assert_eq!(spec(Path::new("react")), None);
+    }
 
-fn build_nested_node_modules(packages: &[ResolvedPackage], options: &InstallOptions) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if options.hoist_strategy == "none" {
-        return Ok(());
+    #[tokio::test]
+    async fn batch_install_reports_each_spec_and_saves_the_ones_that_worked() {
+        let registry = TestRegistry::start();
+        registry.publish("top", &[("1.0.0", json!({ "dependencies": { "b": "^1.0.0" } }))]);
+        registry.publish("b", &[("1.0.0", json!({})), ("2.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        fs::write(options.prefix.join("package.json"), r#"{ "name": "app" }"#).unwrap();
+
+        let specs = ["top", "b@^1", "missing", "c@not a range"].map(str::to_string);
+        let report = handle_install_batch_async(&specs, options.clone()).await.unwrap();
+        assert_eq!(report.installed_specs, ["b@^1: b@1.0.0", "top: top@1.0.0"]);
+        assert_eq!(report.failed_specs.len(), 2);
+        assert!(report.failed_specs[0].starts_with("c@not a range: invalid version range"));
+        assert!(report.failed_specs[1].starts_with("missing: "));
+
+        let manifest = manifest::parse(&fs::read_to_string(options.prefix.join("package.json")).unwrap()).unwrap();
+        assert_eq!(manifest["dependencies"], json!({ "b": "^1.0.0", "top": "^1.0.0" }));
+        let lockfile = fs::read_to_string(options.prefix.join(PACKAGE_LOCK_FILE)).unwrap();
+        let lockfile: PackageLock = serde_json::from_str(&lockfile).unwrap();
+        let mut locked = lockfile.packages.keys().cloned().collect::<Vec<_>>();
+        locked.sort();
+        assert_eq!(locked, ["", "node_modules/b", "node_modules/top"]);
     }
 
-    let root = Path::new("node_modules");
-    let all_package_names = packages
-        .iter()
-        .map(|p| p.info.name.clone())
-        .collect::<Vec<_>>();
+    #[test]
+    fn rnp_only_lockfile_fields_are_snake_case() {
+        let lockfile = PackageLock {
+            name: "app".into(),
+            version: "1.0.0".into(),
+            lockfile_version: 1,
+            requires: true,
+            dependencies: BTreeMap::new(),
+            workspace_paths: BTreeMap::from([("a".into(), "packages/a".into())]),
+            dist_tags: BTreeMap::from([("react".into(), "next".into())]),
+            manifest_hash: Some("sha512-x".into()),
+            packages: BTreeMap::new(),
+        };
+        let value = serde_json::to_value(&lockfile).unwrap();
+        let mut keys = value.as_object().unwrap().keys().map(String::as_str).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["dist_tags", "lockfileVersion", "manifest_hash", "name", "packages", "requires", "version", "workspace_paths"]
+        );
+    }
 
-    for package in packages {
-        let package_root = root.join(&package.info.name);
-        let nested = package_root.join("node_modules");
-        fs::create_dir_all(&nested)?;
+    #[tokio::test]
+    async fn a_corrupt_cache_entry_is_downloaded_again() {
+        let registry = TestRegistry::start();
+        registry.publish("pkg", &[("1.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        fs::write(options.prefix.join("package.json"), r#"{ "name": "app" }"#).unwrap();
+
+        let mut tree = resolve(&[("pkg", "1.0.0")], &options).await;
+        install_tree(&mut tree, &options).await.unwrap();
+        let cache = PackageCache::new(&options.cache_dir).unwrap();
+        fs::write(cache.tarball_path("pkg", "1.0.0"), b"truncated").unwrap();
+        fs::remove_dir_all(options.node_modules_dir()).unwrap();
+
+        let mut tree = resolve(&[("pkg", "1.0.0")], &options).await;
+        install_tree(&mut tree, &options).await.unwrap();
+        assert_eq!(registry.hits("/pkg/-/pkg-1.0.0.tgz"), 2);
+        assert!(options.node_modules_dir().join("pkg/package.json").exists());
+    }
 
-        let dependency_names = if options.hoist_strategy == "aggressive" {
-            all_package_names.clone()
-        } else {
-            package
-                .info
-                .dependencies
-                .keys()
-                .chain(package.info.peer_dependencies.keys())
-                .chain(package.info.optional_dependencies.keys())
-                .cloned()
-                .collect::<Vec<_>>()
+    #[tokio::test]
+    async fn only_a_platform_mismatch_removes_a_leftover_optional_package() {
+        let registry = TestRegistry::start();
+        registry.publish("needs-node", &[("1.0.0", json!({ "engines": { "node": ">=999" } }))]);
+        registry.publish("linux-only", &[("1.0.0", json!({ "os": ["linux"] }))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = InstallOptions {
+            engine_strict: true,
+            os: Some("win32".into()),
+            ..options(&registry, dir.path())
         };
 
-        for dep_name in dependency_names {
-            let hoisted_dep = root.join(&dep_name);
-            if !hoisted_dep.exists() {
-                continue;
-            }
-
-            let nested_dep = nested.join(&dep_name);
-            if nested_dep.exists() {
-                continue;
-            }
+        for (name, kept) in [("needs-node", true), ("linux-only", false)] {
+            let leftover = options.node_modules_dir().join(name);
+            fs::create_dir_all(&leftover).unwrap();
+            fs::write(leftover.join("package.json"), "{}").unwrap();
+            let mut package = resolve(&[(name, "1.0.0")], &options).await.packages.remove(0);
+            package.optional = true;
 
-            if let Err(err) = symlink_dir(&hoisted_dep, &nested_dep) {
-                options.warn(&format!(
-                    "could not create nested symlink {} -> {} ({})",
-                    nested_dep.display(),
-                    hoisted_dep.display(),
-                    err
-                ));
-            }
+            let client = Arc::new(options.registries.client());
+            let node = Some(Version::new(20, 0, 0));
+            assert!(DependencyResolver::download_and_extract_package(client, &package, &options, node, None, None)
+                .await
+                .is_err());
+            assert_eq!(leftover.exists(), kept, "{}", name);
         }
     }
 
-    Ok(())
-}
-
-pub async fn handle_ci_command_async(
-    options: InstallOptions,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    if !Path::new("package-lock.json").exists() {
-        return Err("package-lock.json not found. `rnp ci` requires a lockfile.".into());
+    #[tokio::test]
+    async fn a_lockfile_without_platform_fields_is_re_resolved_for_another_platform() {
+        let registry = TestRegistry::start();
+        registry.publish("pkg", &[("1.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        let manifest_path = options.prefix.join("package.json");
+        fs::write(&manifest_path, r#"{ "name": "app", "dependencies": { "pkg": "^1.0.0" } }"#).unwrap();
+        handle_install_from_manifest_async(options.clone()).await.unwrap();
+
+        let roots = ["pkg".to_string()];
+        let plan = |options: &InstallOptions| locked_install_plan(&options.prefix, &roots, &manifest_path, options).unwrap();
+        assert!(plan(&options).is_some());
+        assert!(plan(&InstallOptions { cpu: Some("arm64".into()), ..options.clone() }).is_none());
     }
 
-    let workspace_packages = load_workspace_packages()?;
-    let manifest_path = workspace_manifest_path(options.workspace.as_deref(), &workspace_packages)?;
-    if !manifest_path.exists() {
-        return Err(format!("{} not found", manifest_path.display()).into());
+    #[tokio::test]
+    async fn a_lockfile_is_stale_once_a_dev_dependency_range_moves_past_it() {
+        let registry = TestRegistry::start();
+        registry.publish("pkg", &[("1.0.0", json!({})), ("2.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        let manifest_path = options.prefix.join("package.json");
+        fs::write(&manifest_path, r#"{ "name": "app", "devDependencies": { "pkg": "^1.0.0" } }"#).unwrap();
+        handle_install_from_manifest_async(options.clone()).await.unwrap();
+
+        let roots = ["pkg".to_string()];
+        assert!(locked_install_plan(&options.prefix, &roots, &manifest_path, &options).unwrap().is_some());
+
+        fs::write(&manifest_path, r#"{ "name": "app", "devDependencies": { "pkg": "^2.0.0" } }"#).unwrap();
+        assert!(locked_install_plan(&options.prefix, &roots, &manifest_path, &options).unwrap().is_none());
+        handle_install_from_manifest_async(options.clone()).await.unwrap();
+        assert_eq!(installed_version(&options.node_modules_dir().join("pkg")).as_deref(), Some("2.0.0"));
     }
 
-    let lock_data = fs::read_to_string("package-lock.json")?;
-    let lockfile: PackageLock = serde_json::from_str(&lock_data)?;
-    ensure_lockfile_in_sync(&lockfile, &manifest_path)?;
+    #[tokio::test]
+    async fn optional_roots_are_checked_against_the_range_that_wins() {
+        let registry = TestRegistry::start();
+        for name in ["pkg", "opt"] {
+            registry.publish(name, &[("1.0.0", json!({})), ("2.0.0", json!({}))]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        let manifest_path = options.prefix.join("package.json");
+        let write_manifest = |opt: &str| {
+            let manifest = json!({
+                "name": "app",
+                "dependencies": { "pkg": "^1.0.0" },
+                "optionalDependencies": { "pkg": "^2.0.0", "opt": opt },
+            });
+            fs::write(&manifest_path, manifest.to_string()).unwrap();
+        };
+        write_manifest("^1.0.0");
+        handle_install_from_manifest_async(options.clone()).await.unwrap();
 
-    let packages = packages_from_lockfile(&lockfile)?;
-    if packages.is_empty() {
-        options.info("Nothing to install from lockfile.");
-        return Ok(());
+        // `dependencies` wins for pkg, so its optional ^2.0.0 doesn't make 1.0.0 stale
+        let roots = ["opt".to_string(), "pkg".to_string()];
+        assert!(locked_install_plan(&options.prefix, &roots, &manifest_path, &options).unwrap().is_some());
+
+        write_manifest("^2.0.0");
+        assert!(locked_install_plan(&options.prefix, &roots, &manifest_path, &options).unwrap().is_none());
+        let lockfile = serde_json::from_str(&fs::read_to_string(active_lockfile_path(&options.prefix)).unwrap()).unwrap();
+        assert!(ensure_lockfile_in_sync(&options.prefix, &lockfile, &manifest_path, &options).is_err());
     }
 
-    let resolver = DependencyResolver::new(workspace_packages);
-    let node_version = current_node_version();
-    let total = resolver
-        .install_packages_parallel(&packages, &options, node_version)
-        .await?;
-    build_nested_node_modules(&packages, &options)?;
+    #[tokio::test]
+    async fn legacy_bundling_records_the_nested_paths() {
+        let registry = TestRegistry::start();
+        registry.publish("a", &[("1.0.0", json!({ "dependencies": { "b": "^1.0.0" } }))]);
+        registry.publish("b", &[("1.0.0", json!({ "dependencies": { "c": "^1.0.0" } }))]);
+        registry.publish("c", &[("1.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = InstallOptions {
+            legacy_bundling: true,
+            ..options(&registry, dir.path())
+        };
+        let manifest = r#"{ "name": "app", "dependencies": { "a": "^1.0.0", "c": "^1.0.0" } }"#;
+        fs::write(options.prefix.join("package.json"), manifest).unwrap();
+        handle_install_from_manifest_async(options.clone()).await.unwrap();
+
+        let expected = [
+            "",
+            "node_modules/a",
+            "node_modules/a/node_modules/b",
+            "node_modules/a/node_modules/b/node_modules/c",
+            "node_modules/c",
+        ];
+        for path in [active_lockfile_path(&options.prefix), hidden_lockfile_path(&options.prefix)] {
+            let lockfile: PackageLock = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(lockfile.packages.keys().collect::<Vec<_>>(), expected, "{}", path.display());
+        }
+        for path in &expected[1..] {
+            assert!(options.prefix.join(path).join("package.json").exists(), "{}", path);
+        }
+        assert!(!options.node_modules_dir().join("b").exists());
 
-    options.success(&format!("Installed {} package(s) from lockfile", total));
-    Ok(())
-}
+        // ci installs each locked package once and nests it the same way
+        fs::remove_dir_all(options.node_modules_dir()).unwrap();
+        handle_ci_command_async(options.clone()).await.unwrap();
+        for path in &expected[1..] {
+            assert!(options.prefix.join(path).join("package.json").exists(), "{}", path);
+        }
+        assert!(!options.node_modules_dir().join("b").exists());
+    }
 
-// Updated main install function
-pub async fn handle_install_command_async(
-    package: &str,
-    options: InstallOptions,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let workspace_packages = load_workspace_packages()?;
-    let manifest_path = workspace_manifest_path(options.workspace.as_deref(), &workspace_packages)?;
+    #[tokio::test]
+    async fn a_package_locked_at_two_versions_is_resolved_again() {
+        let registry = TestRegistry::start();
+        registry.publish("a", &[("1.0.0", json!({ "dependencies": { "b": "^2.0.0" } }))]);
+        registry.publish("b", &[("1.0.0", json!({})), ("2.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        let manifest_path = options.prefix.join("package.json");
+        fs::write(&manifest_path, r#"{ "name": "app", "dependencies": { "a": "^1.0.0" } }"#).unwrap();
+        handle_install_from_manifest_async(options.clone()).await.unwrap();
+
+        let roots = ["a".to_string()];
+        assert!(locked_install_plan(&options.prefix, &roots, &manifest_path, &options).unwrap().is_some());
+
+        // What npm writes when the top-level b is another version than a needs
+        let lockfile_path = active_lockfile_path(&options.prefix);
+        let mut lockfile: Value = serde_json::from_str(&fs::read_to_string(&lockfile_path).unwrap()).unwrap();
+        let packages = lockfile["packages"].as_object_mut().unwrap();
+        let nested = packages["node_modules/b"].clone();
+        packages["node_modules/b"]["version"] = json!("1.0.0");
+        packages.insert("node_modules/a/node_modules/b".into(), nested);
+        fs::write(&lockfile_path, lockfile.to_string()).unwrap();
+        assert!(locked_install_plan(&options.prefix, &roots, &manifest_path, &options).unwrap().is_none());
+    }
 
-    if !manifest_path.exists() {
-        options.warn(&format!("{} not found. Please run `rnp init` first.", manifest_path.display()));
-        return Ok(());
+    #[tokio::test]
+    async fn shrinkwrap_without_a_lockfile_resolves_one() {
+        let registry = TestRegistry::start();
+        registry.publish("pkg", &[("1.0.0", json!({ "dependencies": { "dep": "^1.0.0" } }))]);
+        registry.publish("dep", &[("1.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = InstallOptions {
+            log_level: LogLevel::Silent,
+            ..options(&registry, dir.path())
+        };
+        fs::write(options.prefix.join("package.json"), r#"{ "name": "app", "devDependencies": { "pkg": "^1.0.0" } }"#)
+            .unwrap();
+        handle_shrinkwrap_command_async(&options).await.unwrap();
+
+        let data = fs::read_to_string(options.prefix.join(SHRINKWRAP_FILE)).unwrap();
+        let lockfile: PackageLock = serde_json::from_str(&data).unwrap();
+        assert_eq!(lockfile.packages.keys().collect::<Vec<_>>(), ["", "node_modules/dep", "node_modules/pkg"]);
+        assert!(!options.prefix.join(PACKAGE_LOCK_FILE).exists());
+        assert!(!options.node_modules_dir().join("pkg").exists());
     }
 
-    options.info(&format!("Resolving dependency tree for {}...", package));
+    #[tokio::test]
+    async fn audit_level_applies_when_the_install_is_up_to_date() {
+        let registry = TestRegistry::start();
+        registry.publish("pkg", &[("1.0.0", json!({}))]);
+        let advisory = json!({ "severity": "high", "title": "bad" });
+        registry.route("/-/npm/v1/security/advisories/bulk", vec![Reply::json(&json!({ "pkg": [advisory] }))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = InstallOptions {
+            audit: true,
+            audit_level: Some("high".into()),
+            audit_registry: Some(registry.url().to_string()),
+            ..options(&registry, dir.path())
+        };
+        fs::write(options.prefix.join("package.json"), r#"{ "name": "app", "dependencies": { "pkg": "^1.0.0" } }"#)
+            .unwrap();
 
-    let mut resolver = DependencyResolver::new(workspace_packages);
-    let node_version = current_node_version();
-    if node_version.is_none() {
-        options.warn("Node.js version could not be detected; engines checks are skipped.");
+        assert!(handle_install_from_manifest_async(options.clone()).await.is_err());
+        assert!(install_is_up_to_date(&options.prefix, &options.prefix.join("package.json"), &options).unwrap());
+        assert!(handle_install_from_manifest_async(options.clone()).await.is_err());
     }
-    let locked_versions = if options.no_package_lock {
-        HashMap::new()
-    } else {
-        load_locked_versions()?
-    };
 
-    // Phase 1: Resolve all dependencies
-    let packages = resolver
-        .resolve_dependencies(package, Some(&locked_versions))
-        .await?;
+    #[tokio::test]
+    async fn dedupe_on_install_drops_what_only_the_replaced_version_needed() {
+        let registry = TestRegistry::start();
+        registry.publish("a", &[("1.0.0", json!({ "dependencies": { "shared": "^1.0.0" } }))]);
+        registry.publish("b", &[("1.0.0", json!({ "dependencies": { "shared": "1.0.0" } }))]);
+        registry.publish(
+            "shared",
+            &[("1.0.0", json!({})), ("1.1.0", json!({ "dependencies": { "extra": "^1.0.0" } }))],
+        );
+        registry.publish("extra", &[("1.0.0", json!({}))]);
+        let dir = tempfile::tempdir().unwrap();
+        let roots = [("a", "^1.0.0"), ("b", "^1.0.0")];
+
+        let plain = resolve(&roots, &options(&registry, &dir.path().join("plain"))).await;
+        let deduped_options = InstallOptions {
+            dedupe_on_install: true,
+            ..options(&registry, &dir.path().join("deduped"))
+        };
+        let deduped = resolve(&roots, &deduped_options).await;
+
+        let mut plain_names = names(&plain);
+        plain_names.sort();
+        let mut deduped_names = names(&deduped);
+        deduped_names.sort();
+        assert_eq!(plain_names, ["a", "b", "extra", "shared"]);
+        assert_eq!(deduped_names, ["a", "b", "shared"]);
+        assert!(deduped.conflicts.is_empty());
+    }
 
-    // Report any conflicts
-    if !resolver.conflicts.is_empty() {
-        options.warn("Dependency conflicts detected:");
-        for conflict in &resolver.conflicts {
-            options.warn(conflict);
+    #[tokio::test]
+    async fn cli_and_library_installs_report_alike() {
+        let registry = TestRegistry::start();
+        registry.publish("old", &[("1.0.0", json!({ "deprecated": "use new" }))]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let cli = options(&registry, &dir.path().join("cli"));
+        fs::write(cli.prefix.join("package.json"), r#"{ "name": "app" }"#).unwrap();
+        let cli_report = handle_install_command_async("old", cli.clone()).await.unwrap();
+
+        let library = options(&registry, &dir.path().join("library"));
+        fs::write(library.prefix.join("package.json"), r#"{ "name": "app" }"#).unwrap();
+        let mut tree = resolve(&[("old", "*")], &library).await;
+        let library_report = install_tree(&mut tree, &library).await.unwrap();
+
+        for report in [&cli_report, &library_report] {
+            assert_eq!(report.added, ["old@1.0.0"]);
+            assert_eq!(report.diagnostics.deprecated.len(), 1);
+            assert_eq!(report.diagnostics.deprecated[0].message, "use new");
         }
+        assert!(cli.prefix.join("node_modules/old/package.json").exists());
+        assert!(library.prefix.join("node_modules/old/package.json").exists());
     }
 
-    options.info(&format!("Found {} package(s) to install", packages.len()));
-
-    // Find the root package (the one user requested, should be at depth 0)
-    let root_package = packages
-        .iter()
-        .find(|p| p.info.name == package && p.depth == 0)
-        .ok_or_else(|| format!("Root package '{}' not found in resolved packages", package))?;
-
-    options.info(&format!("Resolved {} to version {}", package, root_package.info.version));
+    #[test]
+    fn extracts_below_the_top_level_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = raw_tarball(&[
+            ("package/", tar::EntryType::Directory, 0o755, ""),
+            ("package/package.json", tar::EntryType::Regular, 0o600, "{}"),
+            ("package/bin/cli.js", tar::EntryType::Regular, 0o4777, "#!/usr/bin/env node"),
+            ("package/link", tar::EntryType::Symlink, 0o777, "/etc/passwd"),
+        ]);
+        extract_tarball(&bytes, dir.path()).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.path().join("package.json")).unwrap(), "{}");
+        assert!(!dir.path().join("package").exists());
+        assert!(fs::symlink_metadata(dir.path().join("link")).is_err());
+        #[cfg(unix)]
+        {
+            let mode = |path: &str| fs::metadata(dir.path().join(path)).unwrap().permissions().mode() & 0o7777;
+            assert_eq!(mode("package.json"), 0o644);
+            assert_eq!(mode("bin/cli.js"), 0o755);
+        }
+    }
 
-    validate_peer_dependencies(&packages, &options);
+    #[test]
+    fn refuses_entries_outside_the_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("pkg");
+        let bytes = raw_tarball(&[("package/../../escaped.js", tar::EntryType::Regular, 0o644, "x")]);
+        let err = extract_tarball(&bytes, &dest).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "refusing to extract 'package/../../escaped.js': path leaves the package directory"
+        );
+        assert!(!dir.path().join("escaped.js").exists());
+    }
 
-    // Phase 2: Install packages in parallel
-    let total_installed = resolver
-        .install_packages_parallel(&packages, &options, node_version)
-        .await?;
+    #[test]
+    fn rejects_a_corrupt_tarball() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(extract_tarball(b"not a tarball", dir.path()).is_err());
+    }
 
-    // Phase 3: Build nested node_modules links while keeping hoisted packages at root
-    build_nested_node_modules(&packages, &options)?;
+    #[test]
+    fn windows_separators_and_names_are_checked() {
+        let path = |raw: &str| tarball_entry_path(raw, true);
+        assert_eq!(path("package\\lib\\index.js").unwrap(), Some(PathBuf::from("lib").join("index.js")));
+        assert_eq!(path("package/lib\\util/./a.js").unwrap(), Some(PathBuf::from("lib").join("util").join("a.js")));
+        assert_eq!(path("package\\").unwrap(), None);
+        assert!(path("package\\..\\..\\evil.js").is_err());
+        assert!(path("\\evil.js").is_err());
+        assert!(path("package/C:evil.js").is_err());
+        assert!(path("package/file.js:stream").is_err());
+        assert!(path("package/what?.js").is_err());
+        assert!(path("package/trailing.").is_err());
+        assert!(path("package/trailing ").is_err());
+        assert_eq!(path("package/lib/nul.js").unwrap_err(), "'nul.js' is a reserved device name on Windows");
+        assert!(path("package/COM1").is_err());
+        // Only Windows cares about those names
+        assert_eq!(tarball_entry_path("package/what?.js", false).unwrap(), Some(PathBuf::from("what?.js")));
+        assert!(tarball_entry_path("package\\..\\evil.js", false).is_err());
+    }
 
-    // Phase 4: Update package.json with the ROOT package version
-    update_package_json(&manifest_path, package, &root_package.info.version, &options).await?;
+    #[tokio::test]
+    async fn gzipped_metadata_without_content_encoding_is_decoded() {
+        let registry = TestRegistry::start();
+        let packument = json!({ "name": "pkg", "versions": {} });
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, packument.to_string().as_bytes()).unwrap();
+        registry.route("/pkg", vec![Reply::bytes(encoder.finish().unwrap())]);
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(fetch_document(&registry, dir.path(), "pkg").await, packument);
+        assert_eq!(decompress_if_gzipped(b"{}").unwrap(), b"{}");
+        assert!(decompress_if_gzipped(&[0x1f, 0x8b, 0x08, 0x00]).is_err());
+    }
 
-    // Phase 5: Generate lockfile unless disabled by flag
-    if options.no_package_lock {
-        options.debug("Skipping package-lock.json generation (--no-package-lock)");
-    } else {
-        generate_lockfile(&packages)?;
-        options.success("Updated package-lock.json");
+    // A project under `dir` that resolves against `registry` with a cache of its own
+    fn options(registry: &TestRegistry, dir: &Path) -> InstallOptions {
+        let prefix = dir.join("project");
+        fs::create_dir_all(&prefix).unwrap();
+        InstallOptions {
+            prefix,
+            cache_dir: dir.join("cache"),
+            registries: Registries::from_config(&Config::default(), Some(registry.url()), &[]),
+            log_level: LogLevel::Silent,
+            ..Default::default()
+        }
     }
 
-    options.success(&format!("Successfully added {} package(s)!", total_installed));
-    Ok(())
-}
+    async fn resolve(roots: &[(&str, &str)], options: &InstallOptions) -> ResolvedTree {
+        let roots = roots
+            .iter()
+            .map(|(name, range)| (name.to_string(), NpmVersionReq::parse(range).unwrap()))
+            .collect::<Vec<_>>();
+        resolve_tree(&roots, options).await.unwrap()
+    }
 
-async fn update_package_json(
-    package_json_path: &Path,
-    package: &str,
-    resolved_version: &Version,
-    options: &InstallOptions,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Read existing package.json
-    let data = std::fs::read_to_string(package_json_path)?;
-    let mut json: serde_json::Value = serde_json::from_str(&data)?;
+    fn names(tree: &ResolvedTree) -> Vec<&str> {
+        tree.packages.iter().map(|package| package.info.name.as_str()).collect()
+    }
 
-    // Ensure package.json root is a valid JSON object
-    let obj = json.as_object_mut().ok_or("Invalid package.json format")?;
+    #[tokio::test]
+    async fn nested_local_packages_resolve_from_their_own_directory() {
+        let registry = TestRegistry::start();
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(&registry, dir.path());
+        for (path, manifest) in [
+            ("vendor/a-1.0.0.tgz", json!({ "name": "a", "version": "1.0.0", "dependencies": { "b": "file:../libs/b-2.0.0.tgz" } })),
+            ("libs/b-2.0.0.tgz", json!({ "name": "b", "version": "2.0.0", "dependencies": { "c": "file:./c-3.0.0.tgz" } })),
+            ("libs/c-3.0.0.tgz", json!({ "name": "c", "version": "3.0.0" })),
+        ] {
+            let path = options.prefix.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, tarball(&[("package/package.json", &manifest.to_string())])).unwrap();
+        }
 
-    let deps = obj
-        .entry("dependencies")
-        .or_insert(serde_json::Value::Object(serde_json::Map::new()));
+        let tree = resolve(&[("a", "file:vendor/a-1.0.0.tgz")], &options).await;
+        assert_eq!(names(&tree), ["a", "b", "c"]);
+        let b = &tree.packages[1].info;
+        assert_eq!(b.version.to_string(), "2.0.0");
+        assert_eq!(b.tarball_url, "file:libs/b-2.0.0.tgz");
+        assert_eq!(tree.packages[2].info.tarball_url, "file:libs/c-3.0.0.tgz");
+        assert_eq!(join_relative(Path::new("vendor"), "../../x"), Path::new("../x"));
+    }
 
-    // Add package with caret range (npm default behavior)
-    if let serde_json::Value::Object(map) = deps {
-        let version_range = format!("^{}", resolved_version);
-        map.insert(
-            package.to_string(),
-            serde_json::Value::String(version_range),
+    #[tokio::test]
+    async fn a_missing_package_is_looked_up_once() {
+        let registry = TestRegistry::start();
+        registry.publish(
+            "app",
+            &[("1.0.0", json!({ "dependencies": { "left": "^1.0.0", "right": "^1.0.0" } }))],
         );
+        registry.publish("left", &[("1.0.0", json!({ "dependencies": { "missing": "^1.0.0" } }))]);
+        registry.publish("right", &[("1.0.0", json!({ "dependencies": { "missing": "^2.0.0" } }))]);
+        let dir = tempfile::tempdir().unwrap();
+        let options = InstallOptions { force: true, ..options(&registry, dir.path()) };
+
+        let tree = resolve(&[("app", "^1.0.0")], &options).await;
+        assert_eq!(names(&tree), ["app", "left", "right"]);
+        assert_eq!(tree.unresolved.len(), 2);
+        assert_eq!(registry.hits("/missing"), 1);
     }
-
-    // Write back with pretty formatting
-    let formatted = serde_json::to_string_pretty(&json)?;
-    std::fs::write(package_json_path, formatted)?;
-
-    options.success(&format!("Updated package.json with {}@^{}", package, resolved_version));
-    Ok(())
 }
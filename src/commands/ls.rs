@@ -0,0 +1,309 @@
+use crate::commands::install::{
+    ResolvedPackage, SAVE_SECTIONS, load_installed_tree, load_locked_tree, read_manifest_section,
+};
+use crate::manifest;
+use crate::output;
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// Bumped only when a field of the `--json` output changes meaning or goes
+// away; new fields may appear without a bump
+const TREE_SCHEMA_VERSION: u32 = 1;
+
+// Which package.json sections `ls` shows; all of them unless a filter flag
+// narrows it down (`--prod` covers dependencies and optionalDependencies)
+#[derive(Debug, Clone, Default)]
+pub struct LsOptions {
+    pub prod: bool,
+    pub dev: bool,
+    pub optional: bool,
+    pub peer: bool,
+    // Everything installed, not just the direct dependencies (`--all`)
+    pub all: bool,
+    pub json: bool,
+}
+
+impl LsOptions {
+    fn shows(&self, section: &str) -> bool {
+        if !(self.prod || self.dev || self.optional || self.peer) {
+            return true;
+        }
+        match section {
+            "dependencies" => self.prod,
+            "devDependencies" => self.dev,
+            "optionalDependencies" => self.prod || self.optional,
+            "peerDependencies" => self.peer,
+            _ => false,
+        }
+    }
+}
+
+// `ls --json`: the project and its packages, read from the installed tree
+// (the lockfile when nothing is installed). Each package's `path` is its
+// lockfile key and `parents` lists the paths of the packages depending on it,
+// `""` being the project itself.
+#[derive(Debug, Serialize)]
+struct TreeDump {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    name: String,
+    version: String,
+    // "node_modules" or "lockfile"
+    source: &'static str,
+    packages: Vec<TreeEntry>,
+    // Declared in package.json but in neither
+    missing: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TreeEntry {
+    path: String,
+    name: String,
+    version: String,
+    resolved: Option<String>,
+    integrity: Option<String>,
+    // 0 for direct dependencies
+    depth: usize,
+    optional: bool,
+    parents: Vec<String>,
+    dependencies: BTreeMap<String, String>,
+}
+
+// The direct dependencies of the project with their installed versions, each
+// annotated with the package.json section(s) declaring it; with `--all` what
+// they depend on is nested below them
+pub fn handle_ls_command(root: &Path, options: &LsOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let manifest_path = root.join("package.json");
+    if !manifest_path.exists() {
+        return Err("package.json not found. Please run `rnp init` first.".into());
+    }
+
+    let mut declared: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for section in SAVE_SECTIONS {
+        for name in read_manifest_section(&manifest_path, section)?.into_keys() {
+            declared.entry(name).or_default().push(section);
+        }
+    }
+
+    let manifest = manifest::parse(&fs::read_to_string(&manifest_path)?)?;
+    let name = manifest.get("name").and_then(|v| v.as_str()).unwrap_or("(unnamed)");
+    let version = manifest.get("version").and_then(|v| v.as_str()).unwrap_or("0.0.0");
+    let entries = declared
+        .iter()
+        .filter(|(_, sections)| sections.iter().any(|section| options.shows(section)))
+        .collect::<Vec<_>>();
+
+    if options.json {
+        let (tree, source) = match load_installed_tree(root)? {
+            Some(tree) => (tree, "node_modules"),
+            None => (load_locked_tree(root)?, "lockfile"),
+        };
+        let direct = entries.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        let dump = tree_dump(name, version, source, &tree, &direct, options.all);
+        println!("{}", serde_json::to_string_pretty(&dump)?);
+        return Ok(());
+    }
+
+    println!(
+        "{}@{} {}",
+        name,
+        version,
+        fs::canonicalize(if root.as_os_str().is_empty() { Path::new(".") } else { root })
+            .unwrap_or_else(|_| root.to_path_buf())
+            .display()
+    );
+
+    let tree = if options.all {
+        match load_installed_tree(root)? {
+            Some(tree) => tree,
+            None => load_locked_tree(root)?,
+        }
+    } else {
+        Vec::new()
+    };
+    let by_name = tree
+        .iter()
+        .map(|package| (package.info.name.as_str(), package))
+        .collect::<HashMap<_, _>>();
+    let glyphs = Glyphs::new();
+    // Packages already expanded once; later occurrences are marked deduped
+    let mut expanded = HashSet::new();
+    for (index, (name, sections)) in entries.iter().enumerate() {
+        let last = index + 1 == entries.len();
+        let marker = if last { glyphs.last_branch } else { glyphs.branch };
+        let label = match installed_version(root, name) {
+            Some(version) => format!("{}@{}", name, version),
+            None => format!("{} {}", name, "(missing)".red()),
+        };
+        println!("{}{} {}", marker, label, format!("({})", sections.join(", ")).dimmed());
+        if options.all {
+            let indent = if last { glyphs.blank } else { glyphs.pipe };
+            print_dependencies(&by_name, name, indent, &glyphs, &mut expanded);
+        }
+    }
+    if entries.is_empty() {
+        println!("{}(empty)", glyphs.last_branch);
+    }
+    Ok(())
+}
+
+struct Glyphs {
+    branch: &'static str,
+    last_branch: &'static str,
+    pipe: &'static str,
+    blank: &'static str,
+}
+
+impl Glyphs {
+    fn new() -> Self {
+        let (branch, last_branch, pipe) = if output::decorations_enabled() {
+            ("├── ", "└── ", "│   ")
+        } else {
+            ("+-- ", "`-- ", "|   ")
+        };
+        Glyphs {
+            branch,
+            last_branch,
+            pipe,
+            blank: "    ",
+        }
+    }
+}
+
+// The dependencies of `name` that are in the tree, recursively. A package
+// shown (and expanded) before is listed again but not expanded, which also
+// stops cycles.
+fn print_dependencies(
+    by_name: &HashMap<&str, &ResolvedPackage>,
+    name: &str,
+    indent: &str,
+    glyphs: &Glyphs,
+    expanded: &mut HashSet<String>,
+) {
+    let Some(package) = by_name.get(name) else {
+        return;
+    };
+    if !expanded.insert(name.to_string()) {
+        return;
+    }
+
+    let children = package
+        .info
+        .dependencies
+        .keys()
+        .filter_map(|child| by_name.get(child.as_str()).map(|&child_package| (child.as_str(), child_package)))
+        .collect::<BTreeMap<_, _>>();
+    for (index, (child, child_package)) in children.iter().enumerate() {
+        let last = index + 1 == children.len();
+        let marker = if last { glyphs.last_branch } else { glyphs.branch };
+        let label = format!("{}@{}", child, child_package.info.version);
+        if expanded.contains(*child) {
+            println!("{}{}{} {}", indent, marker, label, "deduped".dimmed());
+            continue;
+        }
+        println!("{}{}{}", indent, marker, label);
+        let nested = format!("{}{}", indent, if last { glyphs.blank } else { glyphs.pipe });
+        print_dependencies(by_name, child, &nested, glyphs, expanded);
+    }
+}
+
+// The packages `--json` reports: the direct dependencies found in the tree,
+// and with `all` everything they reach, each at its shortest distance from
+// the project. Sorted by path.
+fn tree_dump(
+    name: &str,
+    version: &str,
+    source: &'static str,
+    tree: &[ResolvedPackage],
+    direct: &[&str],
+    all: bool,
+) -> TreeDump {
+    let by_name = tree
+        .iter()
+        .map(|package| (package.info.name.as_str(), package))
+        .collect::<HashMap<_, _>>();
+
+    let mut depths = BTreeMap::new();
+    let mut queue = direct
+        .iter()
+        .filter(|name| by_name.contains_key(*name))
+        .map(|&name| (name, 0))
+        .collect::<VecDeque<_>>();
+    while let Some((name, depth)) = queue.pop_front() {
+        if depths.contains_key(name) {
+            continue;
+        }
+        depths.insert(name, depth);
+        if !all {
+            continue;
+        }
+        queue.extend(
+            by_name[name]
+                .info
+                .dependencies
+                .keys()
+                .map(|child| child.as_str())
+                .filter(|child| by_name.contains_key(child))
+                .map(|child| (child, depth + 1)),
+        );
+    }
+
+    let packages = depths
+        .iter()
+        .map(|(&name, &depth)| {
+            let package = by_name[name];
+            let mut parents = depths
+                .keys()
+                .filter(|&&dependent| by_name[dependent].info.dependencies.contains_key(name))
+                .map(|dependent| lock_path(dependent))
+                .collect::<Vec<_>>();
+            if direct.contains(&name) {
+                parents.insert(0, String::new());
+            }
+            TreeEntry {
+                path: lock_path(name),
+                name: name.to_string(),
+                version: package.info.version.to_string(),
+                resolved: Some(package.info.tarball_url.clone()).filter(|url| !url.is_empty()),
+                integrity: package.info.integrity.clone(),
+                depth,
+                optional: package.optional,
+                parents,
+                dependencies: package
+                    .info
+                    .dependencies
+                    .iter()
+                    .map(|(dep, req)| (dep.clone(), req.display()))
+                    .collect(),
+            }
+        })
+        .collect();
+
+    TreeDump {
+        schema_version: TREE_SCHEMA_VERSION,
+        name: name.to_string(),
+        version: version.to_string(),
+        source,
+        packages,
+        missing: direct
+            .iter()
+            .filter(|name| !by_name.contains_key(*name))
+            .map(|name| name.to_string())
+            .collect(),
+    }
+}
+
+// Packages are installed flat, so this is also their lockfile key
+fn lock_path(name: &str) -> String {
+    format!("node_modules/{}", name)
+}
+
+fn installed_version(root: &Path, name: &str) -> Option<String> {
+    let data = fs::read_to_string(root.join("node_modules").join(name).join("package.json")).ok()?;
+    let manifest = manifest::parse(&data).ok()?;
+    manifest.get("version").and_then(|v| v.as_str()).map(|v| v.to_string())
+}
@@ -4,3 +4,12 @@ pub mod uninstall;
 pub mod update;
 pub mod run;
 pub mod audit;
+pub mod view;
+pub mod verify;
+pub mod cache;
+pub mod print_config;
+pub mod rebuild;
+pub mod ls;
+pub mod prune;
+pub mod outdated;
+pub mod pack;
@@ -0,0 +1,291 @@
+use crate::commands::install::{
+    MAX_CONCURRENT_DOWNLOADS, NpmVersionReq, ResolvedPackage, fetch_registry_document, load_installed_tree,
+    load_locked_tree, published_versions, read_manifest_section,
+};
+use crate::manifest;
+use crate::output;
+use crate::registry::Registries;
+use colored::Colorize;
+use semver::Version;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+// The sections whose entries count as direct dependencies
+const OUTDATED_SECTIONS: [&str; 3] = ["dependencies", "devDependencies", "optionalDependencies"];
+
+#[derive(Debug, Clone)]
+pub struct OutdatedOptions {
+    pub prefix: PathBuf,
+    // How far below the direct dependencies to look (0 = direct only)
+    pub depth: usize,
+    pub json: bool,
+    pub registries: Registries,
+    pub cache_dir: PathBuf,
+    pub cache_min_time: Duration,
+}
+
+// One package whose installed version is behind what its dependents allow
+// (`wanted`) or behind the `latest` dist-tag
+#[derive(Debug, Serialize)]
+struct Outdated {
+    current: Option<String>,
+    wanted: Option<String>,
+    latest: Option<String>,
+    // The package.json section for direct dependencies, else who requires it
+    #[serde(rename = "dependedBy")]
+    depended_by: String,
+    depth: usize,
+}
+
+// A package to check: what is installed and every range asking for it
+struct Candidate {
+    current: Option<Version>,
+    requirements: Vec<NpmVersionReq>,
+    depended_by: BTreeSet<String>,
+    depth: usize,
+}
+
+// Direct dependencies from package.json, plus with `depth` > 0 the transitive
+// ones from the installed tree (or the lockfile). Each distinct package is
+// looked up once, through the same metadata cache installs use.
+pub async fn handle_outdated_command_async(options: &OutdatedOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let root = options.prefix.as_path();
+    let manifest_path = root.join("package.json");
+    if !manifest_path.exists() {
+        return Err("package.json not found. Please run `rnp init` first.".into());
+    }
+
+    let tree = match load_installed_tree(root)? {
+        Some(tree) => tree,
+        None => load_locked_tree(root)?,
+    };
+
+    let mut candidates = BTreeMap::new();
+    for section in OUTDATED_SECTIONS {
+        for (name, range) in read_manifest_section(&manifest_path, section)? {
+            let Ok(req) = NpmVersionReq::parse(&range) else {
+                continue;
+            };
+            if req.is_source_spec() {
+                continue;
+            }
+            candidates.entry(name.clone()).or_insert_with(|| Candidate {
+                current: installed_version(root, &name),
+                requirements: vec![req],
+                depended_by: BTreeSet::from([section.to_string()]),
+                depth: 0,
+            });
+        }
+    }
+    if options.depth > 0 {
+        collect_transitive(&tree, options.depth, &mut candidates);
+    }
+
+    let documents = fetch_documents(candidates.keys().cloned().collect(), options).await;
+
+    let mut outdated = BTreeMap::new();
+    for (name, candidate) in candidates {
+        let versions = match documents.get(&name) {
+            Some(Ok(versions)) => versions,
+            Some(Err(err)) => {
+                output::warn(&format!("Could not check {}: {}", name, err));
+                continue;
+            }
+            None => continue,
+        };
+        let wanted = versions
+            .published
+            .iter()
+            .filter(|version| candidate.requirements.iter().all(|req| req.matches(version)))
+            .max();
+        let behind = |target: Option<&Version>| match (&candidate.current, target) {
+            (None, _) => true,
+            (Some(current), Some(target)) => current < target,
+            (Some(_), None) => false,
+        };
+        if !behind(wanted) && !behind(versions.latest.as_ref()) {
+            continue;
+        }
+        outdated.insert(
+            name,
+            Outdated {
+                current: candidate.current.map(|v| v.to_string()),
+                wanted: wanted.map(|v| v.to_string()),
+                latest: versions.latest.as_ref().map(|v| v.to_string()),
+                depended_by: candidate.depended_by.into_iter().collect::<Vec<_>>().join(", "),
+                depth: candidate.depth,
+            },
+        );
+    }
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&outdated)?);
+    } else {
+        print_outdated(&outdated);
+    }
+    Ok(())
+}
+
+// Packages in the tree between depth 1 and `max_depth`, each with the ranges
+// its dependents (anywhere in the tree) declare for it
+fn collect_transitive(tree: &[ResolvedPackage], max_depth: usize, candidates: &mut BTreeMap<String, Candidate>) {
+    for package in tree {
+        if package.depth == 0 || package.depth > max_depth || package.info.is_workspace {
+            continue;
+        }
+        if candidates.contains_key(&package.info.name) {
+            continue;
+        }
+
+        let mut requirements = Vec::new();
+        let mut depended_by = BTreeSet::new();
+        for dependent in tree {
+            let declared = dependent
+                .info
+                .dependencies
+                .get(&package.info.name)
+                .or_else(|| dependent.info.optional_dependencies.get(&package.info.name));
+            if let Some(req) = declared {
+                if req.is_source_spec() {
+                    continue;
+                }
+                requirements.push(req.clone());
+                depended_by.insert(dependent.info.name.clone());
+            }
+        }
+        if depended_by.is_empty() {
+            continue;
+        }
+
+        candidates.insert(
+            package.info.name.clone(),
+            Candidate {
+                current: Some(package.info.version.clone()),
+                requirements,
+                depended_by,
+                depth: package.depth,
+            },
+        );
+    }
+}
+
+struct PublishedVersions {
+    published: Vec<Version>,
+    latest: Option<Version>,
+}
+
+async fn fetch_documents(
+    names: Vec<String>,
+    options: &OutdatedOptions,
+) -> HashMap<String, Result<PublishedVersions, String>> {
+    let client = Arc::new(options.registries.client());
+    let options = Arc::new(options.clone());
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_DOWNLOADS));
+    let mut handles = Vec::new();
+    for name in names {
+        let client = Arc::clone(&client);
+        let options = Arc::clone(&options);
+        let semaphore = Arc::clone(&semaphore);
+        handles.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            let result = fetch_registry_document(
+                &client,
+                &options.registries,
+                &options.cache_dir,
+                options.cache_min_time,
+                &name,
+            )
+            .await
+            .and_then(|(document, _)| {
+                let published = parse_versions(published_versions(&name, &document)?);
+                let latest = document["dist-tags"]["latest"]
+                    .as_str()
+                    .and_then(|v| Version::parse(v).ok());
+                Ok(PublishedVersions { published, latest })
+            })
+            .map_err(|e| e.to_string());
+            (name, result)
+        }));
+    }
+
+    let mut documents = HashMap::new();
+    for handle in handles {
+        if let Ok((name, result)) = handle.await {
+            documents.insert(name, result);
+        }
+    }
+    documents
+}
+
+fn parse_versions(versions: &Map<String, Value>) -> Vec<Version> {
+    versions.keys().filter_map(|v| Version::parse(v).ok()).collect()
+}
+
+fn installed_version(root: &Path, name: &str) -> Option<Version> {
+    let data = fs::read_to_string(root.join("node_modules").join(name).join("package.json")).ok()?;
+    let manifest = manifest::parse(&data).ok()?;
+    Version::parse(manifest.get("version")?.as_str()?).ok()
+}
+
+// npm's table: red when the installed version is behind what the ranges
+// allow (an install would update it), yellow when only `latest` is newer
+fn print_outdated(outdated: &BTreeMap<String, Outdated>) {
+    if outdated.is_empty() {
+        println!("All dependencies are up to date.");
+        return;
+    }
+
+    let rows = outdated
+        .iter()
+        .map(|(name, entry)| {
+            [
+                name.clone(),
+                entry.current.clone().unwrap_or_else(|| "MISSING".to_string()),
+                entry.wanted.clone().unwrap_or_else(|| "-".to_string()),
+                entry.latest.clone().unwrap_or_else(|| "-".to_string()),
+                entry.depended_by.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["Package", "Current", "Wanted", "Latest", "Depended by"].map(String::from);
+    let widths = (0..4)
+        .map(|column| {
+            rows.iter()
+                .chain(std::iter::once(&header))
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+
+    let format_row = |row: &[String; 5]| {
+        format!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {:<w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        )
+    };
+    println!("{}", format_row(&header).bold());
+    for (row, entry) in rows.iter().zip(outdated.values()) {
+        let line = format_row(row);
+        if entry.current.is_none() || entry.current != entry.wanted {
+            println!("{}", line.red());
+        } else {
+            println!("{}", line.yellow());
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::manifest;
+use crate::packlist::package_files;
+use colored::Colorize;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// npm stamps every entry with this date (1985-10-26T08:15:00Z) so packing the
+// same files twice gives the same tarball
+const PACKED_MTIME: u64 = 499_162_500;
+
+// Writes `<name>-<version>.tgz` (`scope-name-<version>.tgz` for a scoped
+// package) into `root`: the publish file list under `package/`, bundled
+// dependencies included. With `dry_run` the files are only listed.
+pub fn handle_pack_command(root: &Path, dry_run: bool, quiet: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let manifest_path = root.join("package.json");
+    let data = fs::read_to_string(&manifest_path)
+        .map_err(|_| "package.json not found. Please run `rnp init` first.".to_string())?;
+    let manifest = manifest::parse(&data)?;
+    let (Some(name), Some(version)) = (manifest["name"].as_str(), manifest["version"].as_str()) else {
+        return Err("package.json needs a name and a version to be packed".into());
+    };
+
+    let files = package_files(root)?;
+    let filename = tarball_name(name, version);
+    if !quiet {
+        for file in &files {
+            println!("{}", to_slash(file));
+        }
+    }
+    if dry_run {
+        if !quiet {
+            println!("{} {} ({} files, not written)", "Would write".yellow(), filename, files.len());
+        }
+        return Ok(());
+    }
+
+    let bytes = pack_files(root, &files)?;
+    fs::write(root.join(&filename), &bytes)?;
+    if !quiet {
+        println!("{} {} ({} files, {} bytes)", "Wrote".green(), filename, files.len(), bytes.len());
+    }
+    Ok(())
+}
+
+fn tarball_name(name: &str, version: &str) -> String {
+    format!("{}-{}.tgz", name.trim_start_matches('@').replace('/', "-"), version)
+}
+
+// A gzipped tarball of `files` (relative to `root`) under `package/`
+fn pack_files(root: &Path, files: &[PathBuf]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for file in files {
+        let contents = fs::read(root.join(file))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(if is_executable(&root.join(file)) { 0o755 } else { 0o644 });
+        header.set_mtime(PACKED_MTIME);
+        builder.append_data(&mut header, format!("package/{}", to_slash(file)), contents.as_slice())?;
+    }
+    Ok(builder.into_inner()?.finish()?)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    false
+}
+
+fn to_slash(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn packs_the_file_list_with_bundled_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let manifest = r#"{ "name": "@acme/lib", "version": "1.2.0", "dependencies": { "dep": "^1.0.0" }, "bundledDependencies": ["dep"] }"#;
+        fs::write(root.join("package.json"), manifest).unwrap();
+        fs::write(root.join("index.js"), "module.exports = 1").unwrap();
+        fs::write(root.join(".npmignore"), "test/\n").unwrap();
+        fs::create_dir_all(root.join("test")).unwrap();
+        fs::write(root.join("test/index.test.js"), "").unwrap();
+        for name in ["dep", "other"] {
+            fs::create_dir_all(root.join("node_modules").join(name)).unwrap();
+            fs::write(root.join("node_modules").join(name).join("package.json"), "{}").unwrap();
+        }
+
+        handle_pack_command(root, false, true).unwrap();
+        let bytes = fs::read(root.join("acme-lib-1.2.0.tgz")).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(bytes.as_slice()));
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), PACKED_MTIME);
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            if path == "package/index.js" {
+                assert_eq!(contents, "module.exports = 1");
+            }
+            entries.push(path);
+        }
+        entries.sort();
+        assert_eq!(
+            entries,
+            ["package/.npmignore", "package/index.js", "package/node_modules/dep/package.json", "package/package.json"]
+        );
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("package.json"), r#"{ "name": "lib", "version": "1.0.0" }"#).unwrap();
+        handle_pack_command(dir.path(), true, true).unwrap();
+        assert!(!dir.path().join("lib-1.0.0.tgz").exists());
+    }
+
+    #[test]
+    fn needs_a_name_and_version() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("package.json"), r#"{ "name": "lib" }"#).unwrap();
+        assert!(handle_pack_command(dir.path(), false, true).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::commands::install::{SAVE_SECTIONS, read_manifest_section};
+use crate::manifest;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// A package directory somewhere under node_modules, keyed by its path
+// relative to the project (`node_modules/a/node_modules/b`)
+#[derive(Debug)]
+struct Installed {
+    name: String,
+    version: String,
+    // dependencies, optionalDependencies and peerDependencies
+    requires: Vec<String>,
+    // Symlinked packages (workspaces) are never pruned
+    linked: bool,
+}
+
+// Removes whatever in node_modules no dependency in package.json leads to.
+// With `production` devDependencies don't count either, which leaves only
+// what the project needs at runtime. Reachability follows Node's lookup:
+// a package's dependency is the nearest `node_modules/<name>` going up.
+pub fn handle_prune_command(
+    root: &Path,
+    production: bool,
+    dry_run: bool,
+    quiet: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let manifest_path = root.join("package.json");
+    if !manifest_path.exists() {
+        return Err("package.json not found. Please run `rnp init` first.".into());
+    }
+
+    let mut installed = BTreeMap::new();
+    collect_installed(&root.join("node_modules"), "", &mut installed)?;
+
+    let mut queue = VecDeque::new();
+    for section in SAVE_SECTIONS {
+        if production && section == "devDependencies" {
+            continue;
+        }
+        for name in read_manifest_section(&manifest_path, section)?.into_keys() {
+            queue.push_back((String::new(), name));
+        }
+    }
+    for (path, package) in &installed {
+        if package.linked {
+            queue.extend(package.requires.iter().map(|name| (path.clone(), name.clone())));
+        }
+    }
+
+    let mut reachable = BTreeSet::new();
+    while let Some((from, name)) = queue.pop_front() {
+        let Some(path) = lookup(&installed, &from, &name) else {
+            continue;
+        };
+        if reachable.insert(path.clone()) {
+            queue.extend(installed[&path].requires.iter().map(|dep| (path.clone(), dep.clone())));
+        }
+    }
+
+    let unreachable = installed
+        .iter()
+        .filter(|(path, package)| !package.linked && !reachable.contains(*path))
+        .collect::<Vec<_>>();
+
+    for (path, package) in &unreachable {
+        if !quiet {
+            let verb = if dry_run { "would remove" } else { "removed" };
+            println!("{} {}@{}", verb, package.name, package.version);
+        }
+        // Nested copies go along with their parent's directory
+        let inside_removed = unreachable
+            .iter()
+            .any(|(other, _)| path.starts_with(&format!("{}/node_modules/", other)));
+        if !dry_run && !inside_removed {
+            fs::remove_dir_all(root.join(path))?;
+        }
+    }
+
+    if !dry_run {
+        remove_dangling_bin_links(&root.join("node_modules").join(".bin"))?;
+    }
+    if !quiet {
+        let verb = if dry_run { "would prune" } else { "pruned" };
+        println!("{} {} package(s)", verb, unreachable.len());
+    }
+    Ok(())
+}
+
+// Every package under `node_modules`, recursing into nested node_modules
+fn collect_installed(
+    node_modules: &Path,
+    prefix: &str,
+    installed: &mut BTreeMap<String, Installed>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Ok(entries) = fs::read_dir(node_modules) else {
+        return Ok(());
+    };
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if name.starts_with('@') {
+            for scoped in fs::read_dir(entry.path())? {
+                let scoped = scoped?;
+                dirs.push(format!("{}/{}", name, scoped.file_name().to_string_lossy()));
+            }
+        } else {
+            dirs.push(name);
+        }
+    }
+
+    for name in dirs {
+        let dir = node_modules.join(&name);
+        let Ok(data) = fs::read_to_string(dir.join("package.json")) else {
+            continue;
+        };
+        let Ok(manifest) = manifest::parse(&data) else {
+            continue;
+        };
+        let requires = ["dependencies", "optionalDependencies", "peerDependencies"]
+            .iter()
+            .filter_map(|section| manifest.get(*section).and_then(|v| v.as_object()))
+            .flat_map(|deps| deps.keys().cloned())
+            .collect();
+        let linked = fs::symlink_metadata(&dir).is_ok_and(|m| m.file_type().is_symlink());
+        let path = format!("{}node_modules/{}", prefix, name);
+        installed.insert(
+            path.clone(),
+            Installed {
+                name,
+                version: manifest
+                    .get("version")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                requires,
+                linked,
+            },
+        );
+        if !linked {
+            collect_installed(&dir.join("node_modules"), &format!("{}/", path), installed)?;
+        }
+    }
+    Ok(())
+}
+
+// Where `name` required from the package at `from` ("" for the project)
+// resolves: its own node_modules, then each ancestor's
+fn lookup(installed: &BTreeMap<String, Installed>, from: &str, name: &str) -> Option<String> {
+    let mut base = from;
+    loop {
+        let candidate = if base.is_empty() {
+            format!("node_modules/{}", name)
+        } else {
+            format!("{}/node_modules/{}", base, name)
+        };
+        if installed.contains_key(&candidate) {
+            return Some(candidate);
+        }
+        if base.is_empty() {
+            return None;
+        }
+        base = base.rfind("/node_modules/").map_or("", |index| &base[..index]);
+    }
+}
+
+pub fn remove_dangling_bin_links(bin_dir: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Ok(entries) = fs::read_dir(bin_dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        // `exists` follows the link, so it is false once the target is gone
+        if fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()) && !path.exists() {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
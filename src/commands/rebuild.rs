@@ -0,0 +1,130 @@
+use crate::commands::install::{
+    InstallOptions, PackageInfo, create_bin_links, package_info_from_manifest, run_lifecycle_scripts,
+};
+use crate::manifest;
+use semver::Version;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Re-runs the install scripts and recreates the `.bin` links of what is
+// already in node_modules, without downloading anything: the fix after
+// switching Node versions (native modules) or when `.bin` got mangled.
+// With `packages` only those are rebuilt.
+pub fn handle_rebuild_command(packages: &[String], options: &InstallOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let node_modules = options.prefix.join("node_modules");
+    if !node_modules.is_dir() {
+        return Err("node_modules not found. Run `rnp install` first.".into());
+    }
+
+    let installed = installed_packages(&node_modules)?;
+    for package in packages {
+        if !installed.contains_key(package) {
+            return Err(format!("{} is not installed", package).into());
+        }
+    }
+
+    // A full rebuild starts `.bin` from scratch; a targeted one only replaces
+    // the links of the packages it rebuilds
+    let bin_dir = node_modules.join(".bin");
+    if packages.is_empty()
+        && let Ok(metadata) = fs::symlink_metadata(&bin_dir)
+    {
+        if metadata.is_dir() {
+            fs::remove_dir_all(&bin_dir)?;
+        } else {
+            fs::remove_file(&bin_dir)?;
+        }
+    }
+
+    let mut rebuilt = 0;
+    for name in dependency_order(&installed) {
+        if !packages.is_empty() && !packages.contains(&name) {
+            continue;
+        }
+        let (dir, info) = &installed[&name];
+        create_bin_links(info, dir, &node_modules)?;
+        run_lifecycle_scripts(info, dir, options)?;
+        rebuilt += 1;
+    }
+
+    if !options.quiet() {
+        println!("rebuilt {} package(s)", rebuilt);
+    }
+    Ok(())
+}
+
+// Every package directly under node_modules (and under `@scope/` dirs)
+fn installed_packages(node_modules: &Path) -> Result<BTreeMap<String, (PathBuf, PackageInfo)>, Box<dyn Error + Send + Sync>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(node_modules)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if name.starts_with('@') {
+            for scoped in fs::read_dir(entry.path())? {
+                let scoped = scoped?;
+                dirs.push((format!("{}/{}", name, scoped.file_name().to_string_lossy()), scoped.path()));
+            }
+        } else {
+            dirs.push((name, entry.path()));
+        }
+    }
+
+    let mut installed = BTreeMap::new();
+    for (name, dir) in dirs {
+        let Ok(data) = fs::read_to_string(dir.join("package.json")) else {
+            continue;
+        };
+        let manifest = manifest::parse(&data)
+            .map_err(|e| format!("{}: invalid package.json: {}", name, e))?;
+        let Some(version) = manifest
+            .get("version")
+            .and_then(|v| v.as_str())
+            .and_then(|v| Version::parse(v).ok())
+        else {
+            continue;
+        };
+        let info = package_info_from_manifest(&name, version, &manifest, String::new());
+        installed.insert(name, (dir, info));
+    }
+    Ok(installed)
+}
+
+// Dependencies before their dependents, so a native addon's build can use
+// what it depends on; cycles are broken wherever they are first entered
+fn dependency_order(installed: &BTreeMap<String, (PathBuf, PackageInfo)>) -> Vec<String> {
+    fn visit(
+        name: &str,
+        installed: &BTreeMap<String, (PathBuf, PackageInfo)>,
+        visited: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        let Some((_, info)) = installed.get(name) else {
+            return;
+        };
+        let mut deps = info
+            .dependencies
+            .keys()
+            .chain(info.optional_dependencies.keys())
+            .collect::<Vec<_>>();
+        deps.sort();
+        for dep in deps {
+            visit(dep, installed, visited, order);
+        }
+        order.push(name.to_string());
+    }
+
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for name in installed.keys() {
+        visit(name, installed, &mut visited, &mut order);
+    }
+    order
+}
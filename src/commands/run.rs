@@ -1,59 +1,419 @@
+use crate::commands::install::active_lockfile_path;
+use crate::commands::verify::verify_installed_tree;
+use crate::config::Config;
+use crate::manifest;
+use crate::output;
+use crate::workspaces::selected_workspace_dirs;
 use serde_json::Value;
 use std::error::Error;
 use std::fs;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+#[cfg(unix)]
+const DEFAULT_SHELL: &str = "sh";
+#[cfg(windows)]
+const DEFAULT_SHELL: &str = "cmd";
+
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub prefix: PathBuf,
+    pub if_present: bool,
+    pub ignore_scripts: bool,
+    // Check node_modules against the lockfile before running
+    pub verify: bool,
+    // Run in this workspace member, or with `workspaces` in every one
+    pub workspace: Option<String>,
+    pub workspaces: bool,
+}
 
 pub fn handle_run_command(
     script_name: &str,
     args: &[String],
+    options: &RunOptions,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if !Path::new("package.json").exists() {
+    let root = if options.prefix.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        options.prefix.as_path()
+    };
+    if !root.join("package.json").exists() {
         return Err("package.json not found. Please run `rnp init` first.".into());
     }
 
-    let data = fs::read_to_string("package.json")?;
-    let package_json: Value = serde_json::from_str(&data)?;
-
-    let scripts = package_json
-        .get("scripts")
-        .and_then(|v| v.as_object())
-        .ok_or("No scripts section found in package.json")?;
+    // The lockfile and node_modules are shared by every workspace member
+    if options.verify {
+        warn_if_stale(root);
+    }
 
-    let script_cmd = scripts
-        .get(script_name)
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| format!("Script '{}' not found in package.json", script_name))?;
+    let dirs = selected_workspace_dirs(root, options.workspace.as_deref(), options.workspaces)?;
+    for dir in dirs {
+        run_in_package(&dir, root, script_name, args, options)?;
+    }
+    Ok(())
+}
 
-    let full_cmd = if args.is_empty() {
-        script_cmd.to_string()
+// Run a script from the package.json in `project_dir`, which is `root` itself
+// or one of its workspace members
+fn run_in_package(
+    project_dir: &Path,
+    root: &Path,
+    script_name: &str,
+    args: &[String],
+    options: &RunOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let manifest_path = project_dir.join("package.json");
+    let data = fs::read_to_string(&manifest_path)?;
+    let package_json = manifest::parse(&data)?;
+    let manifest_name = if project_dir == root {
+        "package.json".to_string()
     } else {
-        format!("{} {}", script_cmd, args.join(" "))
+        manifest_path.display().to_string()
+    };
+
+    let scripts = match package_json.get("scripts") {
+        None | Some(Value::Null) if options.if_present => return Ok(()),
+        None | Some(Value::Null) => return Err(format!("No scripts section found in {}", manifest_name).into()),
+        Some(Value::Object(scripts)) => scripts,
+        Some(_) => return Err(format!("The \"scripts\" field in {} is not an object", manifest_name).into()),
+    };
+
+    let Some(script_value) = scripts.get(script_name) else {
+        if options.if_present {
+            return Ok(());
+        }
+        return Err(format!("Script '{}' not found in {}", script_name, manifest_name).into());
+    };
+    let script_cmd = script_command(script_name, script_value)?;
+    if project_dir != root {
+        println!("In {}:", project_dir.display());
+    }
+
+    let config = Config::load(root);
+    let location = ScriptLocation { project_dir, root };
+
+    // Arguments after `--` reach the script as the words they were given,
+    // spaces and quotes included
+    let shell = config.get("script-shell");
+    let full_cmd = std::iter::once(script_cmd)
+        .chain(args.iter().map(|arg| shell_quote(shell, arg)))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // Like npm, `pre<name>` and `post<name>` run around the script (without
+    // its arguments); --ignore-scripts skips them but still runs the script
+    // that was asked for
+    let hook = |event: String| -> Result<(), Box<dyn Error + Send + Sync>> {
+        if options.ignore_scripts {
+            return Ok(());
+        }
+        match scripts.get(&event) {
+            Some(value) => {
+                let command = script_command(&event, value)?;
+                run_script(&event, &command, &package_json, &location, &config)
+            }
+            None => Ok(()),
+        }
+    };
+
+    hook(format!("pre{}", script_name))?;
+    run_script(script_name, &full_cmd, &package_json, &location, &config)?;
+    hook(format!("post{}", script_name))
+}
+
+// Where a script runs, and the workspace root whose node_modules it shares
+struct ScriptLocation<'a> {
+    project_dir: &'a Path,
+    root: &'a Path,
+}
+
+// `--verify`: a tree that drifted from the lockfile (usually an install
+// forgotten after a pull) is reported before the script fails on it. Only
+// presence and versions are checked, so it stays cheap enough for every run.
+fn warn_if_stale(root: &Path) {
+    let lockfile_path = active_lockfile_path(root);
+    if !lockfile_path.exists() {
+        output::warn(&format!("{} not found; node_modules was not verified", lockfile_path.display()));
+        return;
+    }
+
+    let report = match verify_installed_tree(root, None) {
+        Ok(report) => report,
+        Err(err) => {
+            output::warn(&format!("Could not verify node_modules: {}", err));
+            return;
+        }
     };
+    let problems = report
+        .missing
+        .iter()
+        .map(|package| format!("{} is missing", package))
+        .chain(report.mismatched.iter().cloned())
+        .collect::<Vec<_>>();
+    if problems.is_empty() {
+        return;
+    }
+
+    const SHOWN: usize = 5;
+    let mut summary = problems.iter().take(SHOWN).cloned().collect::<Vec<_>>().join("; ");
+    if problems.len() > SHOWN {
+        summary.push_str(&format!("; and {} more", problems.len() - SHOWN));
+    }
+    output::warn(&format!(
+        "node_modules does not match {} ({}). Run `rnp install` to bring it up to date.",
+        lockfile_path.display(),
+        summary
+    ));
+}
 
+fn run_script(
+    script_name: &str,
+    full_cmd: &str,
+    package_json: &Value,
+    location: &ScriptLocation,
+    config: &Config,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     println!("Running script '{}': {}", script_name, full_cmd);
 
-    #[cfg(unix)]
-    let status = Command::new("sh")
-        .arg("-c")
-        .arg(&full_cmd)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()?;
+    let mut command = shell_command(config.get("script-shell"), full_cmd);
+    command
+        .current_dir(location.project_dir)
+        .env("npm_lifecycle_event", script_name)
+        .env("npm_lifecycle_script", full_cmd)
+        .env("PATH", script_path_env(location)?);
+    if let Some(name) = package_json.get("name").and_then(|v| v.as_str()) {
+        command.env("npm_package_name", name);
+    }
+    if let Some(version) = package_json.get("version").and_then(|v| v.as_str()) {
+        command.env("npm_package_version", version);
+    }
 
-    #[cfg(windows)]
-    let status = Command::new("cmd")
-        .arg("/C")
-        .arg(&full_cmd)
+    let status = command
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .status()?;
 
     if !status.success() {
-        return Err(format!("Script '{}' failed with status {}", script_name, status).into());
+        return Err(Box::new(ScriptFailed {
+            script: script_name.to_string(),
+            status,
+        }));
     }
 
     Ok(())
 }
+
+// A script that exited unsuccessfully. The CLI exits with the script's own
+// code, so `rnp run` nested inside another script propagates it unchanged.
+pub struct ScriptFailed {
+    pub script: String,
+    pub status: ExitStatus,
+}
+
+impl ScriptFailed {
+    // A script killed by a signal reports 128 + the signal number, as shells do
+    pub fn code(&self) -> i32 {
+        #[cfg(unix)]
+        if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&self.status) {
+            return 128 + signal;
+        }
+        self.status.code().unwrap_or(1)
+    }
+}
+
+impl std::fmt::Display for ScriptFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Script '{}' failed with status {}", self.script, self.status)
+    }
+}
+
+// Reads like the string errors everywhere else when the CLI prints it
+impl std::fmt::Debug for ScriptFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl Error for ScriptFailed {}
+
+// `arg` as one word for the script shell: single quotes for sh-likes, see
+// `cmd_quote` for cmd. Plain words are passed through as they are.
+fn shell_quote(shell: Option<&str>, arg: &str) -> String {
+    let cmd = is_cmd(shell);
+    let plain_chars = if cmd { "-_./:@+\\" } else { "-_./:=@%+," };
+    let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || plain_chars.contains(c));
+    if plain {
+        return arg.to_string();
+    }
+    if cmd {
+        cmd_quote(arg)
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// Quoted for the program's argv parser (backslashes before a `"` doubled and
+// the `"` escaped), then every cmd metacharacter caret-escaped, the quotes
+// included. cmd never sees a quoted section, inside which `%VAR%` would still
+// expand and `^` would not escape anything.
+fn cmd_quote(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '"' {
+            quoted.push_str(&"\\".repeat(backslashes + 1));
+        }
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+        quoted.push(c);
+    }
+    quoted.push_str(&"\\".repeat(backslashes));
+    quoted.push('"');
+
+    let mut escaped = String::with_capacity(quoted.len() * 2);
+    for c in quoted.chars() {
+        if "()[]%!^\"<>&|;, *?".contains(c) {
+            escaped.push('^');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn is_cmd(shell: Option<&str>) -> bool {
+    Path::new(shell.unwrap_or(DEFAULT_SHELL))
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"))
+}
+
+// Some tools emit scripts as an array of commands; run those in sequence
+fn script_command(script_name: &str, value: &Value) -> Result<String, Box<dyn Error + Send + Sync>> {
+    match value {
+        Value::String(command) => Ok(command.clone()),
+        Value::Array(parts) => {
+            let commands = parts
+                .iter()
+                .map(|part| {
+                    part.as_str().map(|s| s.to_string()).ok_or_else(|| {
+                        format!("Script '{}' contains a non-string entry", script_name)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(commands.join(" && "))
+        }
+        _ => Err(format!("Script '{}' in package.json is not a string", script_name).into()),
+    }
+}
+
+// Honors the `script-shell` config, falling back to sh / cmd
+fn shell_command(shell: Option<&str>, script: &str) -> Command {
+    let mut command = Command::new(shell.unwrap_or(DEFAULT_SHELL));
+    command.arg(if is_cmd(shell) { "/C" } else { "-c" }).arg(script);
+    command
+}
+
+// Scripts can call binaries from node_modules/.bin directly; in a workspace
+// member that is its own .bin first, then the root's hoisted one
+fn script_path_env(location: &ScriptLocation) -> Result<OsString, Box<dyn Error + Send + Sync>> {
+    let cwd = env::current_dir()?;
+    let mut paths = vec![cwd.join(location.project_dir).join("node_modules").join(".bin")];
+    if location.project_dir != location.root {
+        paths.push(cwd.join(location.root).join("node_modules").join(".bin"));
+    }
+    if let Some(existing) = env::var_os("PATH") {
+        paths.extend(env::split_paths(&existing));
+    }
+    Ok(env::join_paths(paths)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn run_in(manifest: &str, script: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("package.json"), manifest).unwrap();
+        let options = RunOptions {
+            prefix: dir.path().to_path_buf(),
+            if_present: false,
+            ignore_scripts: false,
+            verify: false,
+            workspace: None,
+            workspaces: false,
+        };
+        handle_run_command(script, &[], &options)
+    }
+
+    #[test]
+    fn a_missing_scripts_section_is_reported() {
+        for manifest in [r#"{ "name": "app" }"#, r#"{ "name": "app", "scripts": null }"#] {
+            let err = run_in(manifest, "build").unwrap_err();
+            assert_eq!(err.to_string(), "No scripts section found in package.json");
+        }
+    }
+
+    #[test]
+    fn scripts_that_are_not_an_object_are_reported() {
+        let err = run_in(r#"{ "scripts": ["build"] }"#, "build").unwrap_err();
+        assert_eq!(err.to_string(), "The \"scripts\" field in package.json is not an object");
+    }
+
+    #[test]
+    fn scripts_that_are_not_strings_are_reported() {
+        let err = run_in(r#"{ "scripts": { "build": 42 } }"#, "build").unwrap_err();
+        assert_eq!(err.to_string(), "Script 'build' in package.json is not a string");
+        let err = script_command("build", &json!(["tsc", { "run": "x" }])).unwrap_err();
+        assert_eq!(err.to_string(), "Script 'build' contains a non-string entry");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn arguments_reach_the_script_as_given() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("args.txt");
+        let manifest = json!({ "scripts": { "echo": format!("printf '%s\\n' > '{}'", out.display()) } });
+        fs::write(dir.path().join("package.json"), manifest.to_string()).unwrap();
+        let options = RunOptions {
+            prefix: dir.path().to_path_buf(),
+            if_present: false,
+            ignore_scripts: false,
+            verify: false,
+            workspace: None,
+            workspaces: false,
+        };
+        let args = ["a b", "it's", "$HOME", "*", "", "plain"].map(String::from);
+        handle_run_command("echo", &args, &options).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "a b\nit's\n$HOME\n*\n\nplain\n");
+    }
+
+    #[test]
+    fn cmd_arguments_escape_expansion_and_carets() {
+        let cmd = Some("cmd.exe");
+        assert_eq!(shell_quote(cmd, "plain"), "plain");
+        assert_eq!(shell_quote(cmd, "a b"), "^\"a^ b^\"");
+        assert_eq!(shell_quote(cmd, "%PATH%"), "^\"^%PATH^%^\"");
+        assert_eq!(shell_quote(cmd, "x^y&z"), "^\"x^^y^&z^\"");
+        assert_eq!(shell_quote(cmd, "say \"hi\""), "^\"say^ \\^\"hi\\^\"^\"");
+        assert_eq!(shell_quote(cmd, "dir\\ x\\"), "^\"dir\\^ x\\\\^\"");
+        assert_eq!(shell_quote(Some("sh"), "it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn a_killed_script_exits_with_128_plus_the_signal() {
+        let err = run_in(r#"{ "scripts": { "die": "kill -9 $$" } }"#, "die").unwrap_err();
+        assert_eq!(err.downcast_ref::<ScriptFailed>().unwrap().code(), 137);
+        let err = run_in(r#"{ "scripts": { "fail": "exit 3" } }"#, "fail").unwrap_err();
+        assert_eq!(err.downcast_ref::<ScriptFailed>().unwrap().code(), 3);
+    }
+
+    #[test]
+    fn array_scripts_run_in_sequence() {
+        assert_eq!(script_command("build", &json!(["tsc", "vite build"])).unwrap(), "tsc && vite build");
+        assert_eq!(script_command("build", &json!("tsc")).unwrap(), "tsc");
+    }
+}
@@ -1,71 +1,96 @@
+use crate::commands::install::active_lockfile_path;
+use crate::manifest;
+use crate::workspaces::workspace_members;
 use serde_json::Value;
 use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+const DEPENDENCY_FIELDS: [&str; 4] = [
+    "dependencies",
+    "devDependencies",
+    "peerDependencies",
+    "optionalDependencies",
+];
+
+// `package_dirs` are the packages whose package.json loses the entries: the
+// root, or the workspace members picked with `--workspace`/`--workspaces`.
+// The hoisted copy in the root's node_modules only goes once no other
+// package in the workspace still declares it.
 pub fn handle_uninstall_command(
+    root: &Path,
+    package_dirs: &[PathBuf],
     packages: &[String],
     quiet: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if !Path::new("package.json").exists() {
+    if !root.join("package.json").exists() {
         if !quiet {
             eprintln!("package.json not found. Please run `rnp init` first.");
         }
         return Ok(());
     }
 
-    let package_json_data = fs::read_to_string("package.json")?;
-    let mut package_json: Value = serde_json::from_str(&package_json_data)?;
-
     let mut removed_from_manifest = 0usize;
-    if let Some(root) = package_json.as_object_mut() {
-        for field in [
-            "dependencies",
-            "devDependencies",
-            "peerDependencies",
-            "optionalDependencies",
-        ] {
-            if let Some(Value::Object(dep_map)) = root.get_mut(field) {
-                for package in packages {
-                    if dep_map.remove(package).is_some() {
-                        removed_from_manifest += 1;
-                    }
-                }
+    for dir in package_dirs {
+        removed_from_manifest += remove_from_manifest(&dir.join("package.json"), packages)?;
+    }
+
+    let mut still_declared = Vec::new();
+    for dir in std::iter::once(root.to_path_buf()).chain(workspace_members(root)?.into_iter().map(|m| m.dir)) {
+        let data = fs::read_to_string(dir.join("package.json"))?;
+        let json = manifest::parse(&data)?;
+        for package in packages {
+            let declared = DEPENDENCY_FIELDS
+                .iter()
+                .any(|field| json.get(field).and_then(|deps| deps.get(package)).is_some());
+            if declared && !still_declared.contains(package) {
+                still_declared.push(package.clone());
             }
         }
     }
-
-    fs::write("package.json", serde_json::to_string_pretty(&package_json)?)?;
+    let packages = packages
+        .iter()
+        .filter(|package| !still_declared.contains(package))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !quiet {
+        for package in &still_declared {
+            println!("Keeping {} in node_modules; another workspace package still depends on it.", package);
+        }
+    }
 
     let mut removed_from_node_modules = 0usize;
-    for package in packages {
-        let path = Path::new("node_modules").join(package);
-        if !path.exists() {
-            continue;
-        }
+    for package in &packages {
+        for dir in std::iter::once(&root.to_path_buf()).chain(package_dirs) {
+            let path = dir.join("node_modules").join(package);
+            if !path.exists() {
+                continue;
+            }
 
-        let metadata = fs::symlink_metadata(&path)?;
-        if metadata.file_type().is_symlink() || metadata.is_file() {
-            fs::remove_file(&path)?;
-        } else {
-            fs::remove_dir_all(&path)?;
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.file_type().is_symlink() || metadata.is_file() {
+                fs::remove_file(&path)?;
+            } else {
+                fs::remove_dir_all(&path)?;
+            }
+            removed_from_node_modules += 1;
         }
-        removed_from_node_modules += 1;
     }
 
-    if Path::new("package-lock.json").exists() {
-        let lock_data = fs::read_to_string("package-lock.json")?;
+    let lockfile_path = active_lockfile_path(root);
+    if lockfile_path.exists() {
+        let lock_data = fs::read_to_string(&lockfile_path)?;
         let mut lock_json: Value = serde_json::from_str(&lock_data)?;
         if let Some(packages_obj) = lock_json
             .get_mut("packages")
             .and_then(|v| v.as_object_mut())
         {
-            for package in packages {
+            for package in &packages {
                 packages_obj.remove(package);
             }
         }
         fs::write(
-            "package-lock.json",
+            &lockfile_path,
             serde_json::to_string_pretty(&lock_json)?,
         )?;
     }
@@ -79,3 +104,26 @@ pub fn handle_uninstall_command(
 
     Ok(())
 }
+
+// Drops `packages` from every dependency section of one package.json and
+// returns how many entries went
+fn remove_from_manifest(manifest_path: &Path, packages: &[String]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let package_json_data = fs::read_to_string(manifest_path)?;
+    let mut package_json = manifest::parse_for_edit(&package_json_data, manifest_path)?;
+
+    let mut removed = 0usize;
+    if let Some(root) = package_json.as_object_mut() {
+        for field in DEPENDENCY_FIELDS {
+            if let Some(Value::Object(dep_map)) = root.get_mut(field) {
+                for package in packages {
+                    if dep_map.remove(package).is_some() {
+                        removed += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fs::write(manifest_path, serde_json::to_string_pretty(&package_json)?)?;
+    Ok(removed)
+}
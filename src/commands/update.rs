@@ -1,44 +1,62 @@
-use crate::commands::install::{InstallOptions, handle_install_command_async};
-use serde_json::Value;
+use crate::commands::install::{InstallOptions, handle_update_packages_async};
+use crate::manifest;
+use crate::workspaces::selected_workspace_dirs;
 use std::error::Error;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
+// `workspaces` updates every member found through the root `workspaces`
+// globs; `include_workspace_root` adds the root manifest to the set.
 pub async fn handle_update_command_async(
     packages: Vec<String>,
     options: InstallOptions,
+    workspaces: bool,
+    include_workspace_root: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if !Path::new("package.json").exists() {
-        if !options.quiet {
+    let root = options.prefix.as_path();
+    if !root.join("package.json").exists() {
+        if !options.quiet() {
             eprintln!("package.json not found. Please run `rnp init` first.");
         }
         return Ok(());
     }
 
+    let mut manifests = selected_workspace_dirs(root, options.workspace.as_deref(), workspaces)?
+        .into_iter()
+        .map(|dir| dir.join("package.json"))
+        .collect::<Vec<_>>();
+    if include_workspace_root && !manifests.contains(&root.join("package.json")) {
+        manifests.insert(0, root.join("package.json"));
+    }
+
     let targets = if packages.is_empty() {
-        let manifest_path = workspace_manifest_path(options.workspace.as_deref())?;
-        read_dependencies_from_manifest(&manifest_path)?
+        let mut all = Vec::new();
+        for manifest_path in &manifests {
+            for name in read_dependencies_from_manifest(manifest_path)? {
+                if !all.contains(&name) {
+                    all.push(name);
+                }
+            }
+        }
+        all
     } else {
         packages
     };
 
     if targets.is_empty() {
-        if !options.quiet {
+        if !options.quiet() {
             println!("No dependencies found to update.");
         }
         return Ok(());
     }
 
-    for package in targets {
-        handle_install_command_async(&package, options.clone()).await?;
-    }
-
+    handle_update_packages_async(&targets, &manifests, options).await?;
     Ok(())
 }
 
 fn read_dependencies_from_manifest(path: &Path) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
     let data = fs::read_to_string(path)?;
-    let json: Value = serde_json::from_str(&data)?;
+    let json = manifest::parse(&data)?;
 
     let mut deps = Vec::new();
     if let Some(obj) = json.get("dependencies").and_then(|v| v.as_object()) {
@@ -49,59 +67,3 @@ fn read_dependencies_from_manifest(path: &Path) -> Result<Vec<String>, Box<dyn E
 
     Ok(deps)
 }
-
-fn workspace_manifest_path(workspace_name: Option<&str>) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
-    if workspace_name.is_none() {
-        return Ok(PathBuf::from("package.json"));
-    }
-
-    let workspace_name = workspace_name.unwrap();
-    let root_data = fs::read_to_string("package.json")?;
-    let root_json: Value = serde_json::from_str(&root_data)?;
-    let mut patterns = Vec::new();
-
-    if let Some(workspaces) = root_json.get("workspaces") {
-        if let Some(arr) = workspaces.as_array() {
-            for item in arr {
-                if let Some(pattern) = item.as_str() {
-                    patterns.push(pattern.to_string());
-                }
-            }
-        } else if let Some(arr) = workspaces.get("packages").and_then(|v| v.as_array()) {
-            for item in arr {
-                if let Some(pattern) = item.as_str() {
-                    patterns.push(pattern.to_string());
-                }
-            }
-        }
-    }
-
-    for pattern in patterns {
-        let dirs = if let Some(prefix) = pattern.strip_suffix("/*") {
-            let mut out = Vec::new();
-            for entry in fs::read_dir(prefix)? {
-                let entry = entry?;
-                if entry.path().is_dir() {
-                    out.push(entry.path());
-                }
-            }
-            out
-        } else {
-            vec![PathBuf::from(pattern)]
-        };
-
-        for dir in dirs {
-            let pkg_path = dir.join("package.json");
-            if !pkg_path.exists() {
-                continue;
-            }
-            let data = fs::read_to_string(&pkg_path)?;
-            let json: Value = serde_json::from_str(&data)?;
-            if json.get("name").and_then(|v| v.as_str()) == Some(workspace_name) {
-                return Ok(pkg_path);
-            }
-        }
-    }
-
-    Err(format!("Workspace '{}' not found", workspace_name).into())
-}
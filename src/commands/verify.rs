@@ -0,0 +1,263 @@
+use crate::cache::PackageCache;
+use crate::commands::install::active_lockfile_path;
+use crate::integrity::Integrity;
+use crate::manifest;
+use colored::Colorize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct TreeReport {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub mismatched: Vec<String>,
+    pub extraneous: Vec<String>,
+    pub integrity_failures: Vec<String>,
+}
+
+impl TreeReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty()
+            && self.mismatched.is_empty()
+            && self.extraneous.is_empty()
+            && self.integrity_failures.is_empty()
+    }
+}
+
+pub fn handle_verify_command(root: &Path, cache_dir: &Path, quiet: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let report = verify_installed_tree(root, Some(cache_dir))?;
+
+    if !quiet {
+        for name in &report.missing {
+            println!("{} {}", "missing:".red(), name);
+        }
+        for detail in &report.mismatched {
+            println!("{} {}", "invalid:".red(), detail);
+        }
+        for detail in &report.integrity_failures {
+            println!("{} {}", "integrity:".red(), detail);
+        }
+        for name in &report.extraneous {
+            println!("{} {}", "extraneous:".yellow(), name);
+        }
+    }
+
+    if !report.is_consistent() {
+        return Err(format!(
+            "node_modules does not match {}",
+            active_lockfile_path(root).display()
+        )
+        .into());
+    }
+
+    if !quiet {
+        println!("{}", format!("{} package(s) match the lockfile", report.checked).green());
+    }
+    Ok(())
+}
+
+// Compares node_modules against the lockfile without touching the network.
+// With a cache dir, integrity is re-checked from the tarball cache when a copy
+// is available.
+pub fn verify_installed_tree(
+    root: &Path,
+    cache_dir: Option<&Path>,
+) -> Result<TreeReport, Box<dyn Error + Send + Sync>> {
+    let lockfile_path = active_lockfile_path(root);
+    if !lockfile_path.exists() {
+        return Err(format!("{} not found. Run `rnp install` first.", lockfile_path.display()).into());
+    }
+
+    let lock_data = fs::read_to_string(&lockfile_path)?;
+    let lock_json: Value = serde_json::from_str(&lock_data)?;
+    // Keyed by lockfile path, so nested copies of a package are checked where
+    // they are installed
+    let mut locked = BTreeMap::new();
+    if let Some(packages) = lock_json.get("packages").and_then(|v| v.as_object()) {
+        for (key, info) in packages {
+            let Some(name) = key.rsplit("node_modules/").next().filter(|n| !n.is_empty()) else {
+                continue;
+            };
+            if key.is_empty() {
+                continue;
+            }
+            locked.insert(key.clone(), (name.to_string(), info.clone()));
+        }
+    }
+
+    let cache = cache_dir.and_then(|dir| PackageCache::new(dir).ok());
+    let mut report = TreeReport::default();
+
+    for (path, (name, info)) in &locked {
+        let locked_version = info.get("version").and_then(|v| v.as_str()).unwrap_or_default();
+        let label = display_path(path);
+        let manifest_path = root.join(path).join("package.json");
+        let Ok(data) = fs::read_to_string(&manifest_path) else {
+            report.missing.push(format!("{}@{}", label, locked_version));
+            continue;
+        };
+
+        report.checked += 1;
+        let installed_version = manifest::parse(&data)
+            .ok()
+            .and_then(|json| json.get("version").and_then(|v| v.as_str()).map(|v| v.to_string()))
+            .unwrap_or_default();
+        if installed_version != locked_version {
+            report.mismatched.push(format!(
+                "{}: installed {}, locked {}",
+                label, installed_version, locked_version
+            ));
+        }
+
+        if let (Some(cache), Some(integrity)) = (&cache, info.get("integrity").and_then(|v| v.as_str()))
+            && let Some(checksum) = Integrity::parse(integrity)
+        {
+            let tarball = cache.tarball_path(name, locked_version);
+            if let Ok(bytes) = fs::read(&tarball)
+                && !checksum.matches(&bytes)
+            {
+                report
+                    .integrity_failures
+                    .push(format!("{}@{} does not match {}", label, locked_version, integrity));
+            }
+        }
+    }
+
+    let mut pending = vec!["node_modules".to_string()];
+    while let Some(dir) = pending.pop() {
+        for name in installed_package_names(&root.join(&dir))? {
+            let path = format!("{}/{}", dir, name);
+            let is_link = fs::symlink_metadata(root.join(&path)).is_ok_and(|m| m.file_type().is_symlink());
+            // Below the top level, links are what hoisting leaves inside
+            // packages, not installs of their own
+            if is_link && dir != "node_modules" {
+                continue;
+            }
+            if !locked.contains_key(&path) {
+                report.extraneous.push(display_path(&path).to_string());
+            } else if !is_link {
+                // Linked packages are the user's own source trees
+                pending.push(format!("{}/node_modules", path));
+            }
+        }
+    }
+    report.extraneous.sort();
+
+    Ok(report)
+}
+
+// A lockfile path as reported: `a` for `node_modules/a`, and
+// `a/node_modules/b` for a copy nested inside it
+fn display_path(path: &str) -> &str {
+    path.strip_prefix("node_modules/").unwrap_or(path)
+}
+
+// Package directories in a node_modules folder, expanding `@scope/*`
+fn installed_package_names(node_modules: &Path) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let mut names = Vec::new();
+    let Ok(entries) = fs::read_dir(node_modules) else {
+        return Ok(names);
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        if name.starts_with('@') {
+            for scoped in fs::read_dir(entry.path())? {
+                let scoped = scoped?;
+                names.push(format!("{}/{}", name, scoped.file_name().to_string_lossy()));
+            }
+        } else {
+            names.push(name);
+        }
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn install(root: &Path, path: &str, version: &str) {
+        fs::create_dir_all(root.join(path)).unwrap();
+        let manifest = format!(r#"{{ "version": "{}" }}"#, version);
+        fs::write(root.join(path).join("package.json"), manifest).unwrap();
+    }
+
+    fn lock(root: &Path, packages: &[(&str, &str)]) {
+        let mut entries = serde_json::Map::new();
+        entries.insert(String::new(), serde_json::json!({ "version": "1.0.0" }));
+        for (path, version) in packages {
+            entries.insert(path.to_string(), serde_json::json!({ "version": version }));
+        }
+        let lockfile = serde_json::json!({ "packages": entries });
+        fs::write(root.join("package-lock.json"), lockfile.to_string()).unwrap();
+    }
+
+    #[test]
+    fn a_matching_tree_is_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        lock(dir.path(), &[("node_modules/a", "1.0.0"), ("node_modules/a/node_modules/b", "2.0.0")]);
+        install(dir.path(), "node_modules/a", "1.0.0");
+        install(dir.path(), "node_modules/a/node_modules/b", "2.0.0");
+
+        let report = verify_installed_tree(dir.path(), None).unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+        assert_eq!(report.checked, 2);
+    }
+
+    #[test]
+    fn reports_missing_and_mismatched_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        lock(dir.path(), &[("node_modules/a", "1.0.0"), ("node_modules/b", "1.0.0")]);
+        install(dir.path(), "node_modules/a", "1.1.0");
+
+        let report = verify_installed_tree(dir.path(), None).unwrap();
+        assert_eq!(report.missing, ["b@1.0.0"]);
+        assert_eq!(report.mismatched, ["a: installed 1.1.0, locked 1.0.0"]);
+    }
+
+    #[test]
+    fn nested_packages_are_checked_where_they_are_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        lock(
+            dir.path(),
+            &[
+                ("node_modules/a", "1.0.0"),
+                ("node_modules/b", "1.0.0"),
+                ("node_modules/a/node_modules/b", "2.0.0"),
+                ("node_modules/a/node_modules/c", "1.0.0"),
+            ],
+        );
+        install(dir.path(), "node_modules/a", "1.0.0");
+        install(dir.path(), "node_modules/b", "1.0.0");
+        // The hoisted b@1.0.0 no longer stands in for the nested b@2.0.0
+        install(dir.path(), "node_modules/a/node_modules/b", "1.0.0");
+
+        let report = verify_installed_tree(dir.path(), None).unwrap();
+        assert_eq!(report.missing, ["a/node_modules/c@1.0.0"]);
+        assert_eq!(report.mismatched, ["a/node_modules/b: installed 1.0.0, locked 2.0.0"]);
+    }
+
+    #[test]
+    fn reports_extraneous_packages_at_any_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        lock(dir.path(), &[("node_modules/a", "1.0.0")]);
+        install(dir.path(), "node_modules/a", "1.0.0");
+        install(dir.path(), "node_modules/@scope/extra", "1.0.0");
+        install(dir.path(), "node_modules/a/node_modules/stray", "1.0.0");
+
+        let report = verify_installed_tree(dir.path(), None).unwrap();
+        assert_eq!(report.extraneous, ["@scope/extra", "a/node_modules/stray"]);
+        assert!(report.missing.is_empty() && report.mismatched.is_empty());
+    }
+}
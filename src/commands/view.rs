@@ -0,0 +1,63 @@
+use crate::commands::install::fetch_registry_document;
+use crate::registry::Registries;
+use serde_json::Value;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+pub async fn handle_view_command_async(
+    package: &str,
+    field: Option<&str>,
+    registries: &Registries,
+    cache_dir: &Path,
+    cache_min_time: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let client = reqwest::Client::new();
+    let (document, _) = fetch_registry_document(&client, registries, cache_dir, cache_min_time, package).await?;
+
+    if let Some(error) = document.get("error").and_then(|v| v.as_str()) {
+        return Err(format!("Could not view '{}': {}", package, error).into());
+    }
+
+    let latest = document
+        .get("dist-tags")
+        .and_then(|tags| tags.get("latest"))
+        .and_then(|v| v.as_str())
+        .and_then(|version| document.get("versions").and_then(|v| v.get(version)));
+
+    let Some(field) = field else {
+        let name = document.get("name").and_then(|v| v.as_str()).unwrap_or(package);
+        let version = latest
+            .and_then(|v| v.get("version"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+        println!("{}@{}", name, version);
+        if let Some(description) = document.get("description").and_then(|v| v.as_str()) {
+            println!("{}", description);
+        }
+        return Ok(());
+    };
+
+    // Like npm, look at the latest version first, then the top-level document
+    let value = latest
+        .and_then(|doc| lookup_dotted(doc, field))
+        .or_else(|| lookup_dotted(&document, field))
+        .ok_or_else(|| format!("Field '{}' not found for {}", field, package))?;
+
+    match value {
+        Value::String(text) => println!("{}", text),
+        Value::Object(_) | Value::Array(_) => println!("{}", serde_json::to_string_pretty(value)?),
+        other => println!("{}", other),
+    }
+
+    Ok(())
+}
+
+// Walks `a.b.c`, treating numeric segments as array indexes
+fn lookup_dotted<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |current, segment| match current {
+        Value::Object(map) => map.get(segment),
+        Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
@@ -0,0 +1,191 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const NPMRC_FILE: &str = ".npmrc";
+const RNPRC_FILE: &str = ".rnprc";
+
+// npm-style configuration merged from every source we know about.
+// Lookup order: `npm_config_*` env vars > project .rnprc > project .npmrc > ~/.npmrc.
+// Command-line flags override all of these where a command offers one.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    values: HashMap<String, String>,
+    // Config files that existed and were read, lowest precedence first
+    files: Vec<PathBuf>,
+    // The directory of the config file each key was last set by; keys set
+    // through the environment have none
+    origins: HashMap<String, PathBuf>,
+}
+
+impl Config {
+    pub fn load(root: &Path) -> Self {
+        let mut config = Self::default();
+
+        if let Some(home) = dirs::home_dir() {
+            config.merge_file(&home.join(NPMRC_FILE));
+        }
+        config.merge_file(&root.join(NPMRC_FILE));
+        config.merge_file(&root.join(RNPRC_FILE));
+        for (key, value) in read_env_config() {
+            config.origins.remove(&key);
+            config.values.insert(key, value);
+        }
+
+        config
+    }
+
+    fn merge_file(&mut self, path: &Path) {
+        let Ok(data) = fs::read_to_string(path) else {
+            return;
+        };
+        let values = if path.ends_with(RNPRC_FILE) {
+            parse_rnprc(path, &data)
+        } else {
+            parse_npmrc(&data)
+        };
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        for (key, value) in values {
+            self.origins.insert(key.clone(), dir.clone());
+            self.values.insert(key, value);
+        }
+        self.files.push(path.to_path_buf());
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    // A path-valued key: `~/` is the home directory, and a relative path is
+    // relative to the config file that set it (the cwd for an env var)
+    pub fn path(&self, key: &str) -> Option<PathBuf> {
+        let value = self.get(key).filter(|value| !value.is_empty())?;
+        if let Some((rest, home)) = value.strip_prefix("~/").zip(dirs::home_dir()) {
+            return Some(home.join(rest));
+        }
+        Some(match self.origins.get(key) {
+            Some(dir) => dir.join(value),
+            None => PathBuf::from(value),
+        })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+fn parse_npmrc(data: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        values.insert(key.trim().to_string(), expand_env_vars(value));
+    }
+    values
+}
+
+// .rnprc is either a JSON object (`{ "save-prefix": "~", "ignore-scripts": true }`)
+// or the same key=value format as .npmrc
+fn parse_rnprc(path: &Path, data: &str) -> HashMap<String, String> {
+    if !data.trim_start().starts_with('{') {
+        return parse_npmrc(data);
+    }
+
+    let object = match serde_json::from_str::<Map<String, Value>>(data) {
+        Ok(object) => object,
+        Err(e) => {
+            eprintln!("Ignoring {}: invalid JSON ({})", path.display(), e);
+            return HashMap::new();
+        }
+    };
+    object
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                Value::String(s) => expand_env_vars(&s),
+                Value::Bool(_) | Value::Number(_) => value.to_string(),
+                _ => return None,
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+// `${NPM_TOKEN}` in .npmrc values is replaced from the environment, like npm does
+fn expand_env_vars(value: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        let name = &rest[start + 2..start + end];
+        expanded.push_str(&env::var(name).unwrap_or_default());
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// `npm_config_script_shell=bash` (or `NPM_CONFIG_SCRIPT_SHELL`) maps to the
+// `script-shell` key. Per-registry keys such as
+// `npm_config_//registry.example.com/:_authToken` are kept verbatim.
+fn read_env_config() -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for (name, value) in env::vars() {
+        const PREFIX: &str = "npm_config_";
+        if name.len() <= PREFIX.len() || !name[..PREFIX.len()].eq_ignore_ascii_case(PREFIX) {
+            continue;
+        }
+
+        let key = &name[PREFIX.len()..];
+        if key.starts_with("//") {
+            values.insert(key.to_string(), value);
+        } else {
+            values.insert(key.to_ascii_lowercase().replace('_', "-"), value);
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_follow_the_file_that_set_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        let project = dir.path().join("project");
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(&project).unwrap();
+        fs::write(home.join(NPMRC_FILE), "rnp-cache=.rnp/cache\noffline-dir=mirror\n").unwrap();
+        fs::write(project.join(RNPRC_FILE), r#"{ "offline-dir": "vendor", "signature-keys": "/etc/keys.json" }"#).unwrap();
+
+        let mut config = Config::default();
+        config.merge_file(&home.join(NPMRC_FILE));
+        config.merge_file(&project.join(RNPRC_FILE));
+        assert_eq!(config.path("rnp-cache"), Some(home.join(".rnp/cache")));
+        assert_eq!(config.path("offline-dir"), Some(project.join("vendor")));
+        assert_eq!(config.path("signature-keys"), Some(PathBuf::from("/etc/keys.json")));
+        assert_eq!(config.path("cache"), None);
+
+        fs::write(project.join(NPMRC_FILE), "cache=~/.npm\n").unwrap();
+        config.merge_file(&project.join(NPMRC_FILE));
+        assert_eq!(config.path("cache"), dirs::home_dir().map(|home| home.join(".npm")));
+    }
+}
@@ -0,0 +1,160 @@
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Keeps concurrent clones of one repository out of each other's staging dirs
+static STAGING_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A dependency fetched from a git repository instead of the registry, e.g.
+// `git+ssh://git@github.com/org/repo.git#v1.2.0` or
+// `git+https://github.com/org/repo.git#4f2c9e1`. Without a `#ref` the
+// remote's default branch is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitSpec {
+    // The spec without its `#ref`, as written in package.json
+    pub spec_url: String,
+    // What `git clone` gets: the spec url minus the `git+` prefix
+    pub clone_url: String,
+    pub committish: Option<String>,
+}
+
+impl GitSpec {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        let (url, committish) = match spec.split_once('#') {
+            Some((url, committish)) if !committish.is_empty() => (url, Some(committish.to_string())),
+            Some((url, _)) => (url, None),
+            None => (spec, None),
+        };
+
+        let clone_url = if let Some(rest) = url.strip_prefix("git+") {
+            rest
+        } else if url.starts_with("git://") {
+            url
+        } else {
+            return None;
+        };
+        if !clone_url.contains("://") {
+            return None;
+        }
+        // Either would reach git's command line, where a leading `-` is an
+        // option (`--upload-pack=…` runs a command)
+        if clone_url.starts_with('-') || committish.as_deref().is_some_and(|c| c.starts_with('-')) {
+            return None;
+        }
+
+        Some(Self {
+            spec_url: url.to_string(),
+            clone_url: clone_url.to_string(),
+            committish,
+        })
+    }
+
+    // The spec pinned to `sha`, as recorded in the lockfile's `resolved`
+    pub fn pinned(&self, sha: &str) -> String {
+        format!("{}#{}", self.spec_url, sha)
+    }
+}
+
+fn is_full_commit_sha(value: &str) -> bool {
+    value.len() == 40 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Check `spec` out under `cache_dir`, reusing an earlier checkout of the same
+// full commit. Returns the checkout directory and the commit it is at.
+//
+// Cloning shells out to the system `git`, so ssh:// URLs rely on the user's
+// ssh setup (keys or a running agent); git is told not to prompt.
+pub fn checkout(spec: &GitSpec, cache_dir: &Path) -> Result<(PathBuf, String), Box<dyn Error + Send + Sync>> {
+    let repo_key = {
+        let mut hasher = Sha256::new();
+        hasher.update(spec.clone_url.as_bytes());
+        format!("{:x}", hasher.finalize())[..16].to_string()
+    };
+    fs::create_dir_all(cache_dir)?;
+
+    if let Some(sha) = spec.committish.as_deref().filter(|c| is_full_commit_sha(c)) {
+        let cached = cache_dir.join(format!("{}-{}", repo_key, sha));
+        if cached.join("package.json").exists() {
+            return Ok((cached, sha.to_string()));
+        }
+    }
+
+    let staging = cache_dir.join(format!(
+        "{}-tmp-{}-{}",
+        repo_key,
+        std::process::id(),
+        STAGING_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    let result = clone_at(spec, &staging);
+    let sha = match result {
+        Ok(sha) => sha,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+
+    let target = cache_dir.join(format!("{}-{}", repo_key, sha));
+    if target.exists() {
+        fs::remove_dir_all(&staging)?;
+    } else {
+        fs::rename(&staging, &target)?;
+    }
+    Ok((target, sha))
+}
+
+fn clone_at(spec: &GitSpec, dest: &Path) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let dest_str = dest.to_string_lossy().to_string();
+    run_git(&["clone", "--quiet", "--", &spec.clone_url, &dest_str], None)?;
+    if let Some(committish) = &spec.committish {
+        run_git(&["checkout", "--quiet", committish, "--"], Some(dest))
+            .map_err(|e| format!("{}#{}: {}", spec.spec_url, committish, e))?;
+    }
+    let sha = run_git(&["rev-parse", "HEAD"], Some(dest))?;
+    Ok(sha.trim().to_string())
+}
+
+fn run_git(args: &[&str], dir: Option<&Path>) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut command = Command::new("git");
+    command.args(args).env("GIT_TERMINAL_PROMPT", "0");
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+
+    let output = command
+        .output()
+        .map_err(|e| format!("could not run git (is it installed and on PATH?): {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("git {} failed: {}", args[0], stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_url_and_committish() {
+        let spec = GitSpec::parse("git+https://host/org/repo.git#v1.2.0").unwrap();
+        assert_eq!(spec.spec_url, "git+https://host/org/repo.git");
+        assert_eq!(spec.clone_url, "https://host/org/repo.git");
+        assert_eq!(spec.committish.as_deref(), Some("v1.2.0"));
+        assert_eq!(GitSpec::parse("git://host/repo.git").unwrap().committish, None);
+    }
+
+    #[test]
+    fn rejects_option_like_url_or_committish() {
+        assert_eq!(GitSpec::parse("git+--upload-pack=touch /tmp/x://host/repo.git"), None);
+        assert_eq!(GitSpec::parse("git+https://host/repo.git#--output=/tmp/x"), None);
+    }
+}
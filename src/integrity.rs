@@ -0,0 +1,153 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::fmt;
+
+// Hash algorithms seen in registry metadata, weakest first so the strongest
+// one compares greatest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha384" => Some(Self::Sha384),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha384 => "sha384",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => Sha1::digest(data).to_vec(),
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Sha384 => Sha384::digest(data).to_vec(),
+            Self::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+// One hash to check a tarball against: the strongest of the SRI strings in
+// `dist.integrity` (`sha512-<base64>`, possibly several separated by spaces),
+// or the legacy hex `dist.shasum`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Integrity {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Integrity {
+    // Unknown algorithms and malformed entries are skipped, as SRI requires;
+    // None when nothing usable is left
+    pub fn parse(sri: &str) -> Option<Self> {
+        sri.split_whitespace()
+            .filter_map(|entry| {
+                let (algorithm, rest) = entry.split_once('-')?;
+                // `?opts` may follow the digest
+                let encoded = rest.split('?').next().unwrap_or(rest);
+                Some(Self {
+                    algorithm: Algorithm::parse(algorithm)?,
+                    digest: STANDARD.decode(encoded).ok()?,
+                })
+            })
+            .max_by_key(|integrity| integrity.algorithm)
+    }
+
+    pub fn from_shasum(hex: &str) -> Option<Self> {
+        let hex = hex.trim();
+        if hex.len() != 40 {
+            return None;
+        }
+        let digest = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            algorithm: Algorithm::Sha1,
+            digest,
+        })
+    }
+
+    // Whichever of `integrity` and `shasum` offers the stronger algorithm
+    pub fn strongest(integrity: Option<&str>, shasum: Option<&str>) -> Option<Self> {
+        let sri = integrity.and_then(Self::parse);
+        let legacy = shasum.and_then(Self::from_shasum);
+        match (sri, legacy) {
+            (Some(sri), Some(legacy)) if legacy.algorithm > sri.algorithm => Some(legacy),
+            (Some(sri), _) => Some(sri),
+            (None, legacy) => legacy,
+        }
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.algorithm.digest(data) == self.digest
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.algorithm.name(), STANDARD.encode(&self.digest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"tarball bytes";
+
+    fn sri(algorithm: Algorithm, data: &[u8]) -> String {
+        format!("{}-{}", algorithm.name(), STANDARD.encode(algorithm.digest(data)))
+    }
+
+    #[test]
+    fn checks_the_strongest_of_several_hashes() {
+        let several = format!("{} {}", sri(Algorithm::Sha1, DATA), sri(Algorithm::Sha512, DATA));
+        let integrity = Integrity::parse(&several).unwrap();
+        assert_eq!(integrity.algorithm, Algorithm::Sha512);
+        assert!(integrity.matches(DATA));
+
+        // A correct weak hash doesn't vouch for bytes the strong one rejects
+        let mixed = format!("{} {}", sri(Algorithm::Sha1, DATA), sri(Algorithm::Sha384, b"other bytes"));
+        assert!(!Integrity::parse(&mixed).unwrap().matches(DATA));
+    }
+
+    #[test]
+    fn skips_unknown_and_malformed_entries() {
+        let entries = format!("md5-AAAA sha512-!!! {}?foo", sri(Algorithm::Sha256, DATA));
+        let integrity = Integrity::parse(&entries).unwrap();
+        assert_eq!(integrity.algorithm, Algorithm::Sha256);
+        assert!(integrity.matches(DATA));
+        assert_eq!(Integrity::parse("md5-AAAA"), None);
+        assert_eq!(integrity.to_string(), sri(Algorithm::Sha256, DATA));
+    }
+
+    #[test]
+    fn falls_back_to_the_legacy_shasum() {
+        let hex = Sha1::digest(DATA).iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        let legacy = Integrity::strongest(None, Some(&hex)).unwrap();
+        assert_eq!(legacy.algorithm, Algorithm::Sha1);
+        assert!(legacy.matches(DATA));
+        assert_eq!(Integrity::from_shasum("abc"), None);
+
+        let sri = sri(Algorithm::Sha512, DATA);
+        let strongest = Integrity::strongest(Some(&sri), Some(&hex)).unwrap();
+        assert_eq!(strongest.algorithm, Algorithm::Sha512);
+    }
+}
@@ -0,0 +1,67 @@
+// rnp as a library: the same resolver and installer the CLI uses, returning
+// structured results instead of printing.
+//
+//     let options = rnp::InstallOptions { prefix: "my-app".into(), ..Default::default() };
+//     let mut tree = rnp::resolve("react@^18", &options).await?;
+//     let report = rnp::install(&mut tree, &options).await?;
+
+pub mod cache;
+pub mod commands;
+pub mod config;
+pub mod git;
+pub mod integrity;
+pub mod manifest;
+pub mod output;
+pub mod packlist;
+pub mod registry;
+pub mod signatures;
+pub mod throttle;
+pub mod workspaces;
+
+#[cfg(test)]
+mod test_registry;
+
+use std::error::Error;
+
+pub use commands::install::{
+    Conflict, Deprecation, Diagnostics, InstallEvent, InstallOptions, InstallReport, NameCaseMismatch, NpmVersionReq,
+    PackageInfo, PackageTiming, PeerIssue, ResolveRequest, ResolvedPackage, ResolvedTree, SkippedOptional,
+    VersionChange,
+};
+pub use output::LogLevel;
+
+// Resolve `spec` (`name`, `name@range`, `@scope/name@range`, `name@git+ssh://…#ref`)
+// and everything it needs
+pub async fn resolve(spec: &str, options: &InstallOptions) -> Result<ResolvedTree, Box<dyn Error + Send + Sync>> {
+    let (name, range) = split_spec(spec);
+    let req = NpmVersionReq::parse(range)?;
+    commands::install::resolve_tree(&[(name.to_string(), req)], &silent(options)).await
+}
+
+// Install a tree from `resolve` into `options.prefix`/node_modules. package.json
+// and the lockfile are left alone.
+pub async fn install(
+    tree: &mut ResolvedTree,
+    options: &InstallOptions,
+) -> Result<InstallReport, Box<dyn Error + Send + Sync>> {
+    commands::install::install_tree(tree, &silent(options)).await
+}
+
+// The first `@` after a scope separates the name; git ranges such as
+// `git+ssh://git@host/repo.git` contain `@`s of their own
+pub fn split_spec(spec: &str) -> (&str, &str) {
+    match spec.get(1..).and_then(|rest| rest.find('@')) {
+        Some(index) => (&spec[..index + 1], &spec[index + 2..]),
+        None => (spec, "*"),
+    }
+}
+
+// Library calls never write progress output
+fn silent(options: &InstallOptions) -> InstallOptions {
+    InstallOptions {
+        log_level: LogLevel::Silent,
+        json: false,
+        json_stream: false,
+        ..options.clone()
+    }
+}
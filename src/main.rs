@@ -1,20 +1,98 @@
 use clap::{Parser, Subcommand};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 
-mod cache;
-mod commands;
-use commands::{
-    audit::handle_audit_command_async,
+use rnp::config::Config;
+use rnp::cache::{DEFAULT_CACHE_MIN_TIME, DEFAULT_MAX_SIZE, PackageCache};
+use rnp::manifest;
+use rnp::output::{self, LogLevel};
+use rnp::registry::{self, Registries};
+use rnp::signatures::{SigningKey, load_keys_file};
+use rnp::workspaces::{selected_workspace_dirs, workspace_members};
+use rnp::commands::{
+    audit::{AuditOptions, AuditReporter, DEFAULT_AUDIT_CONCURRENCY, handle_audit_command_async},
+    cache::{handle_cache_add_command_async, handle_cache_clean_command, handle_cache_ls_command, parse_size},
     init::handle_init,
-    install::{InstallOptions, handle_ci_command_async, handle_install_command_async},
-    run::handle_run_command,
+    print_config::handle_print_config_command,
+    rebuild::handle_rebuild_command,
+    ls::{LsOptions, handle_ls_command},
+    outdated::{OutdatedOptions, handle_outdated_command_async},
+    pack::handle_pack_command,
+    prune::handle_prune_command,
+    install::{
+        DEFAULT_RESOLVE_TIMEOUT, InstallEvent, InstallOptions, NODE_CPUS, NODE_PLATFORMS, handle_ci_command_async, handle_install_batch_async, handle_install_command_async,
+        handle_install_from_manifest_async, handle_shrinkwrap_command_async, omitted_dependency_types, read_spec_list,
+    },
+    run::{RunOptions, ScriptFailed, handle_run_command},
     uninstall::handle_uninstall_command,
     update::handle_update_command_async,
+    verify::handle_verify_command,
+    view::handle_view_command_async,
 };
 
 #[derive(Parser)]
 #[command(name = "rnp")]
 #[command(about = "Rust Node Package CLI", long_about = None)]
 struct Cli {
+    /// Operate on the project in this directory instead of the current one
+    #[arg(long, global = true)]
+    prefix: Option<PathBuf>,
+    /// Disable colored output and emoji (also honors NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+    /// Registry to use instead of the configured one
+    #[arg(long, global = true)]
+    registry: Option<String>,
+    /// Mirror to retry against when the registry fails with a 5xx or times out (repeatable)
+    #[arg(long, global = true)]
+    registry_fallback: Vec<String>,
+    /// How much to print: silent, error, warn, info or verbose (`-q` means error, `-v` verbose)
+    #[arg(long, global = true, value_parser = LogLevel::parse)]
+    loglevel: Option<LogLevel>,
+    /// Print nothing, not even errors; same as --loglevel silent
+    #[arg(long, global = true, conflicts_with = "loglevel")]
+    silent: bool,
+    /// Cache directory (default ~/.rnp/cache; also RNP_CACHE_DIR, or `rnp-cache=`/`cache=` in .npmrc)
+    #[arg(long, global = true)]
+    cache: Option<PathBuf>,
+    /// Seconds cached registry metadata is used before asking the registry again (default 300)
+    #[arg(long, global = true)]
+    cache_min_time: Option<u64>,
+    /// Don't run lifecycle scripts of packages, nor the pre/post hooks of `rnp run`
+    #[arg(long, global = true)]
+    ignore_scripts: bool,
+    /// Copy `file:`/`link:` directory dependencies into node_modules instead of symlinking them
+    #[arg(long, global = true)]
+    install_links: bool,
+    /// Nest every dependency inside the package that needs it instead of hoisting (npm 2 layout)
+    #[arg(long, global = true)]
+    legacy_bundling: bool,
+    /// Install for this os instead of the current one when checking packages' `os` fields (e.g. linux, darwin, win32)
+    #[arg(long, global = true, value_parser = NODE_PLATFORMS)]
+    os: Option<String>,
+    /// Install for this cpu instead of the current one when checking packages' `cpu` fields (e.g. x64, arm64)
+    #[arg(long, global = true, value_parser = NODE_CPUS)]
+    cpu: Option<String>,
+    /// Bulk advisory endpoint for audits (a registry URL; default https://registry.npmjs.org)
+    #[arg(long, global = true)]
+    audit_registry: Option<String>,
+    /// Limit tarball downloads to this many bytes per second in total (e.g. 500k, 2m)
+    #[arg(long, global = true, value_parser = parse_size)]
+    max_rate: Option<u64>,
+    /// Give up on resolving the dependency tree after this many seconds (default 300; 0 = no limit)
+    #[arg(long, global = true)]
+    resolve_timeout: Option<u64>,
+    /// Reject comments and trailing commas in package.json instead of tolerating them
+    #[arg(long, global = true)]
+    strict_json: bool,
+    /// Run `install`, `update`, `ci`, `run` or `uninstall` in this workspace member
+    #[arg(short = 'w', long, global = true)]
+    workspace: Option<String>,
+    /// Run `install`, `update`, `run` or `uninstall` in every workspace member
+    #[arg(long, global = true, conflicts_with = "workspace")]
+    workspaces: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -27,19 +105,75 @@ enum Commands {
     },
     #[command(visible_alias = "i")]
     Install {
-        #[arg(long)]
+        #[arg(long, overrides_with = "package_lock")]
         no_package_lock: bool,
+        #[arg(long, overrides_with = "no_package_lock")]
+        package_lock: bool,
         #[arg(long)]
-        ignore_scripts: bool,
-        #[arg(short = 'w', long)]
-        workspace: Option<String>,
+        no_save: bool,
+        #[arg(short, long)]
+        force: bool,
         #[arg(long, default_value = "safe", value_parser = ["none", "safe", "aggressive"])]
         hoist: String,
+        #[arg(long)]
+        offline_dir: Option<PathBuf>,
         #[arg(short, long)]
         verbose: bool,
         #[arg(short, long)]
         quiet: bool,
-        #[arg(required = true, num_args = 1..)]
+        #[arg(long, value_parser = ["dev", "optional", "peer"])]
+        omit: Vec<String>,
+        #[arg(long, value_parser = ["dev", "optional", "peer"])]
+        include: Vec<String>,
+        #[arg(long, overrides_with = "no_audit")]
+        audit: bool,
+        #[arg(long)]
+        no_audit: bool,
+        #[arg(long, overrides_with = "no_fund")]
+        fund: bool,
+        #[arg(long)]
+        no_fund: bool,
+        #[arg(long, value_parser = ["low", "moderate", "high", "critical"])]
+        audit_level: Option<String>,
+        #[arg(long)]
+        production: bool,
+        #[arg(long)]
+        no_optional: bool,
+        #[arg(long)]
+        engine_strict: bool,
+        #[arg(long)]
+        json: bool,
+        #[arg(long, conflicts_with = "json")]
+        json_stream: bool,
+        #[arg(long)]
+        legacy_peer_deps: bool,
+        #[arg(short = 'D', long, conflicts_with_all = ["save_peer", "save_optional"])]
+        save_dev: bool,
+        #[arg(long, conflicts_with = "save_optional")]
+        save_peer: bool,
+        #[arg(long)]
+        save_optional: bool,
+        #[arg(short = 'B', long, conflicts_with = "no_save")]
+        save_bundle: bool,
+        #[arg(long)]
+        tag: Option<String>,
+        #[arg(long, value_parser = before_date)]
+        before: Option<String>,
+        #[arg(long, conflicts_with = "tag")]
+        latest: bool,
+        #[arg(long)]
+        strict: bool,
+        #[arg(long, conflicts_with = "strict")]
+        allow_conflicts: bool,
+        #[arg(long, value_name = "FILE", conflicts_with = "tag")]
+        from: Option<PathBuf>,
+        #[arg(long)]
+        report: bool,
+        #[arg(long)]
+        dedupe_on_install: bool,
+        #[arg(long)]
+        verify_signatures: bool,
+        #[arg(num_args = 0..)]
         packages: Vec<String>,
     },
     Uninstall {
@@ -49,119 +183,740 @@ enum Commands {
         packages: Vec<String>,
     },
     Update {
-        #[arg(long)]
+        #[arg(long, overrides_with = "package_lock")]
         no_package_lock: bool,
-        #[arg(long)]
-        ignore_scripts: bool,
-        #[arg(short = 'w', long)]
-        workspace: Option<String>,
+        #[arg(long, overrides_with = "no_package_lock")]
+        package_lock: bool,
         #[arg(long, default_value = "safe", value_parser = ["none", "safe", "aggressive"])]
         hoist: String,
+        #[arg(long)]
+        offline_dir: Option<PathBuf>,
         #[arg(short, long)]
         verbose: bool,
         #[arg(short, long)]
         quiet: bool,
+        #[arg(long)]
+        include_workspace_root: bool,
+        #[arg(long, value_parser = before_date)]
+        before: Option<String>,
+        #[arg(long)]
+        json: bool,
+        #[arg(long)]
+        verify_signatures: bool,
         #[arg(num_args = 0..)]
         packages: Vec<String>,
     },
     Ci {
         #[arg(long)]
-        ignore_scripts: bool,
-        #[arg(short = 'w', long)]
-        workspace: Option<String>,
+        no_package_lock: bool,
         #[arg(long, default_value = "safe", value_parser = ["none", "safe", "aggressive"])]
         hoist: String,
+        #[arg(long)]
+        offline_dir: Option<PathBuf>,
         #[arg(short, long)]
         verbose: bool,
         #[arg(short, long)]
         quiet: bool,
+        #[arg(long, value_parser = ["dev", "optional", "peer"])]
+        omit: Vec<String>,
+        #[arg(long, value_parser = ["dev", "optional", "peer"])]
+        include: Vec<String>,
     },
     Run {
+        #[arg(long)]
+        if_present: bool,
+        // Warn when node_modules doesn't match the lockfile
+        #[arg(long)]
+        verify: bool,
         script: String,
         #[arg(trailing_var_arg = true)]
         args: Vec<String>,
     },
-    Audit,
-    // List,
+    Audit {
+        #[arg(long, value_parser = ["low", "moderate", "high", "critical"])]
+        audit_level: Option<String>,
+        #[arg(long)]
+        max_concurrency: Option<usize>,
+        #[arg(long)]
+        depth: Option<usize>,
+        #[arg(long, default_value = "human", value_parser = AuditReporter::parse)]
+        reporter: AuditReporter,
+    },
+    Verify {
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    View {
+        package: String,
+        field: Option<String>,
+    },
+    Outdated {
+        #[arg(long, default_value_t = 0)]
+        depth: usize,
+        #[arg(long)]
+        json: bool,
+    },
+    Shrinkwrap {
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    Rebuild {
+        #[arg(num_args = 0..)]
+        packages: Vec<String>,
+    },
+    // Dump the merged configuration as JSON (tokens redacted) for bug reports
+    #[command(hide = true)]
+    PrintConfig,
+    Prune {
+        #[arg(long)]
+        production: bool,
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    Pack {
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    #[command(visible_alias = "list")]
+    Ls {
+        #[arg(long)]
+        prod: bool,
+        #[arg(long)]
+        dev: bool,
+        #[arg(long)]
+        optional: bool,
+        #[arg(long)]
+        peer: bool,
+        #[arg(long)]
+        all: bool,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    Ls,
+    Add {
+        spec: String,
+        // Also cache every package in its dependency tree
+        #[arg(long)]
+        deps: bool,
+    },
+    Clean {
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
+    output::init(cli.no_color);
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if output::log_level() >= LogLevel::Error {
+                eprintln!("Error: {:?}", e);
+            }
+            // A failing script's own exit code, so `rnp run` chains cleanly
+            match e.downcast_ref::<ScriptFailed>() {
+                Some(failed) => ExitCode::from(u8::try_from(failed.code()).unwrap_or(1)),
+                None => ExitCode::FAILURE,
+            }
+        },
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let prefix = cli.prefix.unwrap_or_default();
+    let config = Config::load(&prefix);
+    let registries = Registries::from_config(&config, cli.registry.as_deref(), &cli.registry_fallback);
+    let explicit_level = cli.loglevel.or(cli.silent.then_some(LogLevel::Silent));
+    let base_level = log_level(explicit_level, &config, false, false);
+    output::set_log_level(base_level);
+    manifest::set_strict(cli.strict_json || config_flag(&config, "strict-json"));
+    let cache_dir = cache_dir(cli.cache, &config);
+    let cache_min_time = cli
+        .cache_min_time
+        .or_else(|| config.get("cache-min-time").and_then(|v| v.parse().ok()))
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CACHE_MIN_TIME);
+    let workspace = cli.workspace;
+    let workspaces = cli.workspaces;
+    let ignore_scripts = cli.ignore_scripts || config_flag(&config, "ignore-scripts");
+    let audit_registry = cli.audit_registry.or_else(|| config.get("audit-registry").map(|v| v.to_string()));
+    let install_links = cli.install_links || config_flag(&config, "install-links");
+    let legacy_bundling = cli.legacy_bundling || config_flag(&config, "legacy-bundling");
+    let os = config_platform(cli.os, &config, "os", &NODE_PLATFORMS)?;
+    let cpu = config_platform(cli.cpu, &config, "cpu", &NODE_CPUS)?;
+    // `audit=true` turns the post-install audit on, `fund=false` the funding notice off
+    let config_audit = config_flag(&config, "audit");
+    let config_fund = config.get("fund") != Some("false");
+    // `package-lock=false`: never read or write package-lock.json
+    let config_no_package_lock = config.get("package-lock") == Some("false");
+    let max_rate = cli.max_rate.or_else(|| config.get("max-rate").and_then(|v| parse_size(v).ok()));
+    let resolve_timeout = match cli
+        .resolve_timeout
+        .or_else(|| config.get("resolve-timeout").and_then(|v| v.parse().ok()))
+    {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(DEFAULT_RESOLVE_TIMEOUT),
+    };
 
     match cli.command {
         Commands::Init { yes } => {
-            handle_init(yes);
+            handle_init(&prefix, yes);
             Ok(())
         },
         Commands::Install {
             packages,
             no_package_lock,
-            ignore_scripts,
-            workspace,
+            package_lock,
+            no_save,
+            force,
             hoist,
+            offline_dir,
             verbose,
             quiet,
+            omit,
+            include,
+            audit,
+            no_audit,
+            fund,
+            no_fund,
+            audit_level,
+            production,
+            no_optional,
+            engine_strict,
+            json,
+            json_stream,
+            legacy_peer_deps,
+            save_dev,
+            save_peer,
+            save_optional,
+            save_bundle,
+            tag,
+            before,
+            latest,
+            strict,
+            allow_conflicts,
+            from,
+            report,
+            dedupe_on_install,
+            verify_signatures,
         } => {
+            let omit = omitted_dependency_types(&omit_with_aliases(omit, production, no_optional), &include);
+            let save_section = if save_dev {
+                "devDependencies"
+            } else if save_peer {
+                "peerDependencies"
+            } else if save_optional {
+                "optionalDependencies"
+            } else {
+                "dependencies"
+            };
+
+            // --json keeps stdout for the report (--json-stream for its events);
+            // warnings still go to stderr
+            let mut level = log_level(explicit_level, &config, quiet, verbose);
+            if json || json_stream {
+                level = level.min(LogLevel::Warn);
+            }
+            output::set_log_level(level);
+            let verify_signatures = verify_signatures || config_flag(&config, "verify-signatures");
+            let signature_keys = config_signature_keys(&config, verify_signatures)?;
+
             let options = InstallOptions {
-                no_package_lock,
-                verbose,
-                quiet,
+                no_package_lock: (no_package_lock || config_no_package_lock) && !package_lock,
+                no_save,
+                refresh_lockfile: false,
+                force,
+                log_level: level,
                 ignore_scripts,
                 workspace,
                 hoist_strategy: hoist,
+                prefix: prefix.clone(),
+                omit,
+                audit: install_audit(audit || config_audit, no_audit, audit_level.as_deref())?,
+                audit_level: audit_level.or_else(|| config_audit_level(&config)),
+                fund: (fund || config_fund) && !no_fund,
+                offline_dir: offline_dir.or_else(|| config_offline_dir(&config)),
+                engine_strict: engine_strict || config_flag(&config, "engine-strict"),
+                registries: registries.clone(),
+                json,
+                json_stream,
+                legacy_peer_deps: legacy_peer_deps || config_flag(&config, "legacy-peer-deps"),
+                cache_max_size: config_cache_max_size(&config),
+                cache_min_time,
+                save_section: save_section.to_string(),
+                save_prefix: config_save_prefix(&config),
+                save_bundle,
+                tag,
+                before,
+                cache_dir: cache_dir.clone(),
+                latest,
+                max_rate,
+                audit_registry: audit_registry.clone(),
+                install_links,
+                legacy_bundling,
+                report,
+                resolve_timeout,
+                dedupe_on_install,
+                verify_signatures,
+                signature_keys,
+                os: os.clone(),
+                cpu: cpu.clone(),
             };
 
-            for package in packages {
-                handle_install_command_async(&package, options.clone()).await?;
-            }
+            // With --json-stream a failure is the stream's last event
+            let events = options.clone();
+            let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+                // --workspaces repeats the install for every member
+                let targets = if workspaces {
+                    let members = workspace_members(&prefix)?;
+                    if members.is_empty() {
+                        return Err("No workspaces found in package.json".into());
+                    }
+                    members.into_iter().map(|member| Some(member.name)).collect()
+                } else {
+                    vec![options.workspace.clone()]
+                };
 
-            Ok(())
+                let mut reports = Vec::new();
+                for target in targets {
+                    let options = InstallOptions { workspace: target, ..options.clone() };
+                    if let Some(file) = &from {
+                        // The list and any packages named alongside it go in one resolve
+                        let mut specs = read_spec_list(file)?;
+                        specs.extend(packages.iter().cloned());
+                        let report = handle_install_batch_async(&specs, options).await?;
+                        if !report.failed_specs.is_empty() {
+                            return Err(format!("{} of {} package(s) could not be installed", report.failed_specs.len(), specs.len()).into());
+                        }
+                        reports.push(report);
+                    } else if packages.is_empty() {
+                        reports.push(handle_install_from_manifest_async(options).await?);
+                    } else {
+                        for package in &packages {
+                            reports.push(handle_install_command_async(package, options.clone()).await?);
+                        }
+                    }
+                }
+
+                // Conflicts are only reported by default; --strict (and --json,
+                // which scripts consume) turn them into a failing exit code
+                let conflicts = reports.iter().map(|report| report.conflicts.len()).sum::<usize>();
+                if conflicts > 0 && (strict || (json && !allow_conflicts)) {
+                    return Err(format!("{} dependency conflict(s) found", conflicts).into());
+                }
+                // So do names whose case differs from the registry's
+                let mismatches = reports
+                    .iter()
+                    .flat_map(|report| &report.diagnostics.name_case_mismatches)
+                    .map(|mismatch| mismatch.to_string())
+                    .collect::<Vec<_>>();
+                if !mismatches.is_empty() && strict {
+                    return Err(format!("package name case mismatch: {}", mismatches.join("; ")).into());
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(err) = &result {
+                events.emit(InstallEvent::Error { message: err.to_string() });
+            }
+            result
         },
         Commands::Uninstall { quiet, packages } => {
-            handle_uninstall_command(&packages, quiet)
+            let package_dirs = selected_workspace_dirs(&prefix, workspace.as_deref(), workspaces)?;
+            handle_uninstall_command(&prefix, &package_dirs, &packages, quiet || base_level < LogLevel::Info)
         },
         Commands::Update {
             no_package_lock,
-            ignore_scripts,
-            workspace,
+            package_lock,
             hoist,
+            offline_dir,
             verbose,
             quiet,
+            include_workspace_root,
+            before,
+            json,
+            verify_signatures,
             packages,
         } => {
+            let mut command_level = log_level(explicit_level, &config, quiet, verbose);
+            if json {
+                command_level = command_level.min(LogLevel::Warn);
+            }
+            output::set_log_level(command_level);
+            let verify_signatures = verify_signatures || config_flag(&config, "verify-signatures");
+            let signature_keys = config_signature_keys(&config, verify_signatures)?;
             let options = InstallOptions {
-                no_package_lock,
-                verbose,
-                quiet,
+                no_package_lock: (no_package_lock || config_no_package_lock) && !package_lock,
+                no_save: false,
+                refresh_lockfile: true,
+                force: false,
+                log_level: command_level,
                 ignore_scripts,
                 workspace,
                 hoist_strategy: hoist,
+                prefix: prefix.clone(),
+                omit: HashSet::new(),
+                audit: config_audit,
+                audit_level: config_audit_level(&config),
+                fund: config_fund,
+                offline_dir: offline_dir.or_else(|| config_offline_dir(&config)),
+                engine_strict: config_flag(&config, "engine-strict"),
+                registries: registries.clone(),
+                json,
+                json_stream: false,
+                legacy_peer_deps: config_flag(&config, "legacy-peer-deps"),
+                cache_max_size: config_cache_max_size(&config),
+                cache_min_time,
+                save_section: "dependencies".to_string(),
+                save_prefix: config_save_prefix(&config),
+                save_bundle: false,
+                tag: None,
+                before,
+                cache_dir: cache_dir.clone(),
+                latest: false,
+                max_rate,
+                audit_registry: audit_registry.clone(),
+                install_links,
+                legacy_bundling,
+                report: false,
+                resolve_timeout,
+                dedupe_on_install: false,
+                verify_signatures,
+                signature_keys,
+                os: os.clone(),
+                cpu: cpu.clone(),
             };
-            handle_update_command_async(packages, options).await
+            handle_update_command_async(packages, options, workspaces, include_workspace_root).await
         },
         Commands::Ci {
-            ignore_scripts,
-            workspace,
+            no_package_lock,
             hoist,
+            offline_dir,
             verbose,
             quiet,
+            omit,
+            include,
         } => {
+            // One lockfile pins the whole workspace; there is nothing per member to repeat
+            if workspaces {
+                return Err("`rnp ci` installs the whole workspace from its lockfile; --workspaces is not supported".into());
+            }
+            let command_level = log_level(explicit_level, &config, quiet, verbose);
+            output::set_log_level(command_level);
             let options = InstallOptions {
-                no_package_lock: false,
-                verbose,
-                quiet,
+                no_package_lock: no_package_lock || config_no_package_lock,
+                no_save: false,
+                refresh_lockfile: false,
+                force: false,
+                log_level: command_level,
                 ignore_scripts,
                 workspace,
                 hoist_strategy: hoist,
+                prefix: prefix.clone(),
+                omit: omitted_dependency_types(&omit, &include),
+                audit: config_audit,
+                audit_level: config_audit_level(&config),
+                fund: config_fund,
+                offline_dir: offline_dir.or_else(|| config_offline_dir(&config)),
+                engine_strict: config_flag(&config, "engine-strict"),
+                registries: registries.clone(),
+                json: false,
+                json_stream: false,
+                legacy_peer_deps: config_flag(&config, "legacy-peer-deps"),
+                cache_max_size: config_cache_max_size(&config),
+                cache_min_time,
+                save_section: "dependencies".to_string(),
+                save_prefix: config_save_prefix(&config),
+                save_bundle: false,
+                tag: None,
+                before: None,
+                cache_dir: cache_dir.clone(),
+                latest: false,
+                max_rate,
+                audit_registry: audit_registry.clone(),
+                install_links,
+                legacy_bundling,
+                report: false,
+                resolve_timeout,
+                dedupe_on_install: false,
+                verify_signatures: false,
+                signature_keys: Vec::new(),
+                os,
+                cpu,
             };
             handle_ci_command_async(options).await
         },
-        Commands::Run { script, args } => {
-            handle_run_command(&script, &args)
+        Commands::Run { if_present, verify, script, args } => {
+            let options = RunOptions {
+                prefix: prefix.clone(),
+                if_present,
+                ignore_scripts,
+                verify: verify || config_flag(&config, "verify-deps-before-run"),
+                workspace,
+                workspaces,
+            };
+            handle_run_command(&script, &args, &options)
         },
-        Commands::Audit => {
-            handle_audit_command_async().await
+        Commands::Audit { audit_level, max_concurrency, depth, reporter } => {
+            let options = AuditOptions {
+                prefix: prefix.clone(),
+                audit_level: audit_level.or_else(|| config_audit_level(&config)),
+                max_concurrency: max_concurrency
+                    .or_else(|| config.get("max-concurrency").and_then(|v| v.parse().ok()))
+                    .unwrap_or(DEFAULT_AUDIT_CONCURRENCY),
+                depth,
+                registries: registries.clone(),
+                audit_registry,
+                reporter,
+            };
+            handle_audit_command_async(&options).await
+        },
+        Commands::Verify { quiet } => {
+            handle_verify_command(&prefix, &cache_dir, quiet || base_level < LogLevel::Info)
+        },
+        Commands::View { package, field } => {
+            handle_view_command_async(&package, field.as_deref(), &registries, &cache_dir, cache_min_time).await
+        },
+        Commands::Outdated { depth, json } => {
+            let options = OutdatedOptions {
+                prefix: prefix.clone(),
+                depth,
+                json,
+                registries,
+                cache_dir,
+                cache_min_time,
+            };
+            handle_outdated_command_async(&options).await
         },
+        Commands::Shrinkwrap { quiet } => {
+            let options = InstallOptions {
+                log_level: if quiet { base_level.min(LogLevel::Error) } else { base_level },
+                prefix: prefix.clone(),
+                registries,
+                cache_dir,
+                cache_max_size: config_cache_max_size(&config),
+                cache_min_time,
+                legacy_peer_deps: config_flag(&config, "legacy-peer-deps"),
+                ..InstallOptions::default()
+            };
+            handle_shrinkwrap_command_async(&options).await
+        },
+        Commands::Cache { command } => match command {
+            CacheCommands::Ls => handle_cache_ls_command(&cache_dir),
+            CacheCommands::Add { spec, deps } => {
+                let options = InstallOptions {
+                    log_level: base_level,
+                    prefix: prefix.clone(),
+                    registries: registries.clone(),
+                    cache_dir: cache_dir.clone(),
+                    cache_max_size: config_cache_max_size(&config),
+                    cache_min_time,
+                    legacy_peer_deps: config_flag(&config, "legacy-peer-deps"),
+                    ..InstallOptions::default()
+                };
+                handle_cache_add_command_async(&spec, deps, &options).await
+            },
+            CacheCommands::Clean { max_size } => handle_cache_clean_command(&cache_dir, max_size),
+        },
+        Commands::Rebuild { packages } => {
+            let options = InstallOptions {
+                log_level: base_level,
+                ignore_scripts,
+                prefix: prefix.clone(),
+                ..InstallOptions::default()
+            };
+            handle_rebuild_command(&packages, &options)
+        },
+        Commands::PrintConfig => {
+            handle_print_config_command(&config, &registries, &cache_dir, config_cache_max_size(&config))
+        },
+        Commands::Prune { production, dry_run, quiet } => {
+            handle_prune_command(&prefix, production, dry_run, quiet || base_level < LogLevel::Info)
+        },
+        Commands::Pack { dry_run, quiet } => {
+            handle_pack_command(&prefix, dry_run, quiet || base_level < LogLevel::Info)
+        },
+        Commands::Ls { prod, dev, optional, peer, all, json } => {
+            let options = LsOptions { prod, dev, optional, peer, all, json };
+            handle_ls_command(&prefix, &options)
+        },
+    }
+}
+
+// `--cache` > `RNP_CACHE_DIR` > `rnp-cache=` > `cache=` in .npmrc > ~/.rnp/cache.
+// rnp's entries don't clash with the `_cacache` folder npm keeps under `cache=`.
+fn cache_dir(flag: Option<PathBuf>, config: &Config) -> PathBuf {
+    if let Some(dir) = flag {
+        return dir;
+    }
+    if let Ok(dir) = std::env::var("RNP_CACHE_DIR")
+        && !dir.is_empty()
+    {
+        return match dir.strip_prefix("~/").zip(dirs::home_dir()) {
+            Some((rest, home)) => home.join(rest),
+            None => PathBuf::from(dir),
+        };
+    }
+    config
+        .path("rnp-cache")
+        .or_else(|| config.path("cache"))
+        .unwrap_or_else(PackageCache::default_dir)
+}
+
+// `--before 2024-03-01` or a full timestamp such as `2024-03-01T12:00:00Z`
+fn before_date(value: &str) -> Result<String, String> {
+    match registry::parse_iso8601(value) {
+        Some(_) => Ok(value.to_string()),
+        None => Err(format!("'{}' is not an ISO 8601 date (e.g. 2024-03-01 or 2024-03-01T12:00:00Z)", value)),
+    }
+}
+
+// `--loglevel`/`--silent` > a command's `-q`/`-v` > `loglevel` config > info
+fn log_level(explicit: Option<LogLevel>, config: &Config, quiet: bool, verbose: bool) -> LogLevel {
+    if let Some(level) = explicit {
+        return level;
+    }
+    if quiet {
+        return LogLevel::Error;
+    }
+    if verbose {
+        return LogLevel::Verbose;
+    }
+    config
+        .get("loglevel")
+        .and_then(|level| LogLevel::parse(level).ok())
+        .unwrap_or(LogLevel::Info)
+}
+
+// `--production` and `--no-optional` are older spellings of `--omit dev` and
+// `--omit optional`, and `--include` overrides them just the same
+fn omit_with_aliases(mut omit: Vec<String>, production: bool, no_optional: bool) -> Vec<String> {
+    if production {
+        omit.push("dev".to_string());
+    }
+    if no_optional {
+        omit.push("optional".to_string());
+    }
+    omit
+}
+
+fn config_offline_dir(config: &Config) -> Option<PathBuf> {
+    config.path("offline-dir")
+}
+
+// The extra keys `--verify-signatures` trusts (`signature-keys=<file>`),
+// only read when signatures are checked
+fn config_signature_keys(
+    config: &Config,
+    verify_signatures: bool,
+) -> Result<Vec<SigningKey>, Box<dyn std::error::Error + Send + Sync>> {
+    match config.path("signature-keys") {
+        Some(file) if verify_signatures => load_keys_file(&file),
+        _ => Ok(Vec::new()),
+    }
+}
+
+// `--os`/`--cpu`, else `os=`/`cpu=` in .npmrc, which must be one of the values
+// the flag accepts
+fn config_platform(
+    flag: Option<String>,
+    config: &Config,
+    key: &str,
+    known: &[&str],
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    match flag.as_deref().or_else(|| config.get(key)) {
+        Some(value) if !known.contains(&value) => Err(format!(
+            "'{}' is not a known value for `{}` in .npmrc (expected one of: {})",
+            value,
+            key,
+            known.join(", ")
+        )
+        .into()),
+        value => Ok(value.map(|v| v.to_string())),
+    }
+}
+
+fn config_flag(config: &Config, key: &str) -> bool {
+    config.get(key) == Some("true")
+}
+
+// Whether an install audits what it installed. `--audit-level` only decides
+// whether that audit fails the install, so without one it is an error rather
+// than silently doing nothing.
+fn install_audit(audit: bool, no_audit: bool, audit_level: Option<&str>) -> Result<bool, String> {
+    let enabled = audit && !no_audit;
+    match audit_level {
+        Some(level) if !enabled => Err(format!(
+            "--audit-level {} applies to the post-install audit; add --audit or set `audit=true` in .npmrc",
+            level
+        )),
+        _ => Ok(enabled),
+    }
+}
+
+// Ignored unless it is one of the levels `--audit-level` accepts
+fn config_audit_level(config: &Config) -> Option<String> {
+    config
+        .get("audit-level")
+        .filter(|level| ["low", "moderate", "high", "critical"].contains(level))
+        .map(|level| level.to_string())
+}
+
+fn config_save_prefix(config: &Config) -> String {
+    config.get("save-prefix").unwrap_or("^").to_string()
+}
+
+fn config_cache_max_size(config: &Config) -> u64 {
+    config
+        .get("cache-max-size")
+        .and_then(|value| parse_size(value).ok())
+        .unwrap_or(DEFAULT_MAX_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (no_package_lock, package_lock) as `args` leave them
+    fn package_lock_flags(args: &[&str]) -> (bool, bool) {
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Install { no_package_lock, package_lock, .. }
+            | Commands::Update { no_package_lock, package_lock, .. } => (no_package_lock, package_lock),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn the_last_package_lock_flag_wins() {
+        for command in ["install", "update"] {
+            assert_eq!(package_lock_flags(&["rnp", command, "--no-package-lock", "--package-lock"]), (false, true));
+            assert_eq!(package_lock_flags(&["rnp", command, "--package-lock", "--no-package-lock"]), (true, false));
+            assert_eq!(package_lock_flags(&["rnp", command, "--no-package-lock"]), (true, false));
+        }
+    }
+
+    #[test]
+    fn audit_level_needs_the_post_install_audit() {
+        assert_eq!(install_audit(true, false, Some("high")), Ok(true));
+        assert_eq!(install_audit(false, false, None), Ok(false));
+        assert_eq!(install_audit(true, true, None), Ok(false));
+        assert!(install_audit(false, false, Some("high")).is_err());
+        assert!(install_audit(true, true, Some("high")).is_err());
     }
 }
@@ -0,0 +1,116 @@
+use crate::output;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Reject anything but plain JSON in package.json (`--strict-json`)
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+// Parse a package.json. npm itself writes strict JSON, but files edited with
+// JSONC-aware editors pick up `//` and `/* */` comments and trailing commas;
+// unless `--strict-json` is set those are tolerated. When the file is still
+// invalid the error is the one strict parsing gave, which points at the
+// original line and column.
+pub fn parse(data: &str) -> Result<Value, serde_json::Error> {
+    serde_json::from_str(data).or_else(|err| {
+        if STRICT.load(Ordering::Relaxed) {
+            return Err(err);
+        }
+        serde_json::from_str(&strip_trailing_commas(&strip_comments(data))).map_err(|_| err)
+    })
+}
+
+// For a package.json rnp is about to rewrite: comments and trailing commas
+// don't survive serializing it again, so say so
+pub fn parse_for_edit(data: &str, path: &Path) -> Result<Value, serde_json::Error> {
+    let json = parse(data)?;
+    if serde_json::from_str::<Value>(data).is_err() {
+        output::warn(&format!(
+            "{} has comments or trailing commas; they are dropped when it is rewritten",
+            path.display()
+        ));
+    }
+    Ok(json)
+}
+
+// Blanks out comments outside of strings, keeping line breaks so error
+// positions stay meaningful
+fn strip_comments(data: &str) -> String {
+    let mut out = String::with_capacity(data.len());
+    let mut chars = data.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => {
+                while chars.next_if(|&next| next != '\n').is_some() {}
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        out.push('\n');
+                    }
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+                    previous = next;
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Drops a comma that only whitespace separates from the `}` or `]` after it
+fn strip_trailing_commas(data: &str) -> String {
+    let chars = data.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(data.len());
+    let mut in_string = false;
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        index += 1;
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' if index < chars.len() => {
+                    out.push(chars[index]);
+                    index += 1;
+                }
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        if c == ',' {
+            let next = chars[index..].iter().find(|next| !next.is_whitespace());
+            if matches!(next, Some('}') | Some(']')) {
+                continue;
+            }
+        }
+        if c == '"' {
+            in_string = true;
+        }
+        out.push(c);
+    }
+    out
+}
@@ -0,0 +1,79 @@
+use colored::{ColoredString, Colorize};
+use std::env;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+static DECORATIONS: AtomicBool = AtomicBool::new(true);
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+// How much to print, npm's `--loglevel`: each level includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Silent,
+    Error,
+    Warn,
+    Info,
+    Verbose,
+}
+
+impl LogLevel {
+    pub const NAMES: [&str; 5] = ["silent", "error", "warn", "info", "verbose"];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "silent" => Ok(Self::Silent),
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" | "notice" | "http" => Ok(Self::Info),
+            "verbose" | "silly" => Ok(Self::Verbose),
+            _ => Err(format!("invalid log level '{}': expected one of {}", value, Self::NAMES.join(", "))),
+        }
+    }
+}
+
+// The level for output that isn't tied to an InstallOptions (set once by the CLI)
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Silent,
+        1 => LogLevel::Error,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Info,
+        _ => LogLevel::Verbose,
+    }
+}
+
+pub fn warn(message: &str) {
+    if log_level() >= LogLevel::Warn {
+        eprintln!("{}{}", warning_marker(), message);
+    }
+}
+
+// Color and emoji are on only for an interactive stdout, and never with
+// `--no-color` or `NO_COLOR` set, so redirected logs stay plain text
+pub fn init(no_color: bool) {
+    let enabled = !no_color && env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal();
+    colored::control::set_override(enabled);
+    DECORATIONS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn decorations_enabled() -> bool {
+    DECORATIONS.load(Ordering::Relaxed)
+}
+
+pub fn warning_marker() -> &'static str {
+    if decorations_enabled() { "⚠️  " } else { "" }
+}
+
+pub fn severity(level: &str) -> ColoredString {
+    match level {
+        "critical" => level.red().bold(),
+        "high" => level.red(),
+        "moderate" => level.yellow(),
+        "low" => level.cyan(),
+        _ => level.normal(),
+    }
+}
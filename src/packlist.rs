@@ -0,0 +1,333 @@
+use crate::manifest;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Never part of a package, whatever `files` or the ignore files say
+const ALWAYS_EXCLUDED: [&str; 9] = [
+    "node_modules",
+    ".git",
+    ".svn",
+    ".hg",
+    "CVS",
+    ".DS_Store",
+    ".npmrc",
+    "npm-debug.log",
+    "package-lock.json",
+];
+
+// Always part of a package when present at its root
+const ALWAYS_INCLUDED_PREFIXES: [&str; 4] = ["readme", "license", "licence", "changelog"];
+
+struct IgnoreRule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+// Relative paths of the files that publishing `dir` would include: the
+// `files` allowlist when package.json has one, otherwise everything not
+// excluded by `.npmignore` (or `.gitignore` when there is no .npmignore).
+// Bundled dependencies come along from `node_modules`, each with its own
+// packlist.
+pub fn package_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    let manifest = fs::read_to_string(dir.join("package.json"))
+        .ok()
+        .and_then(|data| manifest::parse(&data).ok());
+
+    let allowlist = manifest
+        .as_ref()
+        .and_then(|json| json.get("files"))
+        .and_then(|v| v.as_array())
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|v| v.as_str())
+                .map(|entry| entry.trim_start_matches("./").trim_end_matches('/').to_string())
+                .filter(|entry| !entry.is_empty())
+                .collect::<Vec<_>>()
+        });
+
+    let main = manifest
+        .as_ref()
+        .and_then(|json| json.get("main"))
+        .and_then(|v| v.as_str())
+        .map(|main| main.trim_start_matches("./").to_string());
+
+    let ignore_rules = if dir.join(".npmignore").exists() {
+        read_ignore_rules(&dir.join(".npmignore"))
+    } else {
+        read_ignore_rules(&dir.join(".gitignore"))
+    };
+
+    let mut files = Vec::new();
+    collect_files(dir, Path::new(""), &mut files)?;
+
+    files.retain(|relative| {
+        let rel = to_slash_path(relative);
+        if rel == "package.json" || is_always_included(&rel) || main.as_deref() == Some(rel.as_str()) {
+            return true;
+        }
+
+        match &allowlist {
+            Some(entries) => entries.iter().any(|entry| matches_path_or_ancestor(entry, &rel)),
+            None => !is_ignored(&ignore_rules, &rel),
+        }
+    });
+
+    for name in manifest.as_ref().map(bundled_dependencies).unwrap_or_default() {
+        let bundle_dir = Path::new("node_modules").join(&name);
+        if !dir.join(&bundle_dir).is_dir() {
+            continue;
+        }
+        for relative in package_files(&dir.join(&bundle_dir))? {
+            files.push(bundle_dir.join(relative));
+        }
+    }
+
+    Ok(files)
+}
+
+// `bundleDependencies` (or `bundledDependencies`): a list of names, or `true`
+// for everything in `dependencies`
+fn bundled_dependencies(manifest: &Value) -> Vec<String> {
+    match manifest
+        .get("bundleDependencies")
+        .or_else(|| manifest.get("bundledDependencies"))
+    {
+        Some(Value::Bool(true)) => manifest
+            .get("dependencies")
+            .and_then(|v| v.as_object())
+            .map(|deps| deps.keys().cloned().collect())
+            .unwrap_or_default(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(|v| v.as_str())
+            .filter(|name| !name.is_empty() && !name.split('/').any(|part| part == ".." || part == "."))
+            .map(|name| name.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn collect_files(
+    root: &Path,
+    relative: &Path,
+    out: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if ALWAYS_EXCLUDED.iter().any(|excluded| name == *excluded) {
+            continue;
+        }
+
+        let child = relative.join(&name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(root, &child, out)?;
+        } else if file_type.is_file() {
+            out.push(child);
+        }
+    }
+    Ok(())
+}
+
+fn to_slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn is_always_included(rel: &str) -> bool {
+    if rel.contains('/') {
+        return false;
+    }
+    let lowered = rel.to_ascii_lowercase();
+    ALWAYS_INCLUDED_PREFIXES
+        .iter()
+        .any(|prefix| lowered.starts_with(prefix))
+}
+
+fn read_ignore_rules(path: &Path) -> Vec<IgnoreRule> {
+    let Ok(data) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+
+    data.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let dir_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let anchored = line.contains('/');
+            IgnoreRule {
+                pattern: line.trim_start_matches('/').to_string(),
+                negated,
+                dir_only,
+                anchored,
+            }
+        })
+        .collect()
+}
+
+// gitignore semantics: the last matching rule wins, and ignoring a
+// directory ignores everything below it
+fn is_ignored(rules: &[IgnoreRule], rel: &str) -> bool {
+    let segments = rel.split('/').collect::<Vec<_>>();
+    let mut ignored = false;
+
+    for (index, _) in segments.iter().enumerate() {
+        let candidate = segments[..=index].join("/");
+        let name = segments[index];
+        let is_dir = index + 1 < segments.len();
+
+        for rule in rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let matched = if rule.anchored {
+                glob_match(&rule.pattern, &candidate)
+            } else {
+                glob_match(&rule.pattern, name)
+            };
+            if matched {
+                ignored = !rule.negated;
+            }
+        }
+
+        if ignored && is_dir {
+            return true;
+        }
+    }
+
+    ignored
+}
+
+fn matches_path_or_ancestor(pattern: &str, rel: &str) -> bool {
+    let segments = rel.split('/').collect::<Vec<_>>();
+    (1..=segments.len()).any(|len| glob_match(pattern, &segments[..len].join("/")))
+}
+
+// `*` and `?` stay within a path segment, `**` crosses segments
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+
+    fn go(p: &[u8], t: &[u8]) -> bool {
+        match p.first() {
+            None => t.is_empty(),
+            Some(b'*') if p.get(1) == Some(&b'*') => {
+                let rest = p[2..].strip_prefix(b"/").unwrap_or(&p[2..]);
+                (0..=t.len()).any(|i| go(rest, &t[i..]))
+            }
+            Some(b'*') => {
+                let mut i = 0;
+                loop {
+                    if go(&p[1..], &t[i..]) {
+                        return true;
+                    }
+                    if i == t.len() || t[i] == b'/' {
+                        return false;
+                    }
+                    i += 1;
+                }
+            }
+            Some(b'?') => !t.is_empty() && t[0] != b'/' && go(&p[1..], &t[1..]),
+            Some(c) => t.first() == Some(c) && go(&p[1..], &t[1..]),
+        }
+    }
+
+    go(pattern, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, contents) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn packed(dir: &Path) -> Vec<String> {
+        let mut files = package_files(dir).unwrap().iter().map(|file| to_slash_path(file)).collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn the_files_list_is_an_allowlist() {
+        let dir = package(&[
+            ("package.json", r#"{ "files": ["./lib/", "bin/*.js"], "main": "index.js" }"#),
+            ("index.js", ""),
+            ("lib/a.js", ""),
+            ("lib/deep/b.js", ""),
+            ("bin/cli.js", ""),
+            ("bin/cli.sh", ""),
+            ("src/a.ts", ""),
+        ]);
+        assert_eq!(packed(dir.path()), ["bin/cli.js", "index.js", "lib/a.js", "lib/deep/b.js", "package.json"]);
+    }
+
+    #[test]
+    fn npmignore_takes_over_from_gitignore() {
+        let dir = package(&[
+            ("package.json", "{}"),
+            (".gitignore", "dist/\n"),
+            (".npmignore", "*.log\ntest/\n!keep.log\n"),
+            ("index.js", ""),
+            ("dist/out.js", ""),
+            ("debug.log", ""),
+            ("keep.log", ""),
+            ("test/a.test.js", ""),
+        ]);
+        assert_eq!(
+            packed(dir.path()),
+            [".gitignore", ".npmignore", "dist/out.js", "index.js", "keep.log", "package.json"]
+        );
+    }
+
+    #[test]
+    fn gitignore_applies_without_an_npmignore() {
+        let dir = package(&[
+            ("package.json", "{}"),
+            (".gitignore", "/build\n"),
+            ("index.js", ""),
+            ("build/out.js", ""),
+            ("src/build/x.js", ""),
+        ]);
+        assert_eq!(packed(dir.path()), [".gitignore", "index.js", "package.json", "src/build/x.js"]);
+    }
+
+    #[test]
+    fn some_files_are_always_included_and_some_never() {
+        let dir = package(&[
+            ("package.json", r#"{ "files": ["lib"] }"#),
+            (".npmignore", "README.md\n"),
+            ("README.md", ""),
+            ("LICENSE", ""),
+            ("CHANGELOG.md", ""),
+            ("docs/readme.md", ""),
+            ("lib/a.js", ""),
+            ("lib/node_modules/x/index.js", ""),
+            ("lib/.DS_Store", ""),
+            (".npmrc", ""),
+            ("package-lock.json", "{}"),
+            ("node_modules/dep/index.js", ""),
+        ]);
+        assert_eq!(packed(dir.path()), ["CHANGELOG.md", "LICENSE", "README.md", "lib/a.js", "package.json"]);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::config::Config;
+use reqwest::StatusCode;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue, RETRY_AFTER};
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_REGISTRY: &str = "https://registry.npmjs.org";
+
+// How many times one request waits out a 429 before giving up on it
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+// Used when a 429 comes without a usable Retry-After header
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// Set when any request is rate limited so every other in-flight request pauses
+// too, instead of each one running into its own 429.
+static RATE_LIMITED_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
+// Idle keep-alive connections kept per host (`pool-max-idle-per-host`). An
+// install fetches nearly every tarball from one CDN host, so there is a warm
+// connection for each concurrent download instead of a fresh TLS handshake.
+pub const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 32;
+// How long an idle connection stays open (`pool-idle-timeout`, in seconds).
+// reqwest closes them after 90s, which a slow resolve phase easily outlasts.
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// The primary registry followed by fallback mirrors, in the order they were
+// configured. A request only moves on to the next mirror when the previous
+// one answered with a 5xx or could not be reached in time.
+#[derive(Debug, Clone)]
+pub struct Registries {
+    urls: Vec<String>,
+    // `//host/path/` prefixes from `//host/path/:_authToken` config keys
+    auth_tokens: Vec<(String, String)>,
+    // `@scope:registry` entries: where a scope's packages are looked up
+    // instead of the registries above
+    scopes: Vec<(String, String)>,
+    pool_max_idle_per_host: usize,
+    pool_idle_timeout: Duration,
+}
+
+impl Default for Registries {
+    fn default() -> Self {
+        Self {
+            urls: vec![DEFAULT_REGISTRY.to_string()],
+            auth_tokens: Vec::new(),
+            scopes: Vec::new(),
+            pool_max_idle_per_host: DEFAULT_POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout: DEFAULT_POOL_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl Registries {
+    // Primary registry: `--registry` > `registry` config (env, then .npmrc) > npmjs.
+    // `--registry-fallback` mirrors come before the `registry-fallback` config list.
+    pub fn from_config(config: &Config, registry: Option<&str>, fallbacks: &[String]) -> Self {
+        let primary = registry.or_else(|| config.get("registry")).unwrap_or(DEFAULT_REGISTRY);
+        let configured = config
+            .get("registry-fallback")
+            .map(|list| list.split(',').map(|url| url.trim().to_string()).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let mut urls: Vec<String> = Vec::new();
+        for url in std::iter::once(primary.to_string())
+            .chain(fallbacks.iter().cloned())
+            .chain(configured)
+        {
+            let url = url.trim_end_matches('/').to_string();
+            if !url.is_empty() && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+
+        let mut auth_tokens = config
+            .entries()
+            .filter_map(|(key, value)| {
+                let prefix = key.strip_suffix(":_authToken")?;
+                // Always compare on a trailing slash so `//host` can't match `//host.evil`
+                let prefix = format!("{}/", prefix.trim_end_matches('/'));
+                prefix.starts_with("//").then(|| (prefix, value.to_string()))
+            })
+            .collect::<Vec<_>>();
+        // Most specific prefix first
+        auth_tokens.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+
+        let mut scopes = config
+            .entries()
+            .filter_map(|(key, value)| {
+                let scope = key.strip_suffix(":registry")?;
+                let url = value.trim().trim_end_matches('/');
+                (scope.starts_with('@') && !url.is_empty()).then(|| (scope.to_string(), url.to_string()))
+            })
+            .collect::<Vec<_>>();
+        scopes.sort();
+
+        Self {
+            urls,
+            auth_tokens,
+            scopes,
+            pool_max_idle_per_host: config
+                .get("pool-max-idle-per-host")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_POOL_MAX_IDLE_PER_HOST),
+            pool_idle_timeout: config
+                .get("pool-idle-timeout")
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT),
+        }
+    }
+
+    // The HTTP client every registry request goes through: bounded timeouts so
+    // a hung mirror can hand over to the next one, and a keep-alive pool sized
+    // from the config
+    pub fn client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .read_timeout(Duration::from_secs(30))
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(Duration::from_secs(60))
+            .gzip(true)
+            .build()
+            .unwrap_or_default()
+    }
+
+    fn auth_token(&self, url: &str) -> Option<&str> {
+        let without_scheme = &url[url.find("//")?..];
+        self.auth_tokens
+            .iter()
+            .find(|(prefix, _)| without_scheme.starts_with(prefix.as_str()))
+            .map(|(_, token)| token.as_str())
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.urls[0]
+    }
+
+    // The primary registry followed by its fallbacks
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    // The registry configured for the scope of `name` (`@scope:registry`)
+    pub fn scope_registry(&self, name: &str) -> Option<&str> {
+        let (scope, _) = name.split_once('/')?;
+        self.scopes
+            .iter()
+            .find(|(configured, _)| configured == scope)
+            .map(|(_, url)| url.as_str())
+    }
+
+    // `//host/path/` prefixes that have an auth token configured
+    pub fn auth_prefixes(&self) -> impl Iterator<Item = &str> {
+        self.auth_tokens.iter().map(|(prefix, _)| prefix.as_str())
+    }
+
+    // `url` itself, then the same path on every mirror when it points at the primary
+    fn candidates(&self, url: &str) -> Vec<(String, String)> {
+        match url.strip_prefix(self.primary()) {
+            Some(path) => self
+                .urls
+                .iter()
+                .map(|registry| (registry.clone(), format!("{}{}", registry, path)))
+                .collect(),
+            None => vec![(url.to_string(), url.to_string())],
+        }
+    }
+
+    // GET `url`, falling back through the mirrors. Returns the response along
+    // with the registry that served it.
+    pub async fn get(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+    ) -> Result<(reqwest::Response, String), Box<dyn Error + Send + Sync>> {
+        self.get_with_headers(client, url, HeaderMap::new()).await
+    }
+
+    pub async fn get_with_headers(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        headers: HeaderMap,
+    ) -> Result<(reqwest::Response, String), Box<dyn Error + Send + Sync>> {
+        let candidates = self.candidates(url);
+        let last = candidates.len() - 1;
+
+        for (index, (registry, candidate)) in candidates.into_iter().enumerate() {
+            let mut request_headers = headers.clone();
+            if let Some(token) = self.auth_token(&candidate)
+                && let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token))
+            {
+                request_headers.insert(AUTHORIZATION, value);
+            }
+
+            match send_with_retries(client, &candidate, request_headers).await {
+                Ok(response) if response.status().is_server_error() && index < last => continue,
+                // Still rate limited after waiting it out: try a mirror instead
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS && index < last => continue,
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    return Err(rate_limited(&candidate));
+                }
+                Ok(response) => return Ok((response, registry)),
+                Err(err) if (err.is_timeout() || err.is_connect()) && index < last => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        unreachable!("candidates always contains at least one url")
+    }
+
+    // GET `url` from that registry alone, without falling back to mirrors
+    pub async fn get_without_fallback(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+    ) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = self.auth_token(url)
+            && let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token))
+        {
+            headers.insert(AUTHORIZATION, value);
+        }
+        let response = send_with_retries(client, url, headers).await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(rate_limited(url));
+        }
+        Ok(response)
+    }
+
+    // POST a JSON body to `url` (no mirror fallback), with the auth token
+    // configured for it, if any
+    pub async fn post_json<T: serde::Serialize + ?Sized>(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        body: &T,
+    ) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+        let mut request = client.post(url).json(body);
+        if let Some(token) = self.auth_token(url) {
+            request = request.bearer_auth(token);
+        }
+        Ok(request.send().await?)
+    }
+}
+
+// Waits out up to MAX_RATE_LIMIT_RETRIES 429s from `url`
+async fn send_with_retries(
+    client: &reqwest::Client,
+    url: &str,
+    headers: HeaderMap,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut attempts = 0;
+    loop {
+        wait_for_rate_limit().await;
+        match client.get(url).headers(headers.clone()).send().await {
+            Ok(response)
+                if response.status() == StatusCode::TOO_MANY_REQUESTS && attempts < MAX_RATE_LIMIT_RETRIES =>
+            {
+                attempts += 1;
+                back_off(retry_after(response.headers()));
+            }
+            other => return other,
+        }
+    }
+}
+
+fn rate_limited(url: &str) -> Box<dyn Error + Send + Sync> {
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_else(|| url.to_string());
+    format!("rate limited by {} (still answering 429 after {} retries)", host, MAX_RATE_LIMIT_RETRIES).into()
+}
+
+async fn wait_for_rate_limit() {
+    let until = *RATE_LIMITED_UNTIL.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(until) = until {
+        tokio::time::sleep_until(until.into()).await;
+    }
+}
+
+fn back_off(delay: Duration) {
+    let until = Instant::now() + delay.min(MAX_RETRY_AFTER);
+    let mut guard = RATE_LIMITED_UNTIL.lock().unwrap_or_else(|e| e.into_inner());
+    if guard.is_none_or(|current| current < until) {
+        *guard = Some(until);
+    }
+}
+
+// `Retry-After` is either a number of seconds or an HTTP-date
+fn retry_after(headers: &HeaderMap) -> Duration {
+    let Some(value) = headers.get(RETRY_AFTER).and_then(|v| v.to_str().ok()) else {
+        return DEFAULT_RETRY_AFTER;
+    };
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Duration::from_secs(seconds);
+    }
+    parse_http_date(value)
+        .and_then(|at| at.duration_since(SystemTime::now()).ok())
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+// IMF-fixdate only, e.g. `Wed, 21 Oct 2015 07:28:00 GMT`
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    let [_, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS.iter().position(|m| m == month)? as i64 + 1;
+    let day = day.parse::<i64>().ok()?;
+    let year = year.parse::<i64>().ok()?;
+    let mut clock = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+
+    unix_time(year, month, day, hour * 3600 + minute * 60 + second)
+}
+
+// The publish times in a packument's `time` map, e.g. `2021-05-04T17:12:09.123Z`.
+// Also takes a bare date (midnight UTC), no seconds, and `+hh:mm` offsets.
+pub fn parse_iso8601(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let (date, time) = match value.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+
+    let mut ymd = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (ymd.next()??, ymd.next()??, ymd.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut seconds = 0;
+    if let Some(time) = time {
+        let (clock, offset) = if let Some(clock) = time.strip_suffix('Z') {
+            (clock, 0)
+        } else if let Some(at) = time.rfind(['+', '-']) {
+            let (hours, minutes) = time[at + 1..].split_once(':').unwrap_or((&time[at + 1..], "0"));
+            let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            (&time[..at], if time[at..].starts_with('-') { -offset } else { offset })
+        } else {
+            (time, 0)
+        };
+        let clock = clock.split('.').next().unwrap_or(clock);
+        let mut parts = clock.split(':').map(|part| part.parse::<i64>().ok());
+        let hour = parts.next()??;
+        let minute = parts.next()??;
+        let second = parts.next().unwrap_or(Some(0))?;
+        seconds = hour * 3600 + minute * 60 + second - offset;
+    }
+
+    unix_time(year, month, day, seconds)
+}
+
+fn unix_time(year: i64, month: i64, day: i64, seconds: i64) -> Option<SystemTime> {
+    // Days since the epoch for a proleptic Gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_registry::{Reply, TestRegistry};
+    use serde_json::json;
+
+    fn too_many_requests() -> Reply {
+        Reply::bytes(Vec::new()).with_status(429).header("Retry-After", "0")
+    }
+
+    #[tokio::test]
+    async fn waits_out_a_rate_limit() {
+        let registry = TestRegistry::start();
+        registry.route("/pkg", vec![too_many_requests(), Reply::json(&json!({ "name": "pkg" }))]);
+        let registries = Registries::from_config(&Config::default(), Some(registry.url()), &[]);
+
+        let url = format!("{}/pkg", registry.url());
+        let (response, served_by) = registries.get(&registries.client(), &url).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(served_by, registry.url());
+        assert_eq!(registry.hits("/pkg"), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_registry_that_stays_rate_limited() {
+        let registry = TestRegistry::start();
+        registry.route("/pkg", vec![too_many_requests()]);
+        let registries = Registries::from_config(&Config::default(), Some(registry.url()), &[]);
+
+        let url = format!("{}/pkg", registry.url());
+        let err = registries.get(&registries.client(), &url).await.unwrap_err();
+        assert_eq!(err.to_string(), "rate limited by 127.0.0.1 (still answering 429 after 3 retries)");
+        assert_eq!(registry.hits("/pkg"), 1 + MAX_RATE_LIMIT_RETRIES as usize);
+    }
+
+    #[tokio::test]
+    async fn moves_on_to_a_mirror_when_rate_limited() {
+        let primary = TestRegistry::start();
+        primary.route("/pkg", vec![too_many_requests()]);
+        let mirror = TestRegistry::start();
+        mirror.route("/pkg", vec![Reply::json(&json!({ "name": "pkg" }))]);
+        let registries = Registries::from_config(&Config::default(), Some(primary.url()), &[mirror.url().to_string()]);
+
+        let url = format!("{}/pkg", primary.url());
+        let (response, served_by) = registries.get(&registries.client(), &url).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(served_by, mirror.url());
+    }
+
+    #[test]
+    fn reads_retry_after_in_seconds_and_as_a_date() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(retry_after(&headers), Duration::from_secs(7));
+        assert_eq!(
+            parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1445412480))
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::registry::{Registries, parse_iso8601};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ring::signature::{ECDSA_P256_SHA256_ASN1, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// The only scheme the npm registry signs with
+const SUPPORTED_SCHEME: &str = "ecdsa-sha2-nistp256";
+
+// DER header of a SubjectPublicKeyInfo holding an uncompressed P-256 point;
+// what follows it is the 65-byte point itself
+const P256_SPKI_PREFIX: [u8; 26] = [
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48,
+    0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+// A registry signing key, in the format `<registry>/-/npm/v1/keys` lists them
+#[derive(Debug, Clone, Deserialize)]
+pub struct SigningKey {
+    pub keyid: String,
+    pub scheme: String,
+    // Base64 DER SubjectPublicKeyInfo
+    pub key: String,
+    // Signatures on versions published after this are not accepted
+    #[serde(default)]
+    pub expires: Option<String>,
+    // `signature-keys` files only: the registry the key is trusted for; a key
+    // without one is trusted for every registry
+    #[serde(default)]
+    pub registry: Option<String>,
+}
+
+impl SigningKey {
+    pub fn applies_to(&self, registry: &str) -> bool {
+        self.registry
+            .as_deref()
+            .is_none_or(|own| own.trim_end_matches('/') == registry.trim_end_matches('/'))
+    }
+}
+
+#[derive(Deserialize)]
+struct KeysDocument {
+    keys: Vec<SigningKey>,
+}
+
+// The keys a registry publishes. A registry that doesn't sign (no endpoint)
+// has none. Asked of `registry` alone: a mirror must not get to pick the keys
+// its own metadata is checked against.
+pub async fn fetch_registry_keys(
+    client: &reqwest::Client,
+    registries: &Registries,
+    registry: &str,
+) -> Result<Vec<SigningKey>, Box<dyn Error + Send + Sync>> {
+    let url = format!("{}/-/npm/v1/keys", registry.trim_end_matches('/'));
+    let response = registries.get_without_fallback(client, &url).await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(Vec::new());
+    }
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", url, response.status()).into());
+    }
+    let document: KeysDocument = response
+        .json()
+        .await
+        .map_err(|e| format!("{} returned malformed signing keys: {}", url, e))?;
+    Ok(document.keys)
+}
+
+// Extra trusted keys (`signature-keys` in .npmrc), a file in the same
+// `{ "keys": [...] }` format the registry serves
+pub fn load_keys_file(path: &Path) -> Result<Vec<SigningKey>, Box<dyn Error + Send + Sync>> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let document: KeysDocument =
+        serde_json::from_str(&data).map_err(|e| format!("{} is not a valid keys file: {}", path.display(), e))?;
+    Ok(document.keys)
+}
+
+// Check `dist.signatures` of one version against `keys`. The registry signs
+// `<name>@<version>:<integrity>`; one valid signature from a key that hadn't
+// expired when the version was published is enough.
+pub fn verify_version(
+    name: &str,
+    version: &str,
+    version_info: &Value,
+    published: Option<&str>,
+    keys: &[SigningKey],
+) -> Result<(), String> {
+    let package = format!("{}@{}", name, version);
+    let integrity = version_info["dist"]["integrity"]
+        .as_str()
+        .ok_or_else(|| format!("{} has no integrity for its signature to cover", package))?;
+    let signatures = version_info["dist"]["signatures"]
+        .as_array()
+        .filter(|signatures| !signatures.is_empty())
+        .ok_or_else(|| format!("{} has no registry signature", package))?;
+
+    let message = format!("{}:{}", package, integrity);
+    let mut problem = None;
+    for signature in signatures {
+        let (Some(keyid), Some(sig)) = (signature["keyid"].as_str(), signature["sig"].as_str()) else {
+            continue;
+        };
+        let Some(key) = keys.iter().find(|key| key.keyid == keyid) else {
+            problem.get_or_insert_with(|| format!("{} is signed with an untrusted key {}", package, keyid));
+            continue;
+        };
+        if let Some(expires) = key.expires.as_deref().and_then(parse_iso8601)
+            && published.and_then(parse_iso8601).is_none_or(|published| published > expires)
+        {
+            problem = Some(format!("{} is signed with key {}, which expired before it was published", package, keyid));
+            continue;
+        }
+        match verify_signature(key, message.as_bytes(), sig) {
+            Ok(()) => return Ok(()),
+            Err(err) => problem = Some(format!("{}: {}", package, err)),
+        }
+    }
+    Err(problem.unwrap_or_else(|| format!("{} has no usable registry signature", package)))
+}
+
+fn verify_signature(key: &SigningKey, message: &[u8], sig: &str) -> Result<(), String> {
+    if key.scheme != SUPPORTED_SCHEME {
+        return Err(format!("key {} uses unsupported scheme {}", key.keyid, key.scheme));
+    }
+    let spki = STANDARD
+        .decode(&key.key)
+        .map_err(|_| format!("key {} is not valid base64", key.keyid))?;
+    let point = spki
+        .strip_prefix(P256_SPKI_PREFIX.as_slice())
+        .ok_or_else(|| format!("key {} is not a P-256 public key", key.keyid))?;
+    let sig = STANDARD
+        .decode(sig)
+        .map_err(|_| "signature is not valid base64".to_string())?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+        .verify(message, &sig)
+        .map_err(|_| format!("signature from key {} does not verify", key.keyid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, KeyPair};
+    use serde_json::json;
+
+    const INTEGRITY: &str = "sha512-abc";
+
+    fn key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap()
+    }
+
+    fn signing_key(pair: &EcdsaKeyPair, keyid: &str, expires: Option<&str>) -> SigningKey {
+        let mut spki = P256_SPKI_PREFIX.to_vec();
+        spki.extend_from_slice(pair.public_key().as_ref());
+        SigningKey {
+            keyid: keyid.to_string(),
+            scheme: SUPPORTED_SCHEME.to_string(),
+            key: STANDARD.encode(spki),
+            expires: expires.map(|e| e.to_string()),
+            registry: None,
+        }
+    }
+
+    fn signed_version(pair: &EcdsaKeyPair, keyid: &str, message: &str) -> Value {
+        let sig = pair.sign(&SystemRandom::new(), message.as_bytes()).unwrap();
+        json!({ "dist": { "integrity": INTEGRITY, "signatures": [{ "keyid": keyid, "sig": STANDARD.encode(sig.as_ref()) }] } })
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let pair = key_pair();
+        let version = signed_version(&pair, "k1", "pkg@1.0.0:sha512-abc");
+        let keys = [signing_key(&pair, "k1", None)];
+        assert_eq!(verify_version("pkg", "1.0.0", &version, None, &keys), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_signature_over_other_content() {
+        let pair = key_pair();
+        let version = signed_version(&pair, "k1", "pkg@1.0.1:sha512-abc");
+        let keys = [signing_key(&pair, "k1", None)];
+        let err = verify_version("pkg", "1.0.0", &version, None, &keys).unwrap_err();
+        assert!(err.contains("does not verify"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_untrusted_and_missing_signatures() {
+        let pair = key_pair();
+        let version = signed_version(&pair, "k1", "pkg@1.0.0:sha512-abc");
+        let other = [signing_key(&key_pair(), "k2", None)];
+        let err = verify_version("pkg", "1.0.0", &version, None, &other).unwrap_err();
+        assert!(err.contains("untrusted key k1"), "{}", err);
+
+        let unsigned = json!({ "dist": { "integrity": INTEGRITY } });
+        let err = verify_version("pkg", "1.0.0", &unsigned, None, &other).unwrap_err();
+        assert!(err.contains("no registry signature"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_keys_expired_before_publishing() {
+        let pair = key_pair();
+        let version = signed_version(&pair, "k1", "pkg@1.0.0:sha512-abc");
+        let keys = [signing_key(&pair, "k1", Some("2020-01-01T00:00:00Z"))];
+        assert!(verify_version("pkg", "1.0.0", &version, Some("2021-06-01T00:00:00Z"), &keys).is_err());
+        assert_eq!(
+            verify_version("pkg", "1.0.0", &version, Some("2019-06-01T00:00:00Z"), &keys),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn keys_apply_to_their_own_registry() {
+        let mut key = signing_key(&key_pair(), "k1", None);
+        assert!(key.applies_to("https://npm.acme.dev"));
+        key.registry = Some("https://npm.acme.dev/".to_string());
+        assert!(key.applies_to("https://npm.acme.dev"));
+        assert!(!key.applies_to("https://registry.npmjs.org"));
+    }
+}
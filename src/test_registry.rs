@@ -0,0 +1,159 @@
+// A throwaway npm registry for tests: canned responses per path on a local
+// port, with a record of every request each path received.
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone)]
+pub struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn json(value: &Value) -> Self {
+        Self::bytes(value.to_string().into_bytes()).header("Content-Type", "application/json")
+    }
+
+    pub fn bytes(body: Vec<u8>) -> Self {
+        Self { status: 200, headers: Vec::new(), body }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // Replies are served in order; the last one repeats
+    routes: HashMap<String, Vec<Reply>>,
+    // The headers of each request, names lowercased
+    requests: HashMap<String, Vec<HashMap<String, String>>>,
+}
+
+pub struct TestRegistry {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl TestRegistry {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = shared.clone();
+                thread::spawn(move || serve(stream, &state));
+            }
+        });
+        Self { url, state }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn route(&self, path: &str, replies: Vec<Reply>) {
+        self.state.lock().unwrap().routes.insert(path.to_string(), replies);
+    }
+
+    // Serve `name`'s packument with these versions and a tarball for each
+    pub fn publish(&self, name: &str, versions: &[(&str, Value)]) {
+        let mut documents = Map::new();
+        for (version, manifest) in versions {
+            let mut manifest = manifest.as_object().cloned().unwrap_or_default();
+            let path = format!("/{}/-/{}-{}.tgz", name, name.rsplit('/').next().unwrap(), version);
+            manifest.insert("name".into(), json!(name));
+            manifest.insert("version".into(), json!(version));
+            manifest.insert("dist".into(), json!({ "tarball": format!("{}{}", self.url, path) }));
+            let package_json = Value::Object(manifest.clone()).to_string();
+            self.route(&path, vec![Reply::bytes(tarball(&[("package/package.json", &package_json)]))]);
+            documents.insert(version.to_string(), Value::Object(manifest));
+        }
+        let latest = versions.last().map(|(version, _)| *version).unwrap_or_default();
+        let packument = json!({ "name": name, "dist-tags": { "latest": latest }, "versions": documents });
+        self.route(&format!("/{}", name), vec![Reply::json(&packument)]);
+    }
+
+    pub fn requests(&self, path: &str) -> Vec<HashMap<String, String>> {
+        self.state.lock().unwrap().requests.get(path).cloned().unwrap_or_default()
+    }
+
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests(path).len()
+    }
+}
+
+// A gzipped tarball holding `files`
+pub fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    if let Some(length) = headers.get("content-length").and_then(|v| v.parse::<usize>().ok()) {
+        let mut body = vec![0; length];
+        let _ = reader.read_exact(&mut body);
+    }
+
+    let reply = {
+        let mut state = state.lock().unwrap();
+        let served = state.requests.get(&path).map_or(0, |requests| requests.len());
+        state.requests.entry(path.clone()).or_default().push(headers);
+        state
+            .routes
+            .get(&path)
+            .and_then(|replies| replies.get(served).or(replies.last()))
+            .cloned()
+            .unwrap_or_else(|| Reply::json(&json!({ "error": "Not found" })).with_status(404))
+    };
+
+    let mut response = format!("HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n", reply.status, reply.body.len());
+    for (name, value) in &reply.headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    let mut stream = stream;
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.write_all(&reply.body);
+}
@@ -0,0 +1,45 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A token bucket shared by every download of an install (`--max-rate`), so
+// the limit applies to the aggregate throughput rather than per connection.
+// Up to one second worth of bytes may be used in a burst.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    // Tokens available and when they were last refilled; tokens go negative
+    // when a chunk is bigger than what is left, which makes later callers
+    // wait off the debt too
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            state: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+
+    // Takes `bytes` from the bucket, sleeping for as long as it is overdrawn
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.bytes_per_sec)
+                .min(self.bytes_per_sec);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
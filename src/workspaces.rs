@@ -0,0 +1,289 @@
+use crate::manifest;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A package found through the root package.json's `workspaces` globs
+#[derive(Debug, Clone)]
+pub struct WorkspaceMember {
+    pub name: String,
+    pub dir: PathBuf,
+    pub manifest: Value,
+}
+
+// Every named member of the workspace rooted at `root`, sorted by name. A
+// project without `workspaces` has none.
+pub fn workspace_members(root: &Path) -> Result<Vec<WorkspaceMember>, Box<dyn Error + Send + Sync>> {
+    let mut members = Vec::new();
+    for dir in discover_workspaces(root)? {
+        let data = fs::read_to_string(dir.join("package.json"))?;
+        let manifest = manifest::parse(&data)?;
+        let Some(name) = manifest.get("name").and_then(|v| v.as_str()) else {
+            continue;
+        };
+        members.push(WorkspaceMember {
+            name: name.to_string(),
+            dir,
+            manifest,
+        });
+    }
+
+    members.sort_by(|a, b| a.name.cmp(&b.name));
+    members.dedup_by(|a, b| a.name == b.name);
+    Ok(members)
+}
+
+// The directories with a package.json that the root package.json's
+// `workspaces` globs match, sorted. Both the array form and the object form
+// (`{ "packages": [...], "nohoist": [...] }`, whose other fields don't pick
+// members) are read. A pattern starting with `!` drops what it matches, and
+// `node_modules` is never searched.
+pub fn discover_workspaces(root: &Path) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    let root_manifest = root.join("package.json");
+    if !root_manifest.exists() {
+        return Ok(Vec::new());
+    }
+
+    let data = fs::read_to_string(&root_manifest)?;
+    let json = manifest::parse(&data)?;
+
+    let mut dirs = BTreeSet::new();
+    let mut excluded = BTreeSet::new();
+    for pattern in workspace_patterns(&json) {
+        match pattern.strip_prefix('!') {
+            Some(negated) => excluded.extend(expand_workspace_pattern(root, negated)),
+            None => dirs.extend(expand_workspace_pattern(root, &pattern)),
+        }
+    }
+
+    Ok(dirs
+        .into_iter()
+        .filter(|dir| !excluded.contains(dir) && dir.as_path() != root && dir.join("package.json").is_file())
+        .collect())
+}
+
+// The directories a command scoped with `--workspace <name>` or
+// `--workspaces` works in: that member, every member, or else the root
+pub fn selected_workspace_dirs(
+    root: &Path,
+    workspace: Option<&str>,
+    all: bool,
+) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    if all {
+        let dirs = workspace_members(root)?
+            .into_iter()
+            .map(|member| member.dir)
+            .collect::<Vec<_>>();
+        if dirs.is_empty() {
+            return Err("No workspaces found in package.json".into());
+        }
+        return Ok(dirs);
+    }
+
+    match workspace {
+        Some(name) => Ok(vec![workspace_dir(root, name)?]),
+        None => Ok(vec![root.to_path_buf()]),
+    }
+}
+
+pub fn workspace_dir(root: &Path, name: &str) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    workspace_members(root)?
+        .into_iter()
+        .find(|member| member.name == name)
+        .map(|member| member.dir)
+        .ok_or_else(|| format!("Workspace '{}' not found", name).into())
+}
+
+// The `workspaces` globs, from either the array form or the object form
+// (`{ "packages": [...] }`)
+fn workspace_patterns(root_json: &Value) -> Vec<String> {
+    let patterns = match root_json.get("workspaces") {
+        Some(Value::Array(patterns)) => patterns,
+        Some(workspaces) => match workspaces.get("packages").and_then(|v| v.as_array()) {
+            Some(patterns) => patterns,
+            None => return Vec::new(),
+        },
+        None => return Vec::new(),
+    };
+    patterns
+        .iter()
+        .filter_map(|pattern| pattern.as_str().map(|s| s.to_string()))
+        .collect()
+}
+
+// Directories matching a glob relative to `root`, one path segment at a
+// time: `*` and `?` match within a segment, `**` any number of segments
+fn expand_workspace_pattern(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let segments = pattern
+        .trim_start_matches("./")
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".");
+
+    let mut dirs = vec![root.to_path_buf()];
+    for segment in segments {
+        let mut next = Vec::new();
+        for dir in &dirs {
+            if segment == "**" {
+                next.push(dir.clone());
+                collect_subdirs(dir, &mut HashSet::new(), &mut next);
+            } else if segment.contains(['*', '?']) {
+                next.extend(subdirs(dir).into_iter().filter(|sub| {
+                    sub.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| wildcard_match(segment, name))
+                }));
+            } else if dir.join(segment).is_dir() {
+                next.push(dir.join(segment));
+            }
+        }
+        dirs = next;
+    }
+    dirs
+}
+
+// Subdirectories a glob may descend into: not hidden, not node_modules
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    // An empty prefix means the current directory
+    let readable = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let Ok(entries) = fs::read_dir(readable) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| dir.join(entry.file_name()))
+        .filter(|path| path.is_dir())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| !name.starts_with('.') && name != "node_modules")
+        })
+        .collect()
+}
+
+// Every directory below `dir`. Symlinked directories are followed, but each
+// real directory only once, so a link back up the tree can't loop forever.
+fn collect_subdirs(dir: &Path, visited: &mut HashSet<PathBuf>, out: &mut Vec<PathBuf>) {
+    for sub in subdirs(dir) {
+        let Ok(real) = fs::canonicalize(&sub) else {
+            continue;
+        };
+        if !visited.insert(real) {
+            continue;
+        }
+        collect_subdirs(&sub, visited, out);
+        out.push(sub);
+    }
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of `name` it has swallowed so far
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, consumed)) => {
+                    p = star + 1;
+                    n = consumed + 1;
+                    backtrack = Some((star, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(root_manifest: &str, members: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("package.json"), root_manifest).unwrap();
+        for member in members {
+            let member_dir = dir.path().join(member);
+            fs::create_dir_all(&member_dir).unwrap();
+            let name = member.rsplit('/').next().unwrap();
+            fs::write(member_dir.join("package.json"), format!(r#"{{ "name": "{}" }}"#, name)).unwrap();
+        }
+        dir
+    }
+
+    fn relative(root: &Path, dirs: Vec<PathBuf>) -> Vec<String> {
+        dirs.iter()
+            .map(|dir| dir.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_array_form() {
+        let dir = project(r#"{ "workspaces": ["packages/a", "tools/cli"] }"#, &["packages/a", "packages/b", "tools/cli"]);
+        let found = discover_workspaces(dir.path()).unwrap();
+        assert_eq!(relative(dir.path(), found), ["packages/a", "tools/cli"]);
+    }
+
+    #[test]
+    fn reads_the_object_form() {
+        let dir = project(
+            r#"{ "workspaces": { "packages": ["packages/*"], "nohoist": ["**/react"] } }"#,
+            &["packages/a", "packages/b"],
+        );
+        let found = discover_workspaces(dir.path()).unwrap();
+        assert_eq!(relative(dir.path(), found), ["packages/a", "packages/b"]);
+    }
+
+    #[test]
+    fn expands_globs_and_exclusions() {
+        let dir = project(
+            r#"{ "workspaces": ["packages/*", "!packages/private", "apps/**"] }"#,
+            &["packages/a", "packages/private", "apps/web", "apps/group/api"],
+        );
+        // Directories without a package.json are not members
+        fs::create_dir_all(dir.path().join("packages/empty")).unwrap();
+        let found = discover_workspaces(dir.path()).unwrap();
+        assert_eq!(
+            relative(dir.path(), found),
+            ["apps/group/api", "apps/web", "packages/a"]
+        );
+    }
+
+    #[test]
+    fn survives_symlink_loops() {
+        let dir = project(r#"{ "workspaces": ["apps/**"] }"#, &["apps/web"]);
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("apps"), dir.path().join("apps/web/loop")).unwrap();
+        let found = discover_workspaces(dir.path()).unwrap();
+        assert!(relative(dir.path(), found).contains(&"apps/web".to_string()));
+    }
+
+    #[test]
+    fn members_are_sorted_by_name() {
+        let dir = project(r#"{ "workspaces": ["packages/*"] }"#, &["packages/zeta", "packages/alpha"]);
+        let names = workspace_members(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|member| member.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["alpha", "zeta"]);
+    }
+
+    #[test]
+    fn wildcards_match_within_a_segment() {
+        assert!(wildcard_match("pkg-*", "pkg-core"));
+        assert!(wildcard_match("?b*", "abc"));
+        assert!(!wildcard_match("pkg-*", "lib-core"));
+    }
+}